@@ -0,0 +1,23 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+fn main() {
+    let dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let out = PathBuf::from(env::var("OUT_DIR").unwrap()).join("ninat.h");
+
+    cbindgen::generate(&dir)
+        .expect("failed to generate the header")
+        .write_to_file(&out);
+
+    // The header checked in under include is only updated on request, so builds never write into
+    // the source tree
+    if env::var_os("NINAT_FFI_UPDATE_HEADER").is_some() {
+        let header = PathBuf::from(&dir).join("include").join("ninat.h");
+        fs::copy(&out, header).expect("failed to update the header");
+    }
+
+    println!("cargo:rerun-if-changed=src/lib.rs");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+    println!("cargo:rerun-if-env-changed=NINAT_FFI_UPDATE_HEADER");
+}
@@ -0,0 +1,261 @@
+#ifndef NINAT_H
+#define NINAT_H
+
+/* Generated by cbindgen from ninat-ffi, do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * Enumeration of errors.
+ */
+typedef enum NinatError {
+  /**
+   * Represents no error.
+   */
+  NINAT_ERROR_OK = 0,
+  /**
+   * Represents a null pointer or an invalid value passed.
+   */
+  NINAT_ERROR_INVALID_ARGUMENT,
+  /**
+   * Represents a hostname which cannot be resolved.
+   */
+  NINAT_ERROR_NOT_FOUND,
+  /**
+   * Represents the servers not answering in time.
+   */
+  NINAT_ERROR_TIMED_OUT,
+  /**
+   * Represents a permission denied by the system.
+   */
+  NINAT_ERROR_PERMISSION_DENIED,
+  /**
+   * Represents a local address in use.
+   */
+  NINAT_ERROR_ADDR_IN_USE,
+  /**
+   * Represents the network refusing or dropping the connection.
+   */
+  NINAT_ERROR_NETWORK,
+  /**
+   * Represents a test cancelled by `ninat_tester_cancel`.
+   */
+  NINAT_ERROR_CANCELLED,
+  /**
+   * Represents any other I/O error.
+   */
+  NINAT_ERROR_IO,
+  /**
+   * Represents a panic of the library, caught before it reaches C.
+   */
+  NINAT_ERROR_PANIC,
+  /**
+   * Represents a SOCKS proxy which cannot be connected to.
+   */
+  NINAT_ERROR_PROXY_UNREACHABLE,
+  /**
+   * Represents a SOCKS proxy rejecting the username and password.
+   */
+  NINAT_ERROR_PROXY_AUTH,
+  /**
+   * Represents any other error of a SOCKS proxy, like refusing to relay UDP.
+   */
+  NINAT_ERROR_PROXY,
+} NinatError;
+
+/**
+ * Enumeration of NAT types.
+ */
+typedef enum NinatNatType {
+  NINAT_NAT_TYPE_A,
+  NINAT_NAT_TYPE_B,
+  NINAT_NAT_TYPE_C,
+  NINAT_NAT_TYPE_D,
+  NINAT_NAT_TYPE_F,
+} NinatNatType;
+
+/**
+ * Represents the result of a NAT test.
+ */
+typedef struct NinatResult NinatResult;
+
+/**
+ * Represents a tester of NAT tests.
+ */
+typedef struct NinatTester NinatTester;
+
+/**
+ * Represents the callback of `ninat_tester_run_async`, called with the error and the result,
+ * which is null unless the error is `NINAT_ERROR_OK`, and the user data. The callback owns the
+ * result, and frees it by `ninat_result_free`.
+ */
+typedef void (*NinatCallback)(enum NinatError error, struct NinatResult *result, void *user_data);
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Creates a new tester against the Nintendo servers. The tester is freed by `ninat_tester_free`.
+ */
+struct NinatTester *ninat_tester_new(void);
+
+/**
+ * Frees the tester.
+ *
+ * # Safety
+ *
+ * The tester must be created by `ninat_tester_new` and not freed yet, or null.
+ */
+void ninat_tester_free(struct NinatTester *tester);
+
+/**
+ * Sets the servers, by hostname or IP address, resolved when the test runs.
+ *
+ * # Safety
+ *
+ * The tester must be valid, and the servers nul-terminated strings.
+ */
+enum NinatError ninat_tester_set_servers(struct NinatTester *tester,
+                                         const char *server1,
+                                         const char *server2);
+
+/**
+ * Sets the SOCKS5 proxy, as `host:port`, and its username and password, which may be null if
+ * the proxy needs no authentication.
+ *
+ * # Safety
+ *
+ * The tester must be valid, and the strings nul-terminated or null.
+ */
+enum NinatError ninat_tester_set_proxy(struct NinatTester *tester,
+                                       const char *proxy,
+                                       const char *username,
+                                       const char *password);
+
+/**
+ * Sets the timeout of the whole test in milliseconds, `0` as no timeout.
+ *
+ * # Safety
+ *
+ * The tester must be valid.
+ */
+enum NinatError ninat_tester_set_timeout(struct NinatTester *tester, uint64_t timeout);
+
+/**
+ * Sets the number of times to rerun the test while it fails with NAT type F.
+ *
+ * # Safety
+ *
+ * The tester must be valid.
+ */
+enum NinatError ninat_tester_set_retries(struct NinatTester *tester, uint32_t retries);
+
+/**
+ * Sets the local address to bind to, as `ip:port`.
+ *
+ * # Safety
+ *
+ * The tester must be valid, and the address a nul-terminated string.
+ */
+enum NinatError ninat_tester_set_bind(struct NinatTester *tester, const char *bind);
+
+/**
+ * Runs a NAT test, blocking until it finishes. The result is freed by `ninat_result_free`.
+ *
+ * # Safety
+ *
+ * The tester must be valid, and the result a valid pointer.
+ */
+enum NinatError ninat_tester_run(const struct NinatTester *tester, struct NinatResult **result);
+
+/**
+ * Runs a NAT test on another thread, calling the callback once it finishes. The tester may be
+ * changed or freed while the test runs, which does not affect it.
+ *
+ * # Safety
+ *
+ * The tester must be valid, and the user data safe to use from another thread.
+ */
+enum NinatError ninat_tester_run_async(const struct NinatTester *tester,
+                                       NinatCallback callback,
+                                       void *user_data);
+
+/**
+ * Cancels the tests of the tester in progress, which return `NINAT_ERROR_CANCELLED`. Tests run
+ * later are not affected.
+ *
+ * # Safety
+ *
+ * The tester must be valid.
+ */
+enum NinatError ninat_tester_cancel(const struct NinatTester *tester);
+
+/**
+ * Frees the result.
+ *
+ * # Safety
+ *
+ * The result must be returned by a test and not freed yet, or null.
+ */
+void ninat_result_free(struct NinatResult *result);
+
+/**
+ * Returns the NAT type of the result.
+ *
+ * # Safety
+ *
+ * The result must be valid.
+ */
+enum NinatNatType ninat_result_nat(const struct NinatResult *result);
+
+/**
+ * Writes the remote IP address of the result into the 4 bytes of `octets`, returning if it is
+ * known.
+ *
+ * # Safety
+ *
+ * The result must be valid, and `octets` point to 4 bytes at least.
+ */
+bool ninat_result_ip(const struct NinatResult *result, uint8_t *octets);
+
+/**
+ * Returns the Nintendo (Nintendo Switch) NAT type of the result, valid until it is freed.
+ *
+ * # Safety
+ *
+ * The result must be valid.
+ */
+const char *ninat_result_nintendo(const struct NinatResult *result);
+
+/**
+ * Returns the Sony (PlayStation) NAT type of the result, valid until it is freed.
+ *
+ * # Safety
+ *
+ * The result must be valid.
+ */
+const char *ninat_result_sony(const struct NinatResult *result);
+
+/**
+ * Returns the Microsoft (Xbox) NAT type of the result, valid until it is freed.
+ *
+ * # Safety
+ *
+ * The result must be valid.
+ */
+const char *ninat_result_microsoft(const struct NinatResult *result);
+
+/**
+ * Returns the description of the error, a static string.
+ */
+const char *ninat_error_message(enum NinatError error);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif // __cplusplus
+
+#endif /* NINAT_H */
@@ -0,0 +1,540 @@
+//! C bindings of ninat.
+//!
+//! A tester is created by `ninat_tester_new`, configured by the `ninat_tester_set_*` functions,
+//! and run by `ninat_tester_run` synchronously or by `ninat_tester_run_async` with a callback.
+//! Every function returning a `NinatError` leaves its outputs untouched unless it returns
+//! `NINAT_ERROR_OK`. A panic of the library never unwinds into C, and is reported as
+//! `NINAT_ERROR_PANIC`, or as a null pointer or a default value by functions without an error.
+
+use ninat::cancel::{CancelToken, Cancelled};
+use ninat::tester::NatTester;
+use ninat::{NatType, ProxyError};
+use std::ffi::{CStr, CString};
+use std::io;
+use std::mem;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, ToSocketAddrs};
+use std::os::raw::{c_char, c_void};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+/// Enumeration of errors.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum NinatError {
+    /// Represents no error.
+    Ok = 0,
+    /// Represents a null pointer or an invalid value passed.
+    InvalidArgument,
+    /// Represents a hostname which cannot be resolved.
+    NotFound,
+    /// Represents the servers not answering in time.
+    TimedOut,
+    /// Represents a permission denied by the system.
+    PermissionDenied,
+    /// Represents a local address in use.
+    AddrInUse,
+    /// Represents the network refusing or dropping the connection.
+    Network,
+    /// Represents a test cancelled by `ninat_tester_cancel`.
+    Cancelled,
+    /// Represents any other I/O error.
+    Io,
+    /// Represents a panic of the library, caught before it reaches C.
+    Panic,
+    /// Represents a SOCKS proxy which cannot be connected to.
+    ProxyUnreachable,
+    /// Represents a SOCKS proxy rejecting the username and password.
+    ProxyAuth,
+    /// Represents any other error of a SOCKS proxy, like refusing to relay UDP.
+    Proxy,
+}
+
+impl From<&io::Error> for NinatError {
+    fn from(e: &io::Error) -> Self {
+        if Cancelled::is(e) {
+            return NinatError::Cancelled;
+        }
+        if ProxyError::is(e) {
+            return match e.kind() {
+                io::ErrorKind::ConnectionRefused
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::NotConnected
+                | io::ErrorKind::TimedOut
+                | io::ErrorKind::WouldBlock => NinatError::ProxyUnreachable,
+                io::ErrorKind::PermissionDenied => NinatError::ProxyAuth,
+                _ => NinatError::Proxy,
+            };
+        }
+        match e.kind() {
+            io::ErrorKind::InvalidInput => NinatError::InvalidArgument,
+            io::ErrorKind::NotFound => NinatError::NotFound,
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => NinatError::TimedOut,
+            io::ErrorKind::PermissionDenied => NinatError::PermissionDenied,
+            io::ErrorKind::AddrInUse | io::ErrorKind::AddrNotAvailable => NinatError::AddrInUse,
+            io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::NotConnected => NinatError::Network,
+            _ => NinatError::Io,
+        }
+    }
+}
+
+/// Enumeration of NAT types.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum NinatNatType {
+    A,
+    B,
+    C,
+    D,
+    F,
+}
+
+impl From<NatType> for NinatNatType {
+    fn from(s: NatType) -> Self {
+        match s {
+            NatType::A => NinatNatType::A,
+            NatType::B => NinatNatType::B,
+            NatType::C => NinatNatType::C,
+            NatType::D => NinatNatType::D,
+            NatType::F => NinatNatType::F,
+        }
+    }
+}
+
+/// Represents a tester of NAT tests.
+pub struct NinatTester {
+    tester: NatTester,
+    /// Token of the tests started since the last cancellation
+    cancel: Mutex<CancelToken>,
+}
+
+impl NinatTester {
+    /// Returns the tester of a test, cancelled by `ninat_tester_cancel` until the test finishes.
+    fn tester(&self) -> NatTester {
+        let cancel = self.cancel.lock().unwrap().clone();
+
+        self.tester.clone().cancel_token(cancel)
+    }
+}
+
+/// Represents the result of a NAT test.
+pub struct NinatResult {
+    nat: NatType,
+    ip: Option<Ipv4Addr>,
+    nintendo: CString,
+    sony: CString,
+    microsoft: CString,
+}
+
+impl NinatResult {
+    fn new(nat: NatType, ip: Option<Ipv4Addr>) -> NinatResult {
+        // The strings of NAT types never contain a nul
+        let cstring = |s: String| CString::new(s).unwrap();
+
+        NinatResult {
+            nat,
+            ip,
+            nintendo: cstring(nat.nintendo()),
+            sony: cstring(nat.sony()),
+            microsoft: cstring(nat.microsoft()),
+        }
+    }
+}
+
+/// Represents the callback of `ninat_tester_run_async`, called with the error and the result,
+/// which is null unless the error is `NINAT_ERROR_OK`, and the user data. The callback owns the
+/// result, and frees it by `ninat_result_free`.
+pub type NinatCallback = Option<
+    unsafe extern "C" fn(error: NinatError, result: *mut NinatResult, user_data: *mut c_void),
+>;
+
+/// Represents the user data passed to a callback on another thread.
+struct UserData(*mut c_void);
+
+unsafe impl Send for UserData {}
+
+/// Returns the string of a C string, or `None` if it is null or not UTF-8.
+unsafe fn to_str<'a>(s: *const c_char) -> Option<&'a str> {
+    match s.is_null() {
+        true => None,
+        false => CStr::from_ptr(s).to_str().ok(),
+    }
+}
+
+/// Runs the body of an entry point, returning `default` if it panics, since unwinding into C is
+/// undefined.
+fn guard<T, F: FnOnce() -> T>(default: T, f: F) -> T {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or(default)
+}
+
+/// Replaces the tester with the one built from it.
+fn update<F: FnOnce(NatTester) -> NatTester>(tester: &mut NinatTester, f: F) {
+    let inner = mem::take(&mut tester.tester);
+    tester.tester = f(inner);
+}
+
+/// Creates a new tester against the Nintendo servers. The tester is freed by `ninat_tester_free`.
+#[no_mangle]
+pub extern "C" fn ninat_tester_new() -> *mut NinatTester {
+    guard(ptr::null_mut(), || {
+        let tester = NinatTester {
+            tester: NatTester::new(),
+            cancel: Mutex::new(CancelToken::new()),
+        };
+
+        Box::into_raw(Box::new(tester))
+    })
+}
+
+/// Frees the tester.
+///
+/// # Safety
+///
+/// The tester must be created by `ninat_tester_new` and not freed yet, or null.
+#[no_mangle]
+pub unsafe extern "C" fn ninat_tester_free(tester: *mut NinatTester) {
+    guard((), || {
+        if !tester.is_null() {
+            drop(Box::from_raw(tester));
+        }
+    })
+}
+
+/// Sets the servers, by hostname or IP address, resolved when the test runs.
+///
+/// # Safety
+///
+/// The tester must be valid, and the servers nul-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn ninat_tester_set_servers(
+    tester: *mut NinatTester,
+    server1: *const c_char,
+    server2: *const c_char,
+) -> NinatError {
+    guard(NinatError::Panic, || {
+        let tester = match tester.as_mut() {
+            Some(tester) => tester,
+            None => return NinatError::InvalidArgument,
+        };
+        let (server1, server2) = match (to_str(server1), to_str(server2)) {
+            (Some(server1), Some(server2)) => (server1, server2),
+            _ => return NinatError::InvalidArgument,
+        };
+        update(tester, |t| t.servers(server1, server2));
+
+        NinatError::Ok
+    })
+}
+
+/// Sets the SOCKS5 proxy, as `host:port`, and its username and password, which may be null if
+/// the proxy needs no authentication.
+///
+/// # Safety
+///
+/// The tester must be valid, and the strings nul-terminated or null.
+#[no_mangle]
+pub unsafe extern "C" fn ninat_tester_set_proxy(
+    tester: *mut NinatTester,
+    proxy: *const c_char,
+    username: *const c_char,
+    password: *const c_char,
+) -> NinatError {
+    guard(NinatError::Panic, || {
+        let tester = match tester.as_mut() {
+            Some(tester) => tester,
+            None => return NinatError::InvalidArgument,
+        };
+        let proxy = match to_str(proxy) {
+            Some(proxy) => proxy,
+            None => return NinatError::InvalidArgument,
+        };
+        let proxy = match proxy.to_socket_addrs() {
+            Ok(addrs) => match addrs
+                .filter_map(|addr| match addr {
+                    SocketAddr::V4(addr) => Some(addr),
+                    _ => None,
+                })
+                .next()
+            {
+                Some(addr) => addr,
+                None => return NinatError::NotFound,
+            },
+            // Failures of the resolver are reported as the host not found
+            Err(_) => return NinatError::NotFound,
+        };
+        let auth = match (username.is_null(), password.is_null()) {
+            (true, true) => None,
+            _ => match (to_str(username), to_str(password)) {
+                (Some(username), Some(password)) => Some((username, password)),
+                _ => return NinatError::InvalidArgument,
+            },
+        };
+        update(tester, |t| match auth {
+            Some((username, password)) => t.proxy(proxy).proxy_auth(username, password),
+            None => t.proxy(proxy),
+        });
+
+        NinatError::Ok
+    })
+}
+
+/// Sets the timeout of the whole test in milliseconds, `0` as no timeout.
+///
+/// # Safety
+///
+/// The tester must be valid.
+#[no_mangle]
+pub unsafe extern "C" fn ninat_tester_set_timeout(
+    tester: *mut NinatTester,
+    timeout: u64,
+) -> NinatError {
+    guard(NinatError::Panic, || {
+        let tester = match tester.as_mut() {
+            Some(tester) => tester,
+            None => return NinatError::InvalidArgument,
+        };
+        let timeout = match timeout {
+            0 => None,
+            timeout => Some(Duration::from_millis(timeout)),
+        };
+        update(tester, |t| t.timeout(timeout));
+
+        NinatError::Ok
+    })
+}
+
+/// Sets the number of times to rerun the test while it fails with NAT type F.
+///
+/// # Safety
+///
+/// The tester must be valid.
+#[no_mangle]
+pub unsafe extern "C" fn ninat_tester_set_retries(
+    tester: *mut NinatTester,
+    retries: u32,
+) -> NinatError {
+    guard(NinatError::Panic, || {
+        let tester = match tester.as_mut() {
+            Some(tester) => tester,
+            None => return NinatError::InvalidArgument,
+        };
+        update(tester, |t| t.retries(retries as usize));
+
+        NinatError::Ok
+    })
+}
+
+/// Sets the local address to bind to, as `ip:port`.
+///
+/// # Safety
+///
+/// The tester must be valid, and the address a nul-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ninat_tester_set_bind(
+    tester: *mut NinatTester,
+    bind: *const c_char,
+) -> NinatError {
+    guard(NinatError::Panic, || {
+        let tester = match tester.as_mut() {
+            Some(tester) => tester,
+            None => return NinatError::InvalidArgument,
+        };
+        let bind = match to_str(bind).and_then(|bind| bind.parse::<SocketAddrV4>().ok()) {
+            Some(bind) => bind,
+            None => return NinatError::InvalidArgument,
+        };
+        update(tester, |t| t.bind(bind));
+
+        NinatError::Ok
+    })
+}
+
+/// Runs a NAT test, blocking until it finishes. The result is freed by `ninat_result_free`.
+///
+/// # Safety
+///
+/// The tester must be valid, and the result a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn ninat_tester_run(
+    tester: *const NinatTester,
+    result: *mut *mut NinatResult,
+) -> NinatError {
+    guard(NinatError::Panic, || {
+        let tester = match tester.as_ref() {
+            Some(tester) => tester,
+            None => return NinatError::InvalidArgument,
+        };
+        if result.is_null() {
+            return NinatError::InvalidArgument;
+        }
+
+        match tester.tester().run() {
+            Ok(r) => {
+                *result = Box::into_raw(Box::new(NinatResult::new(r.nat(), r.ip())));
+                NinatError::Ok
+            }
+            Err(ref e) => NinatError::from(e),
+        }
+    })
+}
+
+/// Runs a NAT test on another thread, calling the callback once it finishes. The tester may be
+/// changed or freed while the test runs, which does not affect it.
+///
+/// # Safety
+///
+/// The tester must be valid, and the user data safe to use from another thread.
+#[no_mangle]
+pub unsafe extern "C" fn ninat_tester_run_async(
+    tester: *const NinatTester,
+    callback: NinatCallback,
+    user_data: *mut c_void,
+) -> NinatError {
+    guard(NinatError::Panic, || {
+        let (tester, callback) = match (tester.as_ref(), callback) {
+            (Some(tester), Some(callback)) => (tester.tester(), callback),
+            _ => return NinatError::InvalidArgument,
+        };
+        let user_data = UserData(user_data);
+
+        let spawned = thread::Builder::new()
+            .name("ninat".to_string())
+            .spawn(move || {
+                let user_data = user_data;
+                let run = panic::catch_unwind(AssertUnwindSafe(|| {
+                    tester
+                        .run()
+                        .map(|r| Box::into_raw(Box::new(NinatResult::new(r.nat(), r.ip()))))
+                }));
+                match run {
+                    Ok(Ok(result)) => callback(NinatError::Ok, result, user_data.0),
+                    Ok(Err(ref e)) => callback(NinatError::from(e), ptr::null_mut(), user_data.0),
+                    Err(_) => callback(NinatError::Panic, ptr::null_mut(), user_data.0),
+                }
+            });
+
+        match spawned {
+            Ok(_) => NinatError::Ok,
+            Err(ref e) => NinatError::from(e),
+        }
+    })
+}
+
+/// Cancels the tests of the tester in progress, which return `NINAT_ERROR_CANCELLED`. Tests run
+/// later are not affected.
+///
+/// # Safety
+///
+/// The tester must be valid.
+#[no_mangle]
+pub unsafe extern "C" fn ninat_tester_cancel(tester: *const NinatTester) -> NinatError {
+    guard(NinatError::Panic, || match tester.as_ref() {
+        Some(tester) => {
+            let cancel = mem::take(&mut *tester.cancel.lock().unwrap());
+            cancel.cancel();
+            NinatError::Ok
+        }
+        None => NinatError::InvalidArgument,
+    })
+}
+
+/// Frees the result.
+///
+/// # Safety
+///
+/// The result must be returned by a test and not freed yet, or null.
+#[no_mangle]
+pub unsafe extern "C" fn ninat_result_free(result: *mut NinatResult) {
+    guard((), || {
+        if !result.is_null() {
+            drop(Box::from_raw(result));
+        }
+    })
+}
+
+/// Returns the NAT type of the result.
+///
+/// # Safety
+///
+/// The result must be valid.
+#[no_mangle]
+pub unsafe extern "C" fn ninat_result_nat(result: *const NinatResult) -> NinatNatType {
+    guard(NinatNatType::F, || NinatNatType::from((*result).nat))
+}
+
+/// Writes the remote IP address of the result into the 4 bytes of `octets`, returning if it is
+/// known.
+///
+/// # Safety
+///
+/// The result must be valid, and `octets` point to 4 bytes at least.
+#[no_mangle]
+pub unsafe extern "C" fn ninat_result_ip(result: *const NinatResult, octets: *mut u8) -> bool {
+    guard(false, || match ((*result).ip, octets.is_null()) {
+        (Some(ip), false) => {
+            ptr::copy_nonoverlapping(ip.octets().as_ptr(), octets, 4);
+            true
+        }
+        _ => false,
+    })
+}
+
+/// Returns the Nintendo (Nintendo Switch) NAT type of the result, valid until it is freed.
+///
+/// # Safety
+///
+/// The result must be valid.
+#[no_mangle]
+pub unsafe extern "C" fn ninat_result_nintendo(result: *const NinatResult) -> *const c_char {
+    guard(ptr::null(), || (*result).nintendo.as_ptr())
+}
+
+/// Returns the Sony (PlayStation) NAT type of the result, valid until it is freed.
+///
+/// # Safety
+///
+/// The result must be valid.
+#[no_mangle]
+pub unsafe extern "C" fn ninat_result_sony(result: *const NinatResult) -> *const c_char {
+    guard(ptr::null(), || (*result).sony.as_ptr())
+}
+
+/// Returns the Microsoft (Xbox) NAT type of the result, valid until it is freed.
+///
+/// # Safety
+///
+/// The result must be valid.
+#[no_mangle]
+pub unsafe extern "C" fn ninat_result_microsoft(result: *const NinatResult) -> *const c_char {
+    guard(ptr::null(), || (*result).microsoft.as_ptr())
+}
+
+/// Returns the description of the error, a static string.
+#[no_mangle]
+pub extern "C" fn ninat_error_message(error: NinatError) -> *const c_char {
+    guard(ptr::null(), || {
+        let message: &'static [u8] = match error {
+            NinatError::Ok => b"ok\0",
+            NinatError::InvalidArgument => b"invalid argument\0",
+            NinatError::NotFound => b"host not found\0",
+            NinatError::TimedOut => b"timed out\0",
+            NinatError::PermissionDenied => b"permission denied\0",
+            NinatError::AddrInUse => b"address in use\0",
+            NinatError::Network => b"network error\0",
+            NinatError::Cancelled => b"test cancelled\0",
+            NinatError::Io => b"I/O error\0",
+            NinatError::Panic => b"internal error\0",
+            NinatError::ProxyUnreachable => b"proxy unreachable\0",
+            NinatError::ProxyAuth => b"proxy authentication failed\0",
+            NinatError::Proxy => b"proxy error\0",
+        };
+
+        message.as_ptr() as *const c_char
+    })
+}
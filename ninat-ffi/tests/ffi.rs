@@ -0,0 +1,183 @@
+use ninat::protocol::Ports;
+use ninat_ffi::*;
+use std::ffi::{CStr, CString};
+use std::net::{Ipv4Addr, TcpListener};
+use std::os::raw::c_void;
+use std::ptr;
+use std::sync::mpsc::{self, Sender};
+use std::time::Duration;
+
+#[path = "../../tests/common/mod.rs"]
+mod common;
+
+use common::{servers_on, Mock};
+
+fn tester(timeout: u64) -> *mut NinatTester {
+    servers_tester("127.0.0.2", "127.0.0.3", timeout)
+}
+
+fn servers_tester(server1: &str, server2: &str, timeout: u64) -> *mut NinatTester {
+    let server1 = CString::new(server1).unwrap();
+    let server2 = CString::new(server2).unwrap();
+    let bind = CString::new("127.0.0.1:0").unwrap();
+    let tester = ninat_tester_new();
+    unsafe {
+        assert_eq!(
+            ninat_tester_set_servers(tester, server1.as_ptr(), server2.as_ptr()),
+            NinatError::Ok
+        );
+        assert_eq!(ninat_tester_set_bind(tester, bind.as_ptr()), NinatError::Ok);
+        assert_eq!(ninat_tester_set_timeout(tester, timeout), NinatError::Ok);
+    }
+
+    tester
+}
+
+#[test]
+fn invalid_argument() {
+    let tester = ninat_tester_new();
+    let proxy = CString::new("not an address").unwrap();
+    let username = CString::new("username").unwrap();
+    unsafe {
+        let e = ninat_tester_set_servers(tester, ptr::null(), ptr::null());
+        assert_eq!(e, NinatError::InvalidArgument);
+        let e = ninat_tester_set_proxy(tester, proxy.as_ptr(), ptr::null(), ptr::null());
+        assert_ne!(e, NinatError::Ok);
+        let proxy = CString::new("127.0.0.1:1080").unwrap();
+        let e = ninat_tester_set_proxy(tester, proxy.as_ptr(), username.as_ptr(), ptr::null());
+        assert_eq!(e, NinatError::InvalidArgument);
+        let e = ninat_tester_run(tester, ptr::null_mut());
+        assert_eq!(e, NinatError::InvalidArgument);
+        ninat_tester_free(tester);
+    }
+    let message = unsafe { CStr::from_ptr(ninat_error_message(NinatError::Cancelled)) };
+    assert_eq!(message.to_str(), Ok("test cancelled"));
+    let message = unsafe { CStr::from_ptr(ninat_error_message(NinatError::Panic)) };
+    assert_eq!(message.to_str(), Ok("internal error"));
+}
+
+#[test]
+fn header() {
+    // The header checked in is regenerated with NINAT_FFI_UPDATE_HEADER set
+    let generated = include_str!(concat!(env!("OUT_DIR"), "/ninat.h"));
+    let checked_in = include_str!("../include/ninat.h");
+    assert_eq!(generated, checked_in);
+}
+
+#[test]
+fn run() {
+    // Nothing listens on the servers
+    let tester = tester(200);
+    unsafe {
+        let mut result = ptr::null_mut();
+        assert_eq!(ninat_tester_run(tester, &mut result), NinatError::Ok);
+        assert_eq!(ninat_result_nat(result), NinatNatType::F);
+        let nintendo = CStr::from_ptr(ninat_result_nintendo(result));
+        assert_eq!(nintendo.to_str(), Ok("F"));
+        let mut octets = [0u8; 4];
+        assert!(!ninat_result_ip(result, octets.as_mut_ptr()));
+        ninat_result_free(result);
+        ninat_tester_free(tester);
+    }
+}
+
+#[test]
+fn run_mock() {
+    // The servers see the host behind a NAT of type B
+    let (server1, server2) = (Ipv4Addr::new(127, 0, 0, 16), Ipv4Addr::new(127, 0, 0, 17));
+    let public = Ipv4Addr::new(203, 0, 113, 5);
+    let mock = Mock::new().remote_ip(public);
+    servers_on(
+        server1,
+        server2,
+        Ports::default(),
+        mock.clone(),
+        mock,
+        false,
+    );
+
+    let tester = servers_tester("127.0.0.16", "127.0.0.17", 2000);
+    unsafe {
+        let mut result = ptr::null_mut();
+        assert_eq!(ninat_tester_run(tester, &mut result), NinatError::Ok);
+        assert_eq!(ninat_result_nat(result), NinatNatType::B);
+        let mut octets = [0u8; 4];
+        assert!(ninat_result_ip(result, octets.as_mut_ptr()));
+        assert_eq!(Ipv4Addr::from(octets), public);
+        let nintendo = CStr::from_ptr(ninat_result_nintendo(result));
+        assert_eq!(nintendo.to_str(), Ok("B"));
+        let sony = CStr::from_ptr(ninat_result_sony(result));
+        assert_eq!(sony.to_str(), Ok("2"));
+        let microsoft = CStr::from_ptr(ninat_result_microsoft(result));
+        assert_eq!(microsoft.to_str(), Ok("Moderate"));
+        ninat_result_free(result);
+        ninat_tester_free(tester);
+    }
+}
+
+#[test]
+fn run_not_found() {
+    let tester = servers_tester("ninat.invalid", "127.0.0.3", 200);
+    unsafe {
+        let mut result = ptr::null_mut();
+        assert_eq!(ninat_tester_run(tester, &mut result), NinatError::NotFound);
+        assert!(result.is_null());
+        ninat_tester_free(tester);
+    }
+}
+
+#[test]
+fn run_proxy_unreachable() {
+    // Nothing listens on the port of the proxy
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let proxy = CString::new(format!("127.0.0.1:{}", port)).unwrap();
+    let tester = tester(200);
+    unsafe {
+        let e = ninat_tester_set_proxy(tester, proxy.as_ptr(), ptr::null(), ptr::null());
+        assert_eq!(e, NinatError::Ok);
+        let mut result = ptr::null_mut();
+        let e = ninat_tester_run(tester, &mut result);
+        assert_eq!(e, NinatError::ProxyUnreachable);
+        let message = CStr::from_ptr(ninat_error_message(e));
+        assert_eq!(message.to_str(), Ok("proxy unreachable"));
+        ninat_tester_free(tester);
+    }
+}
+
+unsafe extern "C" fn send(error: NinatError, result: *mut NinatResult, user_data: *mut c_void) {
+    ninat_result_free(result);
+    let sender = &*(user_data as *const Sender<NinatError>);
+    sender.send(error).unwrap();
+}
+
+#[test]
+fn run_async_cancel() {
+    // The test waits for responses forever
+    let tester = tester(0);
+    let (sender, receiver) = mpsc::channel();
+    unsafe {
+        let user_data = &sender as *const Sender<NinatError> as *mut c_void;
+        assert_eq!(
+            ninat_tester_run_async(tester, Some(send), user_data),
+            NinatError::Ok
+        );
+        assert!(receiver.recv_timeout(Duration::from_millis(200)).is_err());
+        assert_eq!(ninat_tester_cancel(tester), NinatError::Ok);
+    }
+    let error = receiver.recv_timeout(Duration::from_secs(1)).unwrap();
+    assert_eq!(error, NinatError::Cancelled);
+
+    // A later test is not cancelled
+    unsafe {
+        assert_eq!(ninat_tester_set_timeout(tester, 200), NinatError::Ok);
+        let mut result = ptr::null_mut();
+        assert_eq!(ninat_tester_run(tester, &mut result), NinatError::Ok);
+        assert_eq!(ninat_result_nat(result), NinatNatType::F);
+        ninat_result_free(result);
+        ninat_tester_free(tester);
+    }
+}
@@ -0,0 +1,350 @@
+//! Explanation of NAT types and suggestion of fixes.
+
+use crate::protocol::Ports;
+use crate::topology::Topology;
+use crate::{NatTestResult, NatType};
+use std::fmt::{self, Display};
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+/// Enumeration of findings from the evidence of a NAT test.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Finding {
+    /// Represents the test running through a SOCKS proxy.
+    Proxy,
+    /// Represents no NAT between the host and the servers.
+    NoNat,
+    /// Represents a NAT behind another NAT, which reports the local IP address.
+    NestedNat(Ipv4Addr),
+    /// Represents a carrier-grade NAT, under which the servers see the remote IP address.
+    CarrierGradeNat(Ipv4Addr),
+    /// Represents a mapping independent of the destination, with the mapped port.
+    EndpointIndependentMapping(u16),
+    /// Represents a mapping preserving the local port.
+    PortPreserved(u16),
+    /// Represents a mapping dependent on the destination, with the ports seen by both servers.
+    EndpointDependentMapping(u16, u16),
+    /// Represents ports allocated by the same delta for every destination.
+    PredictableAllocation(u16),
+    /// Represents ports allocated by different deltas for the destinations.
+    UnpredictableAllocation(u16, u16),
+    /// Represents unsolicited inbound traffic reaching the host.
+    InboundAllowed,
+    /// Represents unsolicited inbound traffic filtered.
+    InboundFiltered,
+    /// Represents a socket of the index, from 1, not answered by both servers.
+    Unanswered(usize),
+}
+
+impl Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Finding::Proxy => write!(
+                f,
+                "The test runs through a SOCKS proxy, so the result describes the network of the \
+                 proxy rather than this one."
+            ),
+            Finding::NoNat => write!(
+                f,
+                "The servers see the address of the host itself, so there is no NAT in between."
+            ),
+            Finding::NestedNat(ip) => write!(
+                f,
+                "The server reports the local address {}, which belongs to another NAT between \
+                 the router and the Internet.",
+                ip
+            ),
+            Finding::CarrierGradeNat(ip) => write!(
+                f,
+                "The servers see the non-public address {}, so the ISP shares a public address \
+                 among its customers with carrier-grade NAT.",
+                ip
+            ),
+            Finding::EndpointIndependentMapping(port) => write!(
+                f,
+                "Both servers see the same port {}, so the NAT reuses the mapping for every \
+                 destination.",
+                port
+            ),
+            Finding::PortPreserved(port) => {
+                write!(f, "The NAT preserves the local port {}.", port)
+            }
+            Finding::EndpointDependentMapping(port1, port2) => write!(
+                f,
+                "The servers see different ports {} and {}, so the NAT creates a new mapping for \
+                 every destination.",
+                port1, port2
+            ),
+            Finding::PredictableAllocation(delta) => write!(
+                f,
+                "Ports of the second socket move by {} on both servers, so peers may predict the \
+                 next mapping.",
+                delta
+            ),
+            Finding::UnpredictableAllocation(delta1, delta2) => write!(
+                f,
+                "Ports of the second socket move by {} and {} on the servers, so peers cannot \
+                 predict the next mapping.",
+                delta1, delta2
+            ),
+            Finding::InboundAllowed => write!(
+                f,
+                "The reply from a port never sent to arrives, so unsolicited inbound traffic \
+                 reaches the host."
+            ),
+            Finding::InboundFiltered => write!(
+                f,
+                "The reply from a port never sent to does not arrive, so the NAT or a firewall \
+                 filters unsolicited inbound traffic."
+            ),
+            Finding::Unanswered(n) => write!(
+                f,
+                "Socket {} is not answered by both servers, so UDP to the servers may be blocked.",
+                n
+            ),
+        }
+    }
+}
+
+/// Enumeration of fixes improving the NAT type.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Fix {
+    /// Represents testing without the proxy.
+    DisableProxy,
+    /// Represents checking the proxy relays UDP.
+    ProxyUdp,
+    /// Represents asking the ISP for a public IPv4 address.
+    PublicIpv4,
+    /// Represents enabling UPnP on the router.
+    EnableUpnp,
+    /// Represents forwarding the external port to the local address. The external port is the
+    /// mapped port under a mapping independent of the destination, and the local port otherwise,
+    /// since a dependent mapping moves the port for every destination.
+    PortForward(u16, SocketAddrV4),
+    /// Represents setting the host as the DMZ host of the router.
+    Dmz(Ipv4Addr),
+    /// Represents a static DNAT of the port to the local address with a port-preserving SNAT on a
+    /// Linux router, which makes the port behave as full-cone NAT.
+    FullCone(SocketAddrV4),
+    /// Represents allowing inbound UDP to the local address in the firewall.
+    AllowInbound(SocketAddrV4),
+    /// Represents allowing UDP to and from the ports of the servers in the firewall.
+    AllowUdp(Ports),
+    /// Represents bridging the outer NAT of a nested NAT.
+    Bridge,
+}
+
+impl Fix {
+    /// Returns the nftables commands of the fix on a Linux router, whose WAN interface is `$WAN`.
+    pub fn nftables(&self) -> Option<String> {
+        match self {
+            Fix::FullCone(local) => Some(format!(
+                "nft add table ip ninat\n\
+                 nft add chain ip ninat prerouting '{{ type nat hook prerouting priority dstnat; }}'\n\
+                 nft add chain ip ninat postrouting '{{ type nat hook postrouting priority srcnat; }}'\n\
+                 nft add rule ip ninat prerouting iifname $WAN udp dport {1} dnat to {0}:{1}\n\
+                 nft add rule ip ninat postrouting oifname $WAN ip saddr {0} udp sport {1} masquerade to :{1}",
+                local.ip(),
+                local.port()
+            )),
+            _ => None,
+        }
+    }
+
+    /// Returns the iptables commands of the fix on a Linux router, whose WAN interface is `$WAN`.
+    pub fn iptables(&self) -> Option<String> {
+        match self {
+            Fix::FullCone(local) => Some(format!(
+                "iptables -t nat -A PREROUTING -i $WAN -p udp --dport {1} -j DNAT --to-destination {0}:{1}\n\
+                 iptables -t nat -A POSTROUTING -o $WAN -s {0} -p udp --sport {1} -j MASQUERADE --to-ports {1}\n\
+                 iptables -A FORWARD -i $WAN -d {0} -p udp --dport {1} -j ACCEPT",
+                local.ip(),
+                local.port()
+            )),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Fix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Fix::DisableProxy => write!(f, "Test again without the proxy to see this network."),
+            Fix::ProxyUdp => write!(
+                f,
+                "Check the proxy supports UDP ASSOCIATE and its firewall allows UDP, since nothing \
+                 comes back through it."
+            ),
+            Fix::PublicIpv4 => write!(
+                f,
+                "Ask the ISP for a public IPv4 address, since nothing on the router can open the \
+                 carrier-grade NAT."
+            ),
+            Fix::EnableUpnp => write!(
+                f,
+                "Enable UPnP or NAT-PMP on the router, so consoles and games can open ports \
+                 themselves."
+            ),
+            Fix::PortForward(port, local) => write!(
+                f,
+                "Forward UDP port {} on the router to {}, the port this test used.",
+                port, local
+            ),
+            Fix::Dmz(ip) => write!(
+                f,
+                "Set {} as the DMZ host of the router, which forwards every unsolicited port to it.",
+                ip
+            ),
+            Fix::FullCone(local) => write!(
+                f,
+                "Add a static DNAT of UDP port {1} to {0} with a port-preserving SNAT on a Linux \
+                 router, so the port behaves as full-cone NAT.",
+                local.ip(),
+                local.port()
+            ),
+            Fix::AllowInbound(local) => write!(
+                f,
+                "Allow inbound UDP to {} in the firewall of the host, since there is no NAT to \
+                 configure.",
+                local
+            ),
+            Fix::AllowUdp(ports) => write!(
+                f,
+                "Allow outbound UDP in the firewall of the host and the router, at least to ports \
+                 {} and {} and inbound from port {}.",
+                ports.send_only(),
+                ports.echo(),
+                ports.inbound()
+            ),
+            Fix::Bridge => write!(
+                f,
+                "Put the modem or the outer router in bridge mode, or repeat every forward on both \
+                 NATs."
+            ),
+        }
+    }
+}
+
+/// Represents the explanation of a NAT test and the fixes suggested, ranked by effectiveness.
+#[derive(Clone, Debug)]
+pub struct Advice {
+    findings: Vec<Finding>,
+    fixes: Vec<Fix>,
+}
+
+impl Advice {
+    /// Creates a new `Advice` from the result of a NAT test, the IP address of the host if known,
+    /// and if the test runs through a proxy.
+    pub fn new(result: &NatTestResult, host_ip: Option<Ipv4Addr>, proxy: bool) -> Advice {
+        let mut findings = Vec::new();
+        let mut fixes = Vec::new();
+
+        if proxy {
+            findings.push(Finding::Proxy);
+            fixes.push(Fix::DisableProxy);
+        }
+
+        let topology = host_ip.and_then(|host_ip| result.topology(host_ip));
+        match (topology, result.ip(), result.local_ip()) {
+            (Some(Topology::NoNat), _, _) => findings.push(Finding::NoNat),
+            (Some(Topology::NestedNat), _, Some(local_ip)) => {
+                findings.push(Finding::NestedNat(local_ip))
+            }
+            (Some(Topology::CarrierGradeNat), Some(ip), _) => {
+                findings.push(Finding::CarrierGradeNat(ip));
+                fixes.push(Fix::PublicIpv4);
+            }
+            _ => {}
+        }
+
+        let observations = result.observations();
+        let o1 = &observations[0];
+        match o1.ports() {
+            // Without NAT only the filtering of the host matters
+            Some(_) if topology == Some(Topology::NoNat) => match o1.is_a() {
+                true => findings.push(Finding::InboundAllowed),
+                false => findings.push(Finding::InboundFiltered),
+            },
+            Some((port1, port2)) => {
+                match port1 == port2 {
+                    true => findings.push(Finding::EndpointIndependentMapping(port1)),
+                    false => findings.push(Finding::EndpointDependentMapping(port1, port2)),
+                }
+                if port1 == o1.local().port() {
+                    findings.push(Finding::PortPreserved(port1));
+                }
+                if let (false, Some(o2)) = (port1 == port2, observations.get(1)) {
+                    match o2.ports() {
+                        Some((port3, port4)) => {
+                            let delta1 = port3.wrapping_sub(port1);
+                            let delta2 = port4.wrapping_sub(port2);
+                            match delta1 == delta2 {
+                                true => findings.push(Finding::PredictableAllocation(delta1)),
+                                false => {
+                                    findings.push(Finding::UnpredictableAllocation(delta1, delta2))
+                                }
+                            }
+                        }
+                        None => findings.push(Finding::Unanswered(2)),
+                    }
+                }
+                match o1.is_a() {
+                    true => findings.push(Finding::InboundAllowed),
+                    false => findings.push(Finding::InboundFiltered),
+                }
+            }
+            None => findings.push(Finding::Unanswered(1)),
+        }
+
+        let nested = topology == Some(Topology::NestedNat);
+        match result.nat() {
+            NatType::A => {}
+            NatType::B | NatType::C | NatType::D => match (topology, host_ip, o1.ports()) {
+                // Fixes of this network do not apply to the network of the proxy
+                _ if proxy => {}
+                // Nothing on the router can open the carrier-grade NAT
+                (Some(Topology::CarrierGradeNat), _, _) => {}
+                (Some(Topology::NoNat), Some(host_ip), Some(_)) => {
+                    let local = SocketAddrV4::new(host_ip, o1.local().port());
+                    fixes.push(Fix::AllowInbound(local));
+                }
+                (_, Some(host_ip), Some((port1, port2))) => {
+                    if nested {
+                        fixes.push(Fix::Bridge);
+                    }
+                    fixes.push(Fix::EnableUpnp);
+                    let local = SocketAddrV4::new(host_ip, o1.local().port());
+                    // A dependent mapping moves the port for the next destination, so only a
+                    // forward of the local port keeps to the port the host uses
+                    let port = match port1 == port2 {
+                        true => port1,
+                        false => local.port(),
+                    };
+                    fixes.push(Fix::PortForward(port, local));
+                    fixes.push(Fix::Dmz(host_ip));
+                    // Full-cone NAT of the router cannot help behind another NAT, nor where the
+                    // allocation of ports is unpredictable
+                    if !nested && result.nat() != NatType::D {
+                        fixes.push(Fix::FullCone(local));
+                    }
+                }
+                _ => fixes.push(Fix::EnableUpnp),
+            },
+            NatType::F => match proxy {
+                true => fixes.push(Fix::ProxyUdp),
+                false => fixes.push(Fix::AllowUdp(result.server_ports())),
+            },
+        }
+
+        Advice { findings, fixes }
+    }
+
+    /// Returns the findings explaining the NAT type.
+    pub fn findings(&self) -> &[Finding] {
+        &self.findings
+    }
+
+    /// Returns the fixes suggested, the most effective first.
+    pub fn fixes(&self) -> &[Fix] {
+        &self.fixes
+    }
+}
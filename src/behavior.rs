@@ -0,0 +1,183 @@
+//! Behaviors of NATs in the terms of RFC 4787.
+
+use crate::NatType;
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display};
+
+/// Enumeration of mapping behaviors, deciding when a NAT reuses a mapping for a new destination.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub enum MappingBehavior {
+    /// Represents a mapping reused for every destination.
+    EndpointIndependent,
+    /// Represents a mapping reused for destinations of the same IP address.
+    AddressDependent,
+    /// Represents a mapping reused for the same destination only.
+    AddressAndPortDependent,
+}
+
+impl MappingBehavior {
+    /// Returns if the mapping is reused for every destination.
+    pub fn is_endpoint_independent(&self) -> bool {
+        *self == MappingBehavior::EndpointIndependent
+    }
+}
+
+impl Display for MappingBehavior {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MappingBehavior::EndpointIndependent => write!(f, "Endpoint-independent"),
+            MappingBehavior::AddressDependent => write!(f, "Address-dependent"),
+            MappingBehavior::AddressAndPortDependent => write!(f, "Address and port-dependent"),
+        }
+    }
+}
+
+/// Enumeration of filtering behaviors, deciding which inbound traffic a NAT accepts on a mapping.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub enum FilteringBehavior {
+    /// Represents inbound traffic accepted from every source.
+    EndpointIndependent,
+    /// Represents inbound traffic accepted from IP addresses sent to.
+    AddressDependent,
+    /// Represents inbound traffic accepted from addresses sent to only.
+    AddressAndPortDependent,
+}
+
+impl FilteringBehavior {
+    /// Returns if inbound traffic is accepted from ports not sent to.
+    pub fn is_port_independent(&self) -> bool {
+        *self != FilteringBehavior::AddressAndPortDependent
+    }
+}
+
+impl Display for FilteringBehavior {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FilteringBehavior::EndpointIndependent => write!(f, "Endpoint-independent"),
+            FilteringBehavior::AddressDependent => write!(f, "Address-dependent"),
+            FilteringBehavior::AddressAndPortDependent => write!(f, "Address and port-dependent"),
+        }
+    }
+}
+
+/// Enumeration of port allocations of new mappings.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub enum PortAllocation {
+    /// Represents the local port preserved as the mapped port.
+    Preserved,
+    /// Represents mapped ports moving by the same delta for every destination.
+    Predictable(u16),
+    /// Represents mapped ports moving unpredictably.
+    Unpredictable,
+}
+
+impl Display for PortAllocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PortAllocation::Preserved => write!(f, "Preserved"),
+            PortAllocation::Predictable(delta) => write!(f, "Predictable (delta {})", delta),
+            PortAllocation::Unpredictable => write!(f, "Unpredictable"),
+        }
+    }
+}
+
+/// Represents the behavior of a NAT.
+///
+/// A NAT test can only tell whether a mapping depends on the destination, since the servers differ
+/// in IP address, and whether the filtering depends on the port, since only the server 1 replies
+/// from another port. The mapping is reported as the least restrictive behavior explaining the
+/// observation, so it is either endpoint-independent or address-dependent. A reply from another
+/// port of the same IP address does not show that other IP addresses are accepted, so the
+/// filtering is reported as the most restrictive behavior proven, either address-dependent or
+/// address and port-dependent, and never endpoint-independent.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub struct NatBehavior {
+    mapping: MappingBehavior,
+    filtering: FilteringBehavior,
+    allocation: Option<PortAllocation>,
+    hairpin: Option<bool>,
+}
+
+impl NatBehavior {
+    /// Creates a new `NatBehavior`, with the port allocation if known.
+    pub fn new(
+        mapping: MappingBehavior,
+        filtering: FilteringBehavior,
+        allocation: Option<PortAllocation>,
+    ) -> NatBehavior {
+        NatBehavior {
+            mapping,
+            filtering,
+            allocation,
+            hairpin: None,
+        }
+    }
+
+    /// Returns the `NatBehavior` with the hairpin support.
+    pub fn with_hairpin(self, hairpin: bool) -> NatBehavior {
+        NatBehavior {
+            hairpin: Some(hairpin),
+            ..self
+        }
+    }
+
+    /// Returns the mapping behavior.
+    pub fn mapping(&self) -> MappingBehavior {
+        self.mapping
+    }
+
+    /// Returns the filtering behavior.
+    pub fn filtering(&self) -> FilteringBehavior {
+        self.filtering
+    }
+
+    /// Returns the port allocation, if known.
+    pub fn allocation(&self) -> Option<PortAllocation> {
+        self.allocation
+    }
+
+    /// Returns if the NAT supports hairpinning, traffic between two of its mappings, if known.
+    pub fn hairpin(&self) -> Option<bool> {
+        self.hairpin
+    }
+
+    /// Returns the NAT type.
+    ///
+    /// A mapping dependent on the destination is type C if its port allocation is predictable, or
+    /// type D otherwise.
+    pub fn nat(&self) -> NatType {
+        match self.mapping.is_endpoint_independent() {
+            true => match self.filtering.is_port_independent() {
+                true => NatType::A,
+                false => NatType::B,
+            },
+            false => match self.allocation {
+                Some(PortAllocation::Preserved) | Some(PortAllocation::Predictable(_)) => {
+                    NatType::C
+                }
+                Some(PortAllocation::Unpredictable) | None => NatType::D,
+            },
+        }
+    }
+
+    /// Returns the Nintendo (Nintendo Switch) NAT type.
+    pub fn nintendo(&self) -> String {
+        self.nat().nintendo()
+    }
+
+    /// Returns the Sony (PlayStation) NAT type.
+    pub fn sony(&self) -> String {
+        self.nat().sony()
+    }
+
+    /// Returns the Microsoft (Xbox) NAT type.
+    pub fn microsoft(&self) -> String {
+        self.nat().microsoft()
+    }
+}
+
+impl From<NatBehavior> for NatType {
+    fn from(s: NatBehavior) -> Self {
+        s.nat()
+    }
+}
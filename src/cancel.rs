@@ -0,0 +1,112 @@
+//! Cancellation of tests in progress.
+
+use std::error;
+use std::fmt::{self, Display};
+use std::io;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+/// Represents the longest time a test takes to notice it is cancelled while waiting for responses.
+pub(crate) const CANCEL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Represents a token cancelling the tests it is passed to, from any thread.
+///
+/// Clones of a token share its state, so cancelling one cancels all of them.
+///
+/// # Examples
+///
+/// ```no_run
+/// use ninat::cancel::{CancelToken, Cancelled};
+/// use ninat::tester::NatTester;
+/// use std::thread;
+///
+/// let token = CancelToken::new();
+/// let tester = NatTester::new().cancel_token(token.clone());
+/// let handle = thread::spawn(move || tester.run());
+///
+/// token.cancel();
+/// let e = handle.join().unwrap().unwrap_err();
+/// assert!(Cancelled::is(&e));
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken {
+    state: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl CancelToken {
+    /// Creates a new `CancelToken`.
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    /// Cancels the tests of the token. Tests waiting for responses return within 50 ms.
+    pub fn cancel(&self) {
+        let (cancelled, condvar) = &*self.state;
+        *cancelled.lock().unwrap() = true;
+        condvar.notify_all();
+    }
+
+    /// Returns if the token is cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        *self.state.0.lock().unwrap()
+    }
+
+    /// Returns a `Cancelled` error if the token is cancelled.
+    pub fn check(&self) -> io::Result<()> {
+        match self.is_cancelled() {
+            true => Err(Cancelled.into()),
+            false => Ok(()),
+        }
+    }
+
+    /// Sleeps for the duration unless the token is cancelled, returning a `Cancelled` error if it
+    /// is.
+    pub fn sleep(&self, dur: Duration) -> io::Result<()> {
+        let (cancelled, condvar) = &*self.state;
+        let guard = cancelled.lock().unwrap();
+        let (guard, _) = condvar
+            .wait_timeout_while(guard, dur, |cancelled| !*cancelled)
+            .unwrap();
+        match *guard {
+            true => Err(Cancelled.into()),
+            false => Ok(()),
+        }
+    }
+}
+
+impl PartialEq for CancelToken {
+    /// Returns if the tokens are clones of each other.
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.state, &other.state)
+    }
+}
+
+impl Eq for CancelToken {}
+
+/// Represents the error of a cancelled test, carried by an `io::Error` of the kind `Interrupted`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Cancelled;
+
+impl Cancelled {
+    /// Returns if the error is of a cancelled test.
+    pub fn is(e: &io::Error) -> bool {
+        match e.get_ref() {
+            Some(inner) => inner.is::<Cancelled>(),
+            None => false,
+        }
+    }
+}
+
+impl Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "test cancelled")
+    }
+}
+
+impl error::Error for Cancelled {}
+
+impl From<Cancelled> for io::Error {
+    fn from(s: Cancelled) -> Self {
+        io::Error::new(io::ErrorKind::Interrupted, s)
+    }
+}
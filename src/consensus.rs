@@ -0,0 +1,131 @@
+//! Consensus of repeated NAT tests.
+
+use crate::NatType;
+use std::io;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+/// Represents a run of NAT tests in a series.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Run {
+    nat: NatType,
+    ip: Option<Ipv4Addr>,
+    elapsed: Duration,
+    error: Option<String>,
+}
+
+impl Run {
+    /// Creates a new `Run`.
+    pub fn new(nat: NatType, ip: Option<Ipv4Addr>, elapsed: Duration) -> Run {
+        Run {
+            nat,
+            ip,
+            elapsed,
+            error: None,
+        }
+    }
+
+    /// Creates a new `Run` failing with the error, which counts as NAT type F.
+    pub fn failed(e: &io::Error, elapsed: Duration) -> Run {
+        Run {
+            nat: NatType::F,
+            ip: None,
+            elapsed,
+            error: Some(e.to_string()),
+        }
+    }
+
+    /// Returns the NAT type of the run.
+    pub fn nat(&self) -> NatType {
+        self.nat
+    }
+
+    /// Returns the remote IP address of the run, if known.
+    pub fn ip(&self) -> Option<Ipv4Addr> {
+        self.ip
+    }
+
+    /// Returns the time the run took.
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    /// Returns the error the run failed with, if any.
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+}
+
+/// Represents the consensus of a series of NAT tests.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Consensus {
+    runs: Vec<Run>,
+}
+
+impl Consensus {
+    /// Creates a new `Consensus` of the runs.
+    pub fn new(runs: Vec<Run>) -> Consensus {
+        Consensus { runs }
+    }
+
+    /// Returns the runs in order.
+    pub fn runs(&self) -> &[Run] {
+        &self.runs
+    }
+
+    /// Returns the number of runs of each NAT type found, from the most open.
+    pub fn distribution(&self) -> Vec<(NatType, usize)> {
+        let mut distribution: Vec<(NatType, usize)> = Vec::new();
+        for run in self.runs.iter() {
+            match distribution.iter_mut().find(|(nat, _)| *nat == run.nat) {
+                Some((_, count)) => *count += 1,
+                None => distribution.push((run.nat, 1)),
+            }
+        }
+        distribution.sort();
+
+        distribution
+    }
+
+    /// Returns the NAT type found the most, the stricter one on a tie, if any run. Runs of NAT type
+    /// F are left out unless every run is.
+    pub fn majority(&self) -> Option<NatType> {
+        let distribution = self.distribution();
+        let is_all_f = distribution.iter().all(|(nat, _)| *nat == NatType::F);
+        distribution
+            .into_iter()
+            .filter(|(nat, _)| is_all_f || *nat != NatType::F)
+            .max_by_key(|(nat, count)| (*count, *nat))
+            .map(|(nat, _)| nat)
+    }
+
+    /// Returns the share of runs agreeing with the majority, from 0 to 1.
+    pub fn confidence(&self) -> f64 {
+        match self.majority() {
+            Some(majority) => {
+                let count = self.runs.iter().filter(|run| run.nat == majority).count();
+                count as f64 / self.runs.len() as f64
+            }
+            None => 0.0,
+        }
+    }
+
+    /// Returns the remote IP addresses seen, in order of appearance.
+    pub fn ips(&self) -> Vec<Ipv4Addr> {
+        let mut ips = Vec::new();
+        for ip in self.runs.iter().filter_map(|run| run.ip) {
+            if !ips.contains(&ip) {
+                ips.push(ip);
+            }
+        }
+
+        ips
+    }
+
+    /// Returns the times the remote IP address changes between runs which know it.
+    pub fn ip_changes(&self) -> usize {
+        let ips: Vec<_> = self.runs.iter().filter_map(|run| run.ip).collect();
+
+        ips.windows(2).filter(|w| w[0] != w[1]).count()
+    }
+}
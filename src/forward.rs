@@ -0,0 +1,166 @@
+//! Verification of port forwards.
+
+use crate::cancel::{CancelToken, Cancelled};
+use crate::protocol::Ports;
+use crate::{test_on, Context, Endpoints, RW};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::time::Duration;
+use tracing::{debug, instrument};
+
+/// Represents the interval between runs of a verification, so a stable forward is seen over time
+/// rather than in a burst.
+pub const RUN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Represents the verification of a port forward over repeated runs of a test.
+///
+/// The inbound check only receives from another port of the server 1, which the socket has sent
+/// to, so it shows the forward accepts traffic from ports not sent to, but not that hosts of other
+/// IP addresses reach it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Verification {
+    local: SocketAddrV4,
+    external_port: u16,
+    mapped: Vec<Option<(SocketAddrV4, SocketAddrV4)>>,
+    inbound: Vec<bool>,
+    errors: Vec<Option<String>>,
+}
+
+impl Verification {
+    /// Returns the local address of the forward.
+    pub fn local(&self) -> SocketAddrV4 {
+        self.local
+    }
+
+    /// Returns the external port expected.
+    pub fn external_port(&self) -> u16 {
+        self.external_port
+    }
+
+    /// Returns the mapped addresses seen by the server 1 and the server 2 in each run, or `None` if
+    /// the run fails.
+    pub fn mapped(&self) -> &[Option<(SocketAddrV4, SocketAddrV4)>] {
+        &self.mapped
+    }
+
+    /// Returns if unsolicited inbound traffic arrives in each run.
+    pub fn inbound(&self) -> &[bool] {
+        &self.inbound
+    }
+
+    /// Returns the error of each run, or `None` if the run is answered.
+    pub fn errors(&self) -> &[Option<String>] {
+        &self.errors
+    }
+
+    /// Returns if both servers see the external port in every run.
+    pub fn is_port_matched(&self) -> bool {
+        self.mapped.iter().all(|mapped| match mapped {
+            Some((remote1, remote2)) => {
+                remote1.port() == self.external_port && remote2.port() == self.external_port
+            }
+            None => false,
+        })
+    }
+
+    /// Returns if unsolicited inbound traffic arrives in every run.
+    pub fn is_inbound_allowed(&self) -> bool {
+        self.inbound.iter().all(|inbound| *inbound)
+    }
+
+    /// Returns if both servers see the same mapped address in every run.
+    ///
+    /// The runs are `RUN_INTERVAL` apart from the same local port, so a stable forward keeps one
+    /// external address over that time and towards both servers. A failed run is never stable.
+    pub fn is_stable(&self) -> bool {
+        match self.mapped.first() {
+            Some(Some((first, _))) => self.mapped.iter().all(|mapped| match mapped {
+                Some((remote1, remote2)) => remote1 == first && remote2 == first,
+                None => false,
+            }),
+            _ => false,
+        }
+    }
+
+    /// Returns if the forward passes every check.
+    pub fn is_verified(&self) -> bool {
+        self.is_port_matched() && self.is_inbound_allowed() && self.is_stable()
+    }
+}
+
+/// Verifies the port forward of the socket to the external port, running the test `runs` times
+/// `RUN_INTERVAL` apart.
+///
+/// The read timeout of the socket is used as the deadline of each run. A run failing with an error
+/// is recorded as failed, which fails every check, and the verification goes on.
+#[instrument(level = "info", skip(rw))]
+pub fn verify<R: RW + ?Sized>(
+    rw: &R,
+    server1: Ipv4Addr,
+    server2: Ipv4Addr,
+    external_port: u16,
+    runs: usize,
+) -> io::Result<Verification> {
+    let endpoints = Endpoints::new(server1, server2, Ports::default());
+
+    let ctx = Context {
+        cancel: &CancelToken::new(),
+        observer: &(),
+    };
+
+    verify_on(rw, &endpoints, external_port, runs, ctx)
+}
+
+/// Verifies the port forward of the socket against the endpoints.
+pub(crate) fn verify_on<R: RW + ?Sized>(
+    rw: &R,
+    endpoints: &Endpoints,
+    external_port: u16,
+    runs: usize,
+    ctx: Context,
+) -> io::Result<Verification> {
+    if runs == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "a verification needs a run at least",
+        ));
+    }
+
+    let mut mapped = Vec::new();
+    let mut inbound = Vec::new();
+    let mut errors = Vec::new();
+    for run in 0..runs {
+        if run > 0 {
+            ctx.cancel.sleep(RUN_INTERVAL)?;
+        }
+        match test_on(rw, endpoints, ctx) {
+            Ok(result) => {
+                debug!(
+                    run = run + 1,
+                    remote1 = %result.remote1(),
+                    remote2 = %result.remote2(),
+                    is_a = result.is_a(),
+                    "forward probed"
+                );
+                mapped.push(Some((result.remote1(), result.remote2())));
+                inbound.push(result.is_a());
+                errors.push(None);
+            }
+            Err(e) if Cancelled::is(&e) => return Err(e),
+            Err(e) => {
+                debug!(run = run + 1, error = %e, "forward probe failed");
+                mapped.push(None);
+                inbound.push(false);
+                errors.push(Some(e.to_string()));
+            }
+        }
+    }
+
+    Ok(Verification {
+        local: rw.local_addr()?,
+        external_port,
+        mapped,
+        inbound,
+        errors,
+    })
+}
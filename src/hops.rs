@@ -0,0 +1,353 @@
+//! Location of NAT devices on the path using TTL-limited probes.
+//!
+//! An echo probe is sent to the server with increasing TTL values. Routers on the path report the
+//! probes expiring at them with ICMP time exceeded messages, until the probe reaches the server
+//! and the server replies. Each probe leaves from its own port, so an ICMP message quoting it is
+//! told apart from late messages of earlier probes.
+//!
+//! A NAT device is located where the class of the reporting addresses grows from private to
+//! shared, or from private or shared to public, or where the addresses move to another private
+//! block, or to another `/24` network of `192.168.0.0/16`, as home routers number their LANs. A
+//! NAT device the addresses do not reveal, like one between public addresses, is only known from
+//! the server seeing another address than the host, and is not located. The trace stops at a hop
+//! rejecting the probe.
+
+use crate::protocol::{Request, Response, PORT_2};
+use crate::sys::set_option;
+use crate::topology::AddressClass;
+use crate::Socket;
+use std::io;
+use std::mem;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+use std::time::{Duration, Instant};
+use tracing::{debug, instrument};
+
+/// Represents the ICMP type of destination unreachable messages.
+const ICMP_DEST_UNREACH: u8 = 3;
+/// Represents the ICMP type of time exceeded messages.
+const ICMP_TIME_EXCEEDED: u8 = 11;
+
+/// Enumeration of the outcomes of a TTL-limited probe.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Hop {
+    /// Represents a probe expired at a router, which reported with an ICMP time exceeded message.
+    TimeExceeded(Ipv4Addr),
+    /// Represents a probe rejected with an ICMP destination unreachable message.
+    Unreachable(Ipv4Addr),
+    /// Represents a probe reaching the server, which replied with the remote address it saw.
+    Reply(SocketAddrV4),
+    /// Represents a probe answered by nothing before the timeout.
+    Silent,
+}
+
+impl Hop {
+    /// Returns the IP address of the router reporting at the hop.
+    pub fn ip(&self) -> Option<Ipv4Addr> {
+        match self {
+            Hop::TimeExceeded(ip) | Hop::Unreachable(ip) => Some(*ip),
+            Hop::Reply(_) | Hop::Silent => None,
+        }
+    }
+}
+
+/// Represents the hops of a path, the first of which is probed with TTL 1.
+#[derive(Clone, Debug)]
+pub struct Trace {
+    hops: Vec<Hop>,
+}
+
+impl Trace {
+    /// Creates a new `Trace` of the hops, the first of which is probed with TTL 1.
+    pub fn new(hops: Vec<Hop>) -> Trace {
+        Trace { hops }
+    }
+
+    /// Returns the hops.
+    pub fn hops(&self) -> &[Hop] {
+        &self.hops
+    }
+
+    /// Returns the number of hops to the server, the TTL at which the server starts replying.
+    pub fn distance(&self) -> Option<usize> {
+        self.hops
+            .iter()
+            .position(|hop| matches!(hop, Hop::Reply(_)))
+            .map(|i| i + 1)
+    }
+
+    /// Returns the remote address seen by the server.
+    pub fn remote(&self) -> Option<SocketAddrV4> {
+        self.hops.iter().find_map(|hop| match hop {
+            Hop::Reply(addr) => Some(*addr),
+            _ => None,
+        })
+    }
+
+    /// Returns the hops NAT devices are located at, `0` for the host of the given IP address
+    /// itself, or `None` for a NAT device known to exist but not located.
+    ///
+    /// The remote IP address seen by the server is regarded as the address of the last hop
+    /// before the server. Hops without an address are skipped, so a NAT device is reported at the
+    /// last hop known to be inside it, and hops after one rejecting the probe are ignored. No NAT
+    /// device is reported if the server sees the host itself. If the server sees another address
+    /// than the host while the addresses reveal no NAT device, the NAT device is on the host if the
+    /// server is the first hop, and not located otherwise.
+    pub fn nats(&self, host_ip: Ipv4Addr) -> Vec<Option<usize>> {
+        let remote = self.remote().map(|remote| *remote.ip());
+        if remote == Some(host_ip) {
+            return Vec::new();
+        }
+
+        let mut addrs = vec![(0, host_ip)];
+        for (i, hop) in self.hops.iter().enumerate() {
+            match hop {
+                Hop::TimeExceeded(ip) => addrs.push((i + 1, *ip)),
+                Hop::Unreachable(ip) => {
+                    addrs.push((i + 1, *ip));
+                    break;
+                }
+                Hop::Reply(addr) => addrs.push((i + 1, *addr.ip())),
+                Hop::Silent => {}
+            }
+        }
+
+        let mut nats = Vec::new();
+        let mut last: Option<(usize, Ipv4Addr)> = None;
+        for (i, ip) in addrs {
+            if AddressClass::of(ip) == AddressClass::Special {
+                continue;
+            }
+            if let Some((last_i, last_ip)) = last {
+                if is_boundary(last_ip, ip) {
+                    nats.push(Some(last_i));
+                }
+            }
+            last = Some((i, ip));
+        }
+
+        // The server sees another address than the host, so a NAT device hides between addresses
+        // of the same class, which is on the host only if nothing is in between
+        if let (true, Some(distance)) = (nats.is_empty(), self.distance()) {
+            nats.push(match distance {
+                1 => Some(0),
+                _ => None,
+            });
+        }
+
+        nats
+    }
+}
+
+/// Returns the rank of the class of the address, growing outwards, or `None` for a special one.
+fn rank(ip: Ipv4Addr) -> Option<u8> {
+    match AddressClass::of(ip) {
+        AddressClass::Private => Some(0),
+        AddressClass::Shared => Some(1),
+        AddressClass::Public => Some(2),
+        AddressClass::Special => None,
+    }
+}
+
+/// Returns the private block of the address, `10.0.0.0/8`, `172.16.0.0/12` or `192.168.0.0/16`
+/// in order, or `None` for another one.
+fn block(ip: Ipv4Addr) -> Option<u8> {
+    match ip.octets() {
+        [10, _, _, _] => Some(0),
+        [172, b, _, _] if (b & 0xf0) == 16 => Some(1),
+        [192, 168, _, _] => Some(2),
+        _ => None,
+    }
+}
+
+/// Returns if a NAT device is located between the address `inner` of a hop and the address
+/// `outer` of the next known hop.
+fn is_boundary(inner: Ipv4Addr, outer: Ipv4Addr) -> bool {
+    match (rank(inner), rank(outer)) {
+        (Some(inner), Some(outer)) if outer != inner => outer > inner,
+        _ => match (block(inner), block(outer)) {
+            // A home router numbers its LAN from another block than the network of the ISP
+            (Some(inner), Some(outer)) if inner != outer => true,
+            _ => {
+                let ([a1, b1, c1, _], [a2, b2, c2, _]) = (inner.octets(), outer.octets());
+                // Home routers number their LANs from different `/24` networks of
+                // `192.168.0.0/16`
+                [a1, b1] == [192, 168] && [a2, b2] == [192, 168] && c1 != c2
+            }
+        },
+    }
+}
+
+/// Represents an ICMP error queued on a socket.
+#[derive(Clone, Copy, Debug)]
+struct IcmpError {
+    icmp_type: u8,
+    offender: Option<Ipv4Addr>,
+    dst: SocketAddrV4,
+}
+
+/// Converts a `sockaddr_in` into a socket address.
+fn from_sockaddr(addr: &libc::sockaddr_in) -> Option<SocketAddrV4> {
+    match addr.sin_family as libc::c_int {
+        libc::AF_INET => Some(SocketAddrV4::new(
+            Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+            u16::from_be(addr.sin_port),
+        )),
+        _ => None,
+    }
+}
+
+/// Receives the ICMP errors queued on the socket, which quote the header of a datagram of the
+/// socket and the payload, or as much of it as the reporting router includes.
+fn recv_errors(fd: libc::c_int, payload: &[u8]) -> io::Result<Vec<IcmpError>> {
+    let mut errors = Vec::new();
+
+    loop {
+        let mut name: libc::sockaddr_in = unsafe { mem::zeroed() };
+        let mut buf = [0u8; 64];
+        let mut control = [0u8; 512];
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_name = &mut name as *mut libc::sockaddr_in as *mut libc::c_void;
+        msg.msg_namelen = mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = control.len() as _;
+
+        let ret = unsafe { libc::recvmsg(fd, &mut msg, libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT) };
+        if ret < 0 {
+            let e = io::Error::last_os_error();
+            return match e.kind() {
+                io::ErrorKind::WouldBlock => Ok(errors),
+                _ => Err(e),
+            };
+        }
+
+        let dst = match from_sockaddr(&name) {
+            Some(dst) => dst,
+            None => continue,
+        };
+        if !payload.starts_with(&buf[..(ret as usize).min(buf.len())]) {
+            continue;
+        }
+        let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+        while !cmsg.is_null() {
+            let header = unsafe { ptr::read_unaligned(cmsg) };
+            if header.cmsg_level == libc::IPPROTO_IP && header.cmsg_type == libc::IP_RECVERR {
+                let ee = unsafe { libc::CMSG_DATA(cmsg) } as *const libc::sock_extended_err;
+                let err = unsafe { ptr::read_unaligned(ee) };
+                if err.ee_origin == libc::SO_EE_ORIGIN_ICMP {
+                    let offender = unsafe {
+                        ptr::read_unaligned(libc::SO_EE_OFFENDER(ee) as *const libc::sockaddr_in)
+                    };
+                    errors.push(IcmpError {
+                        icmp_type: err.ee_type,
+                        offender: from_sockaddr(&offender).map(|addr| *addr.ip()),
+                        dst,
+                    });
+                }
+            }
+            cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+        }
+    }
+}
+
+/// Waits for the outcome of the probe of the socket until the deadline. ICMP errors are matched by
+/// the destination and the payload quoted.
+fn wait(socket: &Socket, server: SocketAddrV4, deadline: Instant) -> io::Result<Hop> {
+    let fd = socket.as_raw_fd();
+    let mut buffer = vec![0u8; u16::MAX as usize];
+
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(Hop::Silent);
+        }
+        let timeout = (deadline - now).as_millis().max(1) as libc::c_int;
+
+        let mut pollfd = libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let ret = unsafe { libc::poll(&mut pollfd, 1, timeout) };
+        if ret < 0 {
+            let e = io::Error::last_os_error();
+            match e.kind() {
+                io::ErrorKind::Interrupted => continue,
+                _ => return Err(e),
+            }
+        }
+
+        if pollfd.revents & libc::POLLERR != 0 {
+            for error in recv_errors(fd, Request::Echo.payload())? {
+                if error.dst != server {
+                    continue;
+                }
+                match (error.icmp_type, error.offender) {
+                    (ICMP_TIME_EXCEEDED, Some(offender)) => return Ok(Hop::TimeExceeded(offender)),
+                    (ICMP_DEST_UNREACH, Some(offender)) => return Ok(Hop::Unreachable(offender)),
+                    _ => {}
+                }
+            }
+        }
+        if pollfd.revents & libc::POLLIN != 0 {
+            match socket.socket.recv_from(buffer.as_mut_slice()) {
+                Ok((size, addr)) => {
+                    if addr != server.into() {
+                        continue;
+                    }
+                    if let Ok(resp) = Response::decode(&buffer[..size]) {
+                        if resp.is_reply_to(Request::Echo) {
+                            return Ok(Hop::Reply(resp.remote_addr()));
+                        }
+                    }
+                }
+                // Errors are reported by the error queue
+                Err(_) => continue,
+            }
+        }
+    }
+}
+
+/// Traces the path to the server with echo probes of TTL from 1 to `max_ttl`, from sockets bound to
+/// the local IP address, waiting up to `timeout` for each of them. The trace stops once the server
+/// replies, or a hop rejects the probe.
+#[instrument(level = "info")]
+pub fn trace(
+    local_ip: Ipv4Addr,
+    server: Ipv4Addr,
+    max_ttl: u8,
+    timeout: Duration,
+) -> io::Result<Trace> {
+    let server = SocketAddrV4::new(server, PORT_2);
+
+    let mut hops = Vec::new();
+    for i in 1..=max_ttl {
+        // The port of the socket identifies the probe in the ICMP messages quoting it
+        let socket = Socket::bind(SocketAddrV4::new(local_ip, 0))?;
+        set_option(socket.as_raw_fd(), libc::IPPROTO_IP, libc::IP_RECVERR, 1)?;
+        socket.socket.set_nonblocking(true)?;
+        socket.socket.set_ttl(i as u32)?;
+        socket.socket.send_to(Request::Echo.payload(), server)?;
+
+        let hop = wait(&socket, server, Instant::now() + timeout)?;
+        debug!(
+            ttl = i,
+            port = socket.socket.local_addr()?.port(),
+            ?hop,
+            "hop"
+        );
+        hops.push(hop);
+        if let Hop::Reply(_) | Hop::Unreachable(_) = hop {
+            break;
+        }
+    }
+
+    Ok(Trace { hops })
+}
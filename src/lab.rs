@@ -0,0 +1,361 @@
+//! Labs of network namespaces emulating NATs with netfilter.
+//!
+//! A lab connects three namespaces with veth pairs: the host, the router and the internet. The
+//! router forwards between the LAN of the host, `10.0.0.0/24`, and the WAN, `198.18.0.0/24`, where
+//! the internet serves both servers. The NAT of the router is set up with `iptables`, so a lab
+//! needs root, `ip` and `iptables`, except a lab without NAT, which only routes. Namespaces are
+//! named after the lab, and deleted along with their veth pairs when the lab is dropped.
+
+use crate::netns::NetNs;
+use crate::protocol::Ports;
+use crate::server::Server;
+use crate::tester::NatTester;
+use crate::{NatTestResult, NatType};
+use std::fmt::{self, Display};
+use std::fs;
+use std::io;
+use std::net::Ipv4Addr;
+use std::process::Command;
+use std::str::FromStr;
+use tracing::{debug, instrument};
+
+/// Represents the IP address of the host.
+pub const HOST: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+/// Represents the IP address of the router in the LAN.
+pub const GATEWAY: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+/// Represents the IP address of the router in the WAN, which the NAT maps the host to.
+pub const ROUTER: Ipv4Addr = Ipv4Addr::new(198, 18, 0, 1);
+/// Represents the IP address of the server 1.
+pub const SERVER_1: Ipv4Addr = Ipv4Addr::new(198, 18, 0, 2);
+/// Represents the IP address of the server 2.
+pub const SERVER_2: Ipv4Addr = Ipv4Addr::new(198, 18, 0, 3);
+
+/// Represents the NATs a lab emulates.
+pub const NATS: [Nat; 5] = [
+    Nat::None,
+    Nat::Masquerade,
+    Nat::RandomMasquerade,
+    Nat::FullCone,
+    Nat::Drop,
+];
+
+/// Enumeration of NATs of a router.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Nat {
+    /// Represents routing without NAT.
+    None,
+    /// Represents `MASQUERADE`, which preserves ports and filters by the address and port.
+    Masquerade,
+    /// Represents `MASQUERADE --random`, which allocates a random port for each destination.
+    RandomMasquerade,
+    /// Represents full-cone NAT, emulated with `SNAT` and `DNAT` of every UDP datagram to the host.
+    FullCone,
+    /// Represents `MASQUERADE` dropping every forwarded UDP datagram.
+    Drop,
+}
+
+impl Nat {
+    /// Returns the NAT type a NAT test is expected to find.
+    pub fn expected(&self) -> NatType {
+        match self {
+            Nat::None | Nat::FullCone => NatType::A,
+            Nat::Masquerade => NatType::B,
+            Nat::RandomMasquerade => NatType::D,
+            Nat::Drop => NatType::F,
+        }
+    }
+
+    /// Returns if the NAT is set up with `iptables`.
+    pub fn needs_iptables(&self) -> bool {
+        *self != Nat::None
+    }
+
+    /// Returns the arguments of `iptables` setting up the NAT.
+    fn rules(&self) -> Vec<Vec<String>> {
+        let router = ROUTER.to_string();
+        let host = HOST.to_string();
+        let masquerade = vec![
+            "-t",
+            "nat",
+            "-A",
+            "POSTROUTING",
+            "-o",
+            "wan",
+            "-j",
+            "MASQUERADE",
+        ];
+        let rules = match self {
+            Nat::None => vec![],
+            Nat::Masquerade => vec![masquerade],
+            Nat::RandomMasquerade => vec![[masquerade.as_slice(), &["--random"]].concat()],
+            Nat::FullCone => vec![
+                vec![
+                    "-t",
+                    "nat",
+                    "-A",
+                    "POSTROUTING",
+                    "-o",
+                    "wan",
+                    "-p",
+                    "udp",
+                    "-j",
+                    "SNAT",
+                    "--to-source",
+                    &router,
+                ],
+                vec![
+                    "-t",
+                    "nat",
+                    "-A",
+                    "PREROUTING",
+                    "-i",
+                    "wan",
+                    "-p",
+                    "udp",
+                    "-d",
+                    &router,
+                    "-j",
+                    "DNAT",
+                    "--to-destination",
+                    &host,
+                ],
+            ],
+            Nat::Drop => vec![masquerade, vec!["-A", "FORWARD", "-p", "udp", "-j", "DROP"]],
+        };
+
+        rules
+            .into_iter()
+            .map(|rule| rule.into_iter().map(String::from).collect())
+            .collect()
+    }
+}
+
+impl Display for Nat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Nat::None => write!(f, "none"),
+            Nat::Masquerade => write!(f, "masquerade"),
+            Nat::RandomMasquerade => write!(f, "random"),
+            Nat::FullCone => write!(f, "full-cone"),
+            Nat::Drop => write!(f, "drop"),
+        }
+    }
+}
+
+impl FromStr for Nat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Nat::None),
+            "masquerade" => Ok(Nat::Masquerade),
+            "random" => Ok(Nat::RandomMasquerade),
+            "full-cone" => Ok(Nat::FullCone),
+            "drop" => Ok(Nat::Drop),
+            _ => Err(format!("unknown NAT {}", s)),
+        }
+    }
+}
+
+/// Returns an error unless a lab of the NAT can be built, which needs root, `ip` and `iptables` if
+/// the NAT is set up with it.
+pub fn check(nat: Nat) -> io::Result<()> {
+    if unsafe { libc::geteuid() } != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "a lab needs root",
+        ));
+    }
+    exec("ip", &["-V"])?;
+    if nat.needs_iptables() {
+        exec("iptables", &["-V"])?;
+    }
+
+    Ok(())
+}
+
+/// Represents a lab emulating a NAT between a host and the internet.
+///
+/// # Examples
+///
+/// ```no_run
+/// use ninat::lab::{Lab, Nat};
+/// use ninat::tester::NatTester;
+///
+/// let lab = Lab::new("ninat", Nat::Masquerade).unwrap();
+/// let _server = lab.serve().unwrap();
+///
+/// let result = lab.nat_test(NatTester::new()).unwrap();
+/// assert_eq!(result.nat(), lab.nat().expected());
+/// ```
+#[derive(Debug)]
+pub struct Lab {
+    nat: Nat,
+    host: String,
+    router: String,
+    internet: String,
+    created: Vec<String>,
+}
+
+impl Lab {
+    /// Creates a new `Lab` of the NAT, named `<name>-host`, `<name>-router` and `<name>-internet`.
+    #[instrument(level = "debug", err(level = "debug"))]
+    pub fn new(name: &str, nat: Nat) -> io::Result<Lab> {
+        check(nat)?;
+
+        let mut lab = Lab {
+            nat,
+            host: format!("{}-host", name),
+            router: format!("{}-router", name),
+            internet: format!("{}-internet", name),
+            created: Vec::new(),
+        };
+        // Namespaces created before a failure are deleted when the lab is dropped
+        for netns in [lab.host.clone(), lab.router.clone(), lab.internet.clone()] {
+            exec("ip", &["netns", "add", &netns])?;
+            lab.created.push(netns.clone());
+            exec("ip", &["-n", &netns, "link", "set", "lo", "up"])?;
+        }
+        lab.build()?;
+        debug!(name, %nat, "lab built");
+
+        Ok(lab)
+    }
+
+    /// Connects the namespaces and sets up the NAT.
+    fn build(&self) -> io::Result<()> {
+        let (host, router, internet) = (&self.host, &self.router, &self.internet);
+
+        // Links
+        exec(
+            "ip",
+            &[
+                "link", "add", "eth0", "netns", host, "type", "veth", "peer", "name", "lan",
+                "netns", router,
+            ],
+        )?;
+        exec(
+            "ip",
+            &[
+                "link", "add", "wan", "netns", router, "type", "veth", "peer", "name", "eth0",
+                "netns", internet,
+            ],
+        )?;
+
+        // Addresses
+        let addrs = [
+            (host, HOST, "eth0"),
+            (router, GATEWAY, "lan"),
+            (router, ROUTER, "wan"),
+            (internet, SERVER_1, "eth0"),
+            (internet, SERVER_2, "eth0"),
+        ];
+        for (netns, ip, dev) in addrs.iter() {
+            let addr = format!("{}/24", ip);
+            exec("ip", &["-n", netns, "addr", "add", &addr, "dev", dev])?;
+        }
+        for (netns, dev) in [
+            (host, "eth0"),
+            (router, "lan"),
+            (router, "wan"),
+            (internet, "eth0"),
+        ] {
+            exec("ip", &["-n", netns, "link", "set", dev, "up"])?;
+        }
+
+        // Routes
+        let gateway = GATEWAY.to_string();
+        exec(
+            "ip",
+            &["-n", host, "route", "add", "default", "via", &gateway],
+        )?;
+        // The internet reaches the LAN only without NAT
+        if self.nat == Nat::None {
+            let router = ROUTER.to_string();
+            exec(
+                "ip",
+                &[
+                    "-n",
+                    internet,
+                    "route",
+                    "add",
+                    "10.0.0.0/24",
+                    "via",
+                    &router,
+                ],
+            )?;
+        }
+        NetNs::open(router)?.run(|| fs::write("/proc/sys/net/ipv4/ip_forward", "1"))??;
+
+        // NAT
+        for rule in self.nat.rules() {
+            let mut args = vec!["netns", "exec", router, "iptables"];
+            args.extend(rule.iter().map(String::as_str));
+            exec("ip", &args)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the NAT of the lab.
+    pub fn nat(&self) -> Nat {
+        self.nat
+    }
+
+    /// Returns the name of the namespace of the host.
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// Returns the name of the namespace of the router.
+    pub fn router(&self) -> &str {
+        &self.router
+    }
+
+    /// Returns the name of the namespace of the internet.
+    pub fn internet(&self) -> &str {
+        &self.internet
+    }
+
+    /// Serves both servers in the internet until the returned server is dropped.
+    pub fn serve(&self) -> io::Result<Server> {
+        NetNs::open(&self.internet)?.run(|| Server::bind(SERVER_1, SERVER_2, Ports::default()))?
+    }
+
+    /// Runs the NAT test of the tester in the host, against the servers in the internet.
+    #[instrument(level = "info", skip(self, tester), fields(nat = %self.nat))]
+    pub fn nat_test(&self, tester: NatTester) -> io::Result<NatTestResult> {
+        let tester = tester.servers(SERVER_1, SERVER_2);
+
+        tester.run_in(&NetNs::open(&self.host)?)
+    }
+}
+
+impl Drop for Lab {
+    fn drop(&mut self) {
+        for netns in self.created.drain(..).rev() {
+            if let Err(ref e) = exec("ip", &["netns", "del", &netns]) {
+                debug!(%netns, error = %e, "lab namespace not deleted");
+            }
+        }
+    }
+}
+
+/// Runs the program, returning an error with what it prints if it fails.
+fn exec(program: &str, args: &[&str]) -> io::Result<()> {
+    debug!(program, args = %args.join(" "), "command run");
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .map_err(|e| io::Error::new(e.kind(), format!("cannot run {}: {}", program, e)))?;
+
+    match output.status.success() {
+        true => Ok(()),
+        false => Err(io::Error::other(format!(
+            "{} {} failed: {}",
+            program,
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))),
+    }
+}
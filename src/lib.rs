@@ -1,24 +1,70 @@
 //! Deal with NAT traversal using Nintendo service.
 
+pub mod advisor;
+pub mod behavior;
+pub mod cancel;
+pub mod consensus;
+pub mod forward;
+#[cfg(target_os = "linux")]
+pub mod hops;
+#[cfg(target_os = "linux")]
+pub mod lab;
+pub mod mapping;
+#[cfg(target_os = "linux")]
+pub mod monitor;
+#[cfg(target_os = "linux")]
+pub mod netns;
+pub mod observer;
+pub mod pcap;
+pub mod protocol;
+pub mod server;
+#[cfg(unix)]
+pub mod sidecar;
+#[cfg(unix)]
+mod sys;
+pub mod tester;
+pub mod topology;
+
+use behavior::{FilteringBehavior, MappingBehavior, NatBehavior, PortAllocation};
+use cancel::{CancelToken, CANCEL_INTERVAL};
+use observer::{Observer, Phase};
+use protocol::{Ports, Request, Response, PORT_2, REQUESTS};
+use serde::{Deserialize, Serialize};
 use socks::{Socks5Datagram, TargetAddr};
-use std::convert::TryFrom;
+use std::collections::VecDeque;
+use std::error;
 use std::fmt::{self, Display};
 use std::io;
+#[cfg(unix)]
+use std::mem;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
-use std::time::Duration;
+use std::ops::Add;
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use topology::Topology;
+use tracing::{debug, debug_span, info, instrument, trace, Span};
 
 /// Looks up the IPv4 address for a given hostname via DNS.
+///
+/// Every failure of the resolver is returned as `NotFound`, keeping its message.
+#[instrument(level = "debug", err(level = "debug"))]
 pub fn lookup_host_v4(host: &str) -> io::Result<Ipv4Addr> {
-    dns_lookup::lookup_host(host)?
+    let start = Instant::now();
+    let ip = dns_lookup::lookup_host(host)
+        .map_err(|e| io::Error::new(io::ErrorKind::NotFound, e))?
         .into_iter()
-        .map(|addr| match addr {
+        .find_map(|addr| match addr {
             IpAddr::V4(ip) => Some(ip),
             _ => None,
         })
-        .filter(|addr| addr.is_some())
-        .map(|addr| addr.unwrap())
-        .next()
-        .ok_or(io::Error::from(io::ErrorKind::NotFound))
+        .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
+    debug!(%ip, elapsed = ?start.elapsed(), "host resolved");
+
+    Ok(ip)
 }
 
 /// Represents an socket which can send data to and receive data from a certain address.
@@ -45,8 +91,137 @@ pub trait RW: Send + Sync {
     fn write_timeout(&self) -> io::Result<Option<Duration>>;
 }
 
+/// Implements `RW` for a pointer type by forwarding to the pointee.
+macro_rules! forward_rw {
+    ($($pointer:ty),*) => {
+        $(
+            impl<T: RW + ?Sized> RW for $pointer {
+                fn local_addr(&self) -> io::Result<SocketAddrV4> {
+                    (**self).local_addr()
+                }
+
+                fn send_to(&self, buf: &[u8], addr: SocketAddrV4) -> io::Result<usize> {
+                    (**self).send_to(buf, addr)
+                }
+
+                fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddrV4)> {
+                    (**self).recv_from(buf)
+                }
+
+                fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+                    (**self).set_read_timeout(dur)
+                }
+
+                fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+                    (**self).set_write_timeout(dur)
+                }
+
+                fn read_timeout(&self) -> io::Result<Option<Duration>> {
+                    (**self).read_timeout()
+                }
+
+                fn write_timeout(&self) -> io::Result<Option<Duration>> {
+                    (**self).write_timeout()
+                }
+            }
+        )*
+    };
+}
+
+forward_rw!(&T, Box<T>, Arc<T>);
+
+impl RW for UdpSocket {
+    fn local_addr(&self) -> io::Result<SocketAddrV4> {
+        let addr = UdpSocket::local_addr(self)?;
+
+        match addr {
+            SocketAddr::V4(addr) => Ok(addr),
+            SocketAddr::V6(_) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "IPv6 socket is not supported",
+            )),
+        }
+    }
+
+    fn send_to(&self, buf: &[u8], addr: SocketAddrV4) -> io::Result<usize> {
+        let size = UdpSocket::send_to(self, buf, addr)?;
+
+        Ok(size)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddrV4)> {
+        let (size, addr) = UdpSocket::recv_from(self, buf)?;
+
+        match addr {
+            SocketAddr::V4(addr) => Ok((size, addr)),
+            SocketAddr::V6(_) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "IPv6 datagram is not supported",
+            )),
+        }
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        UdpSocket::set_read_timeout(self, dur)?;
+
+        Ok(())
+    }
+
+    fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        UdpSocket::set_write_timeout(self, dur)?;
+
+        Ok(())
+    }
+
+    fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        let duration = UdpSocket::read_timeout(self)?;
+
+        Ok(duration)
+    }
+
+    fn write_timeout(&self) -> io::Result<Option<Duration>> {
+        let duration = UdpSocket::write_timeout(self)?;
+
+        Ok(duration)
+    }
+}
+
+/// Represents the error of a SOCKS proxy, carried by an `io::Error` of the same kind, which tells
+/// the proxy failing apart from local sockets.
+#[derive(Debug)]
+pub struct ProxyError(io::Error);
+
+impl ProxyError {
+    /// Returns if the error is of a SOCKS proxy.
+    pub fn is(e: &io::Error) -> bool {
+        match e.get_ref() {
+            Some(inner) => inner.is::<ProxyError>(),
+            None => false,
+        }
+    }
+
+    /// Wraps the error of a SOCKS proxy, keeping its kind.
+    fn wrap(e: io::Error) -> io::Error {
+        io::Error::new(e.kind(), ProxyError(e))
+    }
+}
+
+impl Display for ProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SOCKS proxy: {}", self.0)
+    }
+}
+
+impl error::Error for ProxyError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&self.0)
+    }
+}
+
 /// Represents an UDP datagram, containing a TCP stream keeping the SOCKS proxy alive and an UDP
 /// socket sending and receiving data.
+///
+/// Errors of the proxy are returned as `ProxyError`s.
 #[derive(Debug)]
 pub struct Datagram {
     datagram: Socks5Datagram,
@@ -54,6 +229,12 @@ pub struct Datagram {
 
 impl Datagram {
     /// Creates a new `Datagram`.
+    #[instrument(
+        level = "debug",
+        skip(auth),
+        fields(auth = auth.is_some()),
+        err(level = "debug")
+    )]
     pub fn bind(
         proxy: SocketAddrV4,
         addr: SocketAddrV4,
@@ -65,9 +246,15 @@ impl Datagram {
                 addr,
                 username.as_str(),
                 password.as_str(),
-            )?,
-            None => Socks5Datagram::bind(proxy, addr)?,
-        };
+            ),
+            None => Socks5Datagram::bind(proxy, addr),
+        }
+        .map_err(ProxyError::wrap)?;
+        debug!(
+            local = %datagram.get_ref().local_addr()?,
+            relay = %datagram.get_ref().peer_addr()?,
+            "datagram bound"
+        );
 
         Ok(Datagram { datagram })
     }
@@ -84,21 +271,18 @@ impl RW for Datagram {
     }
 
     fn send_to(&self, buf: &[u8], addr: SocketAddrV4) -> io::Result<usize> {
-        let size = self.datagram.send_to(buf, addr)?;
+        let size = self.datagram.send_to(buf, addr).map_err(ProxyError::wrap)?;
 
         Ok(size)
     }
 
     fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddrV4)> {
-        let (size, addr) = self.datagram.recv_from(buf)?;
+        let (size, addr) = self.datagram.recv_from(buf).map_err(ProxyError::wrap)?;
 
-        return match addr {
-            TargetAddr::Ip(addr) => match addr {
-                SocketAddr::V4(addr) => Ok((size, addr)),
-                _ => unreachable!(),
-            },
-            _ => unreachable!(),
-        };
+        match addr {
+            TargetAddr::Ip(SocketAddr::V4(addr)) => Ok((size, addr)),
+            _ => Err(io::Error::from(io::ErrorKind::InvalidData)),
+        }
     }
 
     fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
@@ -139,59 +323,130 @@ impl Socket {
 
         Ok(Socket { socket })
     }
+
+    /// Creates a new `Socket` with `SO_REUSEADDR` and `SO_REUSEPORT`, sharing the port with other
+    /// sockets which set the options.
+    #[cfg(unix)]
+    #[instrument(level = "debug", err(level = "debug"))]
+    pub fn bind_reuse_port(addr: SocketAddrV4) -> io::Result<Socket> {
+        let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // The socket closes the descriptor on errors
+        let socket = unsafe { Socket::from_raw_fd(fd) };
+
+        sys::set_option(fd, libc::SOL_SOCKET, libc::SO_REUSEADDR, 1)?;
+        sys::set_option(fd, libc::SOL_SOCKET, libc::SO_REUSEPORT, 1)?;
+
+        let mut name: libc::sockaddr_in = unsafe { mem::zeroed() };
+        name.sin_family = libc::AF_INET as libc::sa_family_t;
+        name.sin_port = addr.port().to_be();
+        name.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+        let ret = unsafe {
+            libc::bind(
+                fd,
+                &name as *const libc::sockaddr_in as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        debug!(local = %socket.local_addr()?, "socket bound");
+
+        Ok(socket)
+    }
+
+    /// Creates a new `Socket` from the file descriptor of a socket passed by another process,
+    /// checking that it is an open UDP socket of IPv4. The descriptor is left alone on errors.
+    ///
+    /// # Safety
+    ///
+    /// The descriptor must be owned by the caller, and is owned by the socket afterwards.
+    #[cfg(unix)]
+    pub unsafe fn from_raw_fd_checked(fd: RawFd) -> io::Result<Socket> {
+        if libc::fcntl(fd, libc::F_GETFD) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if sys::option(fd, libc::SOL_SOCKET, libc::SO_TYPE)? != libc::SOCK_DGRAM {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("file descriptor {} is not a UDP socket", fd),
+            ));
+        }
+        if sys::family(fd)? != libc::AF_INET {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("file descriptor {} is not an IPv4 socket", fd),
+            ));
+        }
+
+        Ok(Socket::from_raw_fd(fd))
+    }
+
+    /// Creates a new `Socket` from a socket created elsewhere.
+    pub fn from_std(socket: UdpSocket) -> Socket {
+        Socket { socket }
+    }
+
+    /// Returns the underlying socket.
+    pub fn into_std(self) -> UdpSocket {
+        self.socket
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for Socket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+#[cfg(unix)]
+impl FromRawFd for Socket {
+    unsafe fn from_raw_fd(fd: RawFd) -> Self {
+        Socket::from_std(UdpSocket::from_raw_fd(fd))
+    }
+}
+
+impl From<UdpSocket> for Socket {
+    fn from(s: UdpSocket) -> Self {
+        Socket::from_std(s)
+    }
 }
 
 impl RW for Socket {
     fn local_addr(&self) -> io::Result<SocketAddrV4> {
-        let addr = self.socket.local_addr()?;
-
-        match addr {
-            SocketAddr::V4(addr) => Ok(addr),
-            _ => unreachable!(),
-        }
+        RW::local_addr(&self.socket)
     }
 
     fn send_to(&self, buf: &[u8], addr: SocketAddrV4) -> io::Result<usize> {
-        let size = self.socket.send_to(buf, addr)?;
-
-        Ok(size)
+        RW::send_to(&self.socket, buf, addr)
     }
 
     fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddrV4)> {
-        let (size, addr) = self.socket.recv_from(buf)?;
-
-        match addr {
-            SocketAddr::V4(addr) => Ok((size, addr)),
-            _ => unreachable!(),
-        }
+        RW::recv_from(&self.socket, buf)
     }
 
     fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
-        self.socket.set_read_timeout(dur)?;
-
-        Ok(())
+        RW::set_read_timeout(&self.socket, dur)
     }
 
     fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
-        self.socket.set_write_timeout(dur)?;
-
-        Ok(())
+        RW::set_write_timeout(&self.socket, dur)
     }
 
     fn read_timeout(&self) -> io::Result<Option<Duration>> {
-        let duration = self.socket.read_timeout()?;
-
-        Ok(duration)
+        RW::read_timeout(&self.socket)
     }
 
     fn write_timeout(&self) -> io::Result<Option<Duration>> {
-        let duration = self.socket.write_timeout()?;
-
-        Ok(duration)
+        RW::write_timeout(&self.socket)
     }
 }
 
-#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
 /// Enumeration of NAT types.
 pub enum NatType {
     /// Represents the NAT Type A.
@@ -207,6 +462,17 @@ pub enum NatType {
 }
 
 impl NatType {
+    /// Returns if the NAT type meets the expectation, being the NAT type of `expect` if any, or at
+    /// least as open as `expect_at_least` if any, or not F otherwise.
+    pub fn meets(&self, expect: Option<NatType>, expect_at_least: Option<NatType>) -> bool {
+        // NAT types are ordered from the most open
+        match (expect, expect_at_least) {
+            (Some(expect), _) => *self == expect,
+            (None, Some(expect_at_least)) => *self <= expect_at_least,
+            (None, None) => *self != NatType::F,
+        }
+    }
+
     /// Returns the Nintendo (Nintendo Switch) NAT type.
     pub fn nintendo(&self) -> String {
         self.to_string()
@@ -247,236 +513,1441 @@ impl Display for NatType {
     }
 }
 
-/// Represents the payload for sending only.
-const PAYLOAD_1: [u8; 16] = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
-/// Represents the payload for an echoing back.
-const PAYLOAD_2: [u8; 16] = [0, 0, 0, 0x65, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
-/// Represents the payload for receiving from another port.
-const PAYLOAD_3: [u8; 16] = [0, 0, 0, 0x66, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
-/// Represents the payload for an echoing back.
-const PAYLOAD_4: [u8; 16] = [0, 0, 0, 0x67, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+impl FromStr for NatType {
+    type Err = ParseNatTypeError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "A" | "a" => Ok(NatType::A),
+            "B" | "b" => Ok(NatType::B),
+            "C" | "c" => Ok(NatType::C),
+            "D" | "d" => Ok(NatType::D),
+            "F" | "f" => Ok(NatType::F),
+            _ => Err(ParseNatTypeError(s.to_string())),
+        }
+    }
+}
+
+/// Represents an error parsing a NAT type.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ParseNatTypeError(String);
 
-#[allow(dead_code)]
-#[derive(Clone, Debug)]
-/// Represents the response from the server.
-struct Response {
-    payload: [u8; 4],
-    reserved: [u8; 2],
-    port: u16,
-    remote_ip: Ipv4Addr,
-    local_ip: Ipv4Addr,
+impl Display for ParseNatTypeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown NAT type {}", self.0)
+    }
 }
 
-impl Response {
-    fn unique_number(&self) -> u8 {
-        self.payload[3]
+impl error::Error for ParseNatTypeError {}
+
+/// Represents the times of sending packets at once.
+const ONE_TIME_SEND: usize = 5;
+
+/// Represents the minimum time to wait for the receiving only port once both echoes are answered.
+const PORT_3_GRACE: Duration = Duration::from_millis(100);
+
+/// Represents the time to wait for each packet queued before a test.
+const DRAIN_TIMEOUT: Duration = Duration::from_millis(1);
+
+/// Represents the most packets discarded before a test, so a flood cannot hold the test.
+const DRAIN_LIMIT: usize = 64;
+
+/// Represents the payload of hairpin probes, followed by the mapped port of the receiver.
+const HAIRPIN_PAYLOAD: &[u8] = b"ninat hairpin";
+
+/// Represents the addresses of a test.
+#[derive(Clone, Copy, Debug)]
+struct Endpoints {
+    /// Server1:Port1, sending only
+    addr_1_1: SocketAddrV4,
+    /// Server1:Port2, echoing back or requesting receiving from another port
+    addr_1_2: SocketAddrV4,
+    /// Server1:Port3, receiving only
+    addr_1_3: SocketAddrV4,
+    /// Server2:Port2, echoing back
+    addr_2: SocketAddrV4,
+}
+
+impl Endpoints {
+    fn new(server1: Ipv4Addr, server2: Ipv4Addr, ports: Ports) -> Endpoints {
+        Endpoints {
+            addr_1_1: SocketAddrV4::new(server1, ports.send_only()),
+            addr_1_2: SocketAddrV4::new(server1, ports.echo()),
+            addr_1_3: SocketAddrV4::new(server1, ports.inbound()),
+            addr_2: SocketAddrV4::new(server2, ports.echo()),
+        }
     }
 
-    /// Returns if the response is replied to a payload 2.
-    fn is_payload_2(&self) -> bool {
-        self.payload[3] == 0x65
+    /// Returns the ports of the servers.
+    fn ports(&self) -> Ports {
+        Ports::new(
+            self.addr_1_1.port(),
+            self.addr_1_2.port(),
+            self.addr_1_3.port(),
+        )
     }
 
-    /// Returns if the response is replied to a payload 3.
-    fn is_payload_3(&self) -> bool {
-        self.payload[3] == 0x66
+    /// Returns the address the request is sent to.
+    fn addr(&self, request: Request) -> SocketAddrV4 {
+        match request {
+            Request::SendOnly => self.addr_1_1,
+            Request::Echo | Request::AnotherPort => self.addr_1_2,
+            Request::SecondEcho => self.addr_2,
+        }
     }
 
-    /// Returns if the response is replied to a payload 4.
-    fn is_payload_4(&self) -> bool {
-        self.payload[3] == 0x67
+    /// Returns the addresses replies come from, along with the requests they reply to.
+    fn replies(&self) -> [(SocketAddrV4, Request); 3] {
+        [
+            (self.addr_1_2, Request::Echo),
+            (self.addr_1_3, Request::AnotherPort),
+            (self.addr_2, Request::SecondEcho),
+        ]
     }
+}
 
-    #[allow(dead_code)]
-    /// Returns the remote port from the server.
-    fn port(&self) -> u16 {
-        self.port
+/// Represents the packets discarded during a test.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Discarded {
+    invalid: usize,
+    unsolicited: usize,
+}
+
+impl Discarded {
+    /// Returns the number of packets coming from a server which are malformed, do not reply to
+    /// the probe sent to that address, or contradict the replies taken.
+    pub fn invalid(&self) -> usize {
+        self.invalid
     }
 
-    #[allow(dead_code)]
-    /// Returns the remote IP address from the server.
-    fn remote_ip(&self) -> Ipv4Addr {
-        self.remote_ip
+    /// Returns the number of packets coming from other addresses, or queued before the test.
+    pub fn unsolicited(&self) -> usize {
+        self.unsolicited
     }
 
-    #[allow(dead_code)]
-    /// Returns the local IP address from the server.
-    fn local_ip(&self) -> Ipv4Addr {
-        self.local_ip
+    /// Returns the number of packets discarded.
+    pub fn total(&self) -> usize {
+        self.invalid + self.unsolicited
     }
+}
+
+impl Add for Discarded {
+    type Output = Discarded;
 
-    /// Returns the remote address from the server.
-    fn remote_addr(&self) -> SocketAddrV4 {
-        SocketAddrV4::new(self.remote_ip, self.port)
+    fn add(self, rhs: Self) -> Self::Output {
+        Discarded {
+            invalid: self.invalid + rhs.invalid,
+            unsolicited: self.unsolicited + rhs.unsolicited,
+        }
     }
 }
 
-impl From<[u8; 16]> for Response {
-    fn from(s: [u8; 16]) -> Self {
-        let [a, b, c, d, e, f, g, h, i, j, k, l, m, n, o, p] = s;
-        Response {
-            payload: [a, b, c, d],
-            reserved: [e, f],
-            port: u16::from_be_bytes([g, h]),
-            remote_ip: Ipv4Addr::new(i, j, k, l),
-            local_ip: Ipv4Addr::new(m, n, o, p),
+/// Represents the answers received in a test.
+///
+/// Every distinct reply to the echoes is counted, and the remote addresses are only taken once the
+/// answers are settled.
+#[derive(Clone, Debug, Default)]
+struct Answers {
+    echoes1: Vec<((SocketAddrV4, Ipv4Addr), usize)>,
+    echoes2: Vec<(SocketAddrV4, usize)>,
+    remote1: Option<SocketAddrV4>,
+    remote2: Option<SocketAddrV4>,
+    local_ip: Option<Ipv4Addr>,
+    is_a: bool,
+    discarded: Discarded,
+}
+
+impl Answers {
+    /// Returns if both echoes are answered.
+    fn is_answered(&self) -> bool {
+        !self.echoes1.is_empty() && !self.echoes2.is_empty()
+    }
+
+    /// Returns if the answers are sufficient to decide the test.
+    fn is_complete(&self) -> bool {
+        self.is_answered() && self.is_a
+    }
+
+    /// Takes the remote addresses from the replies, counting the others as invalid.
+    ///
+    /// Every probe is sent several times, so the replies of a server agree unless some are forged.
+    /// The replies in which both servers see the same IP address are preferred, and then the
+    /// replies received the most, and then the first ones.
+    fn settle(&mut self) {
+        let indices = |len: usize| match len {
+            0 => vec![None],
+            len => (0..len).map(Some).collect(),
+        };
+        let key = |(i, j): (Option<usize>, Option<usize>)| {
+            let echo1 = i.map(|i| self.echoes1[i]);
+            let echo2 = j.map(|j| self.echoes2[j]);
+            let is_agreed = match (echo1, echo2) {
+                (Some(((remote1, _), _)), Some((remote2, _))) => remote1.ip() == remote2.ip(),
+                _ => false,
+            };
+            let count = echo1.map_or(0, |(_, count)| count) + echo2.map_or(0, |(_, count)| count);
+
+            (is_agreed, count)
+        };
+        let pairs: Vec<_> = indices(self.echoes1.len())
+            .into_iter()
+            .flat_map(|i| indices(self.echoes2.len()).into_iter().map(move |j| (i, j)))
+            .collect();
+        // The first pair wins a tie
+        let (i, j) = pairs
+            .into_iter()
+            .rev()
+            .max_by_key(|pair| key(*pair))
+            .unwrap_or((None, None));
+
+        let total = self.echoes1.iter().map(|(_, count)| count).sum::<usize>()
+            + self.echoes2.iter().map(|(_, count)| count).sum::<usize>();
+        let mut taken = 0;
+        if let Some(i) = i {
+            let ((remote1, local_ip), count) = self.echoes1[i];
+            self.remote1 = Some(remote1);
+            self.local_ip = Some(local_ip);
+            taken += count;
+        }
+        if let Some(j) = j {
+            let (remote2, count) = self.echoes2[j];
+            self.remote2 = Some(remote2);
+            taken += count;
+        }
+        if total > taken {
+            debug!(
+                replies = total,
+                rejected = total - taken,
+                "discarded contradicting replies"
+            );
+            self.discarded.invalid += total - taken;
         }
     }
 }
 
-impl TryFrom<&[u8]> for Response {
-    type Error = io::Error;
+/// Counts the reply.
+fn tally<T: PartialEq>(replies: &mut Vec<(T, usize)>, reply: T) {
+    match replies.iter_mut().find(|(r, _)| *r == reply) {
+        Some((_, count)) => *count += 1,
+        None => replies.push((reply, 1)),
+    }
+}
 
-    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
-        match value.len() {
-            16 => {
-                let mut s = [0u8; 16];
-                s.clone_from_slice(&value);
+/// Returns the instant by which a test must finish, derived from the read timeout of the socket.
+fn deadline(timeout: Option<Duration>, start: Instant) -> Option<Instant> {
+    timeout.map(|timeout| start + timeout)
+}
+
+/// Discards the packets queued before a test, up to `DRAIN_LIMIT`.
+fn drain<R: RW + ?Sized>(rw: &R, cancel: &CancelToken) -> io::Result<Discarded> {
+    let mut discarded = Discarded::default();
 
-                Ok(Response::from(s))
+    rw.set_read_timeout(Some(DRAIN_TIMEOUT))?;
+    let mut buffer = vec![0u8; u16::MAX as usize];
+    loop {
+        cancel.check()?;
+        if discarded.unsolicited >= DRAIN_LIMIT {
+            debug!(discarded = discarded.unsolicited, "drain limit reached");
+            return Ok(discarded);
+        }
+        match rw.recv_from(buffer.as_mut_slice()) {
+            Ok((size, addr)) => {
+                debug!(from = %addr, size, "discarded queued packet");
+                discarded.unsolicited += 1;
             }
-            _ => Err(io::Error::from(io::ErrorKind::InvalidData)),
+            Err(e) => match e.kind() {
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => return Ok(discarded),
+                io::ErrorKind::InvalidData => discarded.unsolicited += 1,
+                _ => return Err(e),
+            },
         }
     }
 }
 
-impl Display for Response {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{} {}", self.unique_number(), self.remote_addr())
+/// Represents the token cancelling a test and the observer of it.
+#[derive(Clone, Copy)]
+struct Context<'a> {
+    cancel: &'a CancelToken,
+    observer: &'a dyn Observer,
+}
+
+/// Sends the probes of a test from the socket of the number `n`.
+fn send<R: RW + ?Sized>(
+    rw: &R,
+    endpoints: &Endpoints,
+    n: usize,
+    ctx: Context,
+) -> Vec<(Request, io::Error)> {
+    let mut errors = Vec::new();
+    for request in REQUESTS.iter() {
+        let addr = endpoints.addr(*request);
+        let mut error = None;
+        let mut sent = 0;
+        for _ in 0..ONE_TIME_SEND {
+            match rw.send_to(request.payload(), addr) {
+                Ok(_) => {
+                    trace!(%request, to = %addr, "sent");
+                    sent += 1;
+                }
+                Err(e) => error = Some(e),
+            }
+        }
+        if sent > 0 {
+            ctx.observer.probe_sent(n, *request, addr);
+        }
+        // A probe fails only if none of its packets is sent
+        if let (0, Some(e)) = (sent, error) {
+            debug!(%request, to = %addr, error = %e, "failed to send");
+            errors.push((*request, e));
+        }
     }
+    debug!(errors = errors.len(), "probes sent");
+
+    errors
 }
 
-/// Represents the port for sending to only.
-const PORT_1: u16 = 33334;
-/// Represents the port for sending to and receiving from.
-const PORT_2: u16 = 10025;
-/// Represents the port for receiving from only.
-const PORT_3: u16 = 50920;
+/// Receives the answers of a test on the socket of the number `n` until they are sufficient or the
+/// deadline is reached.
+///
+/// The test is decided as soon as both echoes and the receiving only port are answered. Once both
+/// echoes are answered, the receiving only port is waited for no longer than the echoes took, and
+/// at least `PORT_3_GRACE`. The wait is split by `CANCEL_INTERVAL` to notice a cancellation.
+fn receive<R: RW + ?Sized>(
+    rw: &R,
+    endpoints: &Endpoints,
+    start: Instant,
+    deadline: Option<Instant>,
+    discarded: Discarded,
+    n: usize,
+    ctx: Context,
+) -> io::Result<Answers> {
+    let mut answers = Answers {
+        discarded,
+        ..Default::default()
+    };
+    let mut is_port_3_phase = false;
+    let mut phase_deadline = deadline;
 
-/// Represents the times of sending packets at once.
-const ONE_TIME_SEND: usize = 5;
+    let replies = endpoints.replies();
+    let mut buffer = vec![0u8; u16::MAX as usize];
+    while !answers.is_complete() {
+        ctx.cancel.check()?;
+
+        // Wait with the remaining time of the current phase
+        let timeout = match phase_deadline {
+            Some(phase_deadline) => {
+                let now = Instant::now();
+                if now >= phase_deadline {
+                    debug!(elapsed = ?now - start, "deadline reached");
+                    break;
+                }
+                phase_deadline - now
+            }
+            None => CANCEL_INTERVAL,
+        };
+        rw.set_read_timeout(Some(timeout.min(CANCEL_INTERVAL)))?;
+
+        let (size, addr) = match rw.recv_from(buffer.as_mut_slice()) {
+            Ok((size, addr)) => (size, addr),
+            Err(e) => match e.kind() {
+                // Reaching the phase deadline will be handled in the next round
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => continue,
+                io::ErrorKind::InvalidData => {
+                    answers.discarded.unsolicited += 1;
+                    continue;
+                }
+                _ => return Err(e),
+            },
+        };
+
+        trace!(from = %addr, size, "received");
+
+        if !replies.iter().any(|(reply_addr, _)| *reply_addr == addr) {
+            debug!(from = %addr, size, "discarded unsolicited packet");
+            answers.discarded.unsolicited += 1;
+            continue;
+        }
+        let resp = match Response::decode(&buffer[..size]) {
+            Ok(resp) => resp,
+            Err(e) => {
+                debug!(from = %addr, error = %e, "discarded invalid packet");
+                answers.discarded.invalid += 1;
+                continue;
+            }
+        };
+        trace!(
+            from = %addr,
+            request = %resp.request(),
+            head = ?resp.body().head(),
+            reserved = ?resp.body().reserved(),
+            port = resp.port(),
+            remote_ip = %resp.remote_ip(),
+            local_ip = %resp.local_ip(),
+            elapsed = ?start.elapsed(),
+            "response"
+        );
+        ctx.observer.response_received(n, addr, &resp);
+        let is_valid = match replies
+            .iter()
+            .find(|(reply_addr, request)| *reply_addr == addr && resp.is_reply_to(*request))
+        {
+            Some((_, Request::Echo)) => {
+                tally(&mut answers.echoes1, (resp.remote_addr(), resp.local_ip()));
+
+                true
+            }
+            Some((_, Request::AnotherPort)) => {
+                answers.is_a = true;
+
+                true
+            }
+            Some((_, Request::SecondEcho)) => {
+                tally(&mut answers.echoes2, resp.remote_addr());
+
+                true
+            }
+            _ => false,
+        };
+        if !is_valid {
+            debug!(from = %addr, response = %resp, "discarded invalid response");
+            answers.discarded.invalid += 1;
+        }
+
+        // Enter the phase waiting for the receiving only port
+        if answers.is_answered() && !is_port_3_phase {
+            is_port_3_phase = true;
+            ctx.observer.phase_started(Phase::Inbound(n));
+
+            let now = Instant::now();
+            let grace = now - start;
+            let grace = match grace > PORT_3_GRACE {
+                true => grace,
+                false => PORT_3_GRACE,
+            };
+            phase_deadline = match deadline {
+                Some(deadline) if deadline < now + grace => Some(deadline),
+                _ => Some(now + grace),
+            };
+            debug!(
+                elapsed = ?now - start,
+                ?grace,
+                "echoes answered, waiting for the receiving only port"
+            );
+        }
+    }
+    if is_port_3_phase {
+        ctx.observer.phase_finished(Phase::Inbound(n));
+    }
+    answers.settle();
+    debug!(
+        remote1 = ?answers.remote1,
+        remote2 = ?answers.remote2,
+        is_a = answers.is_a,
+        elapsed = ?start.elapsed(),
+        "answers received"
+    );
+
+    Ok(answers)
+}
+
+/// Represents the result of a test.
+#[derive(Clone, Debug)]
+pub struct TestResult {
+    remote1: SocketAddrV4,
+    remote2: SocketAddrV4,
+    local_ip: Ipv4Addr,
+    is_a: bool,
+    discarded: Discarded,
+}
+
+impl TestResult {
+    /// Returns the remote address seen by the server 1.
+    pub fn remote1(&self) -> SocketAddrV4 {
+        self.remote1
+    }
+
+    /// Returns the remote address seen by the server 2.
+    pub fn remote2(&self) -> SocketAddrV4 {
+        self.remote2
+    }
+
+    /// Returns the local IP address reported by the server 1.
+    pub fn local_ip(&self) -> Ipv4Addr {
+        self.local_ip
+    }
+
+    /// Returns if the receiving only port is reachable.
+    pub fn is_a(&self) -> bool {
+        self.is_a
+    }
+
+    /// Returns the packets discarded during the test.
+    pub fn discarded(&self) -> Discarded {
+        self.discarded
+    }
+}
 
 /// Performs a test.
-pub fn test(
-    rw: &Box<dyn RW>,
+///
+/// The read timeout of the socket is used as the deadline of the whole test rather than of each
+/// response.
+#[instrument(level = "info", skip(rw))]
+pub fn test<R: RW + ?Sized>(
+    rw: &R,
     server1: Ipv4Addr,
     server2: Ipv4Addr,
-) -> io::Result<(SocketAddrV4, SocketAddrV4, bool)> {
-    // Server1:Port1, sending only
-    let addr_1_1 = SocketAddrV4::new(server1, PORT_1);
-    // Server1:Port2, echoing back or requesting receiving from another port
-    let addr_1_2 = SocketAddrV4::new(server1, PORT_2);
-    // Server1:Port3, receiving only
-    let addr_1_3 = SocketAddrV4::new(server1, PORT_3);
-    // Server2:Port2, echoing back
-    let addr_2 = SocketAddrV4::new(server2, PORT_2);
+) -> io::Result<TestResult> {
+    test_cancellable(rw, server1, server2, &CancelToken::new())
+}
 
-    // Sending only
-    for _ in 0..ONE_TIME_SEND {
-        rw.send_to(&PAYLOAD_1, addr_1_1)?;
+/// Performs a test which returns a `Cancelled` error once the token is cancelled.
+#[instrument(level = "info", skip(rw, cancel))]
+pub fn test_cancellable<R: RW + ?Sized>(
+    rw: &R,
+    server1: Ipv4Addr,
+    server2: Ipv4Addr,
+    cancel: &CancelToken,
+) -> io::Result<TestResult> {
+    let endpoints = Endpoints::new(server1, server2, Ports::default());
+    let ctx = Context {
+        cancel,
+        observer: &(),
+    };
+
+    test_on(rw, &endpoints, ctx)
+}
+
+/// Performs a test reporting its progress to the observer.
+///
+/// The test runs in the `Mapping` phase, and is not classified.
+#[instrument(level = "info", skip(rw, observer))]
+pub fn test_observed<R: RW + ?Sized>(
+    rw: &R,
+    server1: Ipv4Addr,
+    server2: Ipv4Addr,
+    observer: &dyn Observer,
+) -> io::Result<TestResult> {
+    let endpoints = Endpoints::new(server1, server2, Ports::default());
+    let ctx = Context {
+        cancel: &CancelToken::new(),
+        observer,
+    };
+
+    test_on(rw, &endpoints, ctx)
+}
+
+/// Performs a test against the endpoints.
+fn test_on<R: RW + ?Sized>(rw: &R, endpoints: &Endpoints, ctx: Context) -> io::Result<TestResult> {
+    ctx.cancel.check()?;
+    ctx.observer.phase_started(Phase::Mapping);
+    let timeout = rw.read_timeout()?;
+    let result = (|| {
+        let discarded = drain(rw, ctx.cancel)?;
+
+        let start = Instant::now();
+        if let Some((_, e)) = send(rw, endpoints, 1, ctx).into_iter().next() {
+            return Err(e);
+        }
+
+        receive(
+            rw,
+            endpoints,
+            start,
+            deadline(timeout, start),
+            discarded,
+            1,
+            ctx,
+        )
+    })();
+    rw.set_read_timeout(timeout)?;
+    let answers = result?;
+    ctx.observer.phase_finished(Phase::Mapping);
+
+    match (answers.remote1, answers.remote2, answers.local_ip) {
+        (Some(remote1), Some(remote2), Some(local_ip)) => Ok(TestResult {
+            remote1,
+            remote2,
+            local_ip,
+            is_a: answers.is_a,
+            discarded: answers.discarded,
+        }),
+        _ => Err(io::Error::from(io::ErrorKind::TimedOut)),
     }
-    // Echoing back
-    for _ in 0..ONE_TIME_SEND {
-        rw.send_to(&PAYLOAD_2, addr_1_2)?;
+}
+
+/// Represents the mappings of a socket observed by the servers in a NAT test.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Observation {
+    local: SocketAddrV4,
+    remote1: Option<SocketAddrV4>,
+    remote2: Option<SocketAddrV4>,
+    is_a: bool,
+    send_errors: [Option<io::ErrorKind>; 4],
+}
+
+impl Observation {
+    fn new(local: SocketAddrV4, answers: &Answers, errors: &[(Request, io::Error)]) -> Observation {
+        let mut send_errors = [None; 4];
+        for (request, e) in errors {
+            if let Some(i) = REQUESTS.iter().position(|probe| probe == request) {
+                send_errors[i] = Some(e.kind());
+            }
+        }
+
+        Observation {
+            local,
+            remote1: answers.remote1,
+            remote2: answers.remote2,
+            is_a: answers.is_a,
+            send_errors,
+        }
     }
-    // Receiving from another port
-    for _ in 0..ONE_TIME_SEND {
-        rw.send_to(&PAYLOAD_3, addr_1_2)?;
+
+    /// Returns the local address of the socket.
+    pub fn local(&self) -> SocketAddrV4 {
+        self.local
     }
-    // Echoing back
-    for _ in 0..ONE_TIME_SEND {
-        rw.send_to(&PAYLOAD_4, addr_2)?;
+
+    /// Returns the remote address seen by the server 1.
+    pub fn remote1(&self) -> Option<SocketAddrV4> {
+        self.remote1
     }
 
-    let mut remote1 = None;
-    let mut remote2 = None;
-    let mut is_a = false;
+    /// Returns the remote address seen by the server 2.
+    pub fn remote2(&self) -> Option<SocketAddrV4> {
+        self.remote2
+    }
 
+    /// Returns if the receiving only port is reachable.
+    pub fn is_a(&self) -> bool {
+        self.is_a
+    }
+
+    /// Returns the kind of the error sending the request, or `None` if it is sent.
+    pub fn send_error(&self, request: Request) -> Option<io::ErrorKind> {
+        REQUESTS
+            .iter()
+            .position(|probe| *probe == request)
+            .and_then(|i| self.send_errors[i])
+    }
+
+    /// Returns if the request is answered, or `None` if it is never answered.
+    pub fn is_answered(&self, request: Request) -> Option<bool> {
+        match request {
+            Request::SendOnly => None,
+            Request::Echo => Some(self.remote1.is_some()),
+            Request::AnotherPort => Some(self.is_a),
+            Request::SecondEcho => Some(self.remote2.is_some()),
+        }
+    }
+
+    /// Returns the mapped ports seen by the server 1 and 2, if the socket is answered by both.
+    pub fn ports(&self) -> Option<(u16, u16)> {
+        match (self.remote1, self.remote2) {
+            (Some(remote1), Some(remote2)) => Some((remote1.port(), remote2.port())),
+            _ => None,
+        }
+    }
+}
+
+/// Enumeration of causes of a NAT test failing with NAT type F.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Failure {
+    /// Represents UDP to the servers blocked entirely.
+    UdpBlocked,
+    /// Represents sending UDP to the destination ports failing locally, while sending to other
+    /// ports does not, like when a local firewall rejects them.
+    LocalSendFailure(Vec<u16>),
+    /// Represents the server of the index, from 1, never answering.
+    ServerUnreachable(usize),
+    /// Represents a socket after the first one not answered by both servers, while the first one
+    /// is.
+    SecondSocket,
+    /// Represents answers lost on both sockets, while each server answers at least once.
+    Lossy,
+}
+
+impl Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Failure::UdpBlocked => write!(f, "UDP to the servers is blocked"),
+            Failure::LocalSendFailure(ports) => {
+                let ports: Vec<_> = ports.iter().map(|port| port.to_string()).collect();
+                write!(f, "sending UDP to port {} fails locally", ports.join(", "))
+            }
+            Failure::ServerUnreachable(n) => write!(f, "server {} is unreachable", n),
+            Failure::SecondSocket => write!(f, "only the first socket is answered"),
+            Failure::Lossy => write!(f, "answers are lost on both sockets"),
+        }
+    }
+}
+
+/// Diagnoses the cause of a NAT test failing from the observations.
+fn diagnose(observations: &[Observation], endpoints: &Endpoints) -> Failure {
+    // Ports no probe of any socket can be sent to
+    let port = |request: &Request| endpoints.addr(*request).port();
+    let mut ports: Vec<u16> = REQUESTS.iter().map(port).collect();
+    ports.sort_unstable();
+    ports.dedup();
+    let failed: Vec<u16> = ports
+        .iter()
+        .copied()
+        .filter(|p| {
+            REQUESTS
+                .iter()
+                .filter(|request| port(request) == *p)
+                .all(|request| {
+                    observations
+                        .iter()
+                        .all(|o| o.send_error(*request).is_some())
+                })
+        })
+        .collect();
+
+    let answered1 = observations.iter().any(|o| o.remote1.is_some() || o.is_a);
+    let answered2 = observations.iter().any(|o| o.remote2.is_some());
+    match (answered1, answered2) {
+        // Only the echo port is answered, so failing to send to other ports explains nothing
+        (false, false) => {
+            match failed.contains(&endpoints.addr_1_2.port()) && failed.len() < ports.len() {
+                true => Failure::LocalSendFailure(failed),
+                false => Failure::UdpBlocked,
+            }
+        }
+        (true, false) => Failure::ServerUnreachable(2),
+        (false, true) => Failure::ServerUnreachable(1),
+        (true, true) => match observations[0].ports() {
+            Some(_) => Failure::SecondSocket,
+            None => Failure::Lossy,
+        },
+    }
+}
+
+/// Represents the result of a NAT test.
+#[derive(Clone, Debug)]
+pub struct NatTestResult {
+    ip: Option<Ipv4Addr>,
+    local_ip: Option<Ipv4Addr>,
+    nat: NatType,
+    behavior: Option<NatBehavior>,
+    failure: Option<Failure>,
+    observations: Vec<Observation>,
+    discarded: Discarded,
+    ports: Ports,
+}
+
+impl NatTestResult {
+    /// Returns the remote IP address seen by the servers.
+    pub fn ip(&self) -> Option<Ipv4Addr> {
+        self.ip
+    }
+
+    /// Returns the local IP address reported by the server 1.
+    pub fn local_ip(&self) -> Option<Ipv4Addr> {
+        self.local_ip
+    }
+
+    /// Returns the ports of the servers the test ran against.
+    pub fn server_ports(&self) -> Ports {
+        self.ports
+    }
+
+    /// Returns the NAT type.
+    pub fn nat(&self) -> NatType {
+        self.nat
+    }
+
+    /// Returns the NAT topology between the host of the given IP address and the servers.
+    pub fn topology(&self, host_ip: Ipv4Addr) -> Option<Topology> {
+        self.ip
+            .and_then(|ip| Topology::classify(host_ip, self.local_ip, ip))
+    }
+
+    /// Returns the behavior of the NAT, which is unknown for NAT type F.
+    pub fn behavior(&self) -> Option<NatBehavior> {
+        self.behavior
+    }
+
+    /// Returns the cause of the failure for NAT type F.
+    pub fn failure(&self) -> Option<&Failure> {
+        self.failure.as_ref()
+    }
+
+    /// Returns the mappings observed on the sockets.
+    pub fn observations(&self) -> &[Observation] {
+        &self.observations
+    }
+
+    /// Returns the packets discarded during the test.
+    pub fn discarded(&self) -> Discarded {
+        self.discarded
+    }
+}
+
+/// Represents a factory of sockets, which creates them on demand.
+pub trait SocketFactory {
+    /// Creates a new socket.
+    fn create(&mut self) -> io::Result<Box<dyn RW>>;
+
+    /// Releases a socket once the test no longer needs it, which drops it by default.
+    fn release(&mut self, rw: Box<dyn RW>) {
+        drop(rw);
+    }
+}
+
+impl<F> SocketFactory for F
+where
+    F: FnMut() -> io::Result<Box<dyn RW>>,
+{
+    fn create(&mut self) -> io::Result<Box<dyn RW>> {
+        self()
+    }
+}
+
+/// Performs a NAT test with sockets created by the factory.
+///
+/// The second socket is only created if the mapping of the first one depends on the destination,
+/// since only the port allocation of such a mapping needs it.
+#[instrument(level = "info", skip(factory))]
+pub fn nat_test<F: SocketFactory + ?Sized>(
+    factory: &mut F,
+    server1: Ipv4Addr,
+    server2: Ipv4Addr,
+) -> io::Result<NatTestResult> {
+    nat_test_cancellable(factory, server1, server2, &CancelToken::new())
+}
+
+/// Performs a NAT test with sockets created by the factory, reporting its progress to the
+/// observer.
+#[instrument(level = "info", skip(factory, observer))]
+pub fn nat_test_observed<F: SocketFactory + ?Sized>(
+    factory: &mut F,
+    server1: Ipv4Addr,
+    server2: Ipv4Addr,
+    observer: &dyn Observer,
+) -> io::Result<NatTestResult> {
+    let endpoints = Endpoints::new(server1, server2, Ports::default());
+    let ctx = Context {
+        cancel: &CancelToken::new(),
+        observer,
+    };
+
+    nat_test_lazy(factory, 2, &endpoints, ctx)
+}
+
+/// Performs a NAT test with sockets created by the factory, which returns a `Cancelled` error
+/// once the token is cancelled.
+#[instrument(level = "info", skip(factory, cancel))]
+pub fn nat_test_cancellable<F: SocketFactory + ?Sized>(
+    factory: &mut F,
+    server1: Ipv4Addr,
+    server2: Ipv4Addr,
+    cancel: &CancelToken,
+) -> io::Result<NatTestResult> {
+    let endpoints = Endpoints::new(server1, server2, Ports::default());
+    let ctx = Context {
+        cancel,
+        observer: &(),
+    };
+
+    nat_test_lazy(factory, 2, &endpoints, ctx)
+}
+
+/// Performs a NAT test with up to `sockets` sockets created by the factory on demand.
+///
+/// The first socket decides the mapping and filtering behavior. If the mapping depends on the
+/// destination, the others are created and tested at the same time for the port allocation, while
+/// the first one is kept, so they cannot take its port and the NAT holds its mapping meanwhile.
+/// Every socket is released once the test is done with them.
+fn nat_test_lazy<F: SocketFactory + ?Sized>(
+    factory: &mut F,
+    sockets: usize,
+    endpoints: &Endpoints,
+    ctx: Context,
+) -> io::Result<NatTestResult> {
+    assert!(sockets > 0, "a NAT test needs a socket at least");
+    let begin = Instant::now();
+
+    ctx.cancel.check()?;
+    ctx.observer.phase_started(Phase::Mapping);
+    let rw1 = factory.create()?;
+    let mut rws = Vec::new();
+    let result = (|| -> io::Result<Vec<Probed>> {
+        let mut probed = probe(&[rw1.as_ref()], endpoints, 0, ctx)?;
+        ctx.observer.phase_finished(Phase::Mapping);
+
+        let dependent = match probed[0].observation.ports() {
+            Some((port_a, port_b)) => port_a != port_b,
+            None => false,
+        };
+        if dependent && sockets > 1 {
+            ctx.observer.phase_started(Phase::Allocation);
+            for _ in 1..sockets {
+                rws.push(factory.create()?);
+            }
+            let rws: Vec<_> = rws.iter().map(|rw| rw.as_ref()).collect();
+            probed.extend(probe(&rws, endpoints, 1, ctx)?);
+            ctx.observer.phase_finished(Phase::Allocation);
+        }
+
+        Ok(probed)
+    })();
+    factory.release(rw1);
+    for rw in rws {
+        factory.release(rw);
+    }
+    let probed = result?;
+
+    let result = classify(probed, endpoints, begin);
+    ctx.observer.classified(&result);
+
+    Ok(result)
+}
+
+/// Performs a NAT test on the sockets, which are tested at the same time, so the `Allocation`
+/// phase runs within the `Mapping` phase.
+fn nat_test_on(rws: &[&dyn RW], endpoints: &Endpoints, ctx: Context) -> io::Result<NatTestResult> {
+    assert!(!rws.is_empty(), "a NAT test needs a socket at least");
+    let begin = Instant::now();
+
+    ctx.cancel.check()?;
+    let phases = match rws.len() {
+        1 => &[Phase::Mapping][..],
+        _ => &[Phase::Mapping, Phase::Allocation][..],
+    };
+    for phase in phases {
+        ctx.observer.phase_started(*phase);
+    }
+    let probed = probe(rws, endpoints, 0, ctx)?;
+    for phase in phases.iter().rev() {
+        ctx.observer.phase_finished(*phase);
+    }
+
+    let result = classify(probed, endpoints, begin);
+    ctx.observer.classified(&result);
+
+    Ok(result)
+}
+
+/// Represents what a socket learns in a NAT test.
+struct Probed {
+    observation: Observation,
+    local_ip: Option<Ipv4Addr>,
+    discarded: Discarded,
+}
+
+/// Probes the sockets, numbered from `skip` + 1, at the same time.
+///
+/// The probes are sent from the sockets in order so the port allocation of the NAT is not
+/// interleaved.
+fn probe(
+    rws: &[&dyn RW],
+    endpoints: &Endpoints,
+    skip: usize,
+    ctx: Context,
+) -> io::Result<Vec<Probed>> {
+    let timeouts = rws
+        .iter()
+        .map(|rw| rw.read_timeout())
+        .collect::<io::Result<Vec<_>>>()?;
+    type Outcome = (Answers, Vec<(Request, io::Error)>);
+    let result: io::Result<Vec<Outcome>> = (|| {
+        let discarded = rws
+            .iter()
+            .map(|rw| drain(*rw, ctx.cancel))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let start = Instant::now();
+        let errors: Vec<_> = rws
+            .iter()
+            .enumerate()
+            .map(|(i, rw)| {
+                debug_span!("socket", n = skip + i + 1)
+                    .in_scope(|| send(*rw, endpoints, skip + i + 1, ctx))
+            })
+            .collect();
+
+        let span = Span::current();
+        thread::scope(|s| {
+            let handles: Vec<_> = rws
+                .iter()
+                .zip(timeouts.iter())
+                .zip(discarded)
+                .enumerate()
+                .map(|(i, ((rw, timeout), discarded))| {
+                    let span = &span;
+                    s.spawn(move || {
+                        let _enter = span.enter();
+                        let _socket = debug_span!("socket", n = skip + i + 1).entered();
+                        receive(
+                            *rw,
+                            endpoints,
+                            start,
+                            deadline(*timeout, start),
+                            discarded,
+                            skip + i + 1,
+                            ctx,
+                        )
+                    })
+                })
+                .collect();
+
+            handles
+                .into_iter()
+                .zip(errors)
+                .map(|(handle, errors)| Ok((handle.join().unwrap()?, errors)))
+                .collect()
+        })
+    })();
+    for (rw, timeout) in rws.iter().zip(timeouts) {
+        rw.set_read_timeout(timeout)?;
+    }
+
+    rws.iter()
+        .zip(result?)
+        .map(|(rw, (answers, errors))| {
+            Ok(Probed {
+                observation: Observation::new(rw.local_addr()?, &answers, &errors),
+                local_ip: answers.local_ip,
+                discarded: answers.discarded,
+            })
+        })
+        .collect()
+}
+
+/// Classifies the NAT from the sockets probed, the first of which decides the mapping and
+/// filtering behavior, and the others decide the port allocation.
+fn classify(probed: Vec<Probed>, endpoints: &Endpoints, begin: Instant) -> NatTestResult {
+    let discarded = probed
+        .iter()
+        .fold(Discarded::default(), |discarded, p| discarded + p.discarded);
+    let local_ip = probed.iter().find_map(|p| p.local_ip);
+    let observations: Vec<_> = probed.into_iter().map(|p| p.observation).collect();
+    // Keep whatever is learned from a partial answer
+    let f = |observations: Vec<Observation>| {
+        let failure = diagnose(&observations, endpoints);
+        debug!(%failure, elapsed = ?begin.elapsed(), "NAT test failed");
+
+        NatTestResult {
+            ip: observations
+                .iter()
+                .flat_map(|o| o.remote1.iter().chain(o.remote2.iter()))
+                .map(|remote| *remote.ip())
+                .next(),
+            local_ip,
+            nat: NatType::F,
+            behavior: None,
+            failure: Some(failure),
+            observations,
+            discarded,
+            ports: endpoints.ports(),
+        }
+    };
+
+    let (ip, port_a1, port_b1) = match (observations[0].remote1, observations[0].ports()) {
+        (Some(remote1), Some((port_a1, port_b1))) => (*remote1.ip(), port_a1, port_b1),
+        _ => {
+            debug!("socket 1 is not answered by both servers");
+            return f(observations);
+        }
+    };
+    let is_a = observations[0].is_a;
+    debug!(port_a1, port_b1, is_a, "socket 1 mapped");
+
+    let filtering = match is_a {
+        // The reply from another port comes from the same IP address, which proves no more
+        true => FilteringBehavior::AddressDependent,
+        false => FilteringBehavior::AddressAndPortDependent,
+    };
+    let behavior = match port_a1 == port_b1 {
+        true => {
+            let allocation = match port_a1 == observations[0].local.port() {
+                true => Some(PortAllocation::Preserved),
+                false => None,
+            };
+            NatBehavior::new(MappingBehavior::EndpointIndependent, filtering, allocation)
+        }
+        false => {
+            let mut ports = vec![(port_a1, port_b1)];
+            for (i, observation) in observations.iter().enumerate().skip(1) {
+                match observation.ports() {
+                    Some((port_a, port_b)) => {
+                        debug!(n = i + 1, port_a, port_b, "socket mapped");
+                        ports.push((port_a, port_b));
+                    }
+                    None => {
+                        debug!(n = i + 1, "socket is not answered by both servers");
+                        return f(observations);
+                    }
+                }
+            }
+            // Ports of every socket must move by the same delta on both servers
+            let deltas: Vec<_> = ports
+                .windows(2)
+                .map(|w| (w[1].0.wrapping_sub(w[0].0), w[1].1.wrapping_sub(w[0].1)))
+                .collect();
+            let allocation = deltas.first().map(|&(delta, _)| {
+                match deltas.iter().all(|&(da, db)| da == delta && db == delta) {
+                    true => PortAllocation::Predictable(delta),
+                    false => PortAllocation::Unpredictable,
+                }
+            });
+            NatBehavior::new(MappingBehavior::AddressDependent, filtering, allocation)
+        }
+    };
+    let nat = behavior.nat();
+
+    debug!(%ip, %nat, elapsed = ?begin.elapsed(), "NAT type decided");
+
+    NatTestResult {
+        ip: Some(ip),
+        local_ip,
+        nat,
+        behavior: Some(behavior),
+        failure: None,
+        observations,
+        discarded,
+        ports: endpoints.ports(),
+    }
+}
+
+/// Returns the remaining time before the deadline, or `None` once it is reached.
+fn remaining(deadline: Option<Instant>) -> Option<Option<Duration>> {
+    match deadline {
+        Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+            Some(timeout) if !timeout.is_zero() => Some(Some(timeout)),
+            _ => None,
+        },
+        None => Some(None),
+    }
+}
+
+/// Returns the mapping of the socket seen by the echo port of the server 1, or `None` if the
+/// server does not answer before the deadline.
+fn probe_mapping<R: RW + ?Sized>(
+    rw: &R,
+    endpoints: &Endpoints,
+    deadline: Option<Instant>,
+) -> io::Result<Option<SocketAddrV4>> {
+    drain(rw, &CancelToken::new())?;
+
+    let addr = endpoints.addr(Request::Echo);
+    for _ in 0..ONE_TIME_SEND {
+        rw.send_to(Request::Echo.payload(), addr)?;
+    }
     let mut buffer = vec![0u8; u16::MAX as usize];
     loop {
+        let timeout = match remaining(deadline) {
+            Some(timeout) => timeout,
+            None => return Ok(None),
+        };
+        rw.set_read_timeout(timeout)?;
         match rw.recv_from(buffer.as_mut_slice()) {
-            Ok((size, addr)) => {
-                if size == 16 {
-                    if addr == addr_1_2 {
-                        let resp = Response::try_from(&buffer[..16]).unwrap();
-                        if resp.is_payload_2() {
-                            remote1 = Some(resp.remote_addr());
-                        }
-                    } else if addr == addr_1_3 {
-                        let resp = Response::try_from(&buffer[..16]).unwrap();
-                        if resp.is_payload_3() {
-                            is_a = true;
-                        }
-                    } else if addr == addr_2 {
-                        let resp = Response::try_from(&buffer[..16]).unwrap();
-                        if resp.is_payload_4() {
-                            remote2 = Some(resp.remote_addr());
-                        }
-                    }
+            Ok((size, from)) if from == addr => match Response::decode(&buffer[..size]) {
+                Ok(resp) if resp.is_reply_to(Request::Echo) => return Ok(Some(resp.remote_addr())),
+                _ => continue,
+            },
+            Ok(_) => continue,
+            Err(e) => match e.kind() {
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => return Ok(None),
+                io::ErrorKind::InvalidData => continue,
+                _ => return Err(e),
+            },
+        }
+    }
+}
 
-                    if remote1.is_some() && remote2.is_some() && is_a {
-                        break;
-                    }
-                }
+/// Performs a hairpin test against the echo port `PORT_2` of the server 1, sending from the
+/// mapping of `rw2` to the mapping of `rw1`.
+///
+/// Both mappings are probed from the server first, so the test runs on the mappings the sockets
+/// hold at the time. `rw1` then sends to the mapping of `rw2`, so the filtering of the NAT does not
+/// drop the traffic. The read timeout of `rw1` is used as the deadline of the whole test.
+pub fn hairpin_test<R1: RW + ?Sized, R2: RW + ?Sized>(
+    rw1: &R1,
+    rw2: &R2,
+    server1: Ipv4Addr,
+) -> io::Result<bool> {
+    let endpoints = Endpoints::new(server1, server1, Ports::new(PORT_2, PORT_2, PORT_2));
+
+    hairpin_on(rw1, rw2, &endpoints)
+}
+
+/// Performs a hairpin test on the endpoints.
+#[instrument(level = "info", skip(rw1, rw2, endpoints))]
+fn hairpin_on<R1: RW + ?Sized, R2: RW + ?Sized>(
+    rw1: &R1,
+    rw2: &R2,
+    endpoints: &Endpoints,
+) -> io::Result<bool> {
+    let timeout1 = rw1.read_timeout()?;
+    let timeout2 = rw2.read_timeout()?;
+    let result = (|| {
+        let deadline = deadline(timeout1, Instant::now());
+        let (mapped1, mapped2) = match (
+            probe_mapping(rw1, endpoints, deadline)?,
+            probe_mapping(rw2, endpoints, deadline)?,
+        ) {
+            (Some(mapped1), Some(mapped2)) => (mapped1, mapped2),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "the mappings of the hairpin test are not answered",
+                ))
             }
-            Err(e) => {
-                if remote1.is_some() && remote2.is_some() {
-                    break;
+        };
+        debug!(%mapped1, %mapped2, "hairpin mappings probed");
+        let mut payload = HAIRPIN_PAYLOAD.to_vec();
+        payload.extend_from_slice(&mapped1.port().to_be_bytes());
+
+        let start = Instant::now();
+        rw1.send_to(&payload, mapped2)?;
+        rw2.send_to(&payload, mapped1)?;
+        trace!(from = %mapped2, to = %mapped1, "hairpin probe sent");
+
+        let mut buffer = vec![0u8; u16::MAX as usize];
+        loop {
+            let timeout = match remaining(deadline) {
+                Some(timeout) => timeout,
+                None => return Ok(false),
+            };
+            rw1.set_read_timeout(timeout)?;
+            match rw1.recv_from(buffer.as_mut_slice()) {
+                Ok((size, addr)) => {
+                    // Some NATs hairpin with the internal address as the source
+                    if buffer[..size] == payload[..] {
+                        debug!(%addr, elapsed = ?start.elapsed(), "hairpin probe received");
+                        return Ok(true);
+                    }
                 }
-                return Err(e);
+                Err(e) => match e.kind() {
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => return Ok(false),
+                    io::ErrorKind::InvalidData => continue,
+                    _ => return Err(e),
+                },
             }
         }
+    })();
+    rw1.set_read_timeout(timeout1)?;
+    rw2.set_read_timeout(timeout2)?;
+
+    result
+}
+
+/// Represents the statistics of echo probes against a server.
+#[derive(Clone, Debug, Default)]
+pub struct EchoStats {
+    sent: usize,
+    rtts: Vec<Duration>,
+    late: usize,
+}
+
+impl EchoStats {
+    /// Creates a new `EchoStats` of `sent` probes, with the round-trip times of the answered ones
+    /// in sequence.
+    pub fn new(sent: usize, rtts: Vec<Duration>) -> EchoStats {
+        EchoStats {
+            sent,
+            rtts,
+            late: 0,
+        }
+    }
+
+    /// Returns the number of probes sent.
+    pub fn sent(&self) -> usize {
+        self.sent
+    }
+
+    /// Returns the number of probes answered.
+    pub fn received(&self) -> usize {
+        self.rtts.len()
+    }
+
+    /// Returns the ratio of probes lost, from `0.0` to `1.0`.
+    pub fn loss(&self) -> f64 {
+        match self.sent {
+            0 => 0.0,
+            _ => (self.sent - self.received()) as f64 / self.sent as f64,
+        }
+    }
+
+    /// Returns the number of replies arriving after their probes were counted as lost.
+    pub fn late(&self) -> usize {
+        self.late
+    }
+
+    /// Returns the round-trip times of the answered probes in sequence.
+    pub fn rtts(&self) -> &[Duration] {
+        &self.rtts
+    }
+
+    /// Returns the minimum round-trip time.
+    pub fn min(&self) -> Option<Duration> {
+        self.rtts.iter().min().cloned()
+    }
+
+    /// Returns the average round-trip time.
+    pub fn avg(&self) -> Option<Duration> {
+        match self.rtts.len() {
+            0 => None,
+            n => Some(self.rtts.iter().sum::<Duration>() / n as u32),
+        }
+    }
+
+    /// Returns the maximum round-trip time.
+    pub fn max(&self) -> Option<Duration> {
+        self.rtts.iter().max().cloned()
+    }
+
+    /// Returns the 95th percentile round-trip time using the nearest-rank method.
+    pub fn p95(&self) -> Option<Duration> {
+        let mut rtts = self.rtts.clone();
+        rtts.sort();
+
+        match rtts.len() {
+            0 => None,
+            n => Some(rtts[(n * 95).div_ceil(100) - 1]),
+        }
     }
 
-    Ok((remote1.unwrap(), remote2.unwrap(), is_a))
+    /// Returns the jitter, the mean difference of round-trip times between consecutive answered
+    /// probes.
+    pub fn jitter(&self) -> Option<Duration> {
+        match self.rtts.len() {
+            0 => None,
+            1 => Some(Duration::from_secs(0)),
+            n => Some(
+                self.rtts
+                    .windows(2)
+                    .map(|w| match w[1] > w[0] {
+                        true => w[1] - w[0],
+                        false => w[0] - w[1],
+                    })
+                    .sum::<Duration>()
+                    / (n - 1) as u32,
+            ),
+        }
+    }
 }
 
-/// Performs a NAT test.
-pub fn nat_test(
-    rw1: &Box<dyn RW>,
-    rw2: &Box<dyn RW>,
+/// Performs an echo measurement, sending `count` probes to each server one per `interval`.
+///
+/// Replies carry nothing identifying their probes, so each reply is matched to the oldest probe
+/// of its server still waiting, in the order they were sent. A probe not answered within the read
+/// timeout of the socket, or within the interval without one, is counted as lost, and a reply
+/// arriving while no probe is waiting counts as late. A lost probe still waiting takes the reply
+/// of the next one, so under loss a round-trip time may be too long by an interval, and a late
+/// reply to a probe may answer the next one with a round-trip time too short by an interval.
+///
+/// The statistics of each server are also emitted as an event.
+pub fn measure<R: RW + ?Sized>(
+    rw: &R,
     server1: Ipv4Addr,
     server2: Ipv4Addr,
-) -> io::Result<(Option<Ipv4Addr>, NatType)> {
-    let (remote1, remote2, is_a) = match test(rw1, server1, server2) {
-        Ok((remote1, remote2, is_a)) => (remote1, remote2, is_a),
-        Err(e) => match e.kind() {
-            io::ErrorKind::TimedOut => return Ok((None, NatType::F)),
-            _ => return Err(e),
-        },
-    };
+    count: usize,
+    interval: Duration,
+) -> io::Result<(EchoStats, EchoStats)> {
+    // Server1:Port2, echoing back
+    let addr_1 = SocketAddrV4::new(server1, PORT_2);
+    // Server2:Port2, echoing back
+    let addr_2 = SocketAddrV4::new(server2, PORT_2);
 
-    let ip = remote1.ip().clone();
+    let mut stats = [EchoStats::default(), EchoStats::default()];
+    // Send times of the probes waiting for replies, the oldest first
+    let mut waiting = [VecDeque::new(), VecDeque::new()];
 
-    let port_a1 = remote1.port();
-    let port_b1 = remote2.port();
-    let nat = match port_a1 == port_b1 {
-        true => match is_a {
-            true => NatType::A,
-            false => NatType::B,
-        },
-        false => {
-            let (remote1, remote2) = match test(rw2, server1, server2) {
-                Ok((remote1, remote2, _)) => (remote1, remote2),
+    let timeout = rw.read_timeout()?;
+    let lost_after = timeout.unwrap_or(interval);
+    let mut buffer = vec![0u8; u16::MAX as usize];
+    let result = (|| {
+        let mut next = Instant::now();
+        loop {
+            let now = Instant::now();
+            for queue in waiting.iter_mut() {
+                while let Some(sent) = queue.front() {
+                    if now.saturating_duration_since(*sent) < lost_after {
+                        break;
+                    }
+                    queue.pop_front();
+                }
+            }
+
+            let sending = stats[0].sent < count;
+            if sending && now >= next {
+                // The send time is taken before sending, which may be slowed by a capture
+                let sent = Instant::now();
+                rw.send_to(Request::Echo.payload(), addr_1)?;
+                stats[0].sent += 1;
+                waiting[0].push_back(sent);
+                let sent = Instant::now();
+                rw.send_to(Request::SecondEcho.payload(), addr_2)?;
+                stats[1].sent += 1;
+                waiting[1].push_back(sent);
+                next += interval;
+                continue;
+            }
+
+            // Wait until the next probe is sent or the oldest one is lost
+            let deadline = waiting
+                .iter()
+                .filter_map(|queue| queue.front().map(|sent| *sent + lost_after))
+                .chain(match sending {
+                    true => Some(next),
+                    false => None,
+                })
+                .min();
+            let deadline = match deadline {
+                Some(deadline) => deadline,
+                None => break,
+            };
+            let wait = deadline.saturating_duration_since(now);
+            rw.set_read_timeout(Some(wait.max(Duration::from_millis(1))))?;
+
+            let (size, addr) = match rw.recv_from(buffer.as_mut_slice()) {
+                Ok((size, addr)) => (size, addr),
                 Err(e) => match e.kind() {
-                    io::ErrorKind::TimedOut => return Ok((None, NatType::F)),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => continue,
                     _ => return Err(e),
                 },
             };
-            let port_a2 = remote1.port();
-            let port_b2 = remote2.port();
-            match port_a2
-                .checked_sub(port_a1)
-                .unwrap_or_else(|| u16::MAX - (port_a1 - port_a2))
-                == port_b2
-                    .checked_sub(port_b1)
-                    .unwrap_or_else(|| u16::MAX - (port_b1 - port_b2))
-            {
-                true => NatType::C,
-                false => NatType::D,
+            let received = Instant::now();
+            let resp = match Response::decode(&buffer[..size]) {
+                Ok(resp) => resp,
+                Err(_) => continue,
+            };
+            let i = match addr {
+                addr if addr == addr_1 && resp.is_reply_to(Request::Echo) => 0,
+                addr if addr == addr_2 && resp.is_reply_to(Request::SecondEcho) => 1,
+                _ => continue,
+            };
+            match waiting[i].pop_front() {
+                Some(sent) => stats[i].rtts.push(received - sent),
+                None => stats[i].late += 1,
             }
         }
-    };
 
-    Ok((Some(ip), nat))
+        Ok(())
+    })();
+    rw.set_read_timeout(timeout)?;
+    result?;
+
+    let ms = |d: Option<Duration>| d.map(|d| d.as_secs_f64() * 1000.0);
+    for (stats, addr) in stats.iter().zip([addr_1, addr_2].iter()) {
+        info!(
+            server = %addr,
+            sent = stats.sent(),
+            received = stats.received(),
+            late = stats.late(),
+            loss = stats.loss(),
+            min_ms = ms(stats.min()),
+            avg_ms = ms(stats.avg()),
+            max_ms = ms(stats.max()),
+            p95_ms = ms(stats.p95()),
+            jitter_ms = ms(stats.jitter()),
+            "echoes measured"
+        );
+    }
+
+    let [stats1, stats2] = stats;
+
+    Ok((stats1, stats2))
 }
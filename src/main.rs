@@ -1,11 +1,26 @@
-use ninat::{Datagram, Socket, RW};
+use ninat::advisor::Advice;
+use ninat::behavior::NatBehavior;
+use ninat::consensus::Consensus;
+use ninat::forward;
+use ninat::mapping::{self, Gateway, Protocol};
+use ninat::observer::{Observer, Phase};
+use ninat::pcap::{Capture, PcapWriter};
+use ninat::protocol::{Request, Response, REQUESTS};
+use ninat::tester::{NatTester, DEFAULT_TIMEOUT};
+use ninat::topology::{self, AddressClass, Topology};
+use ninat::{EchoStats, NatTestResult, NatType, ProxyError, Socket, SocketFactory, RW};
 use std::clone::Clone;
 use std::fmt::Display;
-use std::io;
-use std::net::{AddrParseError, SocketAddrV4};
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::net::{AddrParseError, Ipv4Addr, SocketAddrV4};
+use std::path::PathBuf;
+use std::process;
 use std::str::FromStr;
-use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use structopt::StructOpt;
+use tracing::Level;
 
 #[derive(Debug)]
 enum ResolvableAddrParseError {
@@ -92,6 +107,51 @@ impl FromStr for ResolvableSocketAddr {
     }
 }
 
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+enum MappingProtocol {
+    Auto,
+    Protocol(Protocol),
+}
+
+impl MappingProtocol {
+    fn protocols(&self) -> Vec<Protocol> {
+        match self {
+            MappingProtocol::Auto => vec![Protocol::Upnp, Protocol::Pcp, Protocol::NatPmp],
+            MappingProtocol::Protocol(protocol) => vec![*protocol],
+        }
+    }
+}
+
+impl FromStr for MappingProtocol {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(MappingProtocol::Auto),
+            "upnp" => Ok(MappingProtocol::Protocol(Protocol::Upnp)),
+            "nat-pmp" => Ok(MappingProtocol::Protocol(Protocol::NatPmp)),
+            "pcp" => Ok(MappingProtocol::Protocol(Protocol::Pcp)),
+            _ => Err(format!("unknown port mapping protocol {}", s)),
+        }
+    }
+}
+
 #[derive(StructOpt, Clone, Debug, Eq, Hash, PartialEq)]
 #[structopt(about)]
 struct Flags {
@@ -122,112 +182,1247 @@ struct Flags {
     #[structopt(
         long,
         short = "w",
-        help = "Timeout to wait for each response",
+        help = "Timeout to wait for responses",
         value_name = "VALUE",
         default_value = "3000",
         display_order(3)
     )]
     pub timeout: u64,
+    #[structopt(
+        long,
+        short = "m",
+        help = "Measure latency with echo probes",
+        value_name = "COUNT",
+        display_order(4)
+    )]
+    pub measure: Option<usize>,
+    #[structopt(
+        long,
+        help = "Interval between echo probes",
+        value_name = "VALUE",
+        default_value = "200",
+        display_order(5)
+    )]
+    pub interval: u64,
+    #[structopt(
+        long,
+        help = "Write datagrams to a pcap-ng file",
+        value_name = "FILE",
+        display_order(6)
+    )]
+    pub pcap: Option<PathBuf>,
+    #[structopt(
+        long,
+        short = "v",
+        help = "Prints logs, -vv for more",
+        parse(from_occurrences),
+        display_order(7)
+    )]
+    pub verbose: u8,
+    #[structopt(
+        long,
+        help = "Format of logs",
+        value_name = "FORMAT",
+        possible_values(&["text", "json"]),
+        default_value = "text",
+        display_order(8)
+    )]
+    pub log_format: LogFormat,
+    #[structopt(
+        long,
+        help = "Locate NAT devices on the path with TTL-limited probes",
+        conflicts_with("proxy"),
+        display_order(9)
+    )]
+    pub trace: bool,
+    #[structopt(
+        long,
+        help = "Maximum TTL of probes",
+        value_name = "VALUE",
+        default_value = "30",
+        display_order(10)
+    )]
+    pub max_ttl: u8,
+    #[structopt(
+        long,
+        help = "Rerun the test through port mappings",
+        value_name = "PROTOCOL",
+        possible_values(&["auto", "upnp", "nat-pmp", "pcp"]),
+        conflicts_with("proxy"),
+        display_order(11)
+    )]
+    pub port_mapping: Option<MappingProtocol>,
+    #[structopt(
+        long,
+        help = "Gateway of port mappings",
+        value_name = "ADDRESS",
+        requires("port-mapping"),
+        display_order(12)
+    )]
+    pub gateway: Option<Ipv4Addr>,
+    #[structopt(
+        long,
+        help = "Explain the NAT type and suggest fixes",
+        display_order(13)
+    )]
+    pub explain: bool,
+    #[structopt(long, help = "Test hairpinning of the NAT", display_order(14))]
+    pub hairpin: bool,
+    #[structopt(
+        long,
+        help = "Test the port of a running application with SO_REUSEPORT",
+        value_name = "PORT",
+        conflicts_with("proxy"),
+        display_order(15)
+    )]
+    pub sidecar: Option<u16>,
+    #[structopt(
+        long,
+        help = "Test the socket of a passed file descriptor",
+        value_name = "FD",
+        conflicts_with_all(&["proxy", "sidecar"]),
+        display_order(16)
+    )]
+    pub fd: Option<i32>,
+    #[structopt(long, help = "Show the progress of the test", display_order(17))]
+    pub progress: bool,
+    #[structopt(
+        long,
+        help = "Fail unless the NAT type is the one given",
+        value_name = "TYPE",
+        possible_values(&["A", "B", "C", "D"]),
+        case_insensitive(true),
+        conflicts_with_all(&["expect-at-least", "sidecar", "fd"]),
+        display_order(18)
+    )]
+    pub expect: Option<NatType>,
+    #[structopt(
+        long,
+        help = "Fail if the NAT type is stricter than the one given",
+        value_name = "TYPE",
+        possible_values(&["A", "B", "C", "D"]),
+        case_insensitive(true),
+        conflicts_with_all(&["sidecar", "fd"]),
+        display_order(19)
+    )]
+    pub expect_at_least: Option<NatType>,
+    #[structopt(
+        long,
+        help = "Run the test several times for a consensus",
+        value_name = "COUNT",
+        conflicts_with_all(&["measure", "hairpin", "port-mapping", "explain", "sidecar", "fd"]),
+        display_order(20)
+    )]
+    pub runs: Option<usize>,
+    #[structopt(
+        long,
+        help = "Interval between runs",
+        value_name = "VALUE",
+        default_value = "1000",
+        display_order(21)
+    )]
+    pub run_interval: u64,
+    #[structopt(
+        long,
+        help = "Run the test in network namespaces, comparing them if several",
+        value_name = "NAME",
+        number_of_values(1),
+        display_order(22)
+    )]
+    pub netns: Vec<String>,
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
 }
 
-const NINTENDO_SRV_1: &str = "nncs1-lp1.n.n.srv.nintendo.net";
-const NINTENDO_SRV_2: &str = "nncs2-lp1.n.n.srv.nintendo.net";
+#[derive(StructOpt, Clone, Debug, Eq, Hash, PartialEq)]
+enum Command {
+    #[structopt(about = "Verify a port forward of the router")]
+    VerifyForward {
+        #[structopt(
+            long,
+            help = "Local port the forward points to",
+            value_name = "PORT",
+            display_order(0)
+        )]
+        local_port: u16,
+        #[structopt(
+            long,
+            help = "External port of the forward",
+            value_name = "PORT",
+            display_order(1)
+        )]
+        external_port: u16,
+        #[structopt(
+            long,
+            help = "Number of runs checking the stability",
+            value_name = "COUNT",
+            default_value = "3",
+            display_order(2)
+        )]
+        runs: usize,
+    },
+    #[structopt(about = "Test a NAT emulated in Linux network namespaces")]
+    Lab {
+        #[structopt(
+            long,
+            help = "NAT of the router",
+            value_name = "NAT",
+            possible_values(&["none", "masquerade", "random", "full-cone", "drop"]),
+            default_value = "masquerade",
+            display_order(0)
+        )]
+        nat: String,
+        #[structopt(
+            long,
+            help = "Prefix of the names of namespaces",
+            value_name = "NAME",
+            default_value = "ninat",
+            display_order(1)
+        )]
+        name: String,
+    },
+    #[structopt(about = "Rerun the test whenever the network changes")]
+    Daemon {
+        #[structopt(
+            long,
+            help = "Time for the network to settle before a rerun",
+            value_name = "VALUE",
+            default_value = "2000",
+            display_order(0)
+        )]
+        debounce: u64,
+    },
+}
 
-fn main() {
-    // Parse arguments
-    let flags = Flags::from_args();
+/// Enumeration of exit codes.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Exit {
+    /// Represents the test finished, and met the expectation if any.
+    Success = 0,
+    /// Represents invalid arguments.
+    Usage = 1,
+    /// Represents the servers not resolved.
+    Dns = 2,
+    /// Represents the SOCKS proxy failing.
+    Proxy = 3,
+    /// Represents sockets failing to bind, send or receive.
+    Socket = 4,
+    /// Represents files failing to be written.
+    Io = 5,
+    /// Represents a port forward failing a check.
+    Unverified = 6,
+    /// Represents a lab failing to be built or tested.
+    Lab = 7,
+    /// Represents a network namespace failing to be entered.
+    Netns = 8,
+    /// Represents NAT type A not meeting the expectation.
+    NatA = 10,
+    /// Represents NAT type B not meeting the expectation.
+    NatB = 11,
+    /// Represents NAT type C not meeting the expectation.
+    NatC = 12,
+    /// Represents NAT type D not meeting the expectation.
+    NatD = 13,
+    /// Represents NAT type F, or not meeting the expectation.
+    NatF = 14,
+}
 
-    // Server
-    let server1 = match ninat::lookup_host_v4(NINTENDO_SRV_1) {
-        Ok(ip) => ip,
-        Err(e) => {
-            eprintln!("{}", e);
-            return;
+impl From<NatType> for Exit {
+    fn from(s: NatType) -> Self {
+        match s {
+            NatType::A => Exit::NatA,
+            NatType::B => Exit::NatB,
+            NatType::C => Exit::NatC,
+            NatType::D => Exit::NatD,
+            NatType::F => Exit::NatF,
         }
+    }
+}
+
+/// Returns the exit code of the NAT type against the expectations. NAT type F always fails.
+fn expect(nat: NatType, expect: Option<NatType>, expect_at_least: Option<NatType>) -> Exit {
+    match nat.meets(expect, expect_at_least) {
+        true => Exit::Success,
+        false => Exit::from(nat),
+    }
+}
+
+/// Returns the exit code of the error of sockets, which fail in the SOCKS proxy or locally.
+fn failure(e: &io::Error) -> Exit {
+    match ProxyError::is(e) {
+        true => Exit::Proxy,
+        false => Exit::Socket,
+    }
+}
+
+fn init_logger(verbose: u8, format: LogFormat) {
+    let level = match verbose {
+        0 => Level::WARN,
+        1 => Level::DEBUG,
+        _ => Level::TRACE,
     };
-    let server2 = match ninat::lookup_host_v4(NINTENDO_SRV_2) {
-        Ok(ip) => ip,
-        Err(e) => {
-            eprintln!("{}", e);
-            return;
+    let builder = tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_writer(io::stderr);
+    match format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.json().init(),
+    }
+}
+
+fn print_nat(nat: NatType) {
+    println!("  Nintendo Switch : {}", nat.nintendo());
+    println!("  Sony PlayStation: {}", nat.sony());
+    println!("  Microsoft Xbox  : {}", nat.microsoft());
+}
+
+fn print_behavior(behavior: &NatBehavior) {
+    println!("NAT Behavior:");
+    println!("  Mapping        : {}", behavior.mapping());
+    println!("  Filtering      : {}", behavior.filtering());
+    match behavior.allocation() {
+        Some(allocation) => println!("  Port Allocation: {}", allocation),
+        None => println!("  Port Allocation: Unknown"),
+    }
+    if let Some(hairpin) = behavior.hairpin() {
+        match hairpin {
+            true => println!("  Hairpinning    : Supported"),
+            false => println!("  Hairpinning    : Unsupported"),
         }
-    };
+    }
+}
+
+fn print_consensus(consensus: &Consensus) {
+    println!("Runs:");
+    for (i, run) in consensus.runs().iter().enumerate() {
+        if let Some(error) = run.error() {
+            println!(
+                "  Run {}: failed, {}, {} ms",
+                i + 1,
+                error,
+                run.elapsed().as_millis()
+            );
+            continue;
+        }
+        let ip = match run.ip() {
+            Some(ip) => ip.to_string(),
+            None => "unknown".to_string(),
+        };
+        println!(
+            "  Run {}: NAT type {}, remote address {}, {} ms",
+            i + 1,
+            run.nat(),
+            ip,
+            run.elapsed().as_millis()
+        );
+    }
+    println!("NAT Type Distribution:");
+    let total = consensus.runs().len();
+    for (nat, count) in consensus.distribution() {
+        println!(
+            "  {}: {} ({:.0}%)",
+            nat,
+            count,
+            count as f64 * 100.0 / total as f64
+        );
+    }
+    if let Some(majority) = consensus.majority() {
+        println!(
+            "Consensus: {} ({:.0}% confidence)",
+            majority,
+            consensus.confidence() * 100.0
+        );
+        println!("NAT Type:");
+        print_nat(majority);
+    }
+    let ips: Vec<_> = consensus.ips().iter().map(|ip| ip.to_string()).collect();
+    if !ips.is_empty() {
+        println!(
+            "Remote Address Changes: {} ({})",
+            consensus.ip_changes(),
+            ips.join(", ")
+        );
+    }
+}
 
-    // Bind socket
-    let local = "0.0.0.0:0".parse().unwrap();
-    let rw1: Box<dyn RW> = match &flags.proxy {
-        Some(proxy) => {
-            let auth = match flags.username.clone() {
-                Some(username) => Some((username, flags.password.clone().unwrap())),
-                None => None,
+fn print_failure(result: &NatTestResult) {
+    let ports = result.server_ports();
+    println!("Probes:");
+    for (i, observation) in result.observations().iter().enumerate() {
+        println!("  Socket {} ({}):", i + 1, observation.local());
+        for request in REQUESTS.iter() {
+            let status = match (
+                observation.send_error(*request),
+                observation.is_answered(*request),
+            ) {
+                (Some(kind), _) => format!("not sent, {:?}", kind),
+                (None, Some(true)) => "answered".to_string(),
+                (None, Some(false)) => "unanswered".to_string(),
+                (None, None) => "sent".to_string(),
             };
-            match Datagram::bind(proxy.addr(), local, auth) {
-                Ok(datagram) => Box::new(datagram),
-                Err(ref e) => {
-                    eprintln!("{}", e);
-                    return;
+            let server = request.server();
+            let probe = match (request, request.reply_port(&ports)) {
+                (Request::AnotherPort, Some(port)) => {
+                    format!("Inbound from server {} port {}", server, port)
+                }
+                (_, Some(port)) => format!("Echo from server {} port {}", server, port),
+                (_, None) => format!(
+                    "Send-only to server {} port {}",
+                    server,
+                    request.port(&ports)
+                ),
+            };
+            println!("    {:<32}: {}", probe, status);
+        }
+    }
+    if let Some(failure) = result.failure() {
+        println!("Failure: {}", failure);
+    }
+}
+
+fn print_advice(advice: &Advice) {
+    println!("Explanation:");
+    for finding in advice.findings() {
+        println!("  - {}", finding);
+    }
+    if advice.fixes().is_empty() {
+        return;
+    }
+    println!("Suggested Fixes:");
+    for (i, fix) in advice.fixes().iter().enumerate() {
+        println!("  {}. {}", i + 1, fix);
+        let snippets = [("nftables", fix.nftables()), ("iptables", fix.iptables())];
+        for (name, snippet) in snippets.iter() {
+            if let Some(snippet) = snippet {
+                println!("     {}:", name);
+                for line in snippet.lines() {
+                    println!("       {}", line);
                 }
             }
         }
-        None => match Socket::bind(local) {
-            Ok(socket) => Box::new(socket),
-            Err(ref e) => {
+    }
+}
+
+fn format_stats(stats: &EchoStats) -> String {
+    let ms = |d: Option<Duration>| match d {
+        Some(d) => format!("{:.1}", d.as_secs_f64() * 1000.0),
+        None => "-".to_string(),
+    };
+
+    let late = match stats.late() {
+        0 => String::new(),
+        late => format!(", {} late", late),
+    };
+
+    format!(
+        "min/avg/max/p95 = {}/{}/{}/{} ms, jitter {} ms, loss {:.1}% ({}/{}{})",
+        ms(stats.min()),
+        ms(stats.avg()),
+        ms(stats.max()),
+        ms(stats.p95()),
+        ms(stats.jitter()),
+        stats.loss() * 100.0,
+        stats.received(),
+        stats.sent(),
+        late
+    )
+}
+
+#[cfg(target_os = "linux")]
+fn print_trace(server: Ipv4Addr, max_ttl: u8, timeout: Duration) -> io::Result<()> {
+    use ninat::hops::{self, Hop};
+
+    let host_ip = topology::local_ip_to(server)?;
+    let trace = hops::trace(Ipv4Addr::UNSPECIFIED, server, max_ttl, timeout)?;
+
+    println!("Path:");
+    for (i, hop) in trace.hops().iter().enumerate() {
+        match hop {
+            Hop::TimeExceeded(ip) => println!("  {:>2}  {} ({})", i + 1, ip, AddressClass::of(*ip)),
+            Hop::Unreachable(ip) => println!(
+                "  {:>2}  {} ({}, unreachable)",
+                i + 1,
+                ip,
+                AddressClass::of(*ip)
+            ),
+            Hop::Reply(addr) => println!("  {:>2}  {} (server, seen as {})", i + 1, server, addr),
+            Hop::Silent => println!("  {:>2}  *", i + 1),
+        }
+    }
+    match trace.distance() {
+        Some(_) => {
+            let nats = trace.nats(host_ip);
+            match nats.is_empty() {
+                true => println!("NAT Devices: 0"),
+                false => println!(
+                    "NAT Devices: {} (at hop {})",
+                    nats.len(),
+                    nats.iter()
+                        .map(|i| match i {
+                            Some(i) => i.to_string(),
+                            None => "unknown".to_string(),
+                        })
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            }
+        }
+        None => println!("NAT Devices: unknown, the server is not reached"),
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn print_trace(_: Ipv4Addr, _: u8, _: Duration) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "tracing is only supported on Linux",
+    ))
+}
+
+/// Tests the NAT of a lab, returning the NAT type found and the one expected.
+#[cfg(target_os = "linux")]
+fn print_lab(tester: NatTester, nat: &str, name: &str) -> io::Result<(NatType, NatType)> {
+    use ninat::lab::{Lab, Nat};
+
+    let nat = Nat::from_str(nat).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let lab = Lab::new(name, nat)?;
+    let _server = lab.serve()?;
+    let result = lab.nat_test(tester)?;
+
+    println!("Lab NAT: {}", nat);
+    if let Some(ip) = result.ip() {
+        println!("Remote Address: {}", ip);
+    }
+    println!("Expected NAT Type: {}", nat.expected());
+    println!("NAT Type:");
+    print_nat(result.nat());
+    if let Some(behavior) = result.behavior() {
+        print_behavior(&behavior);
+    }
+
+    Ok((result.nat(), nat.expected()))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn print_lab(_: NatTester, _: &str, _: &str) -> io::Result<(NatType, NatType)> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "labs are only supported on Linux",
+    ))
+}
+
+/// Runs the test, and again whenever the network changes, printing the result of each run.
+#[cfg(target_os = "linux")]
+fn print_daemon(tester: &NatTester, debounce: Duration) -> io::Result<()> {
+    tester.watch(debounce, |changes, result| {
+        let result = match result {
+            Ok(result) => result,
+            Err(e) => {
                 eprintln!("{}", e);
                 return;
             }
+        };
+        let ip = match result.ip() {
+            Some(ip) => ip.to_string(),
+            None => "unknown".to_string(),
+        };
+        match changes.is_empty() {
+            true => println!("NAT Type: {}, Remote Address: {}", result.nat(), ip),
+            false => println!(
+                "NAT Type: {}, Remote Address: {}, after {}",
+                result.nat(),
+                ip,
+                changes
+                    .iter()
+                    .map(|change| change.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn print_daemon(_: &NatTester, _: Duration) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "the daemon is only supported on Linux",
+    ))
+}
+
+/// Represents the live progress of a test, printed to stderr.
+struct Progress {
+    start: Instant,
+}
+
+impl Progress {
+    fn new() -> Progress {
+        Progress {
+            start: Instant::now(),
+        }
+    }
+
+    fn print(&self, message: String) {
+        eprintln!("[{:>7.3}s] {}", self.start.elapsed().as_secs_f64(), message);
+    }
+}
+
+impl Observer for Progress {
+    fn phase_started(&self, phase: Phase) {
+        self.print(format!("{} started", phase));
+    }
+
+    fn phase_finished(&self, phase: Phase) {
+        self.print(format!("{} finished", phase));
+    }
+
+    fn probe_sent(&self, socket: usize, request: Request, to: SocketAddrV4) {
+        self.print(format!("Socket {}: sent {} to {}", socket, request, to));
+    }
+
+    fn response_received(&self, socket: usize, from: SocketAddrV4, response: &Response) {
+        self.print(format!(
+            "Socket {}: received {} from {}, mapped to {}",
+            socket,
+            response.request(),
+            from,
+            response.remote_addr()
+        ));
+    }
+
+    fn classified(&self, result: &NatTestResult) {
+        self.print(format!("NAT type {}", result.nat()));
+    }
+}
+
+/// Represents the sockets of the CLI, which are kept once released for later tests.
+struct Sockets<'a> {
+    tester: &'a NatTester,
+    writer: Option<Arc<Mutex<PcapWriter<BufWriter<File>>>>>,
+    created: usize,
+    spare: Option<Box<dyn RW>>,
+    kept: Vec<Box<dyn RW>>,
+}
+
+impl<'a> Sockets<'a> {
+    fn new(
+        tester: &'a NatTester,
+        writer: Option<Arc<Mutex<PcapWriter<BufWriter<File>>>>>,
+    ) -> Sockets<'a> {
+        Sockets {
+            tester,
+            writer,
+            created: 0,
+            spare: None,
+            kept: Vec::new(),
+        }
+    }
+
+    /// Creates sockets until `count` are kept.
+    fn reserve(&mut self, count: usize) -> io::Result<()> {
+        while self.kept.len() < count {
+            let rw = self.create()?;
+            self.kept.push(rw);
+        }
+
+        Ok(())
+    }
+
+    /// Binds a socket directly to the address with the read timeout, which is not kept.
+    fn bind(&mut self, addr: SocketAddrV4, timeout: Duration) -> io::Result<Box<dyn RW>> {
+        let rw = Socket::bind(addr)?;
+        rw.set_read_timeout(Some(timeout))?;
+
+        Ok(self.capture(Box::new(rw)))
+    }
+
+    /// Captures the packets of the socket if a capture is written.
+    fn capture(&mut self, rw: Box<dyn RW>) -> Box<dyn RW> {
+        self.created += 1;
+
+        match &self.writer {
+            Some(writer) => Box::new(Capture::new(
+                rw,
+                &format!("socket {}", self.created),
+                writer.clone(),
+            )),
+            None => rw,
+        }
+    }
+}
+
+impl Drop for Sockets<'_> {
+    /// Flushes the capture, which is buffered.
+    fn drop(&mut self) {
+        if let Some(writer) = &self.writer {
+            let result = match writer.lock() {
+                Ok(mut writer) => writer.flush(),
+                Err(_) => Err(io::Error::other("pcap writer poisoned")),
+            };
+            if let Err(e) = result {
+                eprintln!("{}", e);
+            }
+        }
+    }
+}
+
+impl SocketFactory for Sockets<'_> {
+    fn create(&mut self) -> io::Result<Box<dyn RW>> {
+        if let Some(rw) = self.spare.take() {
+            return Ok(rw);
+        }
+        let rw = self.tester.bind_socket()?;
+
+        Ok(self.capture(rw))
+    }
+
+    fn release(&mut self, rw: Box<dyn RW>) {
+        self.kept.push(rw);
+    }
+}
+
+/// Performs a hairpin test on the first two sockets.
+fn hairpin_test(tester: &NatTester, sockets: &mut Sockets) -> io::Result<bool> {
+    sockets.reserve(2)?;
+    let (rw1, rw2) = (&sockets.kept[0], &sockets.kept[1]);
+
+    tester.hairpin_test(rw1.as_ref(), rw2.as_ref())
+}
+
+#[cfg(unix)]
+fn print_sidecar(
+    port: Option<u16>,
+    fd: Option<i32>,
+    server1: Ipv4Addr,
+    server2: Ipv4Addr,
+    timeout: Option<Duration>,
+) -> io::Result<()> {
+    use ninat::sidecar::{self, SharedSocket};
+
+    let socket: Box<dyn RW> = match (port, fd) {
+        (Some(port), _) => Box::new(SharedSocket::bind_for_test(port, server1, server2)?),
+        // The descriptor is passed by the parent process, which gives up its ownership
+        (None, Some(fd)) => Box::new(unsafe { Socket::from_raw_fd_checked(fd)? }),
+        (None, None) => unreachable!(),
+    };
+    socket.set_read_timeout(timeout)?;
+    let mapping = sidecar::test(&socket, server1, server2)?;
+
+    println!("Local Address: {}", mapping.local());
+    println!(
+        "Mapped Address: {} ({})",
+        mapping.mapped(),
+        AddressClass::of(*mapping.mapped().ip())
+    );
+    println!("Port Mapping:");
+    println!("  Mapping        : {}", mapping.mapping());
+    println!("  Filtering      : {}", mapping.filtering());
+    match mapping.is_port_preserved() {
+        true => println!("  Port Allocation: Preserved"),
+        false => println!("  Port Allocation: Not preserved"),
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn print_sidecar(
+    _: Option<u16>,
+    _: Option<i32>,
+    _: Ipv4Addr,
+    _: Ipv4Addr,
+    _: Option<Duration>,
+) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "sidecar mode is only supported on Unix",
+    ))
+}
+
+/// Verifies a port forward, returning if it passes every check.
+fn print_verification(
+    local_port: u16,
+    external_port: u16,
+    runs: usize,
+    server1: Ipv4Addr,
+    server2: Ipv4Addr,
+    timeout: Option<Duration>,
+) -> io::Result<bool> {
+    let socket = Socket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, local_port))?;
+    socket.set_read_timeout(timeout)?;
+    let verification = forward::verify(&socket, server1, server2, external_port, runs)?;
+
+    println!("Local Address: {}", verification.local());
+    println!("Mapped Addresses:");
+    for (i, ((mapped, inbound), error)) in verification
+        .mapped()
+        .iter()
+        .zip(verification.inbound().iter())
+        .zip(verification.errors().iter())
+        .enumerate()
+    {
+        let inbound = match inbound {
+            true => "inbound arrived",
+            false => "inbound filtered",
+        };
+        match (mapped, error) {
+            (Some((remote1, remote2)), _) => {
+                println!("  Run {}: {}, {} ({})", i + 1, remote1, remote2, inbound)
+            }
+            (None, Some(e)) => println!("  Run {}: failed, {}", i + 1, e),
+            (None, None) => println!("  Run {}: failed", i + 1),
+        }
+    }
+    let status = |passed: bool| match passed {
+        true => "Passed",
+        false => "Failed",
+    };
+    println!("Checks:");
+    println!(
+        "  External Port  : {} (expected {})",
+        status(verification.is_port_matched()),
+        external_port
+    );
+    println!(
+        "  Inbound Traffic: {}",
+        status(verification.is_inbound_allowed())
+    );
+    println!("  Stability      : {}", status(verification.is_stable()));
+
+    Ok(verification.is_verified())
+}
+
+const MAPPING_LIFETIME: Duration = Duration::from_secs(120);
+
+fn main() {
+    process::exit(run() as i32);
+}
+
+fn run() -> Exit {
+    // Parse arguments
+    let flags = Flags::from_args();
+
+    // Log
+    init_logger(flags.verbose, flags.log_format);
+
+    // Namespaces
+    match flags.netns.as_slice() {
+        [] => test(&flags),
+        [netns] => match in_netns(netns, || test(&flags)) {
+            Ok(exit) => exit,
+            Err(e) => {
+                eprintln!("{}", e);
+                Exit::Netns
+            }
         },
+        names => compare_netns(&flags, names),
+    }
+}
+
+/// Returns the tester of the flags.
+fn tester(flags: &Flags) -> NatTester {
+    let read_timeout = match flags.timeout {
+        0 => None,
+        timeout => Some(Duration::from_millis(timeout)),
     };
-    if flags.timeout != 0 {
-        if let Err(ref e) = rw1.set_read_timeout(Some(Duration::from_millis(flags.timeout))) {
+    let mut tester = NatTester::new().timeout(read_timeout);
+    if flags.progress {
+        tester = tester.observer(Arc::new(Progress::new()));
+    }
+    if let Some(proxy) = &flags.proxy {
+        tester = tester.proxy(proxy.addr());
+    }
+    if let (Some(username), Some(password)) = (&flags.username, &flags.password) {
+        tester = tester.proxy_auth(username, password);
+    }
+
+    tester
+}
+
+/// Runs the NAT test in each network namespace at the same time, and compares their NAT types.
+fn compare_netns(flags: &Flags, names: &[String]) -> Exit {
+    if flags.trace
+        || flags.measure.is_some()
+        || flags.port_mapping.is_some()
+        || flags.explain
+        || flags.hairpin
+        || flags.sidecar.is_some()
+        || flags.fd.is_some()
+        || flags.runs.is_some()
+        || flags.pcap.is_some()
+        || flags.command.is_some()
+    {
+        eprintln!("only the NAT test can be run in several network namespaces");
+        return Exit::Usage;
+    }
+
+    let tester = tester(flags);
+    let results = nat_test_netns(&tester, names);
+
+    let width = names.iter().map(|name| name.len()).max().unwrap_or(0);
+    let mut exit = Exit::Success;
+    let mut nats = Vec::new();
+    println!("Network Namespaces:");
+    for (name, result) in names.iter().zip(results) {
+        let code = match result {
+            Ok(result) => {
+                match result.ip() {
+                    Some(ip) => println!("  {:<width$}: {} ({})", name, result.nat(), ip),
+                    None => println!("  {:<width$}: {}", name, result.nat()),
+                }
+                if !nats.contains(&result.nat()) {
+                    nats.push(result.nat());
+                }
+                expect(result.nat(), flags.expect, flags.expect_at_least)
+            }
+            Err(e) => {
+                println!("  {:<width$}: {}", name, e);
+                match is_netns_error(&e) {
+                    true => Exit::Netns,
+                    false => failure(&e),
+                }
+            }
+        };
+        // The first namespace failing decides the exit code
+        if exit == Exit::Success {
+            exit = code;
+        }
+    }
+    match nats.len() {
+        0 => {}
+        1 => println!("NAT Types: Consistent"),
+        _ => println!("NAT Types: Different"),
+    }
+
+    exit
+}
+
+/// Returns if the error is of a network namespace failing to be opened or entered.
+#[cfg(target_os = "linux")]
+fn is_netns_error(e: &io::Error) -> bool {
+    ninat::netns::NetNsError::is(e)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_netns_error(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::Unsupported
+}
+
+/// Runs the closure in the network namespace of the name or path.
+#[cfg(target_os = "linux")]
+fn in_netns<T: Send, F: FnOnce() -> T + Send>(name: &str, f: F) -> io::Result<T> {
+    use ninat::netns::NetNs;
+
+    NetNs::open(name)?.run(f)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn in_netns<T: Send, F: FnOnce() -> T + Send>(_: &str, _: F) -> io::Result<T> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "network namespaces are only supported on Linux",
+    ))
+}
+
+/// Runs the NAT test in each network namespace at the same time.
+#[cfg(target_os = "linux")]
+fn nat_test_netns(tester: &NatTester, names: &[String]) -> Vec<io::Result<NatTestResult>> {
+    use ninat::netns::NetNs;
+    use std::thread;
+
+    thread::scope(|scope| {
+        let handles: Vec<_> = names
+            .iter()
+            .map(|name| scope.spawn(move || tester.run_in(&NetNs::open(name)?)))
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect()
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn nat_test_netns(_: &NatTester, names: &[String]) -> Vec<io::Result<NatTestResult>> {
+    names
+        .iter()
+        .map(|_| {
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "network namespaces are only supported on Linux",
+            ))
+        })
+        .collect()
+}
+
+/// Runs the test of the flags in the current network namespace.
+fn test(flags: &Flags) -> Exit {
+    // Tester
+    let read_timeout = match flags.timeout {
+        0 => None,
+        timeout => Some(Duration::from_millis(timeout)),
+    };
+    let tester = tester(flags);
+
+    // Lab, which serves its own servers
+    if let Some(Command::Lab { nat, name }) = &flags.command {
+        if flags.proxy.is_some() {
+            eprintln!("a lab cannot be tested through a SOCKS proxy");
+            return Exit::Usage;
+        }
+        return match print_lab(tester, nat, name) {
+            Ok((nat, expected)) => expect(nat, Some(expected), None),
+            Err(e) => {
+                eprintln!("{}", e);
+                Exit::Lab
+            }
+        };
+    }
+
+    // Daemon, which resolves the servers in every run
+    if let Some(Command::Daemon { debounce }) = flags.command {
+        return match print_daemon(&tester, Duration::from_millis(debounce)) {
+            Ok(_) => Exit::Success,
+            Err(e) => {
+                eprintln!("{}", e);
+                failure(&e)
+            }
+        };
+    }
+
+    // Server
+    let (server1, server2) = match tester.resolve() {
+        Ok(servers) => servers,
+        Err(e) => {
             eprintln!("{}", e);
-            return;
+            return Exit::Dns;
+        }
+    };
+    let tester = tester.servers(server1, server2);
+
+    // Timeout of auxiliary probes, which cannot wait forever
+    let timeout = match flags.timeout {
+        0 => DEFAULT_TIMEOUT,
+        timeout => Duration::from_millis(timeout),
+    };
+
+    // Trace
+    if flags.trace {
+        if let Err(e) = print_trace(server1, flags.max_ttl, timeout) {
+            eprintln!("{}", e);
+            return Exit::Socket;
         }
     }
 
-    let local = "0.0.0.0:0".parse().unwrap();
-    let rw2: Box<dyn RW> = match &flags.proxy {
-        Some(proxy) => {
-            let auth = match flags.username.clone() {
-                Some(username) => Some((username, flags.password.clone().unwrap())),
-                None => None,
-            };
-            match Datagram::bind(proxy.addr(), local, auth) {
-                Ok(datagram) => Box::new(datagram),
-                Err(ref e) => {
-                    eprintln!("{}", e);
-                    return;
-                }
+    // Sidecar
+    if flags.sidecar.is_some() || flags.fd.is_some() {
+        return match print_sidecar(flags.sidecar, flags.fd, server1, server2, read_timeout) {
+            Ok(_) => Exit::Success,
+            Err(e) => {
+                eprintln!("{}", e);
+                Exit::Socket
             }
+        };
+    }
+
+    // Port forward verification
+    if let Some(Command::VerifyForward {
+        local_port,
+        external_port,
+        runs,
+    }) = flags.command
+    {
+        if flags.proxy.is_some() {
+            eprintln!("a port forward cannot be verified through a SOCKS proxy");
+            return Exit::Usage;
+        }
+        // The runs of the verification are given to the command
+        if flags.runs.is_some() {
+            eprintln!("--runs cannot be used with verify-forward, use its own --runs instead");
+            return Exit::Usage;
         }
-        None => match Socket::bind(local) {
-            Ok(socket) => Box::new(socket),
+        return match print_verification(
+            local_port,
+            external_port,
+            runs,
+            server1,
+            server2,
+            read_timeout,
+        ) {
+            Ok(true) => Exit::Success,
+            Ok(false) => Exit::Unverified,
+            Err(e) => {
+                eprintln!("{}", e);
+                Exit::Socket
+            }
+        };
+    }
+
+    // Capture
+    let writer = match &flags.pcap {
+        Some(path) => match File::create(path)
+            .map(BufWriter::new)
+            .and_then(PcapWriter::new)
+        {
+            Ok(writer) => Some(Arc::new(Mutex::new(writer))),
             Err(ref e) => {
                 eprintln!("{}", e);
-                return;
+                return Exit::Io;
             }
         },
+        None => None,
     };
-    if flags.timeout != 0 {
-        if let Err(ref e) = rw2.set_read_timeout(Some(Duration::from_millis(flags.timeout))) {
-            eprintln!("{}", e);
-            return;
-        }
+    let mut sockets = Sockets::new(&tester, writer);
+
+    // Series
+    if let Some(runs) = flags.runs {
+        let interval = Duration::from_millis(flags.run_interval);
+        return match tester.run_series_with_factory(&mut || sockets.create(), runs, interval) {
+            Ok(consensus) => {
+                print_consensus(&consensus);
+                let majority = consensus.majority().unwrap_or(NatType::F);
+                expect(majority, flags.expect, flags.expect_at_least)
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                match e.kind() {
+                    io::ErrorKind::InvalidInput => Exit::Usage,
+                    _ => failure(&e),
+                }
+            }
+        };
     }
 
+    // Latency measurement
+    let stats = match flags.measure {
+        Some(count) => {
+            let result = sockets.create().and_then(|rw| {
+                let stats = ninat::measure(
+                    &rw,
+                    server1,
+                    server2,
+                    count,
+                    Duration::from_millis(flags.interval),
+                );
+                // The NAT test goes on with the socket
+                sockets.spare = Some(rw);
+                stats
+            });
+            match result {
+                Ok(stats) => Some(stats),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return failure(&e);
+                }
+            }
+        }
+        None => None,
+    };
+
     // NAT test
-    match ninat::nat_test(&rw1, &rw2, server1, server2) {
-        Ok((ip, nat)) => {
-            if let Some(ip) = ip {
-                println!("Remote Address: {}", ip);
+    match tester.run_with_factory(&mut sockets) {
+        Ok(result) => {
+            // The host is hidden behind the proxy
+            let host_ip = match flags.proxy {
+                Some(_) => None,
+                None => match sockets.kept[0].local_addr() {
+                    Ok(addr) if !addr.ip().is_unspecified() => Some(*addr.ip()),
+                    _ => topology::local_ip_to(server1).ok(),
+                },
+            };
+            if let Some(host_ip) = host_ip {
+                println!("Local Address: {} ({})", host_ip, AddressClass::of(host_ip));
+            }
+            if let Some(ip) = result.ip() {
+                println!("Remote Address: {} ({})", ip, AddressClass::of(ip));
+            }
+            let topology = host_ip.and_then(|host_ip| result.topology(host_ip));
+            if let Some(topology) = topology {
+                println!("NAT Topology: {}", topology);
             }
             println!("NAT Type:");
-            println!("  Nintendo Switch : {}", nat.nintendo());
-            println!("  Sony PlayStation: {}", nat.sony());
-            println!("  Microsoft Xbox  : {}", nat.microsoft());
+            print_nat(result.nat());
+            if result.nat() == NatType::F {
+                print_failure(&result);
+            }
+            if let Some(behavior) = result.behavior() {
+                let behavior = match flags.hairpin {
+                    true => match hairpin_test(&tester, &mut sockets) {
+                        Ok(hairpin) => behavior.with_hairpin(hairpin),
+                        Err(ref e) => {
+                            eprintln!("{}", e);
+                            behavior
+                        }
+                    },
+                    false => behavior,
+                };
+                print_behavior(&behavior);
+            }
+            if let Some(protocol) = flags.port_mapping {
+                // UPnP is still searched without a default gateway
+                let gateway = flags.gateway.or_else(|| mapping::default_gateway().ok());
+                let result = Gateway::discover(&protocol.protocols(), gateway, timeout).and_then(
+                    |gateway| {
+                        let mapped = mapping::nat_test(
+                            &gateway,
+                            SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0),
+                            |addr| sockets.bind(addr, timeout),
+                            server1,
+                            server2,
+                            MAPPING_LIFETIME,
+                            timeout,
+                        )?;
+                        Ok((gateway, mapped))
+                    },
+                );
+                match result {
+                    Ok((gateway, mapped)) => {
+                        println!("Port Mapping: {}", gateway.protocol());
+                        for mapping in mapped.mappings() {
+                            println!("  {}", mapping);
+                        }
+                        println!("NAT Type with Port Mapping:");
+                        print_nat(mapped.result().nat());
+                        if let Some(e) = mapped.unmap_error() {
+                            println!("Port Mapping Removal: failed, {}", e);
+                        }
+                    }
+                    Err(e) => println!("Port Mapping: unavailable, {}", e),
+                }
+            }
+            let discarded = result.discarded();
+            if discarded.total() > 0 {
+                println!(
+                    "Discarded Packets: {} invalid, {} unsolicited",
+                    discarded.invalid(),
+                    discarded.unsolicited()
+                );
+            }
+            if let Some((stats1, stats2)) = stats {
+                println!("Latency:");
+                println!("  Server 1: {}", format_stats(&stats1));
+                println!("  Server 2: {}", format_stats(&stats2));
+            }
+            if flags.explain {
+                print_advice(&Advice::new(&result, host_ip, flags.proxy.is_some()));
+            }
+            if topology == Some(Topology::CarrierGradeNat) {
+                println!();
+                println!(
+                    "You are behind carrier-grade NAT. Peers cannot reach you unless your ISP"
+                );
+                println!(
+                    "forwards ports to you, which is the most common cause of NAT type C or D."
+                );
+            }
+
+            expect(result.nat(), flags.expect, flags.expect_at_least)
         }
         Err(e) => {
             eprintln!("{}", e);
-            return;
+            failure(&e)
         }
-    };
+    }
 }
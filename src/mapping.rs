@@ -0,0 +1,379 @@
+//! Port mappings on the gateway with UPnP IGD, NAT-PMP or PCP.
+//!
+//! A mapping asks the gateway to forward a UDP port to the host, which is what consoles rely on to
+//! reach an open NAT type. The NAT test can be rerun through mappings of fresh sockets to see
+//! how much the NAT type benefits from them.
+
+pub mod natpmp;
+pub mod pcp;
+pub mod upnp;
+
+use crate::cancel::CancelToken;
+use crate::protocol::Ports;
+use crate::topology;
+use crate::{Context, Endpoints, NatTestResult, RW};
+use std::error;
+use std::fmt::{self, Display};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::time::{Duration, Instant};
+use tracing::{debug, instrument, trace, warn};
+
+/// Represents the initial interval of retransmitting NAT-PMP and PCP requests, which doubles after
+/// each retransmission.
+const RETRANSMIT_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Enumeration of port mapping protocols.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Protocol {
+    /// Represents UPnP Internet Gateway Device.
+    Upnp,
+    /// Represents NAT Port Mapping Protocol (RFC 6886).
+    NatPmp,
+    /// Represents Port Control Protocol (RFC 6887).
+    Pcp,
+}
+
+impl Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Protocol::Upnp => write!(f, "UPnP IGD"),
+            Protocol::NatPmp => write!(f, "NAT-PMP"),
+            Protocol::Pcp => write!(f, "PCP"),
+        }
+    }
+}
+
+/// Enumeration of errors of port mappings.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// Represents a NAT-PMP request rejected with the result code.
+    NatPmp(u16),
+    /// Represents a PCP request rejected with the result code.
+    Pcp(u8),
+    /// Represents a UPnP action rejected with the error code and description.
+    Upnp(u16, String),
+    /// Represents an HTTP request rejected with the status code.
+    Http(u16),
+    /// Represents a malformed response.
+    Malformed(Protocol),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NatPmp(code) => write!(f, "NAT-PMP request rejected: {}", natpmp::result(*code)),
+            Error::Pcp(code) => write!(f, "PCP request rejected: {}", pcp::result(*code)),
+            Error::Upnp(code, desc) => write!(f, "UPnP action rejected: {} {}", code, desc),
+            Error::Http(status) => write!(f, "HTTP request rejected with status {}", status),
+            Error::Malformed(protocol) => write!(f, "malformed {} response", protocol),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<Error> for io::Error {
+    fn from(s: Error) -> Self {
+        match s {
+            Error::Malformed(_) => io::Error::new(io::ErrorKind::InvalidData, s),
+            _ => io::Error::other(s),
+        }
+    }
+}
+
+/// Represents a port mapping on the gateway.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Mapping {
+    protocol: Protocol,
+    internal: SocketAddrV4,
+    external: SocketAddrV4,
+    lifetime: Duration,
+    nonce: Option<[u8; pcp::NONCE_LEN]>,
+}
+
+impl Mapping {
+    /// Returns the protocol the mapping is created with.
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    /// Returns the internal address of the mapping.
+    pub fn internal(&self) -> SocketAddrV4 {
+        self.internal
+    }
+
+    /// Returns the external address of the mapping.
+    pub fn external(&self) -> SocketAddrV4 {
+        self.external
+    }
+
+    /// Returns the lifetime of the mapping granted by the gateway, zero as permanent.
+    pub fn lifetime(&self) -> Duration {
+        self.lifetime
+    }
+}
+
+impl Display for Mapping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} -> {}", self.external, self.internal)
+    }
+}
+
+/// Represents the result of a NAT test through port mappings.
+#[derive(Debug)]
+pub struct MappedTest {
+    mappings: Vec<Mapping>,
+    result: NatTestResult,
+    unmap_error: Option<io::Error>,
+}
+
+impl MappedTest {
+    /// Returns the mappings the test ran through.
+    pub fn mappings(&self) -> &[Mapping] {
+        &self.mappings
+    }
+
+    /// Returns the result of the NAT test.
+    pub fn result(&self) -> &NatTestResult {
+        &self.result
+    }
+
+    /// Returns the error removing the mappings afterwards, if any failed to be removed.
+    pub fn unmap_error(&self) -> Option<&io::Error> {
+        self.unmap_error.as_ref()
+    }
+}
+
+/// Enumeration of gateways supporting port mappings.
+#[derive(Clone, Debug)]
+pub enum Gateway {
+    /// Represents a UPnP Internet Gateway Device.
+    Upnp(upnp::Device),
+    /// Represents a NAT-PMP server of the address.
+    NatPmp(SocketAddrV4),
+    /// Represents a PCP server of the address.
+    Pcp(SocketAddrV4),
+}
+
+impl Gateway {
+    /// Discovers a gateway supporting any of the protocols, trying them in order. UPnP devices are
+    /// searched by SSDP, and NAT-PMP and PCP servers are probed at the given gateway, so they are
+    /// skipped without one.
+    #[instrument(level = "debug", err(level = "debug"))]
+    pub fn discover(
+        protocols: &[Protocol],
+        gateway: Option<Ipv4Addr>,
+        timeout: Duration,
+    ) -> io::Result<Gateway> {
+        let mut last = None;
+        for protocol in protocols {
+            let result = match (protocol, gateway) {
+                (Protocol::Upnp, _) => upnp::search(upnp::SSDP_ADDR, timeout).map(Gateway::Upnp),
+                (Protocol::NatPmp, Some(gateway)) => {
+                    let addr = SocketAddrV4::new(gateway, natpmp::PORT);
+                    natpmp::external_ip(addr, timeout).map(|_| Gateway::NatPmp(addr))
+                }
+                (Protocol::Pcp, Some(gateway)) => {
+                    let addr = SocketAddrV4::new(gateway, pcp::PORT);
+                    pcp::announce(addr, timeout).map(|_| Gateway::Pcp(addr))
+                }
+                (_, None) => Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "default gateway not found",
+                )),
+            };
+            match result {
+                Ok(gateway) => return Ok(gateway),
+                Err(e) => {
+                    debug!(%protocol, error = %e, "gateway not discovered");
+                    last = Some(e);
+                }
+            }
+        }
+
+        Err(last.unwrap_or_else(|| io::Error::from(io::ErrorKind::NotFound)))
+    }
+
+    /// Returns the protocol of the gateway.
+    pub fn protocol(&self) -> Protocol {
+        match self {
+            Gateway::Upnp(_) => Protocol::Upnp,
+            Gateway::NatPmp(_) => Protocol::NatPmp,
+            Gateway::Pcp(_) => Protocol::Pcp,
+        }
+    }
+
+    /// Returns the IP address of the gateway.
+    pub fn ip(&self) -> Ipv4Addr {
+        match self {
+            Gateway::Upnp(device) => *device.control_addr().ip(),
+            Gateway::NatPmp(addr) | Gateway::Pcp(addr) => *addr.ip(),
+        }
+    }
+
+    /// Requests a mapping of the same external port to the internal address. An unspecified
+    /// internal IP address is replaced by the address the host uses to reach the gateway.
+    #[instrument(level = "debug", skip(self), fields(protocol = %self.protocol()))]
+    pub fn map(
+        &self,
+        internal: SocketAddrV4,
+        lifetime: Duration,
+        timeout: Duration,
+    ) -> io::Result<Mapping> {
+        let internal = match internal.ip().is_unspecified() {
+            true => SocketAddrV4::new(topology::local_ip_to(self.ip())?, internal.port()),
+            false => internal,
+        };
+
+        let mapping = match self {
+            Gateway::Upnp(device) => device.map(internal, lifetime, timeout)?,
+            Gateway::NatPmp(addr) => natpmp::map(*addr, internal, lifetime, timeout)?,
+            Gateway::Pcp(addr) => pcp::map(*addr, internal, lifetime, timeout)?,
+        };
+        debug!(%mapping, lifetime = ?mapping.lifetime, "port mapped");
+
+        Ok(mapping)
+    }
+
+    /// Removes the mapping.
+    #[instrument(level = "debug", skip(self), fields(protocol = %self.protocol()))]
+    pub fn unmap(&self, mapping: &Mapping, timeout: Duration) -> io::Result<()> {
+        match self {
+            Gateway::Upnp(device) => device.unmap(mapping, timeout),
+            Gateway::NatPmp(addr) => natpmp::unmap(*addr, mapping, timeout),
+            Gateway::Pcp(addr) => pcp::unmap(*addr, mapping, timeout),
+        }
+    }
+}
+
+/// Returns the IP address of the default gateway.
+#[cfg(target_os = "linux")]
+pub fn default_gateway() -> io::Result<Ipv4Addr> {
+    let routes = std::fs::read_to_string("/proc/net/route")?;
+    routes
+        .lines()
+        .skip(1)
+        .find_map(|line| {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            match fields.as_slice() {
+                [_, "00000000", gateway, _, _, _, _, "00000000", ..] => {
+                    u32::from_str_radix(gateway, 16)
+                        .ok()
+                        .map(|gateway| Ipv4Addr::from(gateway.to_ne_bytes()))
+                        .filter(|gateway| !gateway.is_unspecified())
+                }
+                _ => None,
+            }
+        })
+        .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+}
+
+/// Returns the IP address of the default gateway.
+#[cfg(not(target_os = "linux"))]
+pub fn default_gateway() -> io::Result<Ipv4Addr> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+/// Performs a NAT test through mappings of two ports of the local address, which are removed
+/// afterwards. The ports are bound with sockets from `bind`, which send nothing until mapped, so
+/// the test is not affected by what the NAT saw from the ports before, and is run on them, so no
+/// other process can take the ports in between.
+///
+/// The test fails if a port cannot be mapped. A mapping failing to be removed does not fail the
+/// test, but is reported with the result.
+#[instrument(level = "info", skip(gateway, bind), fields(protocol = %gateway.protocol()))]
+pub fn nat_test<F>(
+    gateway: &Gateway,
+    local: SocketAddrV4,
+    mut bind: F,
+    server1: Ipv4Addr,
+    server2: Ipv4Addr,
+    lifetime: Duration,
+    timeout: Duration,
+) -> io::Result<MappedTest>
+where
+    F: FnMut(SocketAddrV4) -> io::Result<Box<dyn RW>>,
+{
+    let mut mappings = Vec::new();
+    let result = (|| {
+        let rws = [bind(local)?, bind(local)?];
+        for rw in rws.iter() {
+            let internal = SocketAddrV4::new(*local.ip(), rw.local_addr()?.port());
+            mappings.push(gateway.map(internal, lifetime, timeout)?);
+        }
+
+        let rws = rws.iter().map(|rw| rw.as_ref()).collect::<Vec<_>>();
+        let endpoints = Endpoints::new(server1, server2, Ports::default());
+
+        let ctx = Context {
+            cancel: &CancelToken::new(),
+            observer: &(),
+        };
+        crate::nat_test_on(&rws, &endpoints, ctx)
+    })();
+    let mut unmap_error = None;
+    for mapping in mappings.iter() {
+        if let Err(e) = gateway.unmap(mapping, timeout) {
+            warn!(error = %e, %mapping, "mapping not removed");
+            unmap_error = Some(e);
+        }
+    }
+
+    Ok(MappedTest {
+        mappings,
+        result: result?,
+        unmap_error,
+    })
+}
+
+/// Sends the request to the gateway until a response is accepted or the timeout elapses,
+/// retransmitting with an interval doubled each time.
+fn transact<F>(
+    gateway: SocketAddrV4,
+    request: &[u8],
+    timeout: Duration,
+    accept: F,
+) -> io::Result<Vec<u8>>
+where
+    F: Fn(&[u8]) -> bool,
+{
+    let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.connect(gateway)?;
+
+    let deadline = Instant::now() + timeout;
+    let mut interval = RETRANSMIT_INTERVAL;
+    let mut buffer = vec![0u8; 1100];
+
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(io::Error::from(io::ErrorKind::TimedOut));
+        }
+        socket.send(request)?;
+        trace!(len = request.len(), "mapping request sent");
+
+        let retransmit = (now + interval).min(deadline);
+        interval *= 2;
+        loop {
+            let now = Instant::now();
+            if now >= retransmit {
+                break;
+            }
+            socket.set_read_timeout(Some(retransmit - now))?;
+            match socket.recv(buffer.as_mut_slice()) {
+                Ok(size) => {
+                    trace!(len = size, "mapping response received");
+                    if accept(&buffer[..size]) {
+                        buffer.truncate(size);
+                        return Ok(buffer);
+                    }
+                }
+                Err(e) => match e.kind() {
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => break,
+                    _ => return Err(e),
+                },
+            }
+        }
+    }
+}
@@ -0,0 +1,126 @@
+//! NAT Port Mapping Protocol client (RFC 6886).
+
+use super::{transact, Error, Mapping, Protocol};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::time::Duration;
+
+/// Represents the port NAT-PMP servers listen on.
+pub const PORT: u16 = 5351;
+
+/// Represents the version of NAT-PMP.
+const VERSION: u8 = 0;
+/// Represents the opcode of external address requests.
+const OP_EXTERNAL_ADDRESS: u8 = 0;
+/// Represents the opcode of UDP mapping requests.
+const OP_MAP_UDP: u8 = 1;
+/// Represents the bit set in the opcode of responses.
+const OP_RESPONSE: u8 = 0x80;
+
+/// Represents the result code of success.
+const RESULT_SUCCESS: u16 = 0;
+
+/// Returns the description of the result code.
+pub fn result(code: u16) -> &'static str {
+    match code {
+        0 => "success",
+        1 => "unsupported version",
+        2 => "not authorized",
+        3 => "network failure",
+        4 => "out of resources",
+        5 => "unsupported opcode",
+        _ => "unknown result",
+    }
+}
+
+/// Checks the header of the response to the request of the opcode, returning the payload after the
+/// result code and the epoch.
+fn check(buf: &[u8], op: u8, len: usize) -> Result<&[u8], Error> {
+    if buf.len() < 4 || buf[0] != VERSION || buf[1] != OP_RESPONSE | op {
+        return Err(Error::Malformed(Protocol::NatPmp));
+    }
+    match u16::from_be_bytes([buf[2], buf[3]]) {
+        RESULT_SUCCESS if buf.len() >= len => Ok(&buf[8..len]),
+        RESULT_SUCCESS => Err(Error::Malformed(Protocol::NatPmp)),
+        code => Err(Error::NatPmp(code)),
+    }
+}
+
+/// Returns if the buffer is a response to the request of the opcode.
+fn is_response(buf: &[u8], op: u8) -> bool {
+    buf.len() >= 2 && buf[1] == OP_RESPONSE | op
+}
+
+/// Returns the external IP address of the gateway.
+pub fn external_ip(gateway: SocketAddrV4, timeout: Duration) -> io::Result<Ipv4Addr> {
+    let request = [VERSION, OP_EXTERNAL_ADDRESS];
+    let buf = transact(gateway, &request, timeout, |buf| {
+        is_response(buf, OP_EXTERNAL_ADDRESS)
+    })?;
+    let payload = check(&buf, OP_EXTERNAL_ADDRESS, 12)?;
+
+    Ok(Ipv4Addr::new(
+        payload[0], payload[1], payload[2], payload[3],
+    ))
+}
+
+/// Sends a UDP mapping request, returning the mapped external port and the granted lifetime.
+fn request(
+    gateway: SocketAddrV4,
+    internal_port: u16,
+    external_port: u16,
+    lifetime: Duration,
+    timeout: Duration,
+) -> io::Result<(u16, Duration)> {
+    let mut request = vec![VERSION, OP_MAP_UDP, 0, 0];
+    request.extend_from_slice(&internal_port.to_be_bytes());
+    request.extend_from_slice(&external_port.to_be_bytes());
+    request.extend_from_slice(&(lifetime.as_secs() as u32).to_be_bytes());
+
+    let buf = transact(gateway, &request, timeout, |buf| {
+        // Responses without the internal port can only be errors of the whole request
+        is_response(buf, OP_MAP_UDP)
+            && (buf.len() < 10 || u16::from_be_bytes([buf[8], buf[9]]) == internal_port)
+    })?;
+    let payload = check(&buf, OP_MAP_UDP, 16)?;
+
+    Ok((
+        u16::from_be_bytes([payload[2], payload[3]]),
+        Duration::from_secs(
+            u32::from_be_bytes([payload[4], payload[5], payload[6], payload[7]]) as u64,
+        ),
+    ))
+}
+
+/// Requests a mapping of the same external port to the internal address, whose IP address must be
+/// the one the host uses to reach the gateway.
+pub fn map(
+    gateway: SocketAddrV4,
+    internal: SocketAddrV4,
+    lifetime: Duration,
+    timeout: Duration,
+) -> io::Result<Mapping> {
+    let (port, lifetime) = request(gateway, internal.port(), internal.port(), lifetime, timeout)?;
+    let ip = external_ip(gateway, timeout)?;
+
+    Ok(Mapping {
+        protocol: Protocol::NatPmp,
+        internal,
+        external: SocketAddrV4::new(ip, port),
+        lifetime,
+        nonce: None,
+    })
+}
+
+/// Removes the mapping.
+pub fn unmap(gateway: SocketAddrV4, mapping: &Mapping, timeout: Duration) -> io::Result<()> {
+    request(
+        gateway,
+        mapping.internal.port(),
+        0,
+        Duration::from_secs(0),
+        timeout,
+    )?;
+
+    Ok(())
+}
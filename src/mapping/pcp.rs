@@ -0,0 +1,191 @@
+//! Port Control Protocol client (RFC 6887).
+
+use super::{transact, Error, Mapping, Protocol};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::time::{Duration, SystemTime};
+
+/// Represents the port PCP servers listen on.
+pub const PORT: u16 = 5351;
+/// Represents the length of a mapping nonce.
+pub const NONCE_LEN: usize = 12;
+
+/// Represents the version of PCP.
+const VERSION: u8 = 2;
+/// Represents the opcode of announce requests.
+const OP_ANNOUNCE: u8 = 0;
+/// Represents the opcode of map requests.
+const OP_MAP: u8 = 1;
+/// Represents the bit set in the opcode of responses.
+const OP_RESPONSE: u8 = 0x80;
+/// Represents the length of the common header.
+const HEADER_LEN: usize = 24;
+/// Represents the length of the map opcode payload.
+const MAP_LEN: usize = 36;
+/// Represents the protocol number of UDP.
+const IPPROTO_UDP: u8 = 17;
+
+/// Represents the result code of success.
+const RESULT_SUCCESS: u8 = 0;
+
+/// Returns the description of the result code.
+pub fn result(code: u8) -> &'static str {
+    match code {
+        0 => "success",
+        1 => "unsupported version",
+        2 => "not authorized",
+        3 => "malformed request",
+        4 => "unsupported opcode",
+        5 => "unsupported option",
+        6 => "malformed option",
+        7 => "network failure",
+        8 => "no resources",
+        9 => "unsupported protocol",
+        10 => "user exceeded quota",
+        11 => "cannot provide external",
+        12 => "address mismatch",
+        13 => "excessive remote peers",
+        _ => "unknown result",
+    }
+}
+
+/// Returns the IPv4-mapped IPv6 representation of the IP address.
+fn mapped(ip: Ipv4Addr) -> [u8; 16] {
+    ip.to_ipv6_mapped().octets()
+}
+
+/// Returns the IP address from its IPv4-mapped IPv6 representation.
+fn unmapped(buf: &[u8]) -> Option<Ipv4Addr> {
+    match buf[..12] == [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff] {
+        true => Some(Ipv4Addr::new(buf[12], buf[13], buf[14], buf[15])),
+        false => None,
+    }
+}
+
+/// Returns a random mapping nonce.
+fn nonce() -> [u8; NONCE_LEN] {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos(),
+    );
+    let high = hasher.finish();
+    hasher.write_u64(high);
+    let low = hasher.finish();
+
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce[..8].copy_from_slice(&high.to_be_bytes());
+    nonce[8..].copy_from_slice(&low.to_be_bytes()[..4]);
+
+    nonce
+}
+
+/// Returns the common header of requests.
+fn header(op: u8, lifetime: Duration, client_ip: Ipv4Addr) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(HEADER_LEN + MAP_LEN);
+    buf.extend_from_slice(&[VERSION, op, 0, 0]);
+    buf.extend_from_slice(&(lifetime.as_secs() as u32).to_be_bytes());
+    buf.extend_from_slice(&mapped(client_ip));
+
+    buf
+}
+
+/// Checks the common header of the response to the request of the opcode, returning the granted
+/// lifetime and the opcode payload.
+fn check(buf: &[u8], op: u8, len: usize) -> Result<(Duration, &[u8]), Error> {
+    if buf.len() < 4 || buf[1] != OP_RESPONSE | op {
+        return Err(Error::Malformed(Protocol::Pcp));
+    }
+    match buf[3] {
+        RESULT_SUCCESS if buf[0] == VERSION && buf.len() >= HEADER_LEN + len => Ok((
+            Duration::from_secs(u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]) as u64),
+            &buf[HEADER_LEN..HEADER_LEN + len],
+        )),
+        RESULT_SUCCESS => Err(Error::Malformed(Protocol::Pcp)),
+        code => Err(Error::Pcp(code)),
+    }
+}
+
+/// Announces to the gateway, succeeding if it supports PCP.
+pub fn announce(gateway: SocketAddrV4, timeout: Duration) -> io::Result<()> {
+    let client_ip = crate::topology::local_ip_to(*gateway.ip())?;
+    let request = header(OP_ANNOUNCE, Duration::from_secs(0), client_ip);
+    let buf = transact(gateway, &request, timeout, |buf| {
+        buf.len() >= 2 && buf[1] == OP_RESPONSE | OP_ANNOUNCE
+    })?;
+    check(&buf, OP_ANNOUNCE, 0)?;
+
+    Ok(())
+}
+
+/// Sends a map request, returning the granted lifetime and the assigned external address.
+fn request(
+    gateway: SocketAddrV4,
+    nonce: [u8; NONCE_LEN],
+    internal: SocketAddrV4,
+    external: SocketAddrV4,
+    lifetime: Duration,
+    timeout: Duration,
+) -> io::Result<(Duration, SocketAddrV4)> {
+    let mut request = header(OP_MAP, lifetime, *internal.ip());
+    request.extend_from_slice(&nonce);
+    request.extend_from_slice(&[IPPROTO_UDP, 0, 0, 0]);
+    request.extend_from_slice(&internal.port().to_be_bytes());
+    request.extend_from_slice(&external.port().to_be_bytes());
+    request.extend_from_slice(&mapped(*external.ip()));
+
+    let buf = transact(gateway, &request, timeout, |buf| {
+        // Responses without the nonce can only be errors of the whole request
+        buf.len() >= 2
+            && buf[1] == OP_RESPONSE | OP_MAP
+            && (buf.len() < HEADER_LEN + NONCE_LEN
+                || buf[HEADER_LEN..HEADER_LEN + NONCE_LEN] == nonce)
+    })?;
+    let (lifetime, payload) = check(&buf, OP_MAP, MAP_LEN)?;
+    let ip = unmapped(&payload[20..36]).ok_or(Error::Malformed(Protocol::Pcp))?;
+    let port = u16::from_be_bytes([payload[18], payload[19]]);
+
+    Ok((lifetime, SocketAddrV4::new(ip, port)))
+}
+
+/// Requests a mapping of the same external port to the internal address, whose IP address must be
+/// the one the host uses to reach the gateway.
+pub fn map(
+    gateway: SocketAddrV4,
+    internal: SocketAddrV4,
+    lifetime: Duration,
+    timeout: Duration,
+) -> io::Result<Mapping> {
+    let nonce = nonce();
+    let suggested = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, internal.port());
+    let (lifetime, external) = request(gateway, nonce, internal, suggested, lifetime, timeout)?;
+
+    Ok(Mapping {
+        protocol: Protocol::Pcp,
+        internal,
+        external,
+        lifetime,
+        nonce: Some(nonce),
+    })
+}
+
+/// Removes the mapping.
+pub fn unmap(gateway: SocketAddrV4, mapping: &Mapping, timeout: Duration) -> io::Result<()> {
+    let nonce = mapping
+        .nonce
+        .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+    request(
+        gateway,
+        nonce,
+        mapping.internal,
+        mapping.external,
+        Duration::from_secs(0),
+        timeout,
+    )?;
+
+    Ok(())
+}
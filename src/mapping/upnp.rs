@@ -0,0 +1,379 @@
+//! UPnP Internet Gateway Device client.
+//!
+//! Devices are searched by SSDP, and their `WANIPConnection` or `WANPPPConnection` service is
+//! controlled by SOAP over HTTP.
+
+use super::{Error, Mapping, Protocol};
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, SocketAddrV4, TcpStream, UdpSocket};
+use std::time::{Duration, Instant};
+use tracing::{debug, trace};
+
+/// Represents the multicast address of SSDP.
+pub const SSDP_ADDR: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 255, 255, 250), 1900);
+
+/// Represents the search targets of Internet gateway devices.
+const SEARCH_TARGETS: [&str; 2] = [
+    "urn:schemas-upnp-org:device:InternetGatewayDevice:1",
+    "urn:schemas-upnp-org:device:InternetGatewayDevice:2",
+];
+/// Represents the services controlling port mappings.
+const SERVICES: [&str; 2] = ["WANIPConnection", "WANPPPConnection"];
+/// Represents the description of mappings.
+const DESCRIPTION: &str = env!("CARGO_PKG_NAME");
+
+/// Represents the UPnP error code of gateways only supporting permanent mappings.
+const ONLY_PERMANENT_LEASES_SUPPORTED: u16 = 725;
+
+/// Represents an Internet gateway device.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Device {
+    location: String,
+    service_type: String,
+    control_addr: SocketAddrV4,
+    control_path: String,
+}
+
+impl Device {
+    /// Returns the location of the description of the device.
+    pub fn location(&self) -> &str {
+        &self.location
+    }
+
+    /// Returns the type of the service controlling port mappings.
+    pub fn service_type(&self) -> &str {
+        &self.service_type
+    }
+
+    /// Returns the address of the control URL of the service.
+    pub fn control_addr(&self) -> SocketAddrV4 {
+        self.control_addr
+    }
+
+    /// Returns the path of the control URL of the service.
+    pub fn control_path(&self) -> &str {
+        &self.control_path
+    }
+
+    /// Returns the external IP address of the device.
+    pub fn external_ip(&self, timeout: Duration) -> io::Result<Ipv4Addr> {
+        let body = self.action("GetExternalIPAddress", &[], timeout)?;
+
+        element(&body, "NewExternalIPAddress")
+            .and_then(|ip| ip.trim().parse().ok())
+            .ok_or_else(|| Error::Malformed(Protocol::Upnp).into())
+    }
+
+    /// Requests a mapping of the same external port to the internal address.
+    pub fn map(
+        &self,
+        internal: SocketAddrV4,
+        lifetime: Duration,
+        timeout: Duration,
+    ) -> io::Result<Mapping> {
+        let port = internal.port().to_string();
+        let client = internal.ip().to_string();
+        let add = |lifetime: Duration| {
+            let lease = lifetime.as_secs().to_string();
+            self.action(
+                "AddPortMapping",
+                &[
+                    ("NewRemoteHost", ""),
+                    ("NewExternalPort", &port),
+                    ("NewProtocol", "UDP"),
+                    ("NewInternalPort", &port),
+                    ("NewInternalClient", &client),
+                    ("NewEnabled", "1"),
+                    ("NewPortMappingDescription", DESCRIPTION),
+                    ("NewLeaseDuration", &lease),
+                ],
+                timeout,
+            )
+        };
+
+        let lifetime = match add(lifetime) {
+            Ok(_) => lifetime,
+            Err(e) => match e.get_ref().and_then(|e| e.downcast_ref::<Error>()) {
+                Some(Error::Upnp(ONLY_PERMANENT_LEASES_SUPPORTED, _)) => {
+                    debug!("only permanent leases supported");
+                    add(Duration::from_secs(0))?;
+                    Duration::from_secs(0)
+                }
+                _ => return Err(e),
+            },
+        };
+        let ip = self.external_ip(timeout)?;
+
+        Ok(Mapping {
+            protocol: Protocol::Upnp,
+            internal,
+            external: SocketAddrV4::new(ip, internal.port()),
+            lifetime,
+            nonce: None,
+        })
+    }
+
+    /// Removes the mapping.
+    pub fn unmap(&self, mapping: &Mapping, timeout: Duration) -> io::Result<()> {
+        let port = mapping.external.port().to_string();
+        self.action(
+            "DeletePortMapping",
+            &[
+                ("NewRemoteHost", ""),
+                ("NewExternalPort", &port),
+                ("NewProtocol", "UDP"),
+            ],
+            timeout,
+        )?;
+
+        Ok(())
+    }
+
+    /// Invokes the action of the service, returning the body of the response.
+    fn action(&self, name: &str, args: &[(&str, &str)], timeout: Duration) -> io::Result<String> {
+        let args = args
+            .iter()
+            .map(|(k, v)| format!("<{0}>{1}</{0}>", k, v))
+            .collect::<String>();
+        let body = format!(
+            "<?xml version=\"1.0\"?>\r\n\
+             <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+             s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+             <s:Body><u:{0} xmlns:u=\"{1}\">{2}</u:{0}></s:Body></s:Envelope>\r\n",
+            name, self.service_type, args
+        );
+        let soap_action = format!("\"{}#{}\"", self.service_type, name);
+
+        let (status, body) = http(
+            self.control_addr,
+            "POST",
+            &self.control_path,
+            &[
+                ("Content-Type", "text/xml; charset=\"utf-8\""),
+                ("SOAPAction", &soap_action),
+            ],
+            &body,
+            timeout,
+        )?;
+        debug!(action = name, status, "UPnP action invoked");
+
+        match status {
+            200 => Ok(body),
+            _ => match element(&body, "errorCode").and_then(|code| code.trim().parse().ok()) {
+                Some(code) => {
+                    let desc = element(&body, "errorDescription").unwrap_or_default();
+                    Err(Error::Upnp(code, desc.trim().to_string()).into())
+                }
+                None => Err(Error::Http(status).into()),
+            },
+        }
+    }
+}
+
+/// Searches an Internet gateway device by SSDP at the address, which is usually `SSDP_ADDR`.
+pub fn search(addr: SocketAddrV4, timeout: Duration) -> io::Result<Device> {
+    let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_multicast_ttl_v4(2)?;
+    for st in SEARCH_TARGETS.iter() {
+        let request = format!(
+            "M-SEARCH * HTTP/1.1\r\n\
+             HOST: {}\r\n\
+             MAN: \"ssdp:discover\"\r\n\
+             MX: 2\r\n\
+             ST: {}\r\n\r\n",
+            SSDP_ADDR, st
+        );
+        socket.send_to(request.as_bytes(), addr)?;
+    }
+
+    let deadline = Instant::now() + timeout;
+    let mut buffer = vec![0u8; 2048];
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(io::Error::from(io::ErrorKind::TimedOut));
+        }
+        socket.set_read_timeout(Some(deadline - now))?;
+        let size = match socket.recv_from(buffer.as_mut_slice()) {
+            Ok((size, _)) => size,
+            Err(e) => match e.kind() {
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => continue,
+                _ => return Err(e),
+            },
+        };
+
+        let resp = String::from_utf8_lossy(&buffer[..size]);
+        let location = match header(&resp, "LOCATION") {
+            Some(location) => location.to_string(),
+            None => continue,
+        };
+        trace!(%location, "SSDP response received");
+        match describe(
+            &location,
+            deadline.saturating_duration_since(Instant::now()),
+        ) {
+            Ok(device) => return Ok(device),
+            Err(e) => debug!(%location, error = %e, "device not supported"),
+        }
+    }
+}
+
+/// Fetches the description of the device at the location, finding the service controlling port
+/// mappings.
+fn describe(location: &str, timeout: Duration) -> io::Result<Device> {
+    let (addr, path) = parse_url(location)?;
+    let (status, body) = http(addr, "GET", &path, &[], "", timeout)?;
+    if status != 200 {
+        return Err(Error::Http(status).into());
+    }
+
+    let base = element(&body, "URLBase").map(|base| base.trim().to_string());
+    for service in body.split("<service>").skip(1) {
+        let service_type = match element(service, "serviceType") {
+            Some(service_type) => service_type.trim(),
+            None => continue,
+        };
+        if !SERVICES.iter().any(|s| service_type.contains(s)) {
+            continue;
+        }
+        let control = match element(service, "controlURL") {
+            Some(control) => control.trim(),
+            None => continue,
+        };
+
+        let (control_addr, control_path) = match control.starts_with("http://") {
+            true => parse_url(control)?,
+            false => {
+                let (addr, _) = parse_url(base.as_deref().unwrap_or(location))?;
+                let path = match control.starts_with('/') {
+                    true => control.to_string(),
+                    false => format!("/{}", control),
+                };
+                (addr, path)
+            }
+        };
+
+        return Ok(Device {
+            location: location.to_string(),
+            service_type: service_type.to_string(),
+            control_addr,
+            control_path,
+        });
+    }
+
+    Err(io::Error::from(io::ErrorKind::NotFound))
+}
+
+/// Returns the value of the header in the HTTP message, case-insensitively.
+fn header<'a>(message: &'a str, name: &str) -> Option<&'a str> {
+    message.lines().skip(1).find_map(|line| {
+        let mut split = line.splitn(2, ':');
+        match (split.next(), split.next()) {
+            (Some(key), Some(value)) if key.trim().eq_ignore_ascii_case(name) => Some(value.trim()),
+            _ => None,
+        }
+    })
+}
+
+/// Returns the content of the first element of the name in the XML document, ignoring namespace
+/// prefixes.
+fn element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let mut rest = xml;
+    loop {
+        let start = rest.find('<')?;
+        rest = &rest[start + 1..];
+        let end = rest.find('>')?;
+        let tag = &rest[..end];
+        rest = &rest[end + 1..];
+
+        if tag.ends_with('/') {
+            continue;
+        }
+        let tag = tag.split_whitespace().next().unwrap_or_default();
+        if tag.rsplit(':').next() == Some(name) {
+            let close = rest.find("</")?;
+            return Some(&rest[..close]);
+        }
+    }
+}
+
+/// Parses an HTTP URL into the address and the path.
+fn parse_url(url: &str) -> io::Result<(SocketAddrV4, String)> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidInput, format!("invalid URL {}", url));
+
+    let rest = url.strip_prefix("http://").ok_or_else(invalid)?;
+    let (host, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let (host, port) = match host.rfind(':') {
+        Some(i) => (&host[..i], host[i + 1..].parse().map_err(|_| invalid())?),
+        None => (host, 80),
+    };
+    let ip = match host.parse() {
+        Ok(ip) => ip,
+        Err(_) => crate::lookup_host_v4(host)?,
+    };
+
+    Ok((SocketAddrV4::new(ip, port), path.to_string()))
+}
+
+/// Performs an HTTP request, returning the status code and the body of the response.
+fn http(
+    addr: SocketAddrV4,
+    method: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+    body: &str,
+    timeout: Duration,
+) -> io::Result<(u16, String)> {
+    let timeout = timeout.max(Duration::from_millis(1));
+    let mut stream = TcpStream::connect_timeout(&addr.into(), timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    let mut request = format!("{} {} HTTP/1.1\r\nHost: {}\r\n", method, path, addr);
+    for (key, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", key, value));
+    }
+    request.push_str(&format!(
+        "Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    ));
+    stream.write_all(request.as_bytes())?;
+
+    let mut buffer = Vec::new();
+    stream.read_to_end(&mut buffer)?;
+    let resp = String::from_utf8_lossy(&buffer);
+
+    let malformed = || io::Error::from(Error::Malformed(Protocol::Upnp));
+    let (head, body) = resp.split_once("\r\n\r\n").ok_or_else(malformed)?;
+    let status = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(malformed)?;
+    let body = match header(head, "Transfer-Encoding") {
+        Some(encoding) if encoding.eq_ignore_ascii_case("chunked") => {
+            dechunk(body).ok_or_else(malformed)?
+        }
+        _ => body.to_string(),
+    };
+
+    Ok((status, body))
+}
+
+/// Decodes a body in the chunked transfer encoding.
+fn dechunk(mut body: &str) -> Option<String> {
+    let mut decoded = String::new();
+    loop {
+        let (size, rest) = body.split_once("\r\n")?;
+        let size = usize::from_str_radix(size.split(';').next()?.trim(), 16).ok()?;
+        if size == 0 {
+            return Some(decoded);
+        }
+        decoded.push_str(rest.get(..size)?);
+        body = rest.get(size..)?.strip_prefix("\r\n")?;
+    }
+}
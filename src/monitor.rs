@@ -0,0 +1,461 @@
+//! Monitoring of network changes with rtnetlink.
+//!
+//! A netlink socket subscribes to the link, IPv4 address and IPv4 route groups of rtnetlink.
+//! Links are reported when they go up or down, addresses when they are added or removed, except
+//! those of loopback links, and routes only if they are default routes of the main table. Once the
+//! uplink, the link of the default route, is known, only links and addresses of the uplink are
+//! reported. Links and routes are dumped once the socket subscribes, so the first event of a link
+//! is compared to its state, and the uplink is known from the start.
+
+use crate::cancel::{CancelToken, Cancelled, CANCEL_INTERVAL};
+use crate::tester::NatTester;
+use crate::NatTestResult;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fmt::{self, Display};
+use std::io;
+use std::mem;
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, instrument};
+
+/// Represents the length of the header of a netlink message.
+const NLMSG_HDRLEN: usize = 16;
+/// Represents the length of the header of a link message.
+const IFINFOMSG_LEN: usize = 16;
+/// Represents the length of the header of an address message.
+const IFADDRMSG_LEN: usize = 8;
+/// Represents the length of the header of a route message.
+const RTMSG_LEN: usize = 12;
+/// Represents the length of the header of an attribute.
+const RTA_HDRLEN: usize = 4;
+
+/// Enumeration of network changes.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Change {
+    /// Represents a link of the index going up or down. A removed link goes down.
+    Link { index: u32, up: bool },
+    /// Represents an IPv4 address added to or removed from the link of the index.
+    Address {
+        index: u32,
+        ip: Ipv4Addr,
+        added: bool,
+    },
+    /// Represents a default route added or removed, through the gateway and the link of the index
+    /// if any.
+    DefaultRoute {
+        gateway: Option<Ipv4Addr>,
+        index: Option<u32>,
+        added: bool,
+    },
+    /// Represents events lost when the socket buffer overflows, so anything may have changed.
+    Lost,
+}
+
+impl Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let verb = |added: bool| match added {
+            true => "added",
+            false => "removed",
+        };
+        match self {
+            Change::Link { index, up } => match up {
+                true => write!(f, "link {} up", index),
+                false => write!(f, "link {} down", index),
+            },
+            Change::Address { index, ip, added } => {
+                write!(f, "address {} {} on link {}", ip, verb(*added), index)
+            }
+            Change::DefaultRoute {
+                gateway,
+                index,
+                added,
+            } => {
+                write!(f, "default route")?;
+                if let Some(gateway) = gateway {
+                    write!(f, " via {}", gateway)?;
+                }
+                if let Some(index) = index {
+                    write!(f, " on link {}", index)?;
+                }
+                write!(f, " {}", verb(*added))
+            }
+            Change::Lost => write!(f, "events lost"),
+        }
+    }
+}
+
+/// Represents a parser of rtnetlink messages, keeping the states of links and the uplink.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Parser {
+    links: HashMap<u32, bool>,
+    uplink: Option<u32>,
+}
+
+impl Parser {
+    /// Creates a new `Parser`, knowing no link.
+    pub fn new() -> Parser {
+        Parser::default()
+    }
+
+    /// Returns the index of the uplink, the link of the default route, if known.
+    pub fn uplink(&self) -> Option<u32> {
+        self.uplink
+    }
+
+    /// Parses the netlink messages of a datagram, returning the changes reported. Parsing stops at
+    /// a truncated message, and truncated attributes are ignored.
+    pub fn parse(&mut self, datagram: &[u8]) -> Vec<Change> {
+        messages(datagram)
+            .filter_map(|(kind, payload)| self.message(kind, payload))
+            .collect()
+    }
+
+    /// Returns if changes of the link of the index are reported, which are those of the uplink
+    /// once it is known.
+    fn is_reported(&self, index: u32) -> bool {
+        match self.uplink {
+            Some(uplink) => uplink == index,
+            None => true,
+        }
+    }
+
+    /// Parses the message of the kind, returning the change if it is one reported.
+    fn message(&mut self, kind: u16, payload: &[u8]) -> Option<Change> {
+        match kind {
+            libc::RTM_NEWLINK | libc::RTM_DELLINK if payload.len() >= IFINFOMSG_LEN => {
+                let index = u32::from_ne_bytes(payload[4..8].try_into().unwrap());
+                let flags = u32::from_ne_bytes(payload[8..12].try_into().unwrap());
+                if flags & libc::IFF_LOOPBACK as u32 != 0 {
+                    return None;
+                }
+                let running = (libc::IFF_UP | libc::IFF_RUNNING) as u32;
+                let up = kind == libc::RTM_NEWLINK && flags & running == running;
+                let previous = match kind {
+                    libc::RTM_NEWLINK => self.links.insert(index, up),
+                    _ => self.links.remove(&index),
+                };
+                if !self.is_reported(index) {
+                    return None;
+                }
+                match previous {
+                    Some(previous) if previous == up => None,
+                    // Links first seen are only reported if up
+                    None if !up => None,
+                    _ => Some(Change::Link { index, up }),
+                }
+            }
+            libc::RTM_NEWADDR | libc::RTM_DELADDR if payload.len() >= IFADDRMSG_LEN => {
+                if payload[0] != libc::AF_INET as u8 || payload[3] == libc::RT_SCOPE_HOST {
+                    return None;
+                }
+                let index = u32::from_ne_bytes(payload[4..8].try_into().unwrap());
+                if !self.is_reported(index) {
+                    return None;
+                }
+                let attrs = attributes(&payload[IFADDRMSG_LEN..]);
+                let ip = attrs
+                    .clone()
+                    .find(|(kind, _)| *kind == libc::IFA_LOCAL)
+                    .or_else(|| attrs.clone().find(|(kind, _)| *kind == libc::IFA_ADDRESS))
+                    .and_then(|(_, value)| ipv4(value))?;
+
+                Some(Change::Address {
+                    index,
+                    ip,
+                    added: kind == libc::RTM_NEWADDR,
+                })
+            }
+            libc::RTM_NEWROUTE | libc::RTM_DELROUTE if payload.len() >= RTMSG_LEN => {
+                let (family, dst_len, table) = (payload[0], payload[1], payload[4]);
+                let attrs = attributes(&payload[RTMSG_LEN..]);
+                let table = attrs
+                    .clone()
+                    .find(|(kind, _)| *kind == libc::RTA_TABLE)
+                    .and_then(|(_, value)| value.try_into().ok())
+                    .map_or(table as u32, u32::from_ne_bytes);
+                if family != libc::AF_INET as u8
+                    || dst_len != 0
+                    || table != libc::RT_TABLE_MAIN as u32
+                {
+                    return None;
+                }
+                let gateway = attrs
+                    .clone()
+                    .find(|(kind, _)| *kind == libc::RTA_GATEWAY)
+                    .and_then(|(_, value)| ipv4(value));
+                let index = attrs
+                    .clone()
+                    .find(|(kind, _)| *kind == libc::RTA_OIF)
+                    .and_then(|(_, value)| value.try_into().ok())
+                    .map(u32::from_ne_bytes);
+                let added = kind == libc::RTM_NEWROUTE;
+                match added {
+                    true => {
+                        if index.is_some() {
+                            self.uplink = index;
+                        }
+                    }
+                    // Every link is reported again until another default route comes
+                    false => {
+                        if index.is_none() || index == self.uplink {
+                            self.uplink = None;
+                        }
+                    }
+                }
+
+                Some(Change::DefaultRoute {
+                    gateway,
+                    index,
+                    added,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Represents a monitor of network changes in the network namespace it is created in.
+#[derive(Debug)]
+pub struct Monitor {
+    fd: libc::c_int,
+    parser: Parser,
+    buffer: Vec<u8>,
+}
+
+impl Monitor {
+    /// Creates a new `Monitor`, which subscribes to rtnetlink and dumps the links and routes.
+    #[instrument(level = "debug", err(level = "debug"))]
+    pub fn new() -> io::Result<Monitor> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::NETLINK_ROUTE,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut monitor = Monitor {
+            fd,
+            parser: Parser::new(),
+            buffer: vec![0u8; 1 << 16],
+        };
+
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        addr.nl_groups =
+            (libc::RTMGRP_LINK | libc::RTMGRP_IPV4_IFADDR | libc::RTMGRP_IPV4_ROUTE) as u32;
+        let ret = unsafe {
+            libc::bind(
+                fd,
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        monitor.dump(libc::RTM_GETLINK, 0, IFINFOMSG_LEN)?;
+        monitor.dump(libc::RTM_GETROUTE, libc::AF_INET as u8, RTMSG_LEN)?;
+        debug!(
+            links = monitor.parser.links.len(),
+            uplink = ?monitor.parser.uplink,
+            "rtnetlink subscribed"
+        );
+
+        Ok(monitor)
+    }
+
+    /// Dumps the objects of the kind of request, with a header of the length starting with the
+    /// family, recording their states without reporting them.
+    fn dump(&mut self, kind: u16, family: u8, len: usize) -> io::Result<()> {
+        let mut request = vec![0u8; NLMSG_HDRLEN + len];
+        request[0..4].copy_from_slice(&((NLMSG_HDRLEN + len) as u32).to_ne_bytes());
+        request[4..6].copy_from_slice(&kind.to_ne_bytes());
+        request[6..8]
+            .copy_from_slice(&((libc::NLM_F_REQUEST | libc::NLM_F_DUMP) as u16).to_ne_bytes());
+        request[8..12].copy_from_slice(&1u32.to_ne_bytes());
+        request[NLMSG_HDRLEN] = family;
+        let ret = unsafe {
+            libc::send(
+                self.fd,
+                request.as_ptr() as *const libc::c_void,
+                request.len(),
+                0,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        loop {
+            let size = self.recv_raw()?;
+            let buffer = mem::take(&mut self.buffer);
+            let mut done = None;
+            for (kind, payload) in messages(&buffer[..size]) {
+                match kind as libc::c_int {
+                    libc::NLMSG_DONE => done = Some(Ok(())),
+                    libc::NLMSG_ERROR => {
+                        done = Some(Err(io::Error::other("rtnetlink dump failed")))
+                    }
+                    // Changes of the dump, and events interleaved with it, are not reported
+                    _ => {
+                        self.parser.message(kind, payload);
+                    }
+                }
+            }
+            self.buffer = buffer;
+            if let Some(done) = done {
+                return done;
+            }
+        }
+    }
+
+    /// Receives a datagram into the buffer, returning its size.
+    fn recv_raw(&mut self) -> io::Result<usize> {
+        let ret = unsafe {
+            libc::recv(
+                self.fd,
+                self.buffer.as_mut_ptr() as *mut libc::c_void,
+                self.buffer.len(),
+                0,
+            )
+        };
+        match ret < 0 {
+            true => Err(io::Error::last_os_error()),
+            false => Ok(ret as usize),
+        }
+    }
+
+    /// Receives the changes, waiting up to the timeout. Returns no change if the timeout elapses.
+    pub fn recv(&mut self, timeout: Duration) -> io::Result<Vec<Change>> {
+        let mut pollfd = libc::pollfd {
+            fd: self.fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+        let ret = unsafe { libc::poll(&mut pollfd, 1, timeout) };
+        if ret < 0 {
+            let e = io::Error::last_os_error();
+            return match e.kind() {
+                io::ErrorKind::Interrupted => Ok(Vec::new()),
+                _ => Err(e),
+            };
+        }
+        if pollfd.revents & libc::POLLIN == 0 {
+            return Ok(Vec::new());
+        }
+
+        let size = match self.recv_raw() {
+            Ok(size) => size,
+            Err(ref e) if e.raw_os_error() == Some(libc::ENOBUFS) => {
+                debug!("rtnetlink events lost");
+                return Ok(vec![Change::Lost]);
+            }
+            Err(e) => return Err(e),
+        };
+        let changes = self.parser.parse(&self.buffer[..size]);
+
+        Ok(changes)
+    }
+}
+
+impl Drop for Monitor {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+/// Returns the kinds and payloads of the netlink messages in the buffer.
+fn messages(buffer: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    let mut offset = 0;
+    std::iter::from_fn(move || {
+        let header = buffer.get(offset..offset + NLMSG_HDRLEN)?;
+        let len = u32::from_ne_bytes(header[0..4].try_into().unwrap()) as usize;
+        let kind = u16::from_ne_bytes(header[4..6].try_into().unwrap());
+        let payload = buffer.get(offset + NLMSG_HDRLEN..offset + len)?;
+        offset += align(len.max(NLMSG_HDRLEN));
+
+        Some((kind, payload))
+    })
+}
+
+/// Returns the kinds and values of the attributes in the buffer.
+fn attributes(buffer: &[u8]) -> impl Iterator<Item = (u16, &[u8])> + Clone {
+    let mut offset = 0;
+    std::iter::from_fn(move || {
+        let header = buffer.get(offset..offset + RTA_HDRLEN)?;
+        let len = u16::from_ne_bytes(header[0..2].try_into().unwrap()) as usize;
+        let kind = u16::from_ne_bytes(header[2..4].try_into().unwrap());
+        let value = buffer.get(offset + RTA_HDRLEN..offset + len)?;
+        offset += align(len.max(RTA_HDRLEN));
+
+        Some((kind, value))
+    })
+}
+
+/// Returns the length aligned to 4 bytes, as netlink messages and attributes are.
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// Returns the IPv4 address of the value of an attribute.
+fn ipv4(value: &[u8]) -> Option<Ipv4Addr> {
+    let octets: [u8; 4] = value.try_into().ok()?;
+
+    Some(Ipv4Addr::from(octets))
+}
+
+/// Runs the NAT test of the tester once, and again whenever the network changes, until cancelled.
+pub(crate) fn watch<F>(
+    tester: &NatTester,
+    cancel: &CancelToken,
+    debounce: Duration,
+    mut f: F,
+) -> io::Result<()>
+where
+    F: FnMut(&[Change], io::Result<NatTestResult>),
+{
+    let mut monitor = Monitor::new()?;
+    let mut changes: Vec<Change> = Vec::new();
+    let mut last = Instant::now();
+    let mut test = |changes: &[Change]| {
+        let result = match tester.run() {
+            Err(e) if Cancelled::is(&e) => return Err(e),
+            result => result,
+        };
+        match &result {
+            Ok(result) => info!(
+                nat = %result.nat(),
+                ip = ?result.ip(),
+                changes = changes.len(),
+                "NAT tested"
+            ),
+            Err(e) => info!(error = %e, changes = changes.len(), "NAT test failed"),
+        }
+        f(changes, result);
+
+        Ok(())
+    };
+
+    test(&changes)?;
+    loop {
+        cancel.check()?;
+        let received = monitor.recv(CANCEL_INTERVAL)?;
+        if !received.is_empty() {
+            for change in received.iter() {
+                debug!(%change, "network changed");
+            }
+            changes.extend(received);
+            last = Instant::now();
+        }
+        // Changes come in bursts, so the test waits for them to settle
+        if !changes.is_empty() && last.elapsed() >= debounce {
+            test(&changes)?;
+            changes.clear();
+        }
+    }
+}
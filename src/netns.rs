@@ -0,0 +1,115 @@
+//! Network namespaces of Linux.
+//!
+//! A thread entering a namespace with `setns` creates its sockets in the namespace, and the sockets
+//! stay there after the thread exits, so they can be used from any thread. The namespace of a
+//! process is shared by all its threads otherwise, so work in a namespace runs in a thread of its
+//! own.
+
+use std::error;
+use std::fmt::{self, Display};
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::thread;
+use tracing::{debug, instrument};
+
+/// Represents the directory of namespaces named by `ip netns`.
+pub const NETNS_DIR: &str = "/run/netns";
+
+/// Represents the error of a network namespace failing to be opened or entered, carried by an
+/// `io::Error` of the same kind.
+#[derive(Debug)]
+pub struct NetNsError {
+    path: PathBuf,
+    error: io::Error,
+}
+
+impl NetNsError {
+    /// Returns if the error is of a network namespace.
+    pub fn is(e: &io::Error) -> bool {
+        match e.get_ref() {
+            Some(inner) => inner.is::<NetNsError>(),
+            None => false,
+        }
+    }
+
+    /// Wraps the error of the namespace at the path, keeping its kind.
+    fn wrap(path: &Path, e: io::Error) -> io::Error {
+        let kind = e.kind();
+        let error = NetNsError {
+            path: path.to_path_buf(),
+            error: e,
+        };
+
+        io::Error::new(kind, error)
+    }
+}
+
+impl Display for NetNsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "cannot enter network namespace {}: {}",
+            self.path.display(),
+            self.error
+        )
+    }
+}
+
+impl error::Error for NetNsError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// Represents an opened network namespace.
+#[derive(Debug)]
+pub struct NetNs {
+    path: PathBuf,
+    file: File,
+}
+
+impl NetNs {
+    /// Opens the namespace of the name in `/run/netns`, or at the path if it contains a `/`, like
+    /// `/proc/1/ns/net`.
+    #[instrument(level = "debug", err(level = "debug"))]
+    pub fn open(name: &str) -> io::Result<NetNs> {
+        let path = match name.contains('/') {
+            true => PathBuf::from(name),
+            false => Path::new(NETNS_DIR).join(name),
+        };
+        let file = File::open(&path).map_err(|e| NetNsError::wrap(&path, e))?;
+
+        Ok(NetNs { path, file })
+    }
+
+    /// Returns the path of the namespace.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Runs the closure in a new thread in the namespace, returning what it returns. Only errors of
+    /// entering the namespace are `NetNsError`s.
+    pub fn run<T, F>(&self, f: F) -> io::Result<T>
+    where
+        T: Send,
+        F: FnOnce() -> T + Send,
+    {
+        let fd = self.file.as_raw_fd();
+        thread::scope(|scope| {
+            scope
+                .spawn(move || {
+                    let ret = unsafe { libc::setns(fd, libc::CLONE_NEWNET) };
+                    if ret != 0 {
+                        return Err(NetNsError::wrap(&self.path, io::Error::last_os_error()));
+                    }
+                    debug!(path = %self.path.display(), "network namespace entered");
+
+                    Ok(f())
+                })
+                .join()
+                .unwrap_or_else(|e| std::panic::resume_unwind(e))
+        })
+    }
+}
@@ -0,0 +1,52 @@
+//! Observation of tests in progress.
+
+use crate::protocol::{Request, Response};
+use crate::NatTestResult;
+use std::fmt::{self, Display};
+use std::net::SocketAddrV4;
+
+/// Enumeration of phases of a test.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Phase {
+    /// Represents probing the first socket, which decides the mapping and filtering behavior.
+    Mapping,
+    /// Represents probing the other sockets, which decide the port allocation.
+    Allocation,
+    /// Represents waiting for the receiving only port on the socket of the number, from 1, once
+    /// both echoes are answered.
+    Inbound(usize),
+}
+
+impl Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Phase::Mapping => write!(f, "Mapping"),
+            Phase::Allocation => write!(f, "Port allocation"),
+            Phase::Inbound(n) => write!(f, "Inbound on socket {}", n),
+        }
+    }
+}
+
+/// Represents an observer of tests, called as things happen.
+///
+/// Sockets are numbered from 1. Sockets of a NAT test are received from at the same time, so the
+/// observer may be called from several threads. Every method does nothing by default.
+pub trait Observer: Send + Sync {
+    /// Called when a phase starts.
+    fn phase_started(&self, _phase: Phase) {}
+
+    /// Called when a phase finishes, unless the test fails in it.
+    fn phase_finished(&self, _phase: Phase) {}
+
+    /// Called when a probe is sent from the socket.
+    fn probe_sent(&self, _socket: usize, _request: Request, _to: SocketAddrV4) {}
+
+    /// Called when a response from a server is received on the socket.
+    fn response_received(&self, _socket: usize, _from: SocketAddrV4, _response: &Response) {}
+
+    /// Called when a NAT test decides the NAT, before it returns.
+    fn classified(&self, _result: &NatTestResult) {}
+}
+
+/// Represents an observer ignoring every event.
+impl Observer for () {}
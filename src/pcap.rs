@@ -0,0 +1,325 @@
+//! Capture of datagrams into pcap-ng files.
+
+use crate::protocol::{Request, Response};
+use crate::topology;
+use crate::RW;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// Represents the block type of a section header block.
+const BLOCK_TYPE_SHB: u32 = 0x0a0d_0d0a;
+/// Represents the block type of an interface description block.
+const BLOCK_TYPE_IDB: u32 = 0x0000_0001;
+/// Represents the block type of an enhanced packet block.
+const BLOCK_TYPE_EPB: u32 = 0x0000_0006;
+/// Represents the byte-order magic of a section header block.
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+/// Represents the link type of raw IPv4 and IPv6 packets.
+const LINKTYPE_RAW: u16 = 101;
+
+/// Represents the option code of the end of options.
+const OPT_ENDOFOPT: u16 = 0;
+/// Represents the option code of a comment.
+const OPT_COMMENT: u16 = 1;
+/// Represents the option code of the application which wrote the section.
+const SHB_USERAPPL: u16 = 4;
+/// Represents the option code of the name of an interface.
+const IF_NAME: u16 = 2;
+
+/// Represents the TTL of the synthesized IP headers.
+const TTL: u8 = 64;
+/// Represents the protocol number of UDP.
+const IPPROTO_UDP: u8 = 17;
+
+/// Represents a writer of pcap-ng files, writing datagrams with synthesized IPv4 and UDP headers
+/// on a single raw IP interface.
+#[derive(Debug)]
+pub struct PcapWriter<W: Write> {
+    writer: W,
+    id: u16,
+}
+
+impl<W: Write> PcapWriter<W> {
+    /// Creates a new `PcapWriter`, writing the section header and the interface description.
+    pub fn new(writer: W) -> io::Result<PcapWriter<W>> {
+        let mut pcap = PcapWriter { writer, id: 0 };
+
+        // Section header block
+        let mut body = Vec::new();
+        body.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        body.extend_from_slice(&1u16.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        // Section length is not specified
+        body.extend_from_slice(&(-1i64).to_le_bytes());
+        let userappl = format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+        push_option(&mut body, SHB_USERAPPL, userappl.as_bytes());
+        push_option(&mut body, OPT_ENDOFOPT, &[]);
+        pcap.write_block(BLOCK_TYPE_SHB, &body)?;
+
+        // Interface description block
+        let mut body = Vec::new();
+        body.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        // Snap length is not limited
+        body.extend_from_slice(&0u32.to_le_bytes());
+        push_option(&mut body, IF_NAME, env!("CARGO_PKG_NAME").as_bytes());
+        push_option(&mut body, OPT_ENDOFOPT, &[]);
+        pcap.write_block(BLOCK_TYPE_IDB, &body)?;
+
+        Ok(pcap)
+    }
+
+    /// Writes a datagram sent from `src` to `dst` at the given time, with an optional comment.
+    ///
+    /// The writer is not flushed, which is left to `flush`.
+    pub fn write_datagram(
+        &mut self,
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        payload: &[u8],
+        time: SystemTime,
+        comment: Option<&str>,
+    ) -> io::Result<()> {
+        let packet = self.synthesize(src, dst, payload);
+        let ts = time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or(Duration::from_secs(0));
+        let ts = ts.as_micros() as u64;
+
+        // Enhanced packet block
+        let mut body = Vec::new();
+        body.extend_from_slice(&0u32.to_le_bytes());
+        body.extend_from_slice(&((ts >> 32) as u32).to_le_bytes());
+        body.extend_from_slice(&(ts as u32).to_le_bytes());
+        body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        body.extend_from_slice(&packet);
+        pad(&mut body);
+        if let Some(comment) = comment {
+            push_option(&mut body, OPT_COMMENT, comment.as_bytes());
+            push_option(&mut body, OPT_ENDOFOPT, &[]);
+        }
+        self.write_block(BLOCK_TYPE_EPB, &body)
+    }
+
+    /// Flushes the underlying writer.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    /// Returns a reference to the underlying writer.
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    /// Unwraps this `PcapWriter`, returning the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    fn write_block(&mut self, block_type: u32, body: &[u8]) -> io::Result<()> {
+        let len = (body.len() + 12) as u32;
+        let mut block = Vec::with_capacity(len as usize);
+        block.extend_from_slice(&block_type.to_le_bytes());
+        block.extend_from_slice(&len.to_le_bytes());
+        block.extend_from_slice(body);
+        block.extend_from_slice(&len.to_le_bytes());
+
+        self.writer.write_all(&block)
+    }
+
+    /// Synthesizes an IPv4 packet carrying the UDP datagram.
+    fn synthesize(&mut self, src: SocketAddrV4, dst: SocketAddrV4, payload: &[u8]) -> Vec<u8> {
+        let udp_len = (8 + payload.len()) as u16;
+        let total_len = 20 + udp_len;
+        self.id = self.id.wrapping_add(1);
+
+        let mut packet = Vec::with_capacity(total_len as usize);
+        // IPv4 header
+        packet.push(0x45);
+        packet.push(0);
+        packet.extend_from_slice(&total_len.to_be_bytes());
+        packet.extend_from_slice(&self.id.to_be_bytes());
+        packet.extend_from_slice(&0u16.to_be_bytes());
+        packet.push(TTL);
+        packet.push(IPPROTO_UDP);
+        packet.extend_from_slice(&0u16.to_be_bytes());
+        packet.extend_from_slice(&src.ip().octets());
+        packet.extend_from_slice(&dst.ip().octets());
+        let ip_checksum = checksum(&[&packet]);
+        packet[10..12].copy_from_slice(&ip_checksum.to_be_bytes());
+
+        // UDP header
+        let mut udp = Vec::with_capacity(udp_len as usize);
+        udp.extend_from_slice(&src.port().to_be_bytes());
+        udp.extend_from_slice(&dst.port().to_be_bytes());
+        udp.extend_from_slice(&udp_len.to_be_bytes());
+        udp.extend_from_slice(&0u16.to_be_bytes());
+        udp.extend_from_slice(payload);
+        let mut pseudo = Vec::with_capacity(12);
+        pseudo.extend_from_slice(&src.ip().octets());
+        pseudo.extend_from_slice(&dst.ip().octets());
+        pseudo.push(0);
+        pseudo.push(IPPROTO_UDP);
+        pseudo.extend_from_slice(&udp_len.to_be_bytes());
+        let udp_checksum = match checksum(&[&pseudo, &udp]) {
+            0 => 0xffff,
+            udp_checksum => udp_checksum,
+        };
+        udp[6..8].copy_from_slice(&udp_checksum.to_be_bytes());
+
+        packet.extend_from_slice(&udp);
+
+        packet
+    }
+}
+
+/// Pads the buffer to 32 bits.
+fn pad(buf: &mut Vec<u8>) {
+    while buf.len() % 4 != 0 {
+        buf.push(0);
+    }
+}
+
+/// Pushes an option into the buffer.
+fn push_option(buf: &mut Vec<u8>, code: u16, value: &[u8]) {
+    buf.extend_from_slice(&code.to_le_bytes());
+    buf.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    buf.extend_from_slice(value);
+    pad(buf);
+}
+
+/// Returns the Internet checksum of the concatenated data.
+fn checksum(data: &[&[u8]]) -> u16 {
+    let mut sum = 0u32;
+    let mut odd = None;
+    for b in data.iter().flat_map(|data| data.iter()) {
+        match odd.take() {
+            Some(high) => sum += u16::from_be_bytes([high, *b]) as u32,
+            None => odd = Some(*b),
+        }
+    }
+    if let Some(high) = odd {
+        sum += u16::from_be_bytes([high, 0]) as u32;
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    !(sum as u16)
+}
+
+/// Represents a socket which records every datagram sent and received into a pcap-ng file.
+///
+/// Datagrams failing to be recorded are logged, and sent or received all the same. The writer is
+/// not flushed, so it should be buffered and flushed once the capture ends.
+pub struct Capture<W: Write + Send> {
+    rw: Box<dyn RW>,
+    label: String,
+    writer: Arc<Mutex<PcapWriter<W>>>,
+    locals: Mutex<HashMap<Ipv4Addr, SocketAddrV4>>,
+}
+
+impl<W: Write + Send> Capture<W> {
+    /// Creates a new `Capture`. The label names the socket in the comments of the packets, and a
+    /// writer can be shared by multiple sockets.
+    pub fn new(rw: Box<dyn RW>, label: &str, writer: Arc<Mutex<PcapWriter<W>>>) -> Capture<W> {
+        Capture {
+            rw,
+            label: label.to_string(),
+            writer,
+            locals: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the local address of the socket to the remote address. A socket bound to any
+    /// address is given the local IP address the routing table chooses for the remote address,
+    /// which is looked up once per remote IP address.
+    fn local_to(&self, remote: SocketAddrV4) -> SocketAddrV4 {
+        let mut locals = match self.locals.lock() {
+            Ok(locals) => locals,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if let Some(local) = locals.get(remote.ip()) {
+            return *local;
+        }
+
+        let local = self
+            .rw
+            .local_addr()
+            .unwrap_or(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
+        let local = match local.ip().is_unspecified() {
+            true => match topology::local_ip_to(*remote.ip()) {
+                Ok(ip) => SocketAddrV4::new(ip, local.port()),
+                Err(_) => local,
+            },
+            false => local,
+        };
+        locals.insert(*remote.ip(), local);
+
+        local
+    }
+
+    fn record(&self, src: SocketAddrV4, dst: SocketAddrV4, buf: &[u8], comment: &str) {
+        let comment = format!("{}, {}", self.label, comment);
+        let result = match self.writer.lock() {
+            Ok(mut writer) => {
+                writer.write_datagram(src, dst, buf, SystemTime::now(), Some(comment.as_str()))
+            }
+            Err(_) => Err(io::Error::other("pcap writer poisoned")),
+        };
+        if let Err(e) = result {
+            warn!(error = %e, %src, %dst, "datagram not captured");
+        }
+    }
+}
+
+impl<W: Write + Send> RW for Capture<W> {
+    fn local_addr(&self) -> io::Result<SocketAddrV4> {
+        self.rw.local_addr()
+    }
+
+    fn send_to(&self, buf: &[u8], addr: SocketAddrV4) -> io::Result<usize> {
+        let size = self.rw.send_to(buf, addr)?;
+
+        let comment = match Request::decode(buf) {
+            Ok(request) => request.to_string(),
+            Err(_) => "unknown".to_string(),
+        };
+        self.record(self.local_to(addr), addr, buf, comment.as_str());
+
+        Ok(size)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddrV4)> {
+        let (size, addr) = self.rw.recv_from(buf)?;
+
+        let comment = match Response::decode(&buf[..size]) {
+            Ok(resp) => format!("response to {}", resp.request()),
+            Err(_) => "unknown".to_string(),
+        };
+        self.record(addr, self.local_to(addr), &buf[..size], comment.as_str());
+
+        Ok((size, addr))
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.rw.set_read_timeout(dur)
+    }
+
+    fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.rw.set_write_timeout(dur)
+    }
+
+    fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        self.rw.read_timeout()
+    }
+
+    fn write_timeout(&self) -> io::Result<Option<Duration>> {
+        self.rw.write_timeout()
+    }
+}
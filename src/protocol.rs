@@ -0,0 +1,419 @@
+//! Packets of the Nintendo NAT test service.
+//!
+//! Every packet is 16 bytes long. A request carries its unique number in the fourth byte and zeros
+//! elsewhere. A response is laid out as follows, in network byte order:
+//!
+//! | Bytes  | Field         | Semantics                                                  |
+//! | ------ | ------------- | ---------------------------------------------------------- |
+//! | 0..3   | Head          | Echoed head of the request, always zeros                   |
+//! | 3      | Unique number | Echoed unique number of the request                        |
+//! | 4..6   | Reserved      | Always zeros                                               |
+//! | 6..8   | Port          | Source port of the request as seen by the server           |
+//! | 8..12  | Remote IP     | Source IP address of the request as seen by the server     |
+//! | 12..16 | Local IP      | Local IP address reported by the server                    |
+
+use std::convert::TryFrom;
+use std::error;
+use std::fmt::{self, Display};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+/// Represents the length of a packet.
+pub const PACKET_LEN: usize = 16;
+
+/// Represents the payload for sending only.
+pub const PAYLOAD_1: [u8; PACKET_LEN] = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+/// Represents the payload for an echoing back.
+pub const PAYLOAD_2: [u8; PACKET_LEN] = [0, 0, 0, 0x65, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+/// Represents the payload for receiving from another port.
+pub const PAYLOAD_3: [u8; PACKET_LEN] = [0, 0, 0, 0x66, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+/// Represents the payload for an echoing back.
+pub const PAYLOAD_4: [u8; PACKET_LEN] = [0, 0, 0, 0x67, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+/// Represents the port for sending to only.
+pub const PORT_1: u16 = 33334;
+/// Represents the port for sending to and receiving from.
+pub const PORT_2: u16 = 10025;
+/// Represents the port for receiving from only.
+pub const PORT_3: u16 = 50920;
+
+/// Represents the hostname of the server 1.
+pub const SERVER_1: &str = "nncs1-lp1.n.n.srv.nintendo.net";
+/// Represents the hostname of the server 2.
+pub const SERVER_2: &str = "nncs2-lp1.n.n.srv.nintendo.net";
+
+/// Represents the ports of the servers.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct Ports {
+    send_only: u16,
+    echo: u16,
+    inbound: u16,
+}
+
+impl Ports {
+    /// Creates a new `Ports`.
+    pub fn new(send_only: u16, echo: u16, inbound: u16) -> Ports {
+        Ports {
+            send_only,
+            echo,
+            inbound,
+        }
+    }
+
+    /// Returns the port for sending to only.
+    pub fn send_only(&self) -> u16 {
+        self.send_only
+    }
+
+    /// Returns the port for sending to and receiving from.
+    pub fn echo(&self) -> u16 {
+        self.echo
+    }
+
+    /// Returns the port for receiving from only.
+    pub fn inbound(&self) -> u16 {
+        self.inbound
+    }
+}
+
+impl Default for Ports {
+    fn default() -> Self {
+        Ports::new(PORT_1, PORT_2, PORT_3)
+    }
+}
+
+/// Enumeration of errors decoding or validating a packet.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// Represents a packet which is not `PACKET_LEN` bytes long.
+    Length(usize),
+    /// Represents an unknown unique number.
+    UniqueNumber(u8),
+    /// Represents a request which is never answered.
+    Unanswered(Request),
+    /// Represents a head which is not zeros.
+    Head([u8; 3]),
+    /// Represents a reserved field which is not zeros.
+    Reserved([u8; 2]),
+    /// Represents a remote address with an unspecified IP address or a zero port.
+    RemoteAddr(SocketAddrV4),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Length(len) => write!(f, "invalid packet length {}", len),
+            Error::UniqueNumber(n) => write!(f, "unknown unique number {:#04x}", n),
+            Error::Unanswered(request) => write!(f, "request {:?} is never answered", request),
+            Error::Head(head) => write!(f, "invalid head {:02x?}", head),
+            Error::Reserved(reserved) => write!(f, "invalid reserved field {:02x?}", reserved),
+            Error::RemoteAddr(addr) => write!(f, "invalid remote address {}", addr),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<Error> for io::Error {
+    fn from(s: Error) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, s)
+    }
+}
+
+/// Represents the requests in the order they are sent.
+pub const REQUESTS: [Request; 4] = [
+    Request::SendOnly,
+    Request::Echo,
+    Request::AnotherPort,
+    Request::SecondEcho,
+];
+
+/// Enumeration of requests.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Request {
+    /// Represents the payload 1, sent to the send-only port of the server 1 and never answered.
+    SendOnly,
+    /// Represents the payload 2, sent to the echo port of the server 1 and answered from the same
+    /// port.
+    Echo,
+    /// Represents the payload 3, sent to the echo port of the server 1 and answered from the
+    /// receiving only port.
+    AnotherPort,
+    /// Represents the payload 4, sent to the echo port of the server 2 and answered from the same
+    /// port.
+    SecondEcho,
+}
+
+impl Request {
+    /// Returns the unique number of the request.
+    pub fn unique_number(&self) -> u8 {
+        self.payload()[3]
+    }
+
+    /// Returns the payload of the request.
+    pub fn payload(&self) -> &'static [u8; PACKET_LEN] {
+        match self {
+            Request::SendOnly => &PAYLOAD_1,
+            Request::Echo => &PAYLOAD_2,
+            Request::AnotherPort => &PAYLOAD_3,
+            Request::SecondEcho => &PAYLOAD_4,
+        }
+    }
+
+    /// Returns the index, from 1, of the server the request is sent to.
+    pub fn server(&self) -> usize {
+        match self {
+            Request::SecondEcho => 2,
+            _ => 1,
+        }
+    }
+
+    /// Returns the port of the server the request is sent to, out of the ports of the servers.
+    pub fn port(&self, ports: &Ports) -> u16 {
+        match self {
+            Request::SendOnly => ports.send_only(),
+            _ => ports.echo(),
+        }
+    }
+
+    /// Returns the port of the server the response comes from, out of the ports of the servers,
+    /// or `None` if the request is never answered.
+    pub fn reply_port(&self, ports: &Ports) -> Option<u16> {
+        match self {
+            Request::SendOnly => None,
+            Request::Echo | Request::SecondEcho => Some(ports.echo()),
+            Request::AnotherPort => Some(ports.inbound()),
+        }
+    }
+
+    /// Returns the request of the unique number.
+    pub fn from_unique_number(n: u8) -> Result<Request, Error> {
+        match n {
+            0 => Ok(Request::SendOnly),
+            0x65 => Ok(Request::Echo),
+            0x66 => Ok(Request::AnotherPort),
+            0x67 => Ok(Request::SecondEcho),
+            _ => Err(Error::UniqueNumber(n)),
+        }
+    }
+
+    /// Encodes the request into a packet.
+    pub fn encode(&self) -> [u8; PACKET_LEN] {
+        *self.payload()
+    }
+
+    /// Decodes a request from a packet. Only the length and the unique number are checked.
+    pub fn decode(buf: &[u8]) -> Result<Request, Error> {
+        match buf.len() {
+            PACKET_LEN => Request::from_unique_number(buf[3]),
+            len => Err(Error::Length(len)),
+        }
+    }
+}
+
+impl Display for Request {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Request::SendOnly => write!(f, "payload 1"),
+            Request::Echo => write!(f, "payload 2"),
+            Request::AnotherPort => write!(f, "payload 3"),
+            Request::SecondEcho => write!(f, "payload 4"),
+        }
+    }
+}
+
+impl TryFrom<&[u8]> for Request {
+    type Error = Error;
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        Request::decode(value)
+    }
+}
+
+/// Represents the fields of a response.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct Body {
+    head: [u8; 3],
+    reserved: [u8; 2],
+    port: u16,
+    remote_ip: Ipv4Addr,
+    local_ip: Ipv4Addr,
+}
+
+impl Body {
+    /// Creates a new `Body` with zero head and reserved field.
+    pub fn new(remote: SocketAddrV4, local_ip: Ipv4Addr) -> Body {
+        Body {
+            head: [0; 3],
+            reserved: [0; 2],
+            port: remote.port(),
+            remote_ip: *remote.ip(),
+            local_ip,
+        }
+    }
+
+    /// Returns the echoed head of the request, which should be zeros.
+    pub fn head(&self) -> [u8; 3] {
+        self.head
+    }
+
+    /// Returns the reserved field, which should be zeros.
+    pub fn reserved(&self) -> [u8; 2] {
+        self.reserved
+    }
+
+    /// Returns the source port of the request as seen by the server.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Returns the source IP address of the request as seen by the server.
+    pub fn remote_ip(&self) -> Ipv4Addr {
+        self.remote_ip
+    }
+
+    /// Returns the local IP address reported by the server.
+    pub fn local_ip(&self) -> Ipv4Addr {
+        self.local_ip
+    }
+
+    /// Returns the source address of the request as seen by the server.
+    pub fn remote_addr(&self) -> SocketAddrV4 {
+        SocketAddrV4::new(self.remote_ip, self.port)
+    }
+
+    /// Validates the fields.
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.head != [0; 3] {
+            return Err(Error::Head(self.head));
+        }
+        if self.reserved != [0; 2] {
+            return Err(Error::Reserved(self.reserved));
+        }
+        if self.port == 0 || self.remote_ip.is_unspecified() {
+            return Err(Error::RemoteAddr(self.remote_addr()));
+        }
+
+        Ok(())
+    }
+}
+
+/// Enumeration of responses.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Response {
+    /// Represents the response to a `Request::Echo`.
+    Echo(Body),
+    /// Represents the response to a `Request::AnotherPort`.
+    AnotherPort(Body),
+    /// Represents the response to a `Request::SecondEcho`.
+    SecondEcho(Body),
+}
+
+impl Response {
+    /// Creates a new `Response` to the request.
+    pub fn new(request: Request, body: Body) -> Result<Response, Error> {
+        match request {
+            Request::SendOnly => Err(Error::Unanswered(request)),
+            Request::Echo => Ok(Response::Echo(body)),
+            Request::AnotherPort => Ok(Response::AnotherPort(body)),
+            Request::SecondEcho => Ok(Response::SecondEcho(body)),
+        }
+    }
+
+    /// Returns the request the response replies to.
+    pub fn request(&self) -> Request {
+        match self {
+            Response::Echo(_) => Request::Echo,
+            Response::AnotherPort(_) => Request::AnotherPort,
+            Response::SecondEcho(_) => Request::SecondEcho,
+        }
+    }
+
+    /// Returns the fields of the response.
+    pub fn body(&self) -> &Body {
+        match self {
+            Response::Echo(body) | Response::AnotherPort(body) | Response::SecondEcho(body) => body,
+        }
+    }
+
+    /// Returns the unique number of the response.
+    pub fn unique_number(&self) -> u8 {
+        self.request().unique_number()
+    }
+
+    /// Returns the source port of the request as seen by the server.
+    pub fn port(&self) -> u16 {
+        self.body().port()
+    }
+
+    /// Returns the source IP address of the request as seen by the server.
+    pub fn remote_ip(&self) -> Ipv4Addr {
+        self.body().remote_ip()
+    }
+
+    /// Returns the local IP address reported by the server.
+    pub fn local_ip(&self) -> Ipv4Addr {
+        self.body().local_ip()
+    }
+
+    /// Returns the source address of the request as seen by the server.
+    pub fn remote_addr(&self) -> SocketAddrV4 {
+        self.body().remote_addr()
+    }
+
+    /// Returns if the response is well-formed and replied to the given request.
+    ///
+    /// The server echoes only the unique number of the request, so a response cannot be told
+    /// apart from another one replied to the same request. It should be bound to a probe by the
+    /// socket it arrives at, the address it comes from and the time window of the test instead.
+    pub fn is_reply_to(&self, request: Request) -> bool {
+        self.request() == request && self.body().validate().is_ok()
+    }
+
+    /// Encodes the response into a packet.
+    pub fn encode(&self) -> [u8; PACKET_LEN] {
+        let body = self.body();
+
+        let mut buf = [0u8; PACKET_LEN];
+        buf[..3].copy_from_slice(&body.head);
+        buf[3] = self.unique_number();
+        buf[4..6].copy_from_slice(&body.reserved);
+        buf[6..8].copy_from_slice(&body.port.to_be_bytes());
+        buf[8..12].copy_from_slice(&body.remote_ip.octets());
+        buf[12..].copy_from_slice(&body.local_ip.octets());
+
+        buf
+    }
+
+    /// Decodes a response from a packet. The fields are not validated.
+    pub fn decode(buf: &[u8]) -> Result<Response, Error> {
+        if buf.len() != PACKET_LEN {
+            return Err(Error::Length(buf.len()));
+        }
+
+        let request = Request::from_unique_number(buf[3])?;
+        let body = Body {
+            head: [buf[0], buf[1], buf[2]],
+            reserved: [buf[4], buf[5]],
+            port: u16::from_be_bytes([buf[6], buf[7]]),
+            remote_ip: Ipv4Addr::new(buf[8], buf[9], buf[10], buf[11]),
+            local_ip: Ipv4Addr::new(buf[12], buf[13], buf[14], buf[15]),
+        };
+
+        Response::new(request, body)
+    }
+}
+
+impl TryFrom<&[u8]> for Response {
+    type Error = Error;
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        Response::decode(value)
+    }
+}
+
+impl Display for Response {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.unique_number(), self.remote_addr())
+    }
+}
@@ -0,0 +1,141 @@
+//! Servers of the protocol, standing in for the Nintendo service in test environments.
+//!
+//! The server 1 listens on the send-only and echo ports, and answers the request from another port
+//! through its receiving only port. The server 2 listens on its echo port. Servers report the
+//! remote address a request comes from as both the remote and the local address, since they cannot
+//! see the address of the host behind a NAT.
+
+use crate::protocol::{Body, Ports, Request, Response};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tracing::{debug, instrument, trace};
+
+/// Represents the longest time a server takes to notice it is stopped.
+const STOP_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Represents a running pair of servers, which stop when dropped.
+///
+/// # Examples
+///
+/// ```no_run
+/// use ninat::protocol::Ports;
+/// use ninat::server::Server;
+/// use ninat::tester::NatTester;
+/// use std::net::Ipv4Addr;
+///
+/// let server1 = Ipv4Addr::new(127, 0, 0, 2);
+/// let server2 = Ipv4Addr::new(127, 0, 0, 3);
+/// let server = Server::bind(server1, server2, Ports::default()).unwrap();
+///
+/// let result = NatTester::new().servers(server1, server2).run().unwrap();
+/// println!("{}", result.nat());
+/// ```
+#[derive(Debug)]
+pub struct Server {
+    server1: Ipv4Addr,
+    server2: Ipv4Addr,
+    ports: Ports,
+    stop: Arc<AtomicBool>,
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl Server {
+    /// Creates a new `Server` serving both servers on the addresses with the ports.
+    #[instrument(level = "debug", err(level = "debug"))]
+    pub fn bind(server1: Ipv4Addr, server2: Ipv4Addr, ports: Ports) -> io::Result<Server> {
+        let send_only = bind(SocketAddrV4::new(server1, ports.send_only()))?;
+        let echo1 = bind(SocketAddrV4::new(server1, ports.echo()))?;
+        let inbound = bind(SocketAddrV4::new(server1, ports.inbound()))?;
+        let echo2 = bind(SocketAddrV4::new(server2, ports.echo()))?;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let handles = vec![
+            spawn(send_only, None, stop.clone()),
+            spawn(echo1, Some(inbound), stop.clone()),
+            spawn(echo2, None, stop.clone()),
+        ];
+        debug!(%server1, %server2, "server started");
+
+        Ok(Server {
+            server1,
+            server2,
+            ports,
+            stop,
+            handles,
+        })
+    }
+
+    /// Returns the IP address of the server 1.
+    pub fn server1(&self) -> Ipv4Addr {
+        self.server1
+    }
+
+    /// Returns the IP address of the server 2.
+    pub fn server2(&self) -> Ipv4Addr {
+        self.server2
+    }
+
+    /// Returns the ports of the servers.
+    pub fn ports(&self) -> Ports {
+        self.ports
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        for handle in self.handles.drain(..) {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Binds a socket of a server, which wakes up from time to time to check if it is stopped.
+fn bind(addr: SocketAddrV4) -> io::Result<UdpSocket> {
+    let socket = UdpSocket::bind(addr)?;
+    socket.set_read_timeout(Some(STOP_INTERVAL))?;
+
+    Ok(socket)
+}
+
+/// Serves the port of the socket until stopped, replying to the request from another port through
+/// `inbound` if any. Requests never answered, like those to the send-only port, are only read.
+fn spawn(socket: UdpSocket, inbound: Option<UdpSocket>, stop: Arc<AtomicBool>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let mut buffer = [0u8; 64];
+        while !stop.load(Ordering::SeqCst) {
+            let (size, addr) = match socket.recv_from(&mut buffer) {
+                Ok((size, SocketAddr::V4(addr))) => (size, addr),
+                Ok(_) => continue,
+                Err(ref e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut =>
+                {
+                    continue
+                }
+                Err(e) => {
+                    debug!(error = %e, "server stopped");
+                    return;
+                }
+            };
+            let request = match Request::decode(&buffer[..size]) {
+                Ok(request) => request,
+                Err(_) => continue,
+            };
+            trace!(%request, from = %addr, "request received");
+            let response = match Response::new(request, Body::new(addr, *addr.ip())) {
+                Ok(response) => response.encode(),
+                Err(_) => continue,
+            };
+            let replier = match (request, &inbound) {
+                (Request::AnotherPort, Some(inbound)) => inbound,
+                _ => &socket,
+            };
+            let _ = replier.send_to(&response, addr);
+        }
+    })
+}
@@ -0,0 +1,223 @@
+//! Tests of the port of a running application.
+//!
+//! A probe socket shares the local port of the application, either bound with `SO_REUSEPORT` or
+//! passed as a file descriptor, so the probes leave through the same mapping as the traffic of the
+//! application. With `SO_REUSEPORT`, the application must have set the option on its own socket
+//! too. The kernel spreads datagrams among the sockets of a port, except those matching a
+//! connected socket, so the probe socket connects a socket to every address answers come from.
+
+use crate::behavior::{FilteringBehavior, MappingBehavior};
+use crate::protocol::Ports;
+use crate::{Discarded, Socket, RW};
+use std::convert::TryFrom;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::os::unix::io::AsRawFd;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::instrument;
+
+/// Represents sockets sharing a port with `SO_REUSEPORT`, each connected to an address answers
+/// come from, so the answers are not delivered to other sockets of the port.
+#[derive(Debug)]
+pub struct SharedSocket {
+    local: SocketAddrV4,
+    sockets: Vec<(SocketAddrV4, UdpSocket)>,
+    read_timeout: Mutex<Option<Duration>>,
+}
+
+impl SharedSocket {
+    /// Creates a new `SharedSocket` on the address, connected to each of the peers.
+    pub fn bind(addr: SocketAddrV4, peers: &[SocketAddrV4]) -> io::Result<SharedSocket> {
+        if peers.is_empty() {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        let mut sockets = Vec::new();
+        for peer in peers {
+            let socket = Socket::bind_reuse_port(addr)?.into_std();
+            socket.connect(peer)?;
+            sockets.push((*peer, socket));
+        }
+        let local = RW::local_addr(&sockets[0].1)?;
+
+        Ok(SharedSocket {
+            local,
+            sockets,
+            read_timeout: Mutex::new(None),
+        })
+    }
+
+    /// Creates a new `SharedSocket` on the port for a test against the servers.
+    pub fn bind_for_test(
+        port: u16,
+        server1: Ipv4Addr,
+        server2: Ipv4Addr,
+    ) -> io::Result<SharedSocket> {
+        let ports = Ports::default();
+        let peers = [
+            SocketAddrV4::new(server1, ports.echo()),
+            SocketAddrV4::new(server1, ports.inbound()),
+            SocketAddrV4::new(server2, ports.echo()),
+        ];
+
+        SharedSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port), &peers)
+    }
+}
+
+impl RW for SharedSocket {
+    fn local_addr(&self) -> io::Result<SocketAddrV4> {
+        Ok(self.local)
+    }
+
+    fn send_to(&self, buf: &[u8], addr: SocketAddrV4) -> io::Result<usize> {
+        match self.sockets.iter().find(|(peer, _)| *peer == addr) {
+            Some((_, socket)) => socket.send(buf),
+            // A connected socket can still send to other addresses
+            None => self.sockets[0].1.send_to(buf, addr),
+        }
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddrV4)> {
+        let timeout = *self.read_timeout.lock().unwrap();
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut fds: Vec<_> = self
+            .sockets
+            .iter()
+            .map(|(_, socket)| libc::pollfd {
+                fd: socket.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect();
+        loop {
+            let timeout = match deadline {
+                Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                    Some(timeout) => i32::try_from(timeout.as_millis()).unwrap_or(i32::MAX),
+                    None => 0,
+                },
+                None => -1,
+            };
+            let ret = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) };
+            match ret {
+                0 => return Err(io::Error::from(io::ErrorKind::WouldBlock)),
+                n if n < 0 => {
+                    let e = io::Error::last_os_error();
+                    match e.kind() {
+                        io::ErrorKind::Interrupted => continue,
+                        _ => return Err(e),
+                    }
+                }
+                _ => {}
+            }
+            for (fd, (_, socket)) in fds.iter().zip(self.sockets.iter()) {
+                if fd.revents == 0 {
+                    continue;
+                }
+                return match socket.recv_from(buf) {
+                    Ok((size, SocketAddr::V4(addr))) => Ok((size, addr)),
+                    Ok((_, SocketAddr::V6(_))) => Err(io::Error::from(io::ErrorKind::InvalidData)),
+                    // A connected socket reports an unreachable peer, which other sockets ignore
+                    Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => break,
+                    Err(e) => Err(e),
+                };
+            }
+        }
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        if dur == Some(Duration::ZERO) {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        *self.read_timeout.lock().unwrap() = dur;
+
+        Ok(())
+    }
+
+    fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        for (_, socket) in self.sockets.iter() {
+            socket.set_write_timeout(dur)?;
+        }
+
+        Ok(())
+    }
+
+    fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(*self.read_timeout.lock().unwrap())
+    }
+
+    fn write_timeout(&self) -> io::Result<Option<Duration>> {
+        self.sockets[0].1.write_timeout()
+    }
+}
+
+/// Represents the mapping of the port of an application.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PortMapping {
+    local: SocketAddrV4,
+    mapped: SocketAddrV4,
+    mapping: MappingBehavior,
+    filtering: FilteringBehavior,
+    discarded: Discarded,
+}
+
+impl PortMapping {
+    /// Returns the local address of the port.
+    pub fn local(&self) -> SocketAddrV4 {
+        self.local
+    }
+
+    /// Returns the external address of the port seen by the server 1.
+    pub fn mapped(&self) -> SocketAddrV4 {
+        self.mapped
+    }
+
+    /// Returns if the NAT preserves the local port.
+    pub fn is_port_preserved(&self) -> bool {
+        self.mapped.port() == self.local.port()
+    }
+
+    /// Returns the mapping behavior of the port.
+    pub fn mapping(&self) -> MappingBehavior {
+        self.mapping
+    }
+
+    /// Returns the filtering behavior of the port.
+    pub fn filtering(&self) -> FilteringBehavior {
+        self.filtering
+    }
+
+    /// Returns the packets discarded during the test.
+    pub fn discarded(&self) -> Discarded {
+        self.discarded
+    }
+}
+
+/// Performs a test from a socket sharing the port of an application.
+///
+/// The read timeout of the socket is used as the deadline of the test.
+#[instrument(level = "info", skip(rw))]
+pub fn test<R: RW + ?Sized>(
+    rw: &R,
+    server1: Ipv4Addr,
+    server2: Ipv4Addr,
+) -> io::Result<PortMapping> {
+    let result = crate::test(rw, server1, server2)?;
+
+    let mapping = match result.remote1() == result.remote2() {
+        true => MappingBehavior::EndpointIndependent,
+        false => MappingBehavior::AddressDependent,
+    };
+    let filtering = match result.is_a() {
+        // The reply from another port comes from the same IP address, which proves no more
+        true => FilteringBehavior::AddressDependent,
+        false => FilteringBehavior::AddressAndPortDependent,
+    };
+
+    Ok(PortMapping {
+        local: rw.local_addr()?,
+        mapped: result.remote1(),
+        mapping,
+        filtering,
+        discarded: result.discarded(),
+    })
+}
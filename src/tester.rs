@@ -0,0 +1,431 @@
+//! Builder of NAT tests.
+
+use crate::cancel::{CancelToken, Cancelled};
+use crate::consensus::{Consensus, Run};
+use crate::forward::{verify_on, Verification};
+#[cfg(target_os = "linux")]
+use crate::monitor::{self, Change};
+#[cfg(target_os = "linux")]
+use crate::netns::NetNs;
+use crate::observer::Observer;
+use crate::protocol::{Ports, SERVER_1, SERVER_2};
+use crate::{
+    hairpin_on, lookup_host_v4, nat_test_lazy, nat_test_on, Context, Datagram, Endpoints,
+    NatTestResult, NatType, Socket, SocketFactory, RW,
+};
+use std::fmt::{self, Display};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, instrument};
+
+/// Represents the default timeout of a NAT test.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_millis(3000);
+
+/// Enumeration of hosts of servers.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum Host {
+    /// Represents a host of the IP address.
+    Ip(Ipv4Addr),
+    /// Represents a host of the hostname, resolved when the test runs.
+    Name(String),
+}
+
+impl Host {
+    /// Resolves the IP address of the host.
+    pub fn resolve(&self) -> io::Result<Ipv4Addr> {
+        match self {
+            Host::Ip(ip) => Ok(*ip),
+            Host::Name(name) => match name.parse() {
+                Ok(ip) => Ok(ip),
+                Err(_) => lookup_host_v4(name),
+            },
+        }
+    }
+}
+
+impl Display for Host {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Host::Ip(ip) => write!(f, "{}", ip),
+            Host::Name(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+impl From<Ipv4Addr> for Host {
+    fn from(s: Ipv4Addr) -> Self {
+        Host::Ip(s)
+    }
+}
+
+impl From<&str> for Host {
+    fn from(s: &str) -> Self {
+        Host::Name(s.to_string())
+    }
+}
+
+impl From<String> for Host {
+    fn from(s: String) -> Self {
+        Host::Name(s)
+    }
+}
+
+/// Represents a builder of NAT tests.
+///
+/// # Examples
+///
+/// ```no_run
+/// use ninat::tester::NatTester;
+/// use std::time::Duration;
+///
+/// let result = NatTester::new()
+///     .timeout(Some(Duration::from_secs(2)))
+///     .retries(1)
+///     .run()?;
+/// println!("{}", result.nat());
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Clone, Eq, PartialEq)]
+pub struct NatTester {
+    server1: Host,
+    server2: Host,
+    ports: Ports,
+    proxy: Option<SocketAddrV4>,
+    auth: Option<(String, String)>,
+    bind: SocketAddrV4,
+    timeout: Option<Duration>,
+    retries: usize,
+    retry_interval: Duration,
+    sockets: usize,
+    cancel: CancelToken,
+    observer: Option<SharedObserver>,
+}
+
+impl NatTester {
+    /// Creates a new `NatTester` against the Nintendo servers with two sockets.
+    pub fn new() -> NatTester {
+        NatTester {
+            server1: Host::from(SERVER_1),
+            server2: Host::from(SERVER_2),
+            ports: Ports::default(),
+            proxy: None,
+            auth: None,
+            bind: SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0),
+            timeout: Some(DEFAULT_TIMEOUT),
+            retries: 0,
+            retry_interval: Duration::from_secs(1),
+            sockets: 2,
+            cancel: CancelToken::new(),
+            observer: None,
+        }
+    }
+
+    /// Sets the servers, by hostname or IP address.
+    pub fn servers<H1: Into<Host>, H2: Into<Host>>(self, server1: H1, server2: H2) -> NatTester {
+        NatTester {
+            server1: server1.into(),
+            server2: server2.into(),
+            ..self
+        }
+    }
+
+    /// Sets the ports of the servers.
+    pub fn ports(self, ports: Ports) -> NatTester {
+        NatTester { ports, ..self }
+    }
+
+    /// Sets the SOCKS proxy the sockets bind through.
+    pub fn proxy(self, proxy: SocketAddrV4) -> NatTester {
+        NatTester {
+            proxy: Some(proxy),
+            ..self
+        }
+    }
+
+    /// Sets the username and password of the SOCKS proxy.
+    pub fn proxy_auth(self, username: &str, password: &str) -> NatTester {
+        NatTester {
+            auth: Some((username.to_string(), password.to_string())),
+            ..self
+        }
+    }
+
+    /// Sets the local address the sockets bind to. Its port should be 0 with more than one socket.
+    pub fn bind(self, bind: SocketAddrV4) -> NatTester {
+        NatTester { bind, ..self }
+    }
+
+    /// Sets the deadline of the whole test, or `None` to wait for responses forever.
+    pub fn timeout(self, timeout: Option<Duration>) -> NatTester {
+        NatTester { timeout, ..self }
+    }
+
+    /// Sets the number of times to rerun the test while it fails with NAT type F.
+    pub fn retries(self, retries: usize) -> NatTester {
+        NatTester { retries, ..self }
+    }
+
+    /// Sets the interval before rerunning a test.
+    pub fn retry_interval(self, retry_interval: Duration) -> NatTester {
+        NatTester {
+            retry_interval,
+            ..self
+        }
+    }
+
+    /// Sets the number of sockets, at least 2, since the port allocation of a mapping dependent on
+    /// the destination needs two sockets at least. Sockets after the first one are only created
+    /// for such a mapping.
+    pub fn sockets(self, sockets: usize) -> NatTester {
+        NatTester { sockets, ..self }
+    }
+
+    /// Sets the token cancelling the tests, which then return a `Cancelled` error.
+    pub fn cancel_token(self, cancel: CancelToken) -> NatTester {
+        NatTester { cancel, ..self }
+    }
+
+    /// Sets the observer the tests report their progress to.
+    pub fn observer(self, observer: Arc<dyn Observer>) -> NatTester {
+        NatTester {
+            observer: Some(SharedObserver(observer)),
+            ..self
+        }
+    }
+
+    /// Resolves the IP addresses of the servers.
+    pub fn resolve(&self) -> io::Result<(Ipv4Addr, Ipv4Addr)> {
+        Ok((self.server1.resolve()?, self.server2.resolve()?))
+    }
+
+    /// Binds a new socket, through the proxy if any, with the timeout as the read timeout.
+    pub fn bind_socket(&self) -> io::Result<Box<dyn RW>> {
+        let rw: Box<dyn RW> = match self.proxy {
+            Some(proxy) => Box::new(Datagram::bind(proxy, self.bind, self.auth.clone())?),
+            None => Box::new(Socket::bind(self.bind)?),
+        };
+        rw.set_read_timeout(self.timeout)?;
+
+        Ok(rw)
+    }
+
+    /// Runs the test on sockets bound by the tester.
+    pub fn run(&self) -> io::Result<NatTestResult> {
+        self.run_with_factory(&mut || self.bind_socket())
+    }
+
+    /// Runs the test in the network namespace, where the servers are resolved and the sockets are
+    /// bound.
+    #[cfg(target_os = "linux")]
+    pub fn run_in(&self, netns: &NetNs) -> io::Result<NatTestResult> {
+        netns.run(|| self.run())?
+    }
+
+    /// Runs the test on sockets bound by the tester once, and again whenever the default route,
+    /// the addresses or the links of the network namespace change, calling back with the changes
+    /// and the result of each run. A run waits until no change comes for the debounce.
+    ///
+    /// Returns only when the monitor fails, or with a `Cancelled` error when cancelled.
+    #[cfg(target_os = "linux")]
+    pub fn watch<F>(&self, debounce: Duration, f: F) -> io::Result<()>
+    where
+        F: FnMut(&[Change], io::Result<NatTestResult>),
+    {
+        monitor::watch(self, &self.cancel, debounce, f)
+    }
+
+    /// Runs the test on sockets created by the factory rather than bound by the tester.
+    ///
+    /// The read timeouts of the sockets are used as the deadline of the test.
+    #[instrument(level = "info", skip(self, factory), fields(sockets = self.sockets))]
+    pub fn run_with_factory<F: SocketFactory + ?Sized>(
+        &self,
+        factory: &mut F,
+    ) -> io::Result<NatTestResult> {
+        // A mapping dependent on the destination would be type D with an unknown port allocation
+        if self.sockets < 2 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a NAT test needs two sockets at least",
+            ));
+        }
+        let (server1, server2) = self.resolve()?;
+        let endpoints = Endpoints::new(server1, server2, self.ports);
+
+        self.retry(|| nat_test_lazy(factory, self.sockets, &endpoints, self.context()))
+    }
+
+    /// Runs the test on the given sockets rather than ones bound by the tester.
+    ///
+    /// The read timeouts of the sockets are used as the deadline of the test.
+    #[instrument(level = "info", skip(self, rws), fields(sockets = rws.len()))]
+    pub fn run_with(&self, rws: &[&dyn RW]) -> io::Result<NatTestResult> {
+        if rws.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a NAT test needs a socket at least",
+            ));
+        }
+        let (server1, server2) = self.resolve()?;
+        let endpoints = Endpoints::new(server1, server2, self.ports);
+
+        self.retry(|| nat_test_on(rws, &endpoints, self.context()))
+    }
+
+    /// Runs the test `runs` times on sockets bound by the tester, waiting for the interval between
+    /// runs.
+    pub fn run_series(&self, runs: usize, interval: Duration) -> io::Result<Consensus> {
+        self.run_series_with_factory(&mut || self.bind_socket(), runs, interval)
+    }
+
+    /// Runs the test `runs` times on fresh sockets created by the factory, waiting for the
+    /// interval between runs. Each run is retried as configured, and a run failing with an error is
+    /// recorded as such while the series goes on, unless the test is cancelled or invalid.
+    #[instrument(level = "info", skip(self, factory))]
+    pub fn run_series_with_factory<F: SocketFactory + ?Sized>(
+        &self,
+        factory: &mut F,
+        runs: usize,
+        interval: Duration,
+    ) -> io::Result<Consensus> {
+        if runs == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a series needs a run at least",
+            ));
+        }
+
+        let mut series = Vec::new();
+        for run in 0..runs {
+            if run > 0 {
+                self.cancel.sleep(interval)?;
+            }
+            let start = Instant::now();
+            let result = self.run_with_factory(factory);
+            let elapsed = start.elapsed();
+            match result {
+                Ok(result) => {
+                    debug!(run = run + 1, nat = %result.nat(), ?elapsed, "run finished");
+                    series.push(Run::new(result.nat(), result.ip(), elapsed));
+                }
+                Err(e) if Cancelled::is(&e) || e.kind() == io::ErrorKind::InvalidInput => {
+                    return Err(e)
+                }
+                Err(e) => {
+                    debug!(run = run + 1, error = %e, ?elapsed, "run failed");
+                    series.push(Run::failed(&e, elapsed));
+                }
+            }
+        }
+
+        Ok(Consensus::new(series))
+    }
+
+    /// Verifies the port forward of the given socket to the external port, running the test
+    /// `runs` times.
+    ///
+    /// The read timeout of the socket is used as the deadline of each run.
+    #[instrument(level = "info", skip(self, rw))]
+    pub fn verify_forward<R: RW + ?Sized>(
+        &self,
+        rw: &R,
+        external_port: u16,
+        runs: usize,
+    ) -> io::Result<Verification> {
+        let (server1, server2) = self.resolve()?;
+        let endpoints = Endpoints::new(server1, server2, self.ports);
+
+        verify_on(rw, &endpoints, external_port, runs, self.context())
+    }
+
+    /// Performs a hairpin test on the given sockets, probing their mappings from the echo port of
+    /// the server 1 first.
+    ///
+    /// The read timeout of `rw1` is used as the deadline of the whole test.
+    pub fn hairpin_test<R1: RW + ?Sized, R2: RW + ?Sized>(
+        &self,
+        rw1: &R1,
+        rw2: &R2,
+    ) -> io::Result<bool> {
+        let (server1, server2) = self.resolve()?;
+        let endpoints = Endpoints::new(server1, server2, self.ports);
+
+        hairpin_on(rw1, rw2, &endpoints)
+    }
+
+    /// Returns the context of the tests.
+    fn context(&self) -> Context<'_> {
+        Context {
+            cancel: &self.cancel,
+            observer: match &self.observer {
+                Some(observer) => observer.0.as_ref(),
+                None => &(),
+            },
+        }
+    }
+
+    /// Runs the test, rerunning it while it fails with NAT type F.
+    fn retry<T>(&self, mut test: T) -> io::Result<NatTestResult>
+    where
+        T: FnMut() -> io::Result<NatTestResult>,
+    {
+        let mut result = test()?;
+        for retry in 0..self.retries {
+            if result.nat() != NatType::F {
+                break;
+            }
+            debug!(retry = retry + 1, "NAT test failed, retrying");
+            self.cancel.sleep(self.retry_interval)?;
+            result = test()?;
+        }
+
+        Ok(result)
+    }
+}
+
+/// Represents an observer shared by clones of a tester, equal to its clones only.
+#[derive(Clone)]
+struct SharedObserver(Arc<dyn Observer>);
+
+impl fmt::Debug for SharedObserver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SharedObserver")
+    }
+}
+
+impl PartialEq for SharedObserver {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for SharedObserver {}
+
+impl Default for NatTester {
+    fn default() -> Self {
+        NatTester::new()
+    }
+}
+
+impl fmt::Debug for NatTester {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // The username and password of the proxy never show up in logs
+        let auth = self.auth.as_ref().map(|_| "<redacted>");
+        f.debug_struct("NatTester")
+            .field("server1", &self.server1)
+            .field("server2", &self.server2)
+            .field("ports", &self.ports)
+            .field("proxy", &self.proxy)
+            .field("auth", &auth)
+            .field("bind", &self.bind)
+            .field("timeout", &self.timeout)
+            .field("retries", &self.retries)
+            .field("retry_interval", &self.retry_interval)
+            .field("sockets", &self.sockets)
+            .field("cancel", &self.cancel)
+            .field("observer", &self.observer)
+            .finish()
+    }
+}
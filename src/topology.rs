@@ -0,0 +1,138 @@
+//! Classification of addresses and NAT topologies.
+
+use crate::protocol::PORT_2;
+use std::fmt::{self, Display};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddrV4, UdpSocket};
+
+/// Enumeration of address classes.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum AddressClass {
+    /// Represents a public address.
+    Public,
+    /// Represents an address in the shared address space of carrier-grade NAT, `100.64.0.0/10`
+    /// (RFC 6598).
+    Shared,
+    /// Represents a private address, `10.0.0.0/8`, `172.16.0.0/12` or `192.168.0.0/16`
+    /// (RFC 1918).
+    Private,
+    /// Represents a loopback, link-local, unspecified or other special-purpose address.
+    Special,
+}
+
+impl AddressClass {
+    /// Returns the class of the IP address.
+    pub fn of(ip: Ipv4Addr) -> AddressClass {
+        let [a, b, _, _] = ip.octets();
+        if a == 100 && (b & 0xc0) == 64 {
+            AddressClass::Shared
+        } else if ip.is_private() {
+            AddressClass::Private
+        } else if ip.is_loopback()
+            || ip.is_link_local()
+            || ip.is_unspecified()
+            || ip.is_broadcast()
+            || ip.is_multicast()
+            || ip.is_documentation()
+            || a == 0
+            || a >= 240
+        {
+            AddressClass::Special
+        } else {
+            AddressClass::Public
+        }
+    }
+}
+
+impl Display for AddressClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AddressClass::Public => write!(f, "public"),
+            AddressClass::Shared => write!(f, "shared"),
+            AddressClass::Private => write!(f, "private"),
+            AddressClass::Special => write!(f, "special"),
+        }
+    }
+}
+
+/// Enumeration of NAT topologies between the host and the servers.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Topology {
+    /// Represents no NAT, the host owns the address seen by the servers.
+    NoNat,
+    /// Represents a single NAT.
+    SingleNat,
+    /// Represents nested NATs, like a router behind another router.
+    NestedNat,
+    /// Represents a carrier-grade NAT, which may also be nested behind a home router.
+    CarrierGradeNat,
+}
+
+impl Topology {
+    /// Classifies the topology, which is unknown if the addresses do not fit any of them.
+    ///
+    /// `host_ip` is the IP address of the host, `local_ip` is the local IP address reported by the
+    /// server, and `remote_ip` is the IP address seen by the server. A topology is carrier-grade
+    /// NAT if any of the addresses is in the shared address space. It is nested NAT if the servers
+    /// see a private address from a private host, as the outer NAT is itself in a private network,
+    /// or if the server reports a private local address other than the address of the host.
+    pub fn classify(
+        host_ip: Ipv4Addr,
+        local_ip: Option<Ipv4Addr>,
+        remote_ip: Ipv4Addr,
+    ) -> Option<Topology> {
+        if host_ip == remote_ip {
+            return Some(Topology::NoNat);
+        }
+
+        let host_class = AddressClass::of(host_ip);
+        let local_class = local_ip.map(AddressClass::of);
+        let remote_class = AddressClass::of(remote_ip);
+        if host_class == AddressClass::Shared
+            || local_class == Some(AddressClass::Shared)
+            || remote_class == AddressClass::Shared
+        {
+            return Some(Topology::CarrierGradeNat);
+        }
+
+        if remote_class == AddressClass::Private {
+            return match host_class {
+                AddressClass::Private => Some(Topology::NestedNat),
+                _ => None,
+            };
+        }
+
+        match local_ip {
+            Some(local_ip)
+                if local_ip != host_ip
+                    && local_ip != remote_ip
+                    && local_class == Some(AddressClass::Private) =>
+            {
+                Some(Topology::NestedNat)
+            }
+            _ => Some(Topology::SingleNat),
+        }
+    }
+}
+
+impl Display for Topology {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Topology::NoNat => write!(f, "No NAT"),
+            Topology::SingleNat => write!(f, "Single NAT"),
+            Topology::NestedNat => write!(f, "Nested NAT"),
+            Topology::CarrierGradeNat => write!(f, "Carrier-grade NAT"),
+        }
+    }
+}
+
+/// Returns the local IP address the host uses to reach the server, as chosen by the routing table.
+pub fn local_ip_to(server: Ipv4Addr) -> io::Result<Ipv4Addr> {
+    let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.connect(SocketAddrV4::new(server, PORT_2))?;
+
+    match socket.local_addr()?.ip() {
+        IpAddr::V4(ip) => Ok(ip),
+        _ => Err(io::Error::from(io::ErrorKind::AddrNotAvailable)),
+    }
+}
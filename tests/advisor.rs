@@ -0,0 +1,161 @@
+use ninat::advisor::{Advice, Finding, Fix};
+use ninat::protocol::Ports;
+use ninat::tester::NatTester;
+use ninat::{NatTestResult, NatType};
+use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::time::Duration;
+
+mod common;
+
+use common::{servers, Mock, SERVER_1, SERVER_2};
+
+const HOST: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 2);
+const PUBLIC: Ipv4Addr = Ipv4Addr::new(203, 0, 113, 5);
+const SHARED: Ipv4Addr = Ipv4Addr::new(100, 64, 0, 5);
+
+/// Enumeration of mappings the mock servers report.
+#[derive(Clone, Copy)]
+enum Mapping {
+    /// Both servers see the same port.
+    Independent,
+    /// The server 2 sees the port moved by 1.
+    Predictable,
+    /// The server 2 sees the port moved by a delta growing with every new port.
+    Random,
+}
+
+/// Represents a NAT the mock servers pretend to see the host behind.
+#[derive(Clone, Copy)]
+struct Nat {
+    remote_ip: Ipv4Addr,
+    mapping: Mapping,
+    inbound: bool,
+}
+
+/// Serves both servers of the NAT on the loopback addresses, returning their ports.
+fn mock_servers(nat: Nat) -> Ports {
+    let mock = Mock::new().remote_ip(nat.remote_ip).local_ip(HOST);
+    let mock2 = match nat.mapping {
+        Mapping::Independent => mock.clone(),
+        Mapping::Predictable => mock.clone().shift(|_| 1),
+        Mapping::Random => mock.clone().shift(|n| 1 + 7 * n),
+    };
+    // A filtering NAT drops the reply from another port, so it is never sent
+    servers(mock, mock2, nat.inbound)
+}
+
+/// Runs the NAT test behind the NAT, or against servers never answering without one.
+fn nat_test(nat: Option<Nat>) -> NatTestResult {
+    let (ports, _sockets) = match nat {
+        Some(nat) => (mock_servers(nat), Vec::new()),
+        None => {
+            let socket1 = UdpSocket::bind(SocketAddrV4::new(SERVER_1, 0)).unwrap();
+            let port = socket1.local_addr().unwrap().port();
+            (Ports::new(port, port, port), vec![socket1])
+        }
+    };
+
+    NatTester::new()
+        .servers(SERVER_1, SERVER_2)
+        .ports(ports)
+        .bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))
+        .timeout(Some(Duration::from_millis(500)))
+        .run()
+        .unwrap()
+}
+
+/// Returns the fixes of the router for the result.
+fn router_fixes(result: &NatTestResult) -> Vec<Fix> {
+    let o1 = &result.observations()[0];
+    let local = SocketAddrV4::new(HOST, o1.local().port());
+    let mut fixes = match result.nat() {
+        // The mapped port stays the same for every destination
+        NatType::B => vec![
+            Fix::EnableUpnp,
+            Fix::PortForward(o1.remote1().unwrap().port(), local),
+        ],
+        // The mapped port moves for every destination, so the local port is forwarded
+        _ => vec![Fix::EnableUpnp, Fix::PortForward(local.port(), local)],
+    };
+    fixes.push(Fix::Dmz(HOST));
+    if result.nat() != NatType::D {
+        fixes.push(Fix::FullCone(local));
+    }
+
+    fixes
+}
+
+#[test]
+fn advice() {
+    let mock = |remote_ip, mapping, inbound| {
+        Some(Nat {
+            remote_ip,
+            mapping,
+            inbound,
+        })
+    };
+    let cases = [
+        (mock(PUBLIC, Mapping::Independent, true), false, NatType::A),
+        (mock(PUBLIC, Mapping::Independent, false), false, NatType::B),
+        (mock(PUBLIC, Mapping::Predictable, false), false, NatType::C),
+        (mock(PUBLIC, Mapping::Random, false), false, NatType::D),
+        (None, false, NatType::F),
+        (mock(SHARED, Mapping::Independent, false), false, NatType::B),
+        (mock(SHARED, Mapping::Random, false), false, NatType::D),
+        (mock(PUBLIC, Mapping::Independent, false), true, NatType::B),
+        (mock(PUBLIC, Mapping::Random, false), true, NatType::D),
+        (None, true, NatType::F),
+    ];
+    for (mock, proxy, nat) in cases.iter() {
+        let result = nat_test(*mock);
+        assert_eq!(result.nat(), *nat);
+
+        // The address of the host is unknown through a proxy
+        let host_ip = match proxy {
+            true => None,
+            false => Some(HOST),
+        };
+        let advice = Advice::new(&result, host_ip, *proxy);
+        let cgnat = mock.map(|mock| mock.remote_ip) == Some(SHARED);
+        let fixes = match (proxy, cgnat, nat) {
+            (true, _, NatType::F) => vec![Fix::DisableProxy, Fix::ProxyUdp],
+            (true, _, _) => vec![Fix::DisableProxy],
+            (false, _, NatType::F) => vec![Fix::AllowUdp(result.server_ports())],
+            (false, true, _) => vec![Fix::PublicIpv4],
+            (false, false, NatType::A) => vec![],
+            (false, false, _) => router_fixes(&result),
+        };
+        assert_eq!(advice.fixes(), fixes.as_slice(), "NAT type {}", nat);
+        assert_eq!(advice.findings().contains(&Finding::Proxy), *proxy);
+        assert_eq!(
+            advice
+                .findings()
+                .contains(&Finding::CarrierGradeNat(SHARED)),
+            cgnat
+        );
+    }
+}
+
+#[test]
+fn full_cone_commands() {
+    let local = SocketAddrV4::new(HOST, 10025);
+    let fix = Fix::FullCone(local);
+    assert!(fix.to_string().contains("static DNAT"));
+    assert!(fix.to_string().contains("port-preserving SNAT"));
+    assert!(fix
+        .nftables()
+        .unwrap()
+        .contains("dnat to 192.168.1.2:10025"));
+    assert!(fix
+        .iptables()
+        .unwrap()
+        .contains("--to-destination 192.168.1.2:10025"));
+    assert_eq!(Fix::EnableUpnp.nftables(), None);
+}
+
+#[test]
+fn allow_udp_ports() {
+    let fix = Fix::AllowUdp(Ports::new(20025, 43334, 60920));
+    assert!(fix.to_string().contains("ports 20025 and 43334"));
+    assert!(fix.to_string().contains("from port 60920"));
+}
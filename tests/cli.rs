@@ -0,0 +1,48 @@
+#![cfg(target_os = "linux")]
+
+use std::process::{Command, Output};
+
+/// Runs the binary with the arguments.
+fn ninat(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_ninat"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn netns_compare_missing() {
+    // Both namespaces are reported, and fail to be entered
+    let output = ninat(&[
+        "--netns",
+        "ninat-test-missing-1",
+        "--netns",
+        "ninat-test-missing-2",
+    ]);
+    assert_eq!(output.status.code(), Some(8));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("Network Namespaces:"), "{}", stdout);
+    for name in ["ninat-test-missing-1", "ninat-test-missing-2"].iter() {
+        assert!(
+            stdout.contains(&format!("/run/netns/{}", name)),
+            "{}",
+            stdout
+        );
+    }
+    assert!(!stdout.contains("NAT Types:"), "{}", stdout);
+}
+
+#[test]
+fn netns_compare_usage() {
+    // Only the NAT test runs in several namespaces
+    let output = ninat(&["--netns", "a", "--netns", "b", "--explain"]);
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("only the NAT test"), "{}", stderr);
+}
+
+#[test]
+fn netns_missing() {
+    let output = ninat(&["--netns", "ninat-test-missing"]);
+    assert_eq!(output.status.code(), Some(8));
+}
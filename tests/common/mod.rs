@@ -0,0 +1,193 @@
+//! Mock servers shared by the tests, which answer like the servers of the service but can pretend
+//! to see the host behind a NAT, forge replies, or delay and drop them.
+
+#![allow(dead_code)]
+
+use ninat::protocol::{Body, Ports, Request, Response};
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+pub const SERVER_1: Ipv4Addr = Ipv4Addr::new(127, 0, 0, 2);
+pub const SERVER_2: Ipv4Addr = Ipv4Addr::new(127, 0, 0, 3);
+
+/// Represents how a mock server answers.
+#[derive(Clone)]
+pub struct Mock {
+    remote_ip: Option<Ipv4Addr>,
+    local_ip: Option<Ipv4Addr>,
+    shift: Arc<dyn Fn(u16) -> u16 + Send + Sync>,
+    forged: Option<SocketAddrV4>,
+    delays: Vec<Option<Duration>>,
+}
+
+impl Mock {
+    /// Creates a new `Mock`, which reports the address requests come from as both the remote and
+    /// the local address, and replies at once.
+    pub fn new() -> Mock {
+        Mock {
+            remote_ip: None,
+            local_ip: None,
+            shift: Arc::new(|_| 0),
+            forged: None,
+            delays: Vec::new(),
+        }
+    }
+
+    /// Sets the remote IP address reported.
+    pub fn remote_ip(self, remote_ip: Ipv4Addr) -> Mock {
+        Mock {
+            remote_ip: Some(remote_ip),
+            ..self
+        }
+    }
+
+    /// Sets the local IP address reported.
+    pub fn local_ip(self, local_ip: Ipv4Addr) -> Mock {
+        Mock {
+            local_ip: Some(local_ip),
+            ..self
+        }
+    }
+
+    /// Sets the shift of the ports reported, as if mapped by a NAT, from the number of ports seen
+    /// before a port is first seen.
+    pub fn shift<F: Fn(u16) -> u16 + Send + Sync + 'static>(self, shift: F) -> Mock {
+        Mock {
+            shift: Arc::new(shift),
+            ..self
+        }
+    }
+
+    /// Sets the address the first echo is answered with first if any, as if by a spoofer.
+    pub fn forged(self, forged: Option<SocketAddrV4>) -> Mock {
+        Mock { forged, ..self }
+    }
+
+    /// Sets the delays of the replies to the requests in order, `None` dropping a request. Requests
+    /// are answered at once once the delays run out.
+    pub fn delays(self, delays: Vec<Option<Duration>>) -> Mock {
+        Mock { delays, ..self }
+    }
+}
+
+/// Serves the port of `echo` like the mock, replying to the request from another port through
+/// `inbound` if any, or from `echo` as if filtered otherwise.
+pub fn serve(echo: UdpSocket, inbound: Option<UdpSocket>, mock: Mock) {
+    thread::spawn(move || {
+        let mut buffer = [0u8; 64];
+        let mut seen = HashMap::new();
+        let mut forged = mock.forged;
+        let mut delays = mock.delays.clone().into_iter();
+        while let Ok((size, SocketAddr::V4(addr))) = echo.recv_from(&mut buffer) {
+            let request = match Request::decode(&buffer[..size]) {
+                Ok(request) => request,
+                Err(_) => continue,
+            };
+            let delay = match delays.next() {
+                Some(Some(delay)) => delay,
+                Some(None) => continue,
+                None => Duration::default(),
+            };
+            let socket = match (request, &inbound) {
+                (Request::AnotherPort, Some(inbound)) => inbound,
+                _ => &echo,
+            };
+
+            let mut responses = Vec::new();
+            if let (Request::Echo, Some(forged)) = (request, forged.take()) {
+                let response = Response::new(request, Body::new(forged, *forged.ip())).unwrap();
+                responses.push(response.encode());
+            }
+            let n = seen.len() as u16;
+            let shift = *seen.entry(addr.port()).or_insert_with(|| (mock.shift)(n));
+            let remote_ip = mock.remote_ip.unwrap_or(*addr.ip());
+            let mapped = SocketAddrV4::new(remote_ip, addr.port().wrapping_add(shift));
+            let local_ip = mock.local_ip.unwrap_or(*addr.ip());
+            match Response::new(request, Body::new(mapped, local_ip)) {
+                Ok(response) => responses.push(response.encode()),
+                Err(_) => continue,
+            }
+
+            match delay.as_nanos() {
+                0 => {
+                    for response in responses.iter() {
+                        let _ = socket.send_to(response, addr);
+                    }
+                }
+                _ => {
+                    let socket = socket.try_clone().unwrap();
+                    thread::spawn(move || {
+                        thread::sleep(delay);
+                        for response in responses.iter() {
+                            let _ = socket.send_to(response, addr);
+                        }
+                    });
+                }
+            }
+        }
+    });
+}
+
+/// Serves both servers on `SERVER_1` and `SERVER_2`, like `mock1` and `mock2`, returning their
+/// ports. The server 1 answers the request from another port from its echo port unless
+/// `is_inbound`, as if the receiving only port were filtered.
+pub fn servers(mock1: Mock, mock2: Mock, is_inbound: bool) -> Ports {
+    let (echo1, echo2) = loop {
+        let echo1 = UdpSocket::bind(SocketAddrV4::new(SERVER_1, 0)).unwrap();
+        let port = echo1.local_addr().unwrap().port();
+        if let Ok(echo2) = UdpSocket::bind(SocketAddrV4::new(SERVER_2, port)) {
+            break (echo1, echo2);
+        }
+    };
+    let send_only = UdpSocket::bind(SocketAddrV4::new(SERVER_1, 0)).unwrap();
+    let inbound = UdpSocket::bind(SocketAddrV4::new(SERVER_1, 0)).unwrap();
+
+    start([send_only, echo1, inbound, echo2], mock1, mock2, is_inbound)
+}
+
+/// Serves both servers on the addresses at the ports, like `servers`.
+pub fn servers_on(
+    server1: Ipv4Addr,
+    server2: Ipv4Addr,
+    ports: Ports,
+    mock1: Mock,
+    mock2: Mock,
+    is_inbound: bool,
+) {
+    let bind = |ip, port| UdpSocket::bind(SocketAddrV4::new(ip, port)).unwrap();
+    let sockets = [
+        bind(server1, ports.send_only()),
+        bind(server1, ports.echo()),
+        bind(server1, ports.inbound()),
+        bind(server2, ports.echo()),
+    ];
+
+    start(sockets, mock1, mock2, is_inbound);
+}
+
+/// Serves the send-only, echo and receiving only ports of the server 1 and the echo port of the
+/// server 2, returning the ports.
+fn start(sockets: [UdpSocket; 4], mock1: Mock, mock2: Mock, is_inbound: bool) -> Ports {
+    let [send_only, echo1, inbound, echo2] = sockets;
+    let ports = Ports::new(
+        send_only.local_addr().unwrap().port(),
+        echo1.local_addr().unwrap().port(),
+        inbound.local_addr().unwrap().port(),
+    );
+    // The send-only port is never answered
+    thread::spawn(move || {
+        let mut buffer = [0u8; 64];
+        while send_only.recv_from(&mut buffer).is_ok() {}
+    });
+    let inbound = match is_inbound {
+        true => Some(inbound),
+        false => None,
+    };
+    serve(echo1, inbound, mock1);
+    serve(echo2, None, mock2);
+
+    ports
+}
@@ -0,0 +1,116 @@
+#![cfg(target_os = "linux")]
+
+use ninat::hops::{Hop, Trace};
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+fn ip(s: &str) -> Ipv4Addr {
+    s.parse().unwrap()
+}
+
+fn reply(s: &str) -> Hop {
+    Hop::Reply(SocketAddrV4::new(ip(s), 50000))
+}
+
+#[test]
+fn trace_nats() {
+    let exceeded = |s| Hop::TimeExceeded(ip(s));
+    let cases = [
+        // No NAT
+        (
+            "8.8.4.4",
+            vec![exceeded("8.8.4.1"), reply("8.8.4.4")],
+            vec![],
+        ),
+        // A router of a private network
+        (
+            "192.168.1.2",
+            vec![exceeded("192.168.1.1"), reply("1.1.1.2")],
+            vec![Some(1)],
+        ),
+        // A router behind carrier-grade NAT
+        (
+            "192.168.1.2",
+            vec![
+                exceeded("192.168.1.1"),
+                exceeded("100.64.0.1"),
+                reply("1.1.1.2"),
+            ],
+            vec![Some(1), Some(2)],
+        ),
+        // Double NAT in the same class
+        (
+            "192.168.1.2",
+            vec![
+                exceeded("192.168.1.1"),
+                exceeded("192.168.0.1"),
+                exceeded("1.1.1.1"),
+                reply("1.1.1.2"),
+            ],
+            vec![Some(1), Some(2)],
+        ),
+        // A router behind the private network of the ISP
+        (
+            "192.168.1.2",
+            vec![
+                exceeded("192.168.1.1"),
+                exceeded("10.20.0.1"),
+                exceeded("1.1.1.1"),
+                reply("1.1.1.2"),
+            ],
+            vec![Some(1), Some(2)],
+        ),
+        // Silent hops
+        (
+            "192.168.1.2",
+            vec![
+                Hop::Silent,
+                exceeded("192.168.0.1"),
+                Hop::Silent,
+                reply("1.1.1.2"),
+            ],
+            vec![Some(0), Some(2)],
+        ),
+        // NAT between public addresses
+        (
+            "8.8.4.4",
+            vec![exceeded("8.8.4.1"), exceeded("9.9.9.1"), reply("1.1.1.2")],
+            vec![None],
+        ),
+        // NAT on the host itself
+        ("8.8.4.4", vec![reply("1.1.1.2")], vec![Some(0)]),
+        // A routed private network the server sees the host in
+        (
+            "10.0.1.2",
+            vec![exceeded("10.0.1.1"), reply("10.0.1.2")],
+            vec![],
+        ),
+        // Unknown without a reply
+        ("8.8.4.4", vec![exceeded("8.8.4.1"), Hop::Silent], vec![]),
+        // Hops after one rejecting the probe are ignored
+        (
+            "192.168.1.2",
+            vec![
+                exceeded("192.168.1.1"),
+                Hop::Unreachable(ip("10.0.0.1")),
+                exceeded("1.1.1.1"),
+                reply("1.1.1.2"),
+            ],
+            vec![Some(1)],
+        ),
+    ];
+    for (host_ip, hops, nats) in cases.iter() {
+        let trace = Trace::new(hops.clone());
+        assert_eq!(trace.nats(ip(host_ip)), *nats, "{:?}", hops);
+    }
+}
+
+#[test]
+fn trace_distance() {
+    let trace = Trace::new(vec![Hop::Silent, reply("1.1.1.2")]);
+    assert_eq!(trace.distance(), Some(2));
+    assert_eq!(
+        trace.remote(),
+        Some(SocketAddrV4::new(ip("1.1.1.2"), 50000))
+    );
+    assert_eq!(Trace::new(vec![Hop::Silent]).distance(), None);
+}
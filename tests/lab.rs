@@ -0,0 +1,211 @@
+#![cfg(target_os = "linux")]
+
+use ninat::cancel::{CancelToken, Cancelled};
+use ninat::hops::{self, Hop};
+use ninat::lab::{self, Lab, Nat, GATEWAY, HOST, NATS};
+use ninat::monitor::Change;
+use ninat::netns::{NetNs, NetNsError};
+use ninat::tester::NatTester;
+use ninat::NatType;
+use std::io;
+use std::net::Ipv4Addr;
+use std::process::Command;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+/// Panics unless a lab of the NAT can be built. Lab tests are ignored unless run with
+/// `--ignored`, and then fail rather than pass without running.
+fn check(nat: Nat) {
+    if let Err(e) = lab::check(nat) {
+        panic!("the lab of {} cannot be built: {}", nat, e);
+    }
+}
+
+/// Tests the NAT in a lab of the name.
+fn lab_test(name: &str, nat: Nat) {
+    check(nat);
+
+    let lab = Lab::new(name, nat).unwrap();
+    let _server = lab.serve().unwrap();
+    let tester = NatTester::new().timeout(Some(Duration::from_secs(1)));
+    let result = lab.nat_test(tester).unwrap();
+    assert_eq!(result.nat(), nat.expected());
+}
+
+/// Traces the path to the server 1 in a lab of the name, returning the hops NAT devices are
+/// located at.
+fn lab_trace(name: &str, nat: Nat) -> Vec<Option<usize>> {
+    check(nat);
+
+    let lab = Lab::new(name, nat).unwrap();
+    let _server = lab.serve().unwrap();
+    let netns = NetNs::open(lab.host()).unwrap();
+    let trace = netns
+        .run(|| {
+            hops::trace(
+                Ipv4Addr::UNSPECIFIED,
+                lab::SERVER_1,
+                4,
+                Duration::from_secs(1),
+            )
+        })
+        .unwrap()
+        .unwrap();
+    assert_eq!(trace.hops()[0], Hop::TimeExceeded(GATEWAY));
+    assert_eq!(trace.distance(), Some(2));
+
+    trace.nats(HOST)
+}
+
+#[test]
+fn nat_names() {
+    for nat in NATS.iter() {
+        assert_eq!(nat.to_string().parse::<Nat>(), Ok(*nat));
+    }
+    assert!("cone".parse::<Nat>().is_err());
+}
+
+#[test]
+#[ignore = "needs root, ip and iptables"]
+fn lab_none() {
+    lab_test("ninat-test-none", Nat::None);
+}
+
+#[test]
+#[ignore = "needs root, ip and iptables"]
+fn lab_masquerade() {
+    lab_test("ninat-test-masquerade", Nat::Masquerade);
+}
+
+#[test]
+#[ignore = "needs root, ip and iptables"]
+fn lab_random_masquerade() {
+    lab_test("ninat-test-random", Nat::RandomMasquerade);
+}
+
+#[test]
+#[ignore = "needs root, ip and iptables"]
+fn lab_full_cone() {
+    lab_test("ninat-test-full-cone", Nat::FullCone);
+}
+
+#[test]
+#[ignore = "needs root, ip and iptables"]
+fn lab_drop() {
+    lab_test("ninat-test-drop", Nat::Drop);
+}
+
+#[test]
+#[ignore = "needs root, ip and iptables"]
+fn lab_trace_none() {
+    assert!(lab_trace("ninat-test-trace-none", Nat::None).is_empty());
+}
+
+#[test]
+#[ignore = "needs root, ip and iptables"]
+fn lab_trace_masquerade() {
+    // The router masquerades the host
+    assert_eq!(
+        lab_trace("ninat-test-trace-masquerade", Nat::Masquerade),
+        vec![Some(1)]
+    );
+}
+
+#[test]
+fn netns_open() {
+    let e = NetNs::open("ninat-test-missing").unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::NotFound);
+    assert!(NetNsError::is(&e));
+    assert!(e.to_string().contains("/run/netns/ninat-test-missing"));
+
+    let netns = NetNs::open("/proc/self/ns/net").unwrap();
+    assert_eq!(netns.path().to_str(), Some("/proc/self/ns/net"));
+}
+
+#[test]
+#[ignore = "needs root, ip and iptables"]
+fn lab_compare() {
+    check(Nat::None);
+
+    // The tests in every namespace run at the same time, and one missing fails on its own
+    let labs = [
+        Lab::new("ninat-test-compare-1", Nat::None).unwrap(),
+        Lab::new("ninat-test-compare-2", Nat::None).unwrap(),
+    ];
+    let _servers: Vec<_> = labs.iter().map(|lab| lab.serve().unwrap()).collect();
+    let names = [labs[0].host(), "ninat-test-compare-missing", labs[1].host()];
+    let tester = NatTester::new()
+        .servers(lab::SERVER_1, lab::SERVER_2)
+        .timeout(Some(Duration::from_secs(1)));
+    let tester = &tester;
+    let results: Vec<_> = thread::scope(|scope| {
+        let handles: Vec<_> = names
+            .iter()
+            .map(|name| scope.spawn(move || tester.run_in(&NetNs::open(name)?)))
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect()
+    });
+    assert_eq!(results[0].as_ref().unwrap().nat(), NatType::A);
+    assert!(NetNsError::is(results[1].as_ref().unwrap_err()));
+    assert_eq!(results[2].as_ref().unwrap().nat(), NatType::A);
+}
+
+#[test]
+#[ignore = "needs root, ip and iptables"]
+fn lab_watch() {
+    check(Nat::None);
+
+    let lab = Lab::new("ninat-test-watch", Nat::None).unwrap();
+    let _server = lab.serve().unwrap();
+    let netns = NetNs::open(lab.host()).unwrap();
+    let token = CancelToken::new();
+    let tester = NatTester::new()
+        .servers(lab::SERVER_1, lab::SERVER_2)
+        .timeout(Some(Duration::from_secs(1)))
+        .cancel_token(token.clone());
+
+    let (tx, rx) = mpsc::channel();
+    let handle = thread::spawn(move || {
+        netns.run(|| {
+            tester.watch(Duration::from_millis(200), |changes, result| {
+                let _ = tx.send((changes.to_vec(), result.map(|result| result.nat())));
+            })
+        })
+    });
+
+    // The first run needs no change
+    let (changes, nat) = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(changes.is_empty());
+    assert_eq!(nat.unwrap(), NatType::A);
+
+    let status = Command::new("ip")
+        .args([
+            "-n",
+            lab.host(),
+            "addr",
+            "add",
+            "10.0.0.3/24",
+            "dev",
+            "eth0",
+        ])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let (changes, nat) = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(changes.iter().any(|change| matches!(
+        change,
+        Change::Address { ip, added: true, .. } if *ip == Ipv4Addr::new(10, 0, 0, 3)
+    )));
+    assert!(!changes
+        .iter()
+        .any(|change| matches!(change, Change::Address { ip, .. } if *ip == HOST)));
+    assert_eq!(nat.unwrap(), NatType::A);
+
+    token.cancel();
+    let e = handle.join().unwrap().unwrap().unwrap_err();
+    assert!(Cancelled::is(&e));
+}
@@ -0,0 +1,523 @@
+use ninat::mapping::{self, natpmp, pcp, upnp, Error, Gateway, Mapping, Protocol};
+use ninat::protocol::Ports;
+use ninat::server::Server;
+use ninat::{NatType, Socket, RW};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, SocketAddrV4, TcpListener, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+const EXTERNAL_IP: Ipv4Addr = Ipv4Addr::new(203, 0, 113, 1);
+const TIMEOUT: Duration = Duration::from_secs(2);
+const LIFETIME: Duration = Duration::from_secs(120);
+
+/// Mappings of a mock gateway, from external ports to internal addresses and lifetimes.
+type Mappings = Arc<Mutex<HashMap<u16, (SocketAddrV4, u32)>>>;
+
+fn localhost(port: u16) -> SocketAddrV4 {
+    SocketAddrV4::new(Ipv4Addr::LOCALHOST, port)
+}
+
+fn bind_udp() -> (UdpSocket, SocketAddrV4) {
+    let socket = UdpSocket::bind(localhost(0)).unwrap();
+    let port = socket.local_addr().unwrap().port();
+
+    (socket, localhost(port))
+}
+
+fn io_error(e: &io::Error) -> Option<&Error> {
+    e.get_ref().and_then(|e| e.downcast_ref::<Error>())
+}
+
+/// Spawns a mock NAT-PMP server which rejects mappings with the result code if it is not zero.
+fn mock_natpmp(result: u16) -> (SocketAddrV4, Mappings) {
+    let (socket, addr) = bind_udp();
+    let mappings = Mappings::default();
+    let m = mappings.clone();
+    thread::spawn(move || {
+        let mut buf = [0u8; 64];
+        loop {
+            let (size, src) = socket.recv_from(&mut buf).unwrap();
+            let src = match src {
+                std::net::SocketAddr::V4(src) => src,
+                _ => continue,
+            };
+            let mut resp = vec![0, 0x80 | buf[1]];
+            match (buf[1], size) {
+                (0, 2) => {
+                    resp.extend_from_slice(&0u16.to_be_bytes());
+                    resp.extend_from_slice(&1u32.to_be_bytes());
+                    resp.extend_from_slice(&EXTERNAL_IP.octets());
+                }
+                (1, 12) => {
+                    let internal = u16::from_be_bytes([buf[4], buf[5]]);
+                    let external = u16::from_be_bytes([buf[6], buf[7]]);
+                    let lifetime = u32::from_be_bytes([buf[8], buf[9], buf[10], buf[11]]);
+                    let mut mappings = m.lock().unwrap();
+                    let external = match (result, lifetime) {
+                        (0, 0) => {
+                            mappings.retain(|_, (addr, _)| addr.port() != internal);
+                            0
+                        }
+                        (0, _) => {
+                            mappings.insert(
+                                external,
+                                (SocketAddrV4::new(*src.ip(), internal), lifetime),
+                            );
+                            external
+                        }
+                        _ => 0,
+                    };
+                    resp.extend_from_slice(&result.to_be_bytes());
+                    resp.extend_from_slice(&1u32.to_be_bytes());
+                    resp.extend_from_slice(&internal.to_be_bytes());
+                    resp.extend_from_slice(&external.to_be_bytes());
+                    resp.extend_from_slice(&lifetime.min(60).to_be_bytes());
+                }
+                _ => {
+                    resp.extend_from_slice(&5u16.to_be_bytes());
+                    resp.extend_from_slice(&1u32.to_be_bytes());
+                }
+            }
+            socket.send_to(&resp, src).unwrap();
+        }
+    });
+
+    (addr, mappings)
+}
+
+/// Spawns a mock PCP server, which assigns external ports shifted by 1000.
+fn mock_pcp() -> (SocketAddrV4, Mappings) {
+    pcp_server(true)
+}
+
+/// Spawns a mock PCP server like `mock_pcp`, which refuses to delete mappings unless `is_deleting`.
+fn pcp_server(is_deleting: bool) -> (SocketAddrV4, Mappings) {
+    let (socket, addr) = bind_udp();
+    let mappings = Mappings::default();
+    let m = mappings.clone();
+    thread::spawn(move || {
+        let mut nonces = HashMap::new();
+        let mut buf = [0u8; 1100];
+        loop {
+            let (size, src) = socket.recv_from(&mut buf).unwrap();
+            let lifetime = u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]);
+            let mut resp = vec![2, 0x80 | buf[1], 0, 0];
+            resp.extend_from_slice(&lifetime.min(60).to_be_bytes());
+            resp.extend_from_slice(&1u32.to_be_bytes());
+            resp.extend_from_slice(&[0; 12]);
+            match (buf[0], buf[1], size) {
+                (2, 0, 24) => {}
+                (2, 1, 60) => {
+                    let client = Ipv4Addr::new(buf[20], buf[21], buf[22], buf[23]);
+                    let nonce = buf[24..36].to_vec();
+                    let internal = u16::from_be_bytes([buf[40], buf[41]]);
+                    let mut mappings = m.lock().unwrap();
+                    let external = match lifetime {
+                        0 => match is_deleting && nonces.get(&internal) == Some(&nonce) {
+                            true => {
+                                mappings.retain(|_, (addr, _)| addr.port() != internal);
+                                u16::from_be_bytes([buf[42], buf[43]])
+                            }
+                            // Nonce mismatch, or not authorized
+                            false => {
+                                resp[3] = 2;
+                                0
+                            }
+                        },
+                        _ => {
+                            let external = internal + 1000;
+                            nonces.insert(internal, nonce.clone());
+                            mappings
+                                .insert(external, (SocketAddrV4::new(client, internal), lifetime));
+                            external
+                        }
+                    };
+                    resp.extend_from_slice(&nonce);
+                    resp.extend_from_slice(&[17, 0, 0, 0]);
+                    resp.extend_from_slice(&internal.to_be_bytes());
+                    resp.extend_from_slice(&external.to_be_bytes());
+                    resp.extend_from_slice(&EXTERNAL_IP.to_ipv6_mapped().octets());
+                }
+                (2, _, _) => resp[3] = 4,
+                _ => resp[3] = 1,
+            }
+            socket.send_to(&resp, src).unwrap();
+        }
+    });
+
+    (addr, mappings)
+}
+
+/// Represents the behaviors of a mock Internet gateway device.
+#[derive(Clone, Copy, Default)]
+struct Igd {
+    only_permanent: bool,
+    conflict: bool,
+}
+
+/// Spawns a mock Internet gateway device, returning its SSDP address.
+fn mock_igd(igd: Igd) -> (SocketAddrV4, Mappings) {
+    let listener = TcpListener::bind(localhost(0)).unwrap();
+    let http_port = listener.local_addr().unwrap().port();
+    let mappings = Mappings::default();
+    let m = mappings.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 4096];
+            // Read until the whole body arrives
+            loop {
+                let size = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..size]);
+                let text = String::from_utf8_lossy(&request);
+                if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                    let len = head
+                        .lines()
+                        .find_map(|line| line.strip_prefix("Content-Length: "))
+                        .map(|len| len.parse::<usize>().unwrap())
+                        .unwrap_or(0);
+                    if body.len() >= len {
+                        break;
+                    }
+                }
+                if size == 0 {
+                    break;
+                }
+            }
+            let request = String::from_utf8_lossy(&request).to_string();
+            let value = |name: &str| {
+                let start = request.find(&format!("<{}>", name)).unwrap() + name.len() + 2;
+                let end = request.find(&format!("</{}>", name)).unwrap();
+                request[start..end].to_string()
+            };
+
+            let (status, body) = if request.starts_with("GET /desc.xml") {
+                let desc = "<?xml version=\"1.0\"?><root><device>\
+                    <deviceType>urn:schemas-upnp-org:device:InternetGatewayDevice:1</deviceType>\
+                    <serviceList><service>\
+                    <serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType>\
+                    <controlURL>/l3f</controlURL></service></serviceList>\
+                    <deviceList><device><serviceList><service>\
+                    <serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>\
+                    <controlURL>ctl/ipconn</controlURL>\
+                    </service></serviceList></device></deviceList></device></root>";
+                // The description is sent in chunks
+                let (a, b) = desc.split_at(100);
+                let chunked = format!(
+                    "{:x}\r\n{}\r\n{:x}\r\n{}\r\n0\r\n\r\n",
+                    a.len(),
+                    a,
+                    b.len(),
+                    b
+                );
+                let resp = format!(
+                    "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n{}",
+                    chunked
+                );
+                stream.write_all(resp.as_bytes()).unwrap();
+                continue;
+            } else if !request.starts_with("POST /ctl/ipconn") {
+                (404, String::new())
+            } else if request.contains("WANIPConnection:1#GetExternalIPAddress") {
+                (
+                    200,
+                    format!(
+                        "<u:GetExternalIPAddressResponse xmlns:u=\"urn:schemas-upnp-org:service:WANIPConnection:1\">\
+                         <NewExternalIPAddress>{}</NewExternalIPAddress>\
+                         </u:GetExternalIPAddressResponse>",
+                        EXTERNAL_IP
+                    ),
+                )
+            } else if request.contains("WANIPConnection:1#AddPortMapping") {
+                let port: u16 = value("NewExternalPort").parse().unwrap();
+                let lease: u32 = value("NewLeaseDuration").parse().unwrap();
+                let client: Ipv4Addr = value("NewInternalClient").parse().unwrap();
+                let mut mappings = m.lock().unwrap();
+                if igd.conflict {
+                    (718, "ConflictInMappingEntry".to_string())
+                } else if igd.only_permanent && lease != 0 {
+                    (725, "OnlyPermanentLeasesSupported".to_string())
+                } else {
+                    mappings.insert(port, (SocketAddrV4::new(client, port), lease));
+                    (200, String::new())
+                }
+            } else if request.contains("WANIPConnection:1#DeletePortMapping") {
+                let port: u16 = value("NewExternalPort").parse().unwrap();
+                match m.lock().unwrap().remove(&port) {
+                    Some(_) => (200, String::new()),
+                    None => (714, "NoSuchEntryInArray".to_string()),
+                }
+            } else {
+                (401, "Invalid Action".to_string())
+            };
+
+            let resp = match status {
+                200 => format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                    body.len(),
+                    body
+                ),
+                404 => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_string(),
+                code => {
+                    let body = format!(
+                        "<s:Envelope><s:Body><s:Fault><detail><UPnPError>\
+                         <errorCode>{}</errorCode><errorDescription>{}</errorDescription>\
+                         </UPnPError></detail></s:Fault></s:Body></s:Envelope>",
+                        code, body
+                    );
+                    format!(
+                        "HTTP/1.1 500 Internal Server Error\r\nContent-Length: {}\r\n\r\n{}",
+                        body.len(),
+                        body
+                    )
+                }
+            };
+            stream.write_all(resp.as_bytes()).unwrap();
+        }
+    });
+
+    let (socket, addr) = bind_udp();
+    thread::spawn(move || {
+        let mut buf = [0u8; 1024];
+        loop {
+            let (size, src) = socket.recv_from(&mut buf).unwrap();
+            let request = String::from_utf8_lossy(&buf[..size]);
+            if !request.starts_with("M-SEARCH") || !request.contains("InternetGatewayDevice:1") {
+                continue;
+            }
+            let resp = format!(
+                "HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age=120\r\n\
+                 ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\
+                 location: http://127.0.0.1:{}/desc.xml\r\n\r\n",
+                http_port
+            );
+            socket.send_to(resp.as_bytes(), src).unwrap();
+        }
+    });
+
+    (addr, mappings)
+}
+
+#[test]
+fn natpmp_map() {
+    let (addr, mappings) = mock_natpmp(0);
+    assert_eq!(natpmp::external_ip(addr, TIMEOUT).unwrap(), EXTERNAL_IP);
+
+    let internal = localhost(40001);
+    let mapping = natpmp::map(addr, internal, LIFETIME, TIMEOUT).unwrap();
+    assert_eq!(mapping.protocol(), Protocol::NatPmp);
+    assert_eq!(mapping.internal(), internal);
+    assert_eq!(mapping.external(), SocketAddrV4::new(EXTERNAL_IP, 40001));
+    assert_eq!(mapping.lifetime(), Duration::from_secs(60));
+    assert_eq!(
+        mappings.lock().unwrap().get(&40001),
+        Some(&(internal, LIFETIME.as_secs() as u32))
+    );
+
+    natpmp::unmap(addr, &mapping, TIMEOUT).unwrap();
+    assert!(mappings.lock().unwrap().is_empty());
+}
+
+#[test]
+fn natpmp_rejected() {
+    let (addr, mappings) = mock_natpmp(2);
+    let e = natpmp::map(addr, localhost(40002), LIFETIME, TIMEOUT).unwrap_err();
+    assert_eq!(io_error(&e), Some(&Error::NatPmp(2)));
+    assert!(mappings.lock().unwrap().is_empty());
+}
+
+#[test]
+fn natpmp_timeout() {
+    // Nothing answers at a bound but unread socket
+    let (_socket, addr) = bind_udp();
+    let e = natpmp::external_ip(addr, Duration::from_millis(300)).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+}
+
+#[test]
+fn pcp_map() {
+    let (addr, mappings) = mock_pcp();
+    pcp::announce(addr, TIMEOUT).unwrap();
+
+    let internal = localhost(40003);
+    let mapping = pcp::map(addr, internal, LIFETIME, TIMEOUT).unwrap();
+    assert_eq!(mapping.protocol(), Protocol::Pcp);
+    assert_eq!(mapping.external(), SocketAddrV4::new(EXTERNAL_IP, 41003));
+    assert_eq!(mapping.lifetime(), Duration::from_secs(60));
+    assert_eq!(
+        mappings.lock().unwrap().get(&41003),
+        Some(&(internal, LIFETIME.as_secs() as u32))
+    );
+
+    pcp::unmap(addr, &mapping, TIMEOUT).unwrap();
+    assert!(mappings.lock().unwrap().is_empty());
+}
+
+#[test]
+fn pcp_unmap_with_another_nonce() {
+    let (addr, mappings) = mock_pcp();
+    let internal = localhost(40004);
+    let mapping = pcp::map(addr, internal, LIFETIME, TIMEOUT).unwrap();
+    // Mapped again with a new nonce, so the first one is no longer the owner
+    pcp::map(addr, internal, LIFETIME, TIMEOUT).unwrap();
+
+    let e = pcp::unmap(addr, &mapping, TIMEOUT).unwrap_err();
+    assert_eq!(io_error(&e), Some(&Error::Pcp(2)));
+    assert_eq!(mappings.lock().unwrap().len(), 1);
+}
+
+#[test]
+fn upnp_map() {
+    let (addr, mappings) = mock_igd(Igd::default());
+    let device = upnp::search(addr, TIMEOUT).unwrap();
+    assert_eq!(
+        device.service_type(),
+        "urn:schemas-upnp-org:service:WANIPConnection:1"
+    );
+    assert_eq!(device.control_path(), "/ctl/ipconn");
+    assert_eq!(device.external_ip(TIMEOUT).unwrap(), EXTERNAL_IP);
+
+    let internal = localhost(40005);
+    let mapping = device.map(internal, LIFETIME, TIMEOUT).unwrap();
+    assert_eq!(mapping.protocol(), Protocol::Upnp);
+    assert_eq!(mapping.external(), SocketAddrV4::new(EXTERNAL_IP, 40005));
+    assert_eq!(mapping.lifetime(), LIFETIME);
+    assert_eq!(
+        mappings.lock().unwrap().get(&40005),
+        Some(&(internal, LIFETIME.as_secs() as u32))
+    );
+
+    device.unmap(&mapping, TIMEOUT).unwrap();
+    assert!(mappings.lock().unwrap().is_empty());
+
+    let e = device.unmap(&mapping, TIMEOUT).unwrap_err();
+    assert!(matches!(io_error(&e), Some(Error::Upnp(714, _))));
+}
+
+#[test]
+fn upnp_only_permanent_leases() {
+    let (addr, mappings) = mock_igd(Igd {
+        only_permanent: true,
+        ..Igd::default()
+    });
+    let device = upnp::search(addr, TIMEOUT).unwrap();
+
+    let mapping = device.map(localhost(40006), LIFETIME, TIMEOUT).unwrap();
+    assert_eq!(mapping.lifetime(), Duration::from_secs(0));
+    assert_eq!(mappings.lock().unwrap().get(&40006).unwrap().1, 0);
+}
+
+#[test]
+fn upnp_conflict() {
+    let (addr, _) = mock_igd(Igd {
+        conflict: true,
+        ..Igd::default()
+    });
+    let device = upnp::search(addr, TIMEOUT).unwrap();
+
+    let e = device.map(localhost(40007), LIFETIME, TIMEOUT).unwrap_err();
+    assert_eq!(
+        io_error(&e),
+        Some(&Error::Upnp(718, "ConflictInMappingEntry".to_string()))
+    );
+}
+
+#[test]
+fn gateway_map_unspecified() {
+    let (addr, mappings) = mock_pcp();
+    let gateway = Gateway::Pcp(addr);
+    assert_eq!(gateway.protocol(), Protocol::Pcp);
+    assert_eq!(gateway.ip(), Ipv4Addr::LOCALHOST);
+
+    // The internal IP address is the one the host reaches the gateway with
+    let internal = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 40008);
+    let mapping = gateway.map(internal, LIFETIME, TIMEOUT).unwrap();
+    assert_eq!(mapping.internal(), localhost(40008));
+    assert_eq!(
+        mappings.lock().unwrap().get(&41008).unwrap().0,
+        localhost(40008)
+    );
+
+    gateway.unmap(&mapping, TIMEOUT).unwrap();
+    assert!(mappings.lock().unwrap().is_empty());
+}
+
+#[test]
+fn gateway_discover_without_gateway() {
+    // NAT-PMP and PCP are skipped without a gateway to probe
+    let e = Gateway::discover(&[Protocol::NatPmp, Protocol::Pcp], None, TIMEOUT).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::NotFound);
+}
+
+#[test]
+fn nat_test_mapped_sockets() {
+    let (server1, server2) = (Ipv4Addr::new(127, 0, 0, 6), Ipv4Addr::new(127, 0, 0, 7));
+    let _server = Server::bind(server1, server2, Ports::default()).unwrap();
+    let (addr, mappings) = mock_pcp();
+    let gateway = Gateway::Pcp(addr);
+
+    let mut bound = Vec::new();
+    let bind = |addr| {
+        let socket = Socket::bind(addr)?;
+        socket.set_read_timeout(Some(TIMEOUT))?;
+        bound.push(socket.local_addr()?);
+        Ok(Box::new(socket) as Box<dyn RW>)
+    };
+    let mapped = mapping::nat_test(
+        &gateway,
+        localhost(0),
+        bind,
+        server1,
+        server2,
+        LIFETIME,
+        TIMEOUT,
+    )
+    .unwrap();
+    assert_eq!(mapped.result().nat(), NatType::A);
+    assert!(mapped.unmap_error().is_none());
+
+    // The test runs on the sockets the ports are mapped for
+    assert_eq!(bound.len(), 2);
+    assert_ne!(bound[0], bound[1]);
+    assert_eq!(
+        mapped
+            .mappings()
+            .iter()
+            .map(Mapping::internal)
+            .collect::<Vec<_>>(),
+        bound
+    );
+    assert!(mappings.lock().unwrap().is_empty());
+}
+
+#[test]
+fn nat_test_unmap_failure() {
+    let (server1, server2) = (Ipv4Addr::new(127, 0, 0, 8), Ipv4Addr::new(127, 0, 0, 9));
+    let _server = Server::bind(server1, server2, Ports::default()).unwrap();
+    let (addr, mappings) = pcp_server(false);
+    let gateway = Gateway::Pcp(addr);
+
+    let bind = |addr| {
+        let socket = Socket::bind(addr)?;
+        socket.set_read_timeout(Some(TIMEOUT))?;
+        Ok(Box::new(socket) as Box<dyn RW>)
+    };
+    // The result is kept while the mappings are left on the gateway
+    let mapped = mapping::nat_test(
+        &gateway,
+        localhost(0),
+        bind,
+        server1,
+        server2,
+        LIFETIME,
+        TIMEOUT,
+    )
+    .unwrap();
+    assert_eq!(mapped.result().nat(), NatType::A);
+    assert!(mapped.unmap_error().is_some());
+    assert_eq!(mappings.lock().unwrap().len(), 2);
+}
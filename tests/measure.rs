@@ -0,0 +1,125 @@
+use ninat::protocol::PORT_2;
+use ninat::{EchoStats, Socket, RW};
+use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::time::Duration;
+
+mod common;
+
+use common::Mock;
+
+fn ms(ms: u64) -> Duration {
+    Duration::from_millis(ms)
+}
+
+/// Serves the echo port of the server, replying to the probes after the delays in order, or
+/// dropping them without a delay, and replying without delay once they run out.
+fn serve(server: Ipv4Addr, delays: Vec<Option<Duration>>) {
+    let socket = UdpSocket::bind(SocketAddrV4::new(server, PORT_2)).unwrap();
+    common::serve(socket, None, Mock::new().delays(delays));
+}
+
+fn socket(timeout: Duration) -> Socket {
+    let socket = Socket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)).unwrap();
+    socket.set_read_timeout(Some(timeout)).unwrap();
+
+    socket
+}
+
+#[test]
+fn echo_stats() {
+    let stats = EchoStats::new(5, vec![ms(10), ms(30), ms(20), ms(40)]);
+    assert_eq!(stats.sent(), 5);
+    assert_eq!(stats.received(), 4);
+    assert_eq!(stats.loss(), 0.2);
+    assert_eq!(stats.min(), Some(ms(10)));
+    assert_eq!(stats.avg(), Some(ms(25)));
+    assert_eq!(stats.max(), Some(ms(40)));
+    // The nearest rank of 4 round-trip times is the 4th
+    assert_eq!(stats.p95(), Some(ms(40)));
+    // Differences of 20, 10 and 20 ms
+    assert_eq!(stats.jitter(), Some(ms(50) / 3));
+
+    let stats = EchoStats::new(20, (1..=20).rev().map(ms).collect());
+    assert_eq!(stats.loss(), 0.0);
+    assert_eq!(stats.p95(), Some(ms(19)));
+    assert_eq!(stats.jitter(), Some(ms(1)));
+
+    let stats = EchoStats::new(1, vec![ms(10)]);
+    assert_eq!(stats.p95(), Some(ms(10)));
+    assert_eq!(stats.jitter(), Some(ms(0)));
+
+    let stats = EchoStats::new(3, Vec::new());
+    assert_eq!(stats.loss(), 1.0);
+    for value in [
+        stats.min(),
+        stats.avg(),
+        stats.max(),
+        stats.p95(),
+        stats.jitter(),
+    ]
+    .iter()
+    {
+        assert_eq!(*value, None);
+    }
+    assert_eq!(EchoStats::default().loss(), 0.0);
+}
+
+#[test]
+fn measure_in_order() {
+    // Replies arrive after the next probes are sent
+    let (server1, server2) = (Ipv4Addr::new(127, 0, 0, 10), Ipv4Addr::new(127, 0, 0, 11));
+    serve(server1, vec![Some(ms(80)); 4]);
+    serve(server2, vec![Some(ms(80)); 4]);
+
+    let socket = socket(ms(500));
+    let (stats1, stats2) = ninat::measure(&socket, server1, server2, 4, ms(30)).unwrap();
+    for stats in [stats1, stats2].iter() {
+        assert_eq!(stats.sent(), 4);
+        assert_eq!(stats.received(), 4);
+        assert_eq!(stats.late(), 0);
+        assert!(stats.min().unwrap() >= ms(80), "{:?}", stats.rtts());
+    }
+    assert_eq!(socket.read_timeout().unwrap(), Some(ms(500)));
+}
+
+#[test]
+fn measure_late() {
+    // The first reply arrives once its probe is lost, before the next probe is sent
+    let (server1, server2) = (Ipv4Addr::new(127, 0, 0, 12), Ipv4Addr::new(127, 0, 0, 13));
+    serve(server1, vec![Some(ms(150))]);
+    serve(server2, Vec::new());
+
+    let socket = socket(ms(100));
+    let (stats1, stats2) = ninat::measure(&socket, server1, server2, 2, ms(300)).unwrap();
+    assert_eq!(stats1.received(), 1);
+    assert_eq!(stats1.late(), 1);
+    assert_eq!(stats1.loss(), 0.5);
+    assert!(stats1.max().unwrap() < ms(100), "{:?}", stats1.rtts());
+    assert_eq!(stats2.received(), 2);
+    assert_eq!(stats2.late(), 0);
+}
+
+#[test]
+fn measure_lost() {
+    // The second probe is lost, while the others are answered before the next probe is sent
+    let (server1, server2) = (Ipv4Addr::new(127, 0, 0, 14), Ipv4Addr::new(127, 0, 0, 15));
+    serve(
+        server1,
+        vec![Some(ms(10)), None, Some(ms(10)), Some(ms(10))],
+    );
+    serve(server2, vec![Some(ms(10)); 4]);
+
+    let socket = socket(ms(500));
+    let (stats1, stats2) = ninat::measure(&socket, server1, server2, 4, ms(100)).unwrap();
+    assert_eq!(stats1.sent(), 4);
+    assert_eq!(stats1.received(), 3);
+    assert_eq!(stats1.late(), 0);
+    assert_eq!(stats1.loss(), 0.25);
+    // The lost probe takes the replies of the following ones, whose round-trip times are too long
+    // by an interval
+    let rtts = stats1.rtts();
+    assert!(rtts[0] < ms(100), "{:?}", rtts);
+    assert!(rtts[1] >= ms(100) && rtts[2] >= ms(100), "{:?}", rtts);
+    assert_eq!(stats2.received(), 4);
+    assert!(stats2.max().unwrap() < ms(100), "{:?}", stats2.rtts());
+}
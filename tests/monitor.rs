@@ -0,0 +1,228 @@
+#![cfg(target_os = "linux")]
+
+use ninat::monitor::{Change, Parser};
+use std::net::Ipv4Addr;
+
+/// Returns a netlink message of the kind, with the length of the header and the payload.
+fn message(kind: u16, payload: &[u8]) -> Vec<u8> {
+    let mut message = Vec::new();
+    message.extend_from_slice(&(16 + payload.len() as u32).to_ne_bytes());
+    message.extend_from_slice(&kind.to_ne_bytes());
+    message.extend_from_slice(&[0u8; 10]);
+    message.extend_from_slice(payload);
+    padded(message)
+}
+
+/// Returns an attribute of the kind, padded to 4 bytes.
+fn attribute(kind: u16, value: &[u8]) -> Vec<u8> {
+    let mut attribute = Vec::new();
+    attribute.extend_from_slice(&(4 + value.len() as u16).to_ne_bytes());
+    attribute.extend_from_slice(&kind.to_ne_bytes());
+    attribute.extend_from_slice(value);
+    padded(attribute)
+}
+
+fn padded(mut bytes: Vec<u8>) -> Vec<u8> {
+    bytes.resize((bytes.len() + 3) & !3, 0);
+
+    bytes
+}
+
+/// Returns a link message of the link of the index with the flags.
+fn link(kind: u16, index: u32, flags: u32) -> Vec<u8> {
+    let mut payload = vec![0u8; 4];
+    payload.extend_from_slice(&index.to_ne_bytes());
+    payload.extend_from_slice(&flags.to_ne_bytes());
+    payload.extend_from_slice(&[0u8; 4]);
+    message(kind, &payload)
+}
+
+/// Returns an address message of the link of the index with the attributes.
+fn address(kind: u16, index: u32, attributes: &[Vec<u8>]) -> Vec<u8> {
+    let mut payload = vec![libc::AF_INET as u8, 24, 0, libc::RT_SCOPE_UNIVERSE];
+    payload.extend_from_slice(&index.to_ne_bytes());
+    payload.extend(attributes.iter().flatten());
+    message(kind, &payload)
+}
+
+/// Returns a default route message of the main table through the gateway and the link of the
+/// index.
+fn default_route(kind: u16, gateway: Ipv4Addr, index: u32) -> Vec<u8> {
+    let mut payload = vec![libc::AF_INET as u8, 0, 0, 0, libc::RT_TABLE_MAIN, 0, 0, 0];
+    payload.extend_from_slice(&[0u8; 4]);
+    payload.extend(attribute(libc::RTA_GATEWAY, &gateway.octets()));
+    payload.extend(attribute(libc::RTA_OIF, &index.to_ne_bytes()));
+    message(kind, &payload)
+}
+
+const UP: u32 = (libc::IFF_UP | libc::IFF_RUNNING) as u32;
+const GATEWAY: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 1);
+const IP: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 2);
+
+#[test]
+fn parse_links() {
+    let mut parser = Parser::new();
+    // Links first seen are only reported if up, and loopback links never
+    assert!(parser.parse(&link(libc::RTM_NEWLINK, 2, 0)).is_empty());
+    assert!(parser
+        .parse(&link(libc::RTM_NEWLINK, 1, UP | libc::IFF_LOOPBACK as u32))
+        .is_empty());
+    assert_eq!(
+        parser.parse(&link(libc::RTM_NEWLINK, 2, UP)),
+        vec![Change::Link { index: 2, up: true }]
+    );
+    assert!(parser.parse(&link(libc::RTM_NEWLINK, 2, UP)).is_empty());
+    assert_eq!(
+        parser.parse(&link(libc::RTM_DELLINK, 2, UP)),
+        vec![Change::Link {
+            index: 2,
+            up: false
+        }]
+    );
+}
+
+#[test]
+fn parse_addresses() {
+    let mut parser = Parser::new();
+    let local = attribute(libc::IFA_LOCAL, &IP.octets());
+    let peer = attribute(libc::IFA_ADDRESS, &Ipv4Addr::new(192, 168, 1, 3).octets());
+    assert_eq!(
+        parser.parse(&address(libc::RTM_NEWADDR, 2, &[peer.clone(), local])),
+        vec![Change::Address {
+            index: 2,
+            ip: IP,
+            added: true
+        }]
+    );
+    assert_eq!(
+        parser.parse(&address(libc::RTM_DELADDR, 2, &[peer])),
+        vec![Change::Address {
+            index: 2,
+            ip: Ipv4Addr::new(192, 168, 1, 3),
+            added: false
+        }]
+    );
+    // Addresses without a value of 4 bytes are not reported
+    let short = attribute(libc::IFA_LOCAL, &[192, 168, 1]);
+    assert!(parser
+        .parse(&address(libc::RTM_NEWADDR, 2, &[short]))
+        .is_empty());
+}
+
+#[test]
+fn parse_uplink() {
+    let mut parser = Parser::new();
+    assert_eq!(parser.uplink(), None);
+    let mut datagram = link(libc::RTM_NEWLINK, 2, UP);
+    datagram.extend(link(libc::RTM_NEWLINK, 3, UP));
+    datagram.extend(default_route(libc::RTM_NEWROUTE, GATEWAY, 2));
+    assert_eq!(
+        parser.parse(&datagram),
+        vec![
+            Change::Link { index: 2, up: true },
+            Change::Link { index: 3, up: true },
+            Change::DefaultRoute {
+                gateway: Some(GATEWAY),
+                index: Some(2),
+                added: true
+            },
+        ]
+    );
+    assert_eq!(parser.uplink(), Some(2));
+
+    // Only changes of the uplink are reported
+    let local = || attribute(libc::IFA_LOCAL, &IP.octets());
+    assert!(parser.parse(&link(libc::RTM_NEWLINK, 3, 0)).is_empty());
+    assert!(parser
+        .parse(&address(libc::RTM_NEWADDR, 3, &[local()]))
+        .is_empty());
+    assert_eq!(
+        parser
+            .parse(&address(libc::RTM_NEWADDR, 2, &[local()]))
+            .len(),
+        1
+    );
+    assert_eq!(
+        parser.parse(&link(libc::RTM_NEWLINK, 2, 0)),
+        vec![Change::Link {
+            index: 2,
+            up: false
+        }]
+    );
+
+    // Every link is reported once the default route is removed
+    assert_eq!(
+        parser
+            .parse(&default_route(libc::RTM_DELROUTE, GATEWAY, 2))
+            .len(),
+        1
+    );
+    assert_eq!(parser.uplink(), None);
+    assert_eq!(
+        parser.parse(&link(libc::RTM_NEWLINK, 3, UP)),
+        vec![Change::Link { index: 3, up: true }]
+    );
+}
+
+#[test]
+fn parse_truncated() {
+    let mut parser = Parser::new();
+    let up = link(libc::RTM_NEWLINK, 2, UP);
+
+    // A message longer than the datagram ends it
+    let mut datagram = up.clone();
+    let mut truncated = link(libc::RTM_NEWLINK, 3, UP);
+    truncated.truncate(20);
+    datagram.extend(truncated);
+    assert_eq!(
+        parser.parse(&datagram),
+        vec![Change::Link { index: 2, up: true }]
+    );
+
+    // So does a message of zero length, which would never advance
+    let mut parser = Parser::new();
+    let mut datagram = vec![0u8; 16];
+    datagram.extend(up.clone());
+    assert!(parser.parse(&datagram).is_empty());
+
+    // A header shorter than its payload needs is ignored
+    let mut parser = Parser::new();
+    let mut datagram = message(libc::RTM_NEWLINK, &[0u8; 8]);
+    datagram.extend(up);
+    assert_eq!(
+        parser.parse(&datagram),
+        vec![Change::Link { index: 2, up: true }]
+    );
+    assert!(parser.parse(&[]).is_empty());
+    assert!(parser.parse(&[0u8; 7]).is_empty());
+}
+
+#[test]
+fn parse_truncated_attributes() {
+    let mut parser = Parser::new();
+    let local = attribute(libc::IFA_LOCAL, &IP.octets());
+
+    // An attribute longer than the message ends the attributes
+    let mut long = attribute(libc::IFA_ADDRESS, &GATEWAY.octets());
+    long[0..2].copy_from_slice(&64u16.to_ne_bytes());
+    assert!(parser
+        .parse(&address(libc::RTM_NEWADDR, 2, &[long, local.clone()]))
+        .is_empty());
+
+    // So does an attribute of zero length
+    let zero = vec![0u8; 4];
+    assert!(parser
+        .parse(&address(libc::RTM_NEWADDR, 2, &[zero, local.clone()]))
+        .is_empty());
+
+    // An attribute of its header only is skipped
+    let empty = attribute(libc::IFA_ADDRESS, &[]);
+    assert_eq!(
+        parser.parse(&address(libc::RTM_NEWADDR, 2, &[empty, local])),
+        vec![Change::Address {
+            index: 2,
+            ip: IP,
+            added: true
+        }]
+    );
+}
@@ -0,0 +1,976 @@
+use ninat::behavior::{FilteringBehavior, MappingBehavior, NatBehavior, PortAllocation};
+use ninat::cancel::{CancelToken, Cancelled};
+use ninat::consensus::{Consensus, Run};
+use ninat::observer::{Observer, Phase};
+use ninat::protocol::{Ports, Request, Response, REQUESTS};
+use ninat::server::Server;
+use ninat::tester::NatTester;
+use ninat::{Datagram, Failure, NatTestResult, NatType, ProxyError, Socket, SocketFactory, RW};
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener, UdpSocket};
+#[cfg(unix)]
+use std::os::unix::io::{FromRawFd, IntoRawFd};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+mod common;
+
+use common::{servers, Mock, SERVER_1, SERVER_2};
+
+const NAT_TYPES: [NatType; 5] = [NatType::A, NatType::B, NatType::C, NatType::D, NatType::F];
+
+/// Serves both servers on the loopback addresses, returning their ports. The server 2 reports the
+/// ports moved by `shift`.
+fn mock_servers(shift: u16) -> Ports {
+    forging_servers(shift, None)
+}
+
+/// Serves both servers like `mock_servers`, the server 1 answering the first echo with `forged`
+/// first if any.
+fn forging_servers(shift: u16, forged: Option<SocketAddrV4>) -> Ports {
+    servers(
+        Mock::new().forged(forged),
+        Mock::new().shift(move |_| shift),
+        true,
+    )
+}
+
+/// Serves both servers like `mock_servers`, the server 1 answering the request from another port
+/// from its echo port, as if the receiving only port were filtered.
+fn filtering_servers(shift: u16) -> Ports {
+    servers(Mock::new(), Mock::new().shift(move |_| shift), false)
+}
+
+fn tester(shift: u16) -> NatTester {
+    NatTester::new()
+        .servers(SERVER_1, SERVER_2)
+        .ports(mock_servers(shift))
+        .bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))
+        .timeout(Some(Duration::from_secs(2)))
+}
+
+#[test]
+fn nat_type_from_str() {
+    for nat in NAT_TYPES.iter() {
+        assert_eq!(nat.to_string().parse::<NatType>(), Ok(*nat));
+        assert_eq!(nat.nintendo().parse::<NatType>(), Ok(*nat));
+    }
+    assert_eq!(" c ".parse::<NatType>(), Ok(NatType::C));
+    assert_eq!("d".parse::<NatType>(), Ok(NatType::D));
+    assert!("E".parse::<NatType>().is_err());
+    assert!("Open".parse::<NatType>().is_err());
+}
+
+#[test]
+fn nat_type_serde() {
+    for nat in NAT_TYPES.iter() {
+        let json = serde_json::to_string(nat).unwrap();
+        assert_eq!(json, format!("\"{}\"", nat));
+        assert_eq!(serde_json::from_str::<NatType>(&json).unwrap(), *nat);
+    }
+    assert!(serde_json::from_str::<NatType>("\"E\"").is_err());
+}
+
+#[test]
+fn nat_type_meets() {
+    use NatType::*;
+
+    let cases = [
+        // Any type but F without an expectation
+        (A, None, None, true),
+        (D, None, None, true),
+        (F, None, None, false),
+        // The type expected only
+        (B, Some(B), None, true),
+        (A, Some(B), None, false),
+        (C, Some(B), None, false),
+        (F, Some(F), None, true),
+        // Types at least as open as the one expected
+        (A, None, Some(B), true),
+        (B, None, Some(B), true),
+        (C, None, Some(B), false),
+        (F, None, Some(D), false),
+        (F, None, Some(F), true),
+        // The type expected overrides the other
+        (A, Some(B), Some(C), false),
+        (B, Some(B), Some(A), true),
+    ];
+    for (nat, expect, expect_at_least, is_met) in cases.iter() {
+        assert_eq!(
+            nat.meets(*expect, *expect_at_least),
+            *is_met,
+            "{} against {:?} and {:?}",
+            nat,
+            expect,
+            expect_at_least
+        );
+    }
+}
+
+#[test]
+fn behavior_nat_type() {
+    let cases = [
+        (
+            MappingBehavior::EndpointIndependent,
+            FilteringBehavior::EndpointIndependent,
+            Some(PortAllocation::Preserved),
+            NatType::A,
+        ),
+        (
+            MappingBehavior::EndpointIndependent,
+            FilteringBehavior::EndpointIndependent,
+            None,
+            NatType::A,
+        ),
+        (
+            MappingBehavior::EndpointIndependent,
+            FilteringBehavior::AddressDependent,
+            None,
+            NatType::A,
+        ),
+        (
+            MappingBehavior::EndpointIndependent,
+            FilteringBehavior::AddressAndPortDependent,
+            None,
+            NatType::B,
+        ),
+        (
+            MappingBehavior::AddressDependent,
+            FilteringBehavior::AddressDependent,
+            Some(PortAllocation::Predictable(1)),
+            NatType::C,
+        ),
+        (
+            MappingBehavior::AddressAndPortDependent,
+            FilteringBehavior::AddressAndPortDependent,
+            Some(PortAllocation::Predictable(2)),
+            NatType::C,
+        ),
+        (
+            MappingBehavior::AddressAndPortDependent,
+            FilteringBehavior::AddressAndPortDependent,
+            Some(PortAllocation::Unpredictable),
+            NatType::D,
+        ),
+        (
+            MappingBehavior::AddressDependent,
+            FilteringBehavior::EndpointIndependent,
+            None,
+            NatType::D,
+        ),
+    ];
+    for (mapping, filtering, allocation, nat) in cases.iter() {
+        let behavior = NatBehavior::new(*mapping, *filtering, *allocation);
+        assert_eq!(behavior.nat(), *nat);
+        assert_eq!(NatType::from(behavior), *nat);
+        assert_eq!(behavior.nintendo(), nat.nintendo());
+        assert_eq!(behavior.sony(), nat.sony());
+        assert_eq!(behavior.microsoft(), nat.microsoft());
+    }
+}
+
+#[test]
+fn behavior_serde() {
+    let behavior = NatBehavior::new(
+        MappingBehavior::AddressAndPortDependent,
+        FilteringBehavior::AddressAndPortDependent,
+        Some(PortAllocation::Predictable(2)),
+    )
+    .with_hairpin(false);
+    assert_eq!(behavior.hairpin(), Some(false));
+
+    let json = serde_json::to_string(&behavior).unwrap();
+    assert_eq!(
+        serde_json::from_str::<NatBehavior>(&json).unwrap(),
+        behavior
+    );
+}
+
+#[test]
+fn nat_test_udp_blocked() {
+    let mut factory = || -> io::Result<Box<dyn RW>> {
+        let socket = Socket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        socket.set_read_timeout(Some(Duration::from_millis(200)))?;
+        Ok(Box::new(socket))
+    };
+
+    // Nothing listens on the servers
+    let result = ninat::nat_test(&mut factory, SERVER_1, SERVER_2).unwrap();
+    assert_eq!(result.nat(), NatType::F);
+    assert_eq!(result.failure(), Some(&Failure::UdpBlocked));
+    assert_eq!(result.ip(), None);
+    for observation in result.observations().iter() {
+        for request in REQUESTS.iter() {
+            assert_eq!(observation.send_error(*request), None);
+            assert_ne!(observation.is_answered(*request), Some(true));
+        }
+    }
+}
+
+/// Represents a socket on which sending to the echo port fails, like when a local firewall
+/// rejects it.
+struct Rejecting(Socket);
+
+impl RW for Rejecting {
+    fn local_addr(&self) -> io::Result<SocketAddrV4> {
+        self.0.local_addr()
+    }
+
+    fn send_to(&self, buf: &[u8], addr: SocketAddrV4) -> io::Result<usize> {
+        match addr.port() == Ports::default().echo() {
+            true => Err(io::Error::from(io::ErrorKind::PermissionDenied)),
+            false => self.0.send_to(buf, addr),
+        }
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddrV4)> {
+        self.0.recv_from(buf)
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.0.set_read_timeout(dur)
+    }
+
+    fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.0.set_write_timeout(dur)
+    }
+
+    fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        self.0.read_timeout()
+    }
+
+    fn write_timeout(&self) -> io::Result<Option<Duration>> {
+        self.0.write_timeout()
+    }
+}
+
+#[test]
+fn nat_test_local_send_failure() {
+    let mut factory = || -> io::Result<Box<dyn RW>> {
+        let socket = Socket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        socket.set_read_timeout(Some(Duration::from_millis(200)))?;
+        Ok(Box::new(Rejecting(socket)))
+    };
+
+    let result = ninat::nat_test(&mut factory, SERVER_1, SERVER_2).unwrap();
+    assert_eq!(result.nat(), NatType::F);
+    assert_eq!(result.server_ports(), Ports::default());
+    let echo = Ports::default().echo();
+    assert_eq!(
+        result.failure(),
+        Some(&Failure::LocalSendFailure(vec![echo]))
+    );
+    assert_eq!(
+        result.failure().unwrap().to_string(),
+        format!("sending UDP to port {} fails locally", echo)
+    );
+    let observation = &result.observations()[0];
+    assert_eq!(
+        observation.send_error(Request::Echo),
+        Some(io::ErrorKind::PermissionDenied)
+    );
+    assert_eq!(observation.send_error(Request::SendOnly), None);
+}
+
+#[test]
+fn tester_run() {
+    let result = tester(0).run().unwrap();
+    assert_eq!(result.nat(), NatType::A);
+    assert_eq!(result.ip(), Some(Ipv4Addr::LOCALHOST));
+    assert_eq!(result.failure(), None);
+    let behavior = result.behavior().unwrap();
+    assert_eq!(behavior.mapping(), MappingBehavior::EndpointIndependent);
+    assert_eq!(behavior.filtering(), FilteringBehavior::AddressDependent);
+    assert_eq!(behavior.allocation(), Some(PortAllocation::Preserved));
+
+    // A mapping dependent on the destination is only told apart as address-dependent
+    let result = tester(1).run().unwrap();
+    assert_eq!(result.nat(), NatType::C);
+    let behavior = result.behavior().unwrap();
+    assert_eq!(behavior.mapping(), MappingBehavior::AddressDependent);
+    assert_eq!(behavior.filtering(), FilteringBehavior::AddressDependent);
+}
+
+#[test]
+fn tester_debug() {
+    let tester = NatTester::new().proxy_auth("user", "secret");
+    let debug = format!("{:?}", tester);
+    assert!(debug.contains("<redacted>"), "{}", debug);
+    assert!(!debug.contains("user"), "{}", debug);
+    assert!(!debug.contains("secret"), "{}", debug);
+    assert!(format!("{:?}", NatTester::new()).contains("auth: None"));
+}
+
+#[test]
+fn tester_sockets() {
+    // The port allocation is unknown with one socket
+    for sockets in 0..2 {
+        let e = tester(7).sockets(sockets).run().unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    // Sockets after the first one are only created for a mapping dependent on the destination
+    let cases = [(0, 3, NatType::A, 1), (7, 2, NatType::C, 2)];
+    for (shift, sockets, nat, created) in cases.iter() {
+        let tester = tester(*shift).sockets(*sockets);
+        let mut count = 0;
+        let result = tester
+            .run_with_factory(&mut || {
+                count += 1;
+                tester.bind_socket()
+            })
+            .unwrap();
+        assert_eq!(result.nat(), *nat);
+        assert_eq!(result.observations().len(), *created);
+        assert_eq!(count, *created);
+    }
+}
+
+/// Represents a factory of sockets recording when they are created and released.
+struct Sockets<'a> {
+    tester: &'a NatTester,
+    events: Vec<(bool, u16)>,
+}
+
+impl SocketFactory for Sockets<'_> {
+    fn create(&mut self) -> io::Result<Box<dyn RW>> {
+        let rw = self.tester.bind_socket()?;
+        self.events.push((true, rw.local_addr()?.port()));
+
+        Ok(rw)
+    }
+
+    fn release(&mut self, rw: Box<dyn RW>) {
+        self.events.push((false, rw.local_addr().unwrap().port()));
+    }
+}
+
+#[test]
+fn tester_keeps_first_socket() {
+    let tester = tester(7);
+    let mut sockets = Sockets {
+        tester: &tester,
+        events: Vec::new(),
+    };
+    let result = tester.run_with_factory(&mut sockets).unwrap();
+    assert_eq!(result.nat(), NatType::C);
+
+    // The first socket is released only after the second one is tested
+    let port1 = result.observations()[0].local().port();
+    let port2 = result.observations()[1].local().port();
+    assert_ne!(port1, port2);
+    assert_eq!(
+        sockets.events,
+        vec![(true, port1), (true, port2), (false, port1), (false, port2)]
+    );
+}
+
+#[test]
+fn generic_sockets() {
+    let bind = || {
+        let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        socket
+    };
+    let udp = bind();
+    let arc = Arc::new(Socket::from_std(bind()));
+
+    let result = tester(7).run_with(&[&udp, &arc]).unwrap();
+    assert_eq!(result.nat(), NatType::C);
+    assert_eq!(
+        result.observations()[0].local(),
+        RW::local_addr(&udp).unwrap()
+    );
+    assert_eq!(result.observations()[1].local(), arc.local_addr().unwrap());
+    // The mappings are probed again, and loopback delivers between them
+    assert!(tester(7).hairpin_test(&udp, &arc).unwrap());
+}
+
+#[test]
+fn hairpin_test_unanswered() {
+    let bind = || {
+        let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_millis(300)))
+            .unwrap();
+        socket
+    };
+    let (udp1, udp2) = (bind(), bind());
+    // The server never answers, so the mappings are unknown
+    let server = UdpSocket::bind(SocketAddrV4::new(SERVER_1, 0)).unwrap();
+    let port = server.local_addr().unwrap().port();
+    let e = NatTester::new()
+        .servers(SERVER_1, SERVER_2)
+        .ports(Ports::new(port, port, port))
+        .hairpin_test(&udp1, &udp2)
+        .unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+    // The read timeouts are restored
+    assert_eq!(
+        udp1.read_timeout().unwrap(),
+        Some(Duration::from_millis(300))
+    );
+}
+
+#[derive(Debug, Eq, PartialEq)]
+enum Event {
+    Started(Phase),
+    Finished(Phase),
+    Sent(usize, Request),
+    Received(usize, Request),
+    Classified(NatType),
+}
+
+#[derive(Default)]
+struct Recorder(Mutex<Vec<Event>>);
+
+impl Observer for Recorder {
+    fn phase_started(&self, phase: Phase) {
+        self.0.lock().unwrap().push(Event::Started(phase));
+    }
+
+    fn phase_finished(&self, phase: Phase) {
+        self.0.lock().unwrap().push(Event::Finished(phase));
+    }
+
+    fn probe_sent(&self, socket: usize, request: Request, _: SocketAddrV4) {
+        self.0.lock().unwrap().push(Event::Sent(socket, request));
+    }
+
+    fn response_received(&self, socket: usize, _: SocketAddrV4, response: &Response) {
+        self.0
+            .lock()
+            .unwrap()
+            .push(Event::Received(socket, response.request()));
+    }
+
+    fn classified(&self, result: &NatTestResult) {
+        self.0.lock().unwrap().push(Event::Classified(result.nat()));
+    }
+}
+
+#[test]
+fn tester_observer() {
+    let recorder = Arc::new(Recorder::default());
+    let result = tester(7).observer(recorder.clone()).run().unwrap();
+    assert_eq!(result.nat(), NatType::C);
+
+    // Responses arrive in any order, repeated as every probe is sent several times
+    let mut events = recorder.0.lock().unwrap().split_off(0);
+    for socket in 1..=2 {
+        for request in REQUESTS[1..].iter() {
+            assert!(events.contains(&Event::Received(socket, *request)));
+        }
+    }
+    events.retain(|event| !matches!(event, Event::Received(_, _)));
+    let mut expected = Vec::new();
+    for (socket, phase) in [(1, Phase::Mapping), (2, Phase::Allocation)].iter() {
+        expected.push(Event::Started(*phase));
+        for request in REQUESTS.iter() {
+            expected.push(Event::Sent(*socket, *request));
+        }
+        expected.push(Event::Started(Phase::Inbound(*socket)));
+        expected.push(Event::Finished(Phase::Inbound(*socket)));
+        expected.push(Event::Finished(*phase));
+    }
+    expected.push(Event::Classified(NatType::C));
+    assert_eq!(events, expected);
+}
+
+#[test]
+fn tester_cancel() {
+    let token = CancelToken::new();
+    // Nothing listens on the servers, and the test waits for responses forever
+    let tester = NatTester::new()
+        .servers(SERVER_1, SERVER_2)
+        .bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))
+        .timeout(None)
+        .cancel_token(token.clone());
+    let handle = thread::spawn(move || tester.run());
+
+    thread::sleep(Duration::from_millis(200));
+    let start = Instant::now();
+    token.cancel();
+    let e = handle.join().unwrap().unwrap_err();
+    assert!(Cancelled::is(&e));
+    assert_eq!(e.kind(), io::ErrorKind::Interrupted);
+    assert!(start.elapsed() < Duration::from_secs(1));
+
+    // A cancelled token cancels tests before they start
+    let socket = Socket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let e = ninat::test_cancellable(&socket, SERVER_1, SERVER_2, &token).unwrap_err();
+    assert!(Cancelled::is(&e));
+}
+
+/// Serves a SOCKS5 proxy accepting UDP associations without authentication, relaying to a socket
+/// which never answers, and returns its address.
+fn socks_proxy() -> SocketAddrV4 {
+    let listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let proxy = match listener.local_addr().unwrap() {
+        SocketAddr::V4(addr) => addr,
+        _ => unreachable!(),
+    };
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => return,
+            };
+            thread::spawn(move || -> io::Result<()> {
+                let relay = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+                let port = relay.local_addr()?.port().to_be_bytes();
+                // Greeting without authentication, then the UDP association to any address
+                let mut buffer = [0u8; 10];
+                stream.read_exact(&mut buffer[..3])?;
+                stream.write_all(&[5, 0])?;
+                stream.read_exact(&mut buffer)?;
+                stream.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, port[0], port[1]])?;
+                // The association lasts as long as the connection
+                while stream.read(&mut buffer)? > 0 {}
+                drop(relay);
+                Ok(())
+            });
+        }
+    });
+
+    proxy
+}
+
+#[test]
+fn datagram_cancel() {
+    let token = CancelToken::new();
+    // The relay never answers, and the test waits for responses forever
+    let tester = NatTester::new()
+        .servers(SERVER_1, SERVER_2)
+        .proxy(socks_proxy())
+        .bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))
+        .timeout(None)
+        .cancel_token(token.clone());
+    let handle = thread::spawn(move || tester.run());
+
+    thread::sleep(Duration::from_millis(200));
+    let start = Instant::now();
+    token.cancel();
+    let e = handle.join().unwrap().unwrap_err();
+    assert!(Cancelled::is(&e), "{}", e);
+    assert!(start.elapsed() < Duration::from_secs(1));
+}
+
+/// Represents a socket on which a packet is queued every 50 ms, counting the probes sent.
+struct Queued {
+    socket: Socket,
+    sent: AtomicUsize,
+}
+
+impl RW for Queued {
+    fn local_addr(&self) -> io::Result<SocketAddrV4> {
+        self.socket.local_addr()
+    }
+
+    fn send_to(&self, buf: &[u8], addr: SocketAddrV4) -> io::Result<usize> {
+        self.sent.fetch_add(1, Ordering::SeqCst);
+        self.socket.send_to(buf, addr)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddrV4)> {
+        thread::sleep(Duration::from_millis(50));
+        buf[0] = 0;
+        Ok((1, SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1)))
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.socket.set_read_timeout(dur)
+    }
+
+    fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.socket.set_write_timeout(dur)
+    }
+
+    fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        self.socket.read_timeout()
+    }
+
+    fn write_timeout(&self) -> io::Result<Option<Duration>> {
+        self.socket.write_timeout()
+    }
+}
+
+#[test]
+fn drain_cancel() {
+    let token = CancelToken::new();
+    // Draining the queued packets takes seconds
+    let queued = Arc::new(Queued {
+        socket: Socket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)).unwrap(),
+        sent: AtomicUsize::new(0),
+    });
+    let rw = queued.clone();
+    let cancel = token.clone();
+    let handle =
+        thread::spawn(move || ninat::test_cancellable(rw.as_ref(), SERVER_1, SERVER_2, &cancel));
+
+    thread::sleep(Duration::from_millis(200));
+    let start = Instant::now();
+    token.cancel();
+    let e = handle.join().unwrap().unwrap_err();
+    assert!(Cancelled::is(&e), "{}", e);
+    assert!(start.elapsed() < Duration::from_millis(500));
+    // The test is cancelled before any probe is sent
+    assert_eq!(queued.sent.load(Ordering::SeqCst), 0);
+}
+
+#[test]
+fn consensus() {
+    let ip1 = Ipv4Addr::new(192, 0, 2, 1);
+    let ip2 = Ipv4Addr::new(192, 0, 2, 2);
+    let runs = [
+        (NatType::C, Some(ip1)),
+        (NatType::B, Some(ip1)),
+        (NatType::F, None),
+        (NatType::C, Some(ip2)),
+        (NatType::B, Some(ip1)),
+    ];
+    let consensus = Consensus::new(
+        runs.iter()
+            .map(|(nat, ip)| Run::new(*nat, *ip, Duration::from_millis(100)))
+            .collect(),
+    );
+    assert_eq!(
+        consensus.distribution(),
+        vec![(NatType::B, 2), (NatType::C, 2), (NatType::F, 1)]
+    );
+    // The stricter type wins a tie
+    assert_eq!(consensus.majority(), Some(NatType::C));
+    assert!((consensus.confidence() - 0.4).abs() < f64::EPSILON);
+    assert_eq!(consensus.ips(), vec![ip1, ip2]);
+    assert_eq!(consensus.ip_changes(), 2);
+
+    // Failed runs lose to any NAT type found
+    let e = io::Error::from(io::ErrorKind::ConnectionRefused);
+    let elapsed = Duration::from_millis(100);
+    let consensus = Consensus::new(vec![
+        Run::failed(&e, elapsed),
+        Run::new(NatType::D, Some(ip1), elapsed),
+        Run::failed(&e, elapsed),
+    ]);
+    assert_eq!(consensus.majority(), Some(NatType::D));
+    assert!((consensus.confidence() - 1.0 / 3.0).abs() < f64::EPSILON);
+    assert_eq!(consensus.runs()[0].nat(), NatType::F);
+    assert!(consensus.runs()[0].error().is_some());
+    assert_eq!(consensus.runs()[1].error(), None);
+
+    let consensus = Consensus::new(vec![Run::failed(&e, elapsed); 2]);
+    assert_eq!(consensus.majority(), Some(NatType::F));
+    assert_eq!(consensus.confidence(), 1.0);
+
+    let consensus = Consensus::new(Vec::new());
+    assert_eq!(consensus.majority(), None);
+    assert_eq!(consensus.confidence(), 0.0);
+}
+
+#[test]
+fn tester_series() {
+    let tester = tester(0);
+    let consensus = tester.run_series(3, Duration::from_millis(10)).unwrap();
+    assert_eq!(consensus.runs().len(), 3);
+    assert_eq!(consensus.distribution(), vec![(NatType::A, 3)]);
+    assert_eq!(consensus.majority(), Some(NatType::A));
+    assert_eq!(consensus.ips(), vec![Ipv4Addr::LOCALHOST]);
+    assert_eq!(consensus.ip_changes(), 0);
+
+    let e = tester.run_series(0, Duration::ZERO).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+
+    // The series goes on after a run fails
+    let mut created = 0;
+    let mut factory = || -> io::Result<Box<dyn RW>> {
+        created += 1;
+        match created {
+            1 => Err(io::Error::from(io::ErrorKind::AddrInUse)),
+            _ => Ok(Box::new(Socket::bind(SocketAddrV4::new(
+                Ipv4Addr::LOCALHOST,
+                0,
+            ))?)),
+        }
+    };
+    let consensus = tester
+        .run_series_with_factory(&mut factory, 3, Duration::from_millis(10))
+        .unwrap();
+    assert_eq!(consensus.runs().len(), 3);
+    assert!(consensus.runs()[0].error().is_some());
+    assert_eq!(
+        consensus.distribution(),
+        vec![(NatType::A, 2), (NatType::F, 1)]
+    );
+    assert_eq!(consensus.majority(), Some(NatType::A));
+
+    // A cancelled series stops
+    let token = CancelToken::new();
+    token.cancel();
+    let e = tester
+        .cancel_token(token)
+        .run_series(3, Duration::from_millis(10))
+        .unwrap_err();
+    assert!(Cancelled::is(&e));
+}
+
+#[test]
+fn verify_forward() {
+    let socket = Socket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)).unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    let port = socket.local_addr().unwrap().port();
+
+    let verification = tester(0).verify_forward(&socket, port, 2).unwrap();
+    assert_eq!(verification.mapped().len(), 2);
+    assert!(verification.is_verified());
+
+    let verification = tester(0).verify_forward(&socket, port ^ 1, 1).unwrap();
+    assert!(!verification.is_port_matched());
+    assert!(verification.is_inbound_allowed());
+    assert!(verification.is_stable());
+
+    // The server 2 sees another port
+    let verification = tester(7).verify_forward(&socket, port, 1).unwrap();
+    assert!(!verification.is_stable());
+    assert!(!verification.is_verified());
+
+    let e = tester(0).verify_forward(&socket, port, 0).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn verify_forward_unanswered() {
+    let socket = Socket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)).unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_millis(300)))
+        .unwrap();
+    let port = socket.local_addr().unwrap().port();
+    // The servers never answer
+    let server = UdpSocket::bind(SocketAddrV4::new(SERVER_1, 0)).unwrap();
+    let server_port = server.local_addr().unwrap().port();
+
+    // A failed run is recorded rather than aborting the verification
+    let start = Instant::now();
+    let verification = NatTester::new()
+        .servers(SERVER_1, SERVER_2)
+        .ports(Ports::new(server_port, server_port, server_port))
+        .verify_forward(&socket, port, 2)
+        .unwrap();
+    assert!(start.elapsed() >= ninat::forward::RUN_INTERVAL);
+    assert_eq!(verification.mapped(), &[None, None]);
+    assert!(verification.errors().iter().all(|e| e.is_some()));
+    assert!(!verification.is_port_matched());
+    assert!(!verification.is_stable());
+    assert!(!verification.is_verified());
+}
+
+#[test]
+fn server() {
+    let server1 = Ipv4Addr::new(127, 0, 0, 4);
+    let server2 = Ipv4Addr::new(127, 0, 0, 5);
+    let server = Server::bind(server1, server2, Ports::default()).unwrap();
+
+    let result = NatTester::new()
+        .servers(server.server1(), server.server2())
+        .timeout(Some(Duration::from_millis(500)))
+        .run()
+        .unwrap();
+    assert_eq!(result.nat(), NatType::A);
+    assert_eq!(result.ip(), Some(Ipv4Addr::LOCALHOST));
+}
+
+#[test]
+fn test_flooded() {
+    let socket = Socket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)).unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .unwrap();
+    let addr = socket.local_addr().unwrap();
+
+    // A peer keeps sending faster than one packet per millisecond
+    let stop = Arc::new(AtomicBool::new(false));
+    let flooding = stop.clone();
+    let flooder = thread::spawn(move || {
+        let peer = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)).unwrap();
+        while !flooding.load(Ordering::SeqCst) {
+            let _ = peer.send_to(b"flood", addr);
+            thread::sleep(Duration::from_micros(100));
+        }
+    });
+    thread::sleep(Duration::from_millis(50));
+
+    let start = Instant::now();
+    let result = tester(0).run_with(&[&socket]);
+    let elapsed = start.elapsed();
+    stop.store(true, Ordering::SeqCst);
+    flooder.join().unwrap();
+
+    let result = result.unwrap();
+    assert!(elapsed < Duration::from_secs(2), "took {:?}", elapsed);
+    assert!(result.discarded().unsolicited() > 0);
+}
+
+#[test]
+fn test_deadline() {
+    let socket = Socket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)).unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_millis(500)))
+        .unwrap();
+    let addr = socket.local_addr().unwrap();
+
+    // A peer keeps sending more often than the read timeout, while no server answers
+    let stop = Arc::new(AtomicBool::new(false));
+    let trickling = stop.clone();
+    let trickler = thread::spawn(move || {
+        let peer = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)).unwrap();
+        while !trickling.load(Ordering::SeqCst) {
+            let _ = peer.send_to(b"trickle", addr);
+            thread::sleep(Duration::from_millis(20));
+        }
+    });
+
+    let start = Instant::now();
+    let result = ninat::test(&socket, SERVER_1, SERVER_2);
+    let elapsed = start.elapsed();
+    stop.store(true, Ordering::SeqCst);
+    trickler.join().unwrap();
+
+    assert!(result.is_err());
+    assert!(elapsed >= Duration::from_millis(500), "took {:?}", elapsed);
+    assert!(elapsed < Duration::from_millis(900), "took {:?}", elapsed);
+    // The timeout is restored
+    assert_eq!(
+        socket.read_timeout().unwrap(),
+        Some(Duration::from_millis(500))
+    );
+}
+
+#[test]
+fn test_early_termination() {
+    let timeout = Some(Duration::from_secs(5));
+
+    // The test ends as soon as every port is answered
+    let tester = tester(1).timeout(timeout);
+    let start = Instant::now();
+    let result = tester.run().unwrap();
+    let elapsed = start.elapsed();
+    assert_eq!(result.nat(), NatType::C);
+    assert!(elapsed < Duration::from_secs(1), "took {:?}", elapsed);
+
+    // Or once the receiving only port is given up on shortly after both echoes
+    let ports = filtering_servers(0);
+    let tester = NatTester::new()
+        .servers(SERVER_1, SERVER_2)
+        .ports(ports)
+        .bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))
+        .timeout(timeout);
+    let start = Instant::now();
+    let result = tester.run().unwrap();
+    let elapsed = start.elapsed();
+    assert_eq!(result.nat(), NatType::B);
+    assert_eq!(result.server_ports(), ports);
+    assert!(result
+        .observations()
+        .iter()
+        .all(|o| o.is_answered(Request::AnotherPort) == Some(false)),);
+    assert!(elapsed < Duration::from_secs(1), "took {:?}", elapsed);
+}
+
+#[test]
+fn test_forged_reply() {
+    // A forged IP address disagrees with the server 2, and a forged port is outnumbered
+    let forgeries = [
+        SocketAddrV4::new(Ipv4Addr::new(203, 0, 113, 9), 4242),
+        SocketAddrV4::new(Ipv4Addr::LOCALHOST, 4242),
+    ];
+    for forged in forgeries.iter() {
+        let tester = NatTester::new()
+            .servers(SERVER_1, SERVER_2)
+            .ports(forging_servers(0, Some(*forged)))
+            .bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))
+            .timeout(Some(Duration::from_secs(2)));
+        let socket = tester.bind_socket().unwrap();
+        let local = socket.local_addr().unwrap();
+
+        let result = tester.run_with(&[socket.as_ref()]).unwrap();
+        assert_eq!(result.nat(), NatType::A);
+        assert_eq!(result.ip(), Some(Ipv4Addr::LOCALHOST));
+        assert_eq!(result.observations()[0].remote1(), Some(local));
+        assert!(result.discarded().invalid() >= 1);
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn socket_from_raw_fd() {
+    let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let addr = socket.local_addr().unwrap();
+    let socket = unsafe { Socket::from_raw_fd(socket.into_raw_fd()) };
+    assert_eq!(SocketAddr::V4(socket.local_addr().unwrap()), addr);
+
+    // Nothing listens on the servers
+    socket
+        .set_read_timeout(Some(Duration::from_millis(200)))
+        .unwrap();
+    let e = ninat::test(&socket, SERVER_1, SERVER_2).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+}
+
+#[cfg(unix)]
+#[test]
+fn socket_from_raw_fd_checked() {
+    use std::fs::File;
+    use std::net::UdpSocket as StdUdpSocket;
+    use std::os::unix::io::AsRawFd;
+
+    let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let addr = socket.local_addr().unwrap();
+    let socket = unsafe { Socket::from_raw_fd_checked(socket.into_raw_fd()) }.unwrap();
+    assert_eq!(SocketAddr::V4(socket.local_addr().unwrap()), addr);
+
+    // Descriptors which are not UDP sockets of IPv4 are left alone
+    let listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let e = unsafe { Socket::from_raw_fd_checked(listener.as_raw_fd()) }.unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    assert!(listener.local_addr().is_ok());
+    if let Ok(socket) = StdUdpSocket::bind("[::1]:0") {
+        let e = unsafe { Socket::from_raw_fd_checked(socket.as_raw_fd()) }.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    }
+    let file = File::open("/dev/null").unwrap();
+    let e = unsafe { Socket::from_raw_fd_checked(file.as_raw_fd()) }.unwrap_err();
+    assert_eq!(e.raw_os_error(), Some(libc::ENOTSOCK));
+}
+
+#[test]
+fn proxy_error() {
+    // Nothing listens on the proxy
+    let listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let proxy = match listener.local_addr().unwrap() {
+        SocketAddr::V4(addr) => addr,
+        _ => unreachable!(),
+    };
+    drop(listener);
+
+    let bind = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0);
+    let e = Datagram::bind(proxy, bind, None).unwrap_err();
+    assert!(ProxyError::is(&e), "{}", e);
+    assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused);
+
+    // Local sockets fail on their own
+    let socket = Socket::bind(bind).unwrap();
+    let e = Socket::bind(socket.local_addr().unwrap()).unwrap_err();
+    assert!(!ProxyError::is(&e));
+
+    let e = NatTester::new()
+        .servers(SERVER_1, SERVER_2)
+        .proxy(proxy)
+        .run()
+        .unwrap_err();
+    assert!(ProxyError::is(&e), "{}", e);
+}
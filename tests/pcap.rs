@@ -0,0 +1,254 @@
+use ninat::pcap::{Capture, PcapWriter};
+use ninat::{Socket, RW};
+use std::convert::TryInto;
+use std::io::{self, Write};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, UNIX_EPOCH};
+
+fn u16_le(bytes: &[u8]) -> u16 {
+    u16::from_le_bytes(bytes[..2].try_into().unwrap())
+}
+
+fn u32_le(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(bytes[..4].try_into().unwrap())
+}
+
+/// Returns the types and bodies of the blocks of a pcap-ng file, checking their lengths.
+fn blocks(mut file: &[u8]) -> Vec<(u32, &[u8])> {
+    let mut blocks = Vec::new();
+    while !file.is_empty() {
+        let len = u32_le(&file[4..]) as usize;
+        assert_eq!(len % 4, 0, "block of {} bytes", len);
+        assert_eq!(u32_le(&file[len - 4..]) as usize, len);
+        blocks.push((u32_le(file), &file[8..len - 4]));
+        file = &file[len..];
+    }
+
+    blocks
+}
+
+/// Returns the options of the body, checking their padding and end.
+fn options(mut body: &[u8]) -> Vec<(u16, &[u8])> {
+    let mut options = Vec::new();
+    loop {
+        let (code, len) = (u16_le(body), u16_le(&body[2..]) as usize);
+        if code == 0 {
+            assert_eq!(len, 0);
+            assert_eq!(body.len(), 4);
+            return options;
+        }
+        options.push((code, &body[4..4 + len]));
+        body = &body[4 + ((len + 3) & !3)..];
+    }
+}
+
+/// Returns the Internet checksum of the concatenated data, which is 0 if it holds its checksum.
+fn checksum(data: &[&[u8]]) -> u16 {
+    let bytes: Vec<u8> = data.iter().flat_map(|data| data.iter()).copied().collect();
+    let mut sum: u32 = bytes
+        .chunks(2)
+        .map(|chunk| match chunk {
+            [high, low] => u16::from_be_bytes([*high, *low]) as u32,
+            [high] => u16::from_be_bytes([*high, 0]) as u32,
+            _ => unreachable!(),
+        })
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    !(sum as u16)
+}
+
+/// Returns the source, the destination and the payload of a synthesized packet, checking its
+/// headers and checksums.
+fn packet(packet: &[u8]) -> (SocketAddrV4, SocketAddrV4, &[u8]) {
+    let (ip, udp) = packet.split_at(20);
+    assert_eq!(ip[0], 0x45);
+    assert_eq!(u16::from_be_bytes([ip[2], ip[3]]) as usize, packet.len());
+    assert_eq!(ip[9], 17);
+    assert_eq!(checksum(&[ip]), 0);
+
+    let src = Ipv4Addr::new(ip[12], ip[13], ip[14], ip[15]);
+    let dst = Ipv4Addr::new(ip[16], ip[17], ip[18], ip[19]);
+    assert_eq!(u16::from_be_bytes([udp[4], udp[5]]) as usize, udp.len());
+    let mut pseudo = Vec::new();
+    pseudo.extend_from_slice(&ip[12..20]);
+    pseudo.extend_from_slice(&[0, 17]);
+    pseudo.extend_from_slice(&(udp.len() as u16).to_be_bytes());
+    assert_ne!(u16::from_be_bytes([udp[6], udp[7]]), 0);
+    assert_eq!(checksum(&[&pseudo, udp]), 0);
+
+    (
+        SocketAddrV4::new(src, u16::from_be_bytes([udp[0], udp[1]])),
+        SocketAddrV4::new(dst, u16::from_be_bytes([udp[2], udp[3]])),
+        &udp[8..],
+    )
+}
+
+/// Returns the packet and the comment of an enhanced packet block.
+fn enhanced_packet(body: &[u8]) -> (&[u8], Option<&[u8]>) {
+    assert_eq!(u32_le(body), 0);
+    let (captured, original) = (u32_le(&body[12..]) as usize, u32_le(&body[16..]) as usize);
+    assert_eq!(captured, original);
+    let packet = &body[20..20 + captured];
+    let options = options_of(&body[20 + ((captured + 3) & !3)..]);
+    let comment = options
+        .iter()
+        .find(|(code, _)| *code == 1)
+        .map(|(_, value)| *value);
+
+    (packet, comment)
+}
+
+fn options_of(body: &[u8]) -> Vec<(u16, &[u8])> {
+    match body.is_empty() {
+        true => Vec::new(),
+        false => options(body),
+    }
+}
+
+#[test]
+fn pcap_blocks() {
+    let src = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 2), 50000);
+    let dst = SocketAddrV4::new(Ipv4Addr::new(203, 0, 113, 5), 10025);
+    let time = UNIX_EPOCH + Duration::from_micros(0x1_0000_0002);
+
+    let mut writer = PcapWriter::new(Vec::new()).unwrap();
+    // Payloads of odd lengths are padded, and sum with a byte of zero
+    writer
+        .write_datagram(src, dst, b"odd", time, Some("a comment"))
+        .unwrap();
+    writer
+        .write_datagram(dst, src, &[0xff; 4], time, None)
+        .unwrap();
+    let file = writer.into_inner();
+
+    let blocks = blocks(&file);
+    let types: Vec<_> = blocks.iter().map(|(kind, _)| *kind).collect();
+    assert_eq!(types, vec![0x0a0d_0d0a, 1, 6, 6]);
+
+    // Section header
+    let shb = blocks[0].1;
+    assert_eq!(u32_le(shb), 0x1a2b_3c4d);
+    assert_eq!((u16_le(&shb[4..]), u16_le(&shb[6..])), (1, 0));
+    assert_eq!(&shb[8..16], &[0xff; 8]);
+    let userappl = options(&shb[16..]);
+    assert_eq!(userappl[0].0, 4);
+    assert!(userappl[0].1.starts_with(b"ninat "));
+
+    // Interface description of raw IP
+    let idb = blocks[1].1;
+    assert_eq!(u16_le(idb), 101);
+    assert_eq!(u32_le(&idb[4..]), 0);
+    assert_eq!(options(&idb[8..]), vec![(2, &b"ninat"[..])]);
+
+    // Enhanced packets
+    let epb = blocks[2].1;
+    assert_eq!((u32_le(&epb[4..]), u32_le(&epb[8..])), (1, 2));
+    let (data, comment) = enhanced_packet(epb);
+    assert_eq!(packet(data), (src, dst, &b"odd"[..]));
+    assert_eq!(comment, Some(&b"a comment"[..]));
+    let (data, comment) = enhanced_packet(blocks[3].1);
+    assert_eq!(packet(data), (dst, src, &[0xff; 4][..]));
+    assert_eq!(comment, None);
+    // Packets are numbered
+    assert_ne!(&blocks[2].1[24..26], &blocks[3].1[24..26]);
+}
+
+/// Represents a writer failing once `fail` is set, counting the flushes.
+struct Failing {
+    fail: Arc<AtomicBool>,
+    flushes: Arc<AtomicUsize>,
+}
+
+impl Write for Failing {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.fail.load(Ordering::SeqCst) {
+            true => Err(io::Error::other("disk full")),
+            false => Ok(buf.len()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flushes.fetch_add(1, Ordering::SeqCst);
+
+        Ok(())
+    }
+}
+
+#[test]
+fn capture() {
+    let peer = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)).unwrap();
+    peer.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+    let peer_addr = match peer.local_addr().unwrap() {
+        SocketAddr::V4(addr) => addr,
+        _ => unreachable!(),
+    };
+    // The socket is bound to any address
+    let socket = Socket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)).unwrap();
+    let port = socket.local_addr().unwrap().port();
+    let writer = Arc::new(Mutex::new(PcapWriter::new(Vec::new()).unwrap()));
+    let capture = Capture::new(Box::new(socket), "socket 1", writer.clone());
+    capture
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .unwrap();
+
+    capture.send_to(b"probe", peer_addr).unwrap();
+    let mut buffer = [0u8; 16];
+    let (size, from) = peer.recv_from(&mut buffer).unwrap();
+    assert_eq!(&buffer[..size], b"probe");
+    peer.send_to(b"answer", from).unwrap();
+    assert_eq!(capture.recv_from(&mut buffer).unwrap(), (6, peer_addr));
+
+    // The source is the local address of the route
+    let local = SocketAddrV4::new(Ipv4Addr::LOCALHOST, port);
+    let writer = writer.lock().unwrap();
+    let blocks = blocks(writer.get_ref());
+    let (data, comment) = enhanced_packet(blocks[2].1);
+    assert_eq!(packet(data), (local, peer_addr, &b"probe"[..]));
+    assert!(comment.unwrap().starts_with(b"socket 1, "));
+    let (data, _) = enhanced_packet(blocks[3].1);
+    assert_eq!(packet(data), (peer_addr, local, &b"answer"[..]));
+}
+
+#[test]
+fn capture_write_error() {
+    let peer = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)).unwrap();
+    peer.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+    let peer_addr = match peer.local_addr().unwrap() {
+        SocketAddr::V4(addr) => addr,
+        _ => unreachable!(),
+    };
+    let fail = Arc::new(AtomicBool::new(false));
+    let flushes = Arc::new(AtomicUsize::new(0));
+    let writer = PcapWriter::new(Failing {
+        fail: fail.clone(),
+        flushes: flushes.clone(),
+    })
+    .unwrap();
+    let writer = Arc::new(Mutex::new(writer));
+    let socket = Socket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let capture = Capture::new(Box::new(socket), "socket 1", writer.clone());
+    capture
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .unwrap();
+
+    // Datagrams are recorded without flushing, which is left to the end
+    capture.send_to(b"probe", peer_addr).unwrap();
+    peer.recv_from(&mut [0u8; 16]).unwrap();
+    assert_eq!(flushes.load(Ordering::SeqCst), 0);
+    writer.lock().unwrap().flush().unwrap();
+    assert_eq!(flushes.load(Ordering::SeqCst), 1);
+
+    // Datagrams are sent and received even if they cannot be recorded
+    fail.store(true, Ordering::SeqCst);
+    assert_eq!(capture.send_to(b"probe", peer_addr).unwrap(), 5);
+    let mut buffer = [0u8; 16];
+    let (size, from) = peer.recv_from(&mut buffer).unwrap();
+    assert_eq!(&buffer[..size], b"probe");
+    peer.send_to(b"answer", from).unwrap();
+    assert_eq!(capture.recv_from(&mut buffer).unwrap(), (6, peer_addr));
+}
@@ -0,0 +1,125 @@
+use ninat::protocol::{
+    Body, Error, Ports, Request, Response, PACKET_LEN, PORT_1, PORT_2, PORT_3, REQUESTS,
+};
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+fn body() -> Body {
+    Body::new(
+        SocketAddrV4::new(Ipv4Addr::new(203, 0, 113, 7), 54321),
+        Ipv4Addr::new(192, 168, 1, 2),
+    )
+}
+
+#[test]
+fn request_round_trip() {
+    for request in REQUESTS.iter() {
+        let buf = request.encode();
+        assert_eq!(buf.len(), PACKET_LEN);
+        assert_eq!(buf[3], request.unique_number());
+        assert_eq!(Request::decode(&buf), Ok(*request));
+    }
+}
+
+#[test]
+fn request_ports() {
+    let ports = Ports::default();
+    assert_eq!(Request::SendOnly.port(&ports), PORT_1);
+    assert_eq!(Request::SendOnly.reply_port(&ports), None);
+    assert_eq!(Request::Echo.reply_port(&ports), Some(PORT_2));
+    assert_eq!(Request::AnotherPort.port(&ports), PORT_2);
+    assert_eq!(Request::AnotherPort.reply_port(&ports), Some(PORT_3));
+    assert_eq!(Request::SecondEcho.reply_port(&ports), Some(PORT_2));
+
+    // Custom ports are followed
+    let ports = Ports::new(1001, 1002, 1003);
+    assert_eq!(Request::SendOnly.port(&ports), 1001);
+    assert_eq!(Request::Echo.port(&ports), 1002);
+    assert_eq!(Request::Echo.reply_port(&ports), Some(1002));
+    assert_eq!(Request::AnotherPort.reply_port(&ports), Some(1003));
+    assert_eq!(Request::SecondEcho.port(&ports), 1002);
+}
+
+#[test]
+fn response_round_trip() {
+    for request in REQUESTS.iter().skip(1) {
+        let resp = Response::new(*request, body()).unwrap();
+        let buf = resp.encode();
+        assert_eq!(&buf[..4], &request.encode()[..4]);
+        assert_eq!(&buf[6..8], &54321u16.to_be_bytes());
+        assert_eq!(&buf[8..12], &[203, 0, 113, 7]);
+        assert_eq!(&buf[12..], &[192, 168, 1, 2]);
+
+        let decoded = Response::decode(&buf).unwrap();
+        assert_eq!(decoded, resp);
+        assert_eq!(decoded.request(), *request);
+        assert_eq!(
+            decoded.remote_addr(),
+            SocketAddrV4::new(Ipv4Addr::new(203, 0, 113, 7), 54321)
+        );
+        assert_eq!(decoded.local_ip(), Ipv4Addr::new(192, 168, 1, 2));
+        assert!(decoded.is_reply_to(*request));
+    }
+}
+
+#[test]
+fn response_to_send_only() {
+    assert_eq!(
+        Response::new(Request::SendOnly, body()),
+        Err(Error::Unanswered(Request::SendOnly))
+    );
+    assert_eq!(
+        Response::decode(&Request::SendOnly.encode()),
+        Err(Error::Unanswered(Request::SendOnly))
+    );
+}
+
+#[test]
+fn malformed_length() {
+    for len in [0, 1, 15, 17, 64].iter() {
+        let buf = vec![0u8; *len];
+        assert_eq!(Request::decode(&buf), Err(Error::Length(*len)));
+        assert_eq!(Response::decode(&buf), Err(Error::Length(*len)));
+    }
+}
+
+#[test]
+fn malformed_unique_number() {
+    let mut buf = Response::new(Request::Echo, body()).unwrap().encode();
+    buf[3] = 0x68;
+    assert_eq!(Request::decode(&buf), Err(Error::UniqueNumber(0x68)));
+    assert_eq!(Response::decode(&buf), Err(Error::UniqueNumber(0x68)));
+}
+
+#[test]
+fn malformed_fields() {
+    let buf = Response::new(Request::Echo, body()).unwrap().encode();
+
+    let mut head = buf;
+    head[0] = 1;
+    let resp = Response::decode(&head).unwrap();
+    assert_eq!(resp.body().validate(), Err(Error::Head([1, 0, 0])));
+    assert!(!resp.is_reply_to(Request::Echo));
+
+    let mut reserved = buf;
+    reserved[5] = 0xff;
+    let resp = Response::decode(&reserved).unwrap();
+    assert_eq!(resp.body().validate(), Err(Error::Reserved([0, 0xff])));
+    assert!(!resp.is_reply_to(Request::Echo));
+
+    let mut port = buf;
+    port[6..8].copy_from_slice(&[0, 0]);
+    let resp = Response::decode(&port).unwrap();
+    assert!(matches!(resp.body().validate(), Err(Error::RemoteAddr(_))));
+
+    let mut ip = buf;
+    ip[8..12].copy_from_slice(&[0, 0, 0, 0]);
+    let resp = Response::decode(&ip).unwrap();
+    assert!(matches!(resp.body().validate(), Err(Error::RemoteAddr(_))));
+}
+
+#[test]
+fn reply_to_another_request() {
+    let resp = Response::new(Request::Echo, body()).unwrap();
+    assert!(!resp.is_reply_to(Request::AnotherPort));
+    assert!(!resp.is_reply_to(Request::SecondEcho));
+}
@@ -0,0 +1,59 @@
+#![cfg(unix)]
+
+use ninat::sidecar::SharedSocket;
+use ninat::{Socket, RW};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::time::Duration;
+
+fn localhost(port: u16) -> SocketAddrV4 {
+    SocketAddrV4::new(Ipv4Addr::LOCALHOST, port)
+}
+
+#[test]
+fn shared_socket_receives_from_peers() {
+    let app = Socket::bind_reuse_port(localhost(0)).unwrap();
+    let local = app.local_addr().unwrap();
+
+    let peers: Vec<_> = (0..3)
+        .map(|_| UdpSocket::bind(localhost(0)).unwrap())
+        .collect();
+    let addrs: Vec<_> = peers
+        .iter()
+        .map(|peer| match peer.local_addr().unwrap() {
+            SocketAddr::V4(addr) => addr,
+            _ => unreachable!(),
+        })
+        .collect();
+    let shared = SharedSocket::bind(local, &addrs).unwrap();
+    assert_eq!(shared.local_addr().unwrap(), local);
+    shared
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .unwrap();
+
+    // Every answer reaches the shared socket rather than the application
+    let mut buffer = [0u8; 16];
+    for (peer, addr) in peers.iter().zip(addrs.iter()) {
+        peer.send_to(b"answer", local).unwrap();
+        let (size, from) = shared.recv_from(&mut buffer).unwrap();
+        assert_eq!(&buffer[..size], b"answer");
+        assert_eq!(from, *addr);
+    }
+    app.set_read_timeout(Some(Duration::from_millis(100)))
+        .unwrap();
+    let e = app.recv_from(&mut buffer).unwrap_err();
+    assert!(matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    ));
+
+    // Probes to other addresses leave from the same port
+    let other = UdpSocket::bind(localhost(0)).unwrap();
+    let other_addr = match other.local_addr().unwrap() {
+        SocketAddr::V4(addr) => addr,
+        _ => unreachable!(),
+    };
+    shared.send_to(b"probe", other_addr).unwrap();
+    let (_, from) = other.recv_from(&mut buffer).unwrap();
+    assert_eq!(from, SocketAddr::V4(local));
+}
@@ -0,0 +1,58 @@
+use ninat::topology::{AddressClass, Topology};
+use std::net::Ipv4Addr;
+
+#[test]
+fn address_class() {
+    let cases = [
+        (Ipv4Addr::new(203, 0, 114, 1), AddressClass::Public),
+        (Ipv4Addr::new(100, 64, 0, 1), AddressClass::Shared),
+        (Ipv4Addr::new(100, 127, 255, 254), AddressClass::Shared),
+        (Ipv4Addr::new(100, 128, 0, 1), AddressClass::Public),
+        (Ipv4Addr::new(10, 0, 0, 1), AddressClass::Private),
+        (Ipv4Addr::new(172, 31, 0, 1), AddressClass::Private),
+        (Ipv4Addr::new(192, 168, 1, 1), AddressClass::Private),
+        (Ipv4Addr::LOCALHOST, AddressClass::Special),
+        (Ipv4Addr::new(169, 254, 0, 1), AddressClass::Special),
+        (Ipv4Addr::new(240, 0, 0, 1), AddressClass::Special),
+    ];
+    for (ip, class) in cases.iter() {
+        assert_eq!(AddressClass::of(*ip), *class, "{}", ip);
+    }
+}
+
+#[test]
+fn topology_classify() {
+    let public = Ipv4Addr::new(203, 0, 114, 1);
+    let shared = Ipv4Addr::new(100, 64, 0, 1);
+    let host = Ipv4Addr::new(192, 168, 1, 2);
+    let router = Ipv4Addr::new(192, 168, 0, 2);
+    let lan = Ipv4Addr::new(10, 0, 0, 2);
+
+    let cases = [
+        // Host, local IP reported by the server, remote IP seen by the server, topology
+        (public, None, public, Some(Topology::NoNat)),
+        (host, Some(host), host, Some(Topology::NoNat)),
+        (host, None, public, Some(Topology::SingleNat)),
+        (host, Some(host), public, Some(Topology::SingleNat)),
+        (host, Some(public), public, Some(Topology::SingleNat)),
+        (host, Some(router), public, Some(Topology::NestedNat)),
+        (host, None, shared, Some(Topology::CarrierGradeNat)),
+        (shared, None, public, Some(Topology::CarrierGradeNat)),
+        (host, Some(shared), public, Some(Topology::CarrierGradeNat)),
+        // The servers see a private address, behind which the outer NAT is
+        (host, None, router, Some(Topology::NestedNat)),
+        (host, Some(host), lan, Some(Topology::NestedNat)),
+        (public, None, router, None),
+        (Ipv4Addr::LOCALHOST, None, router, None),
+    ];
+    for (host_ip, local_ip, remote_ip, topology) in cases.iter() {
+        assert_eq!(
+            Topology::classify(*host_ip, *local_ip, *remote_ip),
+            *topology,
+            "{} {:?} {}",
+            host_ip,
+            local_ip,
+            remote_ip
+        );
+    }
+}